@@ -0,0 +1,165 @@
+//! Benchmarks of the work every import does for a large ledger: indexing the ledger, indexing the
+//! imported transactions and comparing the fetched ones with them (for the detection of the
+//! transactions changed by the bank), and a whole import into the ledger.
+
+use beancount_gocardless_importer::{
+    dedup, import, ledger_io,
+    mapper::TransactionMapper,
+    mutations, pending, rules,
+    source::{ImportSource, SourceAccount},
+    ImportOptions, ImportState, LedgerIndex,
+};
+use beanru::types::{Account, Directive, Ledger};
+use clap::Parser;
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
+use gocardless::models::{AccountBalance, AccountTransactions, TransactionSchema};
+use rust_decimal::Decimal;
+use std::{collections::HashMap, path::Path};
+
+const TRANSACTIONS: usize = 10_000;
+
+/// Transactions fetched by an import: the newest ones of the ledger and as many new ones.
+const FETCHED: usize = 100;
+
+fn transaction(i: usize) -> TransactionSchema {
+    let day = 1 + i % 28;
+    serde_json::from_value(serde_json::json!({
+        "internalTransactionId": format!("tx-{}", i),
+        "bookingDate": format!("2024-03-{:02}", day),
+        "bookingDateTime": format!("2024-03-{:02}T10:00:00Z", day),
+        "transactionAmount": {
+            "amount": format!("-{}.{:02}", i / 100, i % 100),
+            "currency": "EUR",
+        },
+        "creditorName": "Coffee Shop",
+        "remittanceInformationUnstructured": format!("Card payment {} at Coffee Shop", i),
+    }))
+    .unwrap()
+}
+
+fn transactions(mapper: &TransactionMapper, account: &Account) -> Vec<Directive<Decimal>> {
+    (0..TRANSACTIONS)
+        .map(|i| mapper.map(&transaction(i), account).unwrap())
+        .collect()
+}
+
+/// Writes the large ledger, with the account imported from the [`Fetched`] source.
+fn write_ledger(path: &Path, directives: &[Directive<Decimal>]) {
+    let mut content = "2020-01-01 open Assets:Bank\n  importer: \"bench\"\n\n".to_string();
+    for d in directives {
+        content += &d.to_string();
+        content += "\n";
+    }
+    std::fs::write(path, content).unwrap();
+}
+
+fn read_ledger(rt: &tokio::runtime::Runtime, path: &Path) -> Ledger<Decimal> {
+    rt.block_on(ledger_io::read_ledger(path.to_path_buf()))
+        .unwrap()
+        .0
+}
+
+/// Returns the same transactions on every import.
+struct Fetched(AccountTransactions);
+
+#[async_trait::async_trait]
+impl ImportSource for Fetched {
+    fn name(&self) -> &str {
+        "bench"
+    }
+
+    async fn accounts(&self) -> anyhow::Result<Vec<String>> {
+        Ok(vec![])
+    }
+
+    async fn transactions(&self, _: &SourceAccount) -> anyhow::Result<AccountTransactions> {
+        Ok(self.0.clone())
+    }
+
+    async fn balances(&self, _: &SourceAccount) -> anyhow::Result<AccountBalance> {
+        Ok(AccountBalance::default())
+    }
+}
+
+#[derive(Parser)]
+struct Cli {
+    #[command(flatten)]
+    options: ImportOptions,
+}
+
+fn index(c: &mut Criterion) {
+    let mapper = TransactionMapper::default();
+    let account = Account("Assets:Bank".to_string());
+    let directives = transactions(&mapper, &account);
+    c.bench_function("add_recorded", |b| {
+        b.iter(|| {
+            let mut recorded = HashMap::new();
+            for d in &directives {
+                mutations::add_recorded(&mut recorded, &mapper, d);
+            }
+            recorded
+        })
+    });
+
+    let mut recorded = HashMap::new();
+    for d in &directives {
+        mutations::add_recorded(&mut recorded, &mapper, d);
+    }
+    c.bench_function("detect", |b| {
+        b.iter(|| {
+            directives
+                .iter()
+                .filter(|d| mutations::detect(&recorded, &mapper, black_box(d), &account).is_some())
+                .count()
+        })
+    });
+
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("ledger.beancount");
+    write_ledger(&path, &directives);
+    let ledger = read_ledger(&rt, &path);
+    c.bench_function("index_ledger", |b| {
+        b.iter(|| {
+            let mut dedup = dedup::from_config(None, &mapper).unwrap();
+            LedgerIndex::build(black_box(&ledger), &mapper, dedup.as_mut(), false)
+        })
+    });
+
+    let fetched: Vec<_> = (TRANSACTIONS - FETCHED..TRANSACTIONS + FETCHED)
+        .map(transaction)
+        .collect();
+    let fetched = serde_json::from_value(serde_json::json!({
+        "transactions": { "booked": fetched },
+    }))
+    .unwrap();
+    let sources: Vec<Box<dyn ImportSource>> = vec![Box::new(Fetched(fetched))];
+    let options = Cli::parse_from(["bench"]).options;
+    let rules = rules::Rules::default();
+    c.bench_function("import", |b| {
+        b.iter_batched(
+            || read_ledger(&rt, &path),
+            |mut ledger| {
+                let mut dedup = dedup::from_config(None, &mapper).unwrap();
+                let mut state = ImportState {
+                    pending: pending::State::default(),
+                    payees: None,
+                };
+                rt.block_on(import(
+                    &mut ledger,
+                    &options,
+                    &rules,
+                    &sources,
+                    &mapper,
+                    dedup.as_mut(),
+                    &mut state,
+                ))
+                .unwrap()
+            },
+            BatchSize::LargeInput,
+        )
+    });
+}
+
+criterion_group!(benches, index);
+criterion_main!(benches);
@@ -0,0 +1,65 @@
+//! The `update-accounts` command: keeps the details of the bank accounts in the ledger.
+
+use crate::{config_with_token, ledger_config, ledger_io, quota, timed};
+use beanru::types::MetadataValue;
+use gocardless::models::AccountDetail;
+use tracing::{info, warn};
+
+/// Metadata keys written on the account configuration, with the detail they come from.
+const KEYS: &[&str] = &["iban", "owner_name", "product"];
+
+/// Fetches the details of the GoCardless accounts configured in the ledger and writes them as
+/// `iban`, `owner_name` and `product` metadata of the `open` (or `custom "gocardless"`)
+/// directives. Returns the number of updated accounts.
+pub async fn update(beancount_path: &std::path::Path) -> anyhow::Result<usize> {
+    let _lock = ledger_io::lock_ledger(beancount_path)?;
+    let (mut ledger, snapshot) = ledger_io::read_ledger(beancount_path.to_path_buf()).await?;
+    let config = &config_with_token().await?;
+    let mut updated = 0;
+    for account in ledger_config::configured_accounts(&ledger)? {
+        if account.metadata_str("importer") != Some("gocardless") {
+            continue;
+        }
+        let Some(account_id) = account.metadata_str("account_id") else {
+            warn!("{} has no account_id", account.account);
+            continue;
+        };
+        let details: AccountDetail =
+            quota::counted(account_id, quota::DETAILS, |headers| async move {
+                timed("retrieve_account_details", || {
+                    quota::get(config, account_id, quota::DETAILS, &[], &headers)
+                })
+                .await
+            })
+            .await?;
+        let details = details.account;
+        let values = [&details.iban, &details.owner_name, &details.product];
+        let d = ledger_config::config_directive_mut(&mut ledger, &account)
+            .expect("the configured account has a directive");
+        let mut changed = false;
+        for (key, value) in KEYS.iter().zip(values) {
+            let Some(value) = value else {
+                continue;
+            };
+            let value = MetadataValue::String(value.clone());
+            if d.metadata.get(*key) != Some(&value) {
+                d.metadata.insert(key.to_string(), value);
+                changed = true;
+            }
+        }
+        if changed {
+            info!("updated the details of {}", account.account);
+            updated += 1;
+        }
+    }
+    if updated > 0 {
+        snapshot.check_unmodified().await?;
+        ledger
+            .write(|p, content| {
+                let content = snapshot.restore(&p, content);
+                async move { Ok(tokio::fs::write(p, content).await?) }
+            })
+            .await?;
+    }
+    Ok(updated)
+}
@@ -0,0 +1,154 @@
+//! Parsing of the amounts returned by the banks, which don't always follow the API format
+//! (`-1234.56`): some add thousands separators (`1,234.56`) or use a decimal comma (`1234,56`),
+//! configured for the account with the `decimal_separator` metadata.
+
+use anyhow::Context;
+use gocardless::models::{AccountBalance, BankTransaction, TransactionSchema};
+use rust_decimal::Decimal;
+
+/// Decimal separator of the API format.
+pub const API_SEPARATOR: char = '.';
+
+/// Parses the amount with the given decimal separator (`.` or `,`). The other one separates the
+/// thousands and is dropped, as are the spaces and apostrophes (`1'234.56`). It has to be followed
+/// by exactly three digits before the decimal separator, so an amount with the other decimal
+/// separator (`12,50` instead of `12.50`) is rejected rather than read 100 times larger.
+pub fn parse(raw: &str, decimal: char) -> anyhow::Result<Decimal> {
+    let s: String = raw
+        .chars()
+        .filter(|c| !c.is_whitespace() && *c != '\'' && *c != '+')
+        .collect();
+    let (negative, s) = match (s.strip_prefix('-'), s.strip_suffix('-')) {
+        (Some(s), _) | (_, Some(s)) => (true, s),
+        _ => (false, s.as_str()),
+    };
+    let chars: Vec<char> = s.chars().collect();
+    let mut normalized = String::with_capacity(chars.len());
+    let mut fraction = false;
+    for (i, &c) in chars.iter().enumerate() {
+        match c {
+            '.' | ',' if c == decimal => {
+                fraction = true;
+                normalized.push('.');
+            }
+            '.' | ',' => {
+                let digits = chars[i + 1..]
+                    .iter()
+                    .take_while(|c| c.is_ascii_digit())
+                    .count();
+                if fraction || i == 0 || digits != 3 {
+                    anyhow::bail!(
+                        "invalid amount {:?}: unexpected {:?} with the decimal separator {:?}",
+                        raw,
+                        c,
+                        decimal
+                    );
+                }
+            }
+            c => normalized.push(c),
+        }
+    }
+    let value = Decimal::from_str_exact(&normalized)
+        .with_context(|| format!("invalid amount {:?}", raw))?;
+    Ok(if negative { -value } else { value })
+}
+
+/// The amount of the transaction, with the transaction in the error.
+pub fn transaction(t: &TransactionSchema) -> anyhow::Result<Decimal> {
+    parse(&t.transaction_amount.amount, API_SEPARATOR).with_context(|| {
+        format!(
+            "transaction {}",
+            t.internal_transaction_id
+                .as_deref()
+                .or(t.transaction_id.as_deref())
+                .unwrap_or("without id")
+        )
+    })
+}
+
+fn normalize(amount: &mut String, decimal: char) -> anyhow::Result<()> {
+    *amount = parse(amount, decimal)?.to_string();
+    Ok(())
+}
+
+/// Rewrites the amounts of the booked and pending transactions in the API format.
+pub fn normalize_transactions(
+    transactions: &mut BankTransaction,
+    decimal: char,
+) -> anyhow::Result<()> {
+    let pending = transactions.pending.iter_mut().flatten();
+    for t in transactions.booked.iter_mut().chain(pending) {
+        let id = t.internal_transaction_id.clone();
+        let context = || format!("transaction {}", id.as_deref().unwrap_or("without id"));
+        normalize(&mut t.transaction_amount.amount, decimal).with_context(context)?;
+        if let Some(amount) = t
+            .balance_after_transaction
+            .as_mut()
+            .and_then(|b| b.balance_amount.as_mut())
+        {
+            normalize(&mut amount.amount, decimal).with_context(context)?;
+        }
+    }
+    Ok(())
+}
+
+/// Rewrites the amounts of the balances in the API format.
+pub fn normalize_balances(balances: &mut AccountBalance, decimal: char) -> anyhow::Result<()> {
+    for b in balances.balances.iter_mut().flatten() {
+        normalize(&mut b.balance_amount.amount, decimal)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dec(s: &str) -> Decimal {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn api_format() {
+        assert_eq!(parse("-1234.56", '.').unwrap(), dec("-1234.56"));
+        assert_eq!(parse("+12", '.').unwrap(), dec("12"));
+        assert_eq!(parse("1,234.56", '.').unwrap(), dec("1234.56"));
+        assert_eq!(parse("1'234.56", '.').unwrap(), dec("1234.56"));
+        assert_eq!(parse("1,234", '.').unwrap(), dec("1234"));
+    }
+
+    #[test]
+    fn decimal_comma() {
+        assert_eq!(parse("1234,56", ',').unwrap(), dec("1234.56"));
+        assert_eq!(parse("1.234,56-", ',').unwrap(), dec("-1234.56"));
+        assert_eq!(parse("1 234,56", ',').unwrap(), dec("1234.56"));
+        assert_eq!(parse("1,234", ',').unwrap(), dec("1.234"));
+        assert_eq!(parse("1.234", ',').unwrap(), dec("1234"));
+    }
+
+    #[test]
+    fn three_decimals() {
+        // Currencies like KWD or BHD have three decimals.
+        assert_eq!(parse("1.234", '.').unwrap(), dec("1.234"));
+        assert_eq!(parse("-0,125", ',').unwrap(), dec("-0.125"));
+        assert_eq!(parse("1.000,250", ',').unwrap(), dec("1000.250"));
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(parse("", '.').is_err());
+        assert!(parse("EUR 12", '.').is_err());
+    }
+
+    #[test]
+    fn ambiguous_separators() {
+        // A decimal comma when a decimal point is expected isn't taken for thousands.
+        assert!(parse("12,50", '.').is_err());
+        assert!(parse("1,2", '.').is_err());
+        assert!(parse("1,2345", '.').is_err());
+        assert!(parse(",500", '.').is_err());
+        assert!(parse("1.5,000", '.').is_err());
+        assert!(parse("12.50", ',').is_err());
+        assert_eq!(parse("1,234,567.5", '.').unwrap(), dec("1234567.5"));
+    }
+}
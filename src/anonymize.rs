@@ -0,0 +1,92 @@
+use serde_json::Value;
+use std::collections::HashMap;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum Kind {
+    Account,
+    Name,
+    Reference,
+    Id,
+}
+
+/// Kind of the personal data in the field of the API response (both the snake case and camel case
+/// names are accepted).
+fn kind(field: &str) -> Option<Kind> {
+    let field = field.replace('_', "").to_lowercase();
+    match field.as_str() {
+        "iban" | "bban" | "pan" | "maskedpan" | "msisdn" => Some(Kind::Account),
+        "creditorname" | "debtorname" | "ultimatecreditor" | "ultimatedebtor" | "ownername"
+        | "name" => Some(Kind::Name),
+        "remittanceinformationunstructured"
+        | "remittanceinformationunstructuredarray"
+        | "remittanceinformationstructured"
+        | "remittanceinformationstructuredarray"
+        | "additionalinformation"
+        | "endtoendid"
+        | "mandateid"
+        | "creditorid"
+        | "checkid"
+        | "entryreference" => Some(Kind::Reference),
+        "transactionid" | "internaltransactionid" => Some(Kind::Id),
+        _ => None,
+    }
+}
+
+/// Replaces the personal data in the API responses with fake values. The same value is always
+/// replaced with the same fake, so the relations between the transactions (e.g. payments to the
+/// same creditor, or duplicates) are kept.
+#[derive(Default)]
+pub struct Anonymizer {
+    replacements: HashMap<(Kind, String), String>,
+    counts: HashMap<Kind, usize>,
+}
+
+impl Anonymizer {
+    fn fake(&mut self, kind: Kind, original: &str) -> String {
+        if let Some(fake) = self.replacements.get(&(kind, original.to_string())) {
+            return fake.clone();
+        }
+        let count = self.counts.entry(kind).or_default();
+        *count += 1;
+        let fake = match kind {
+            Kind::Account => format!("XX00ANON{:010}", count),
+            Kind::Name => format!("Name {}", count),
+            Kind::Reference => format!("Reference {}", count),
+            Kind::Id => format!("anon-{}", count),
+        };
+        self.replacements
+            .insert((kind, original.to_string()), fake.clone());
+        fake
+    }
+
+    fn replace(&mut self, kind: Kind, value: &mut Value) {
+        match value {
+            Value::String(s) => *s = self.fake(kind, s),
+            Value::Array(values) => {
+                for v in values {
+                    self.replace(kind, v);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    pub fn anonymize(&mut self, value: &mut Value) {
+        match value {
+            Value::Object(fields) => {
+                for (field, v) in fields.iter_mut() {
+                    match kind(field) {
+                        Some(kind) => self.replace(kind, v),
+                        None => self.anonymize(v),
+                    }
+                }
+            }
+            Value::Array(values) => {
+                for v in values {
+                    self.anonymize(v);
+                }
+            }
+            _ => {}
+        }
+    }
+}
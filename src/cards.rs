@@ -0,0 +1,62 @@
+//! Cards sharing one bank account: the card of each transaction is extracted from the bank data
+//! (`card` metadata), and the transactions can be routed to per-card sub-accounts configured in the
+//! account metadata, e.g. `card_1234: Liabilities:CC:Alice`.
+
+use crate::source::SourceAccount;
+use anyhow::Context;
+use beanru::types::{Account, Directive, DirectiveContent, MetadataValue};
+use gocardless::models::TransactionSchema;
+use regex::Regex;
+use rust_decimal::Decimal;
+
+/// Masked card numbers, e.g. `516865******1234`, `XXXX XXXX XXXX 1234` or `**** 1234`. The first
+/// group is the card id.
+const DEFAULT_PATTERN: &str = r"(?:\b\d{4,6})?[*X]{2,}[\s*X]*(\d{4})\b";
+
+/// The pattern extracting the card id, from the `card_pattern` metadata (with the id in the first
+/// group, or the whole match without groups) or the default one.
+pub fn pattern(account: &SourceAccount) -> anyhow::Result<Regex> {
+    let pattern = account
+        .metadata_str("card_pattern")
+        .unwrap_or(DEFAULT_PATTERN);
+    Regex::new(pattern).with_context(|| format!("{}: invalid card_pattern", account.account))
+}
+
+/// The id of the card that made the transaction, if the bank data mentions it.
+pub fn card_id(t: &TransactionSchema, pattern: &Regex) -> Option<String> {
+    let texts = [
+        t.additional_information.as_ref(),
+        t.remittance_information_unstructured.as_ref(),
+        t.remittance_information_structured.as_ref(),
+        t.entry_reference.as_ref(),
+    ];
+    let arrays = t
+        .remittance_information_unstructured_array
+        .iter()
+        .chain(&t.remittance_information_structured_array)
+        .flatten();
+    texts.into_iter().flatten().chain(arrays).find_map(|text| {
+        let c = pattern.captures(text)?;
+        Some(c.get(1).unwrap_or(c.get(0)?).as_str().to_string())
+    })
+}
+
+/// Moves the bank posting of the transaction made with a card to the sub-account configured for
+/// the card.
+pub fn route(d: &mut Directive<Decimal>, account: &SourceAccount) {
+    let Some(MetadataValue::String(card)) = d.metadata.get("card") else {
+        return;
+    };
+    let target = match account.metadata.get(&format!("card_{}", card)) {
+        Some(MetadataValue::Account(a)) => a.clone(),
+        Some(MetadataValue::String(s)) => Account(s.clone()),
+        _ => return,
+    };
+    if let DirectiveContent::Transaction(t) = &mut d.content {
+        for p in &mut t.postings {
+            if p.account == account.account {
+                p.account = target.clone();
+            }
+        }
+    }
+}
@@ -0,0 +1,137 @@
+//! `event "gocardless"` directives recording when the access to the accounts of the ledger was
+//! granted or renewed, as an audit trail of the consents:
+//!
+//! ```beancount
+//! 2024-03-02 event "gocardless" "relinked REVOLUT_REVOGB21"
+//!   agreement: "3fa85f64-5717-4562-b3fc-2c963f66afa6"
+//!   requisition: "0d6b8a1e-7c4f-4b8e-9a51-2f0c7d3e1a90"
+//!   expires: 2024-05-31
+//! ```
+
+use crate::{config_with_token, ledger_config, metadata_str, timed};
+use anyhow::Context;
+use beanru::types::{Directive, DirectiveContent, Event, Ledger, MetadataValue};
+use chrono::DateTime;
+use rust_decimal::Decimal;
+use std::collections::{HashMap, HashSet};
+use tracing::info;
+
+/// Type of the recorded events.
+pub const EVENT_TYPE: &str = "gocardless";
+
+/// The institution of the recorded event, e.g. `REVOLUT_REVOGB21` of `linked REVOLUT_REVOGB21`.
+fn institution(value: &str) -> Option<&str> {
+    value.split_whitespace().nth(1)
+}
+
+/// Adds the events of the accepted agreements of the requisitions with accounts configured in the
+/// ledger that are not recorded yet, to the file of the first such account. The first agreement
+/// of an institution is `linked`, the following ones `relinked`. Returns the number of the events.
+pub async fn record(ledger: &mut Ledger<Decimal>) -> anyhow::Result<usize> {
+    // Account id -> the file receiving the directives of the account.
+    let mut files = HashMap::new();
+    for account in ledger_config::configured_accounts(ledger)? {
+        if account.metadata_str("importer") != Some("gocardless") {
+            continue;
+        }
+        if let Some(id) = account.metadata_str("account_id") {
+            files.insert(
+                id.to_string(),
+                ledger_config::target_file(ledger, &account)?,
+            );
+        }
+    }
+    let mut recorded = HashSet::new();
+    let mut linked = HashSet::new();
+    for file in ledger.files.values() {
+        for d in &file.directives {
+            let DirectiveContent::Event(e) = &d.content else {
+                continue;
+            };
+            if e.name != EVENT_TYPE {
+                continue;
+            }
+            if let Some(agreement) = metadata_str(d, "agreement") {
+                recorded.insert(agreement.to_string());
+            }
+            if let Some(institution) = institution(&e.value) {
+                linked.insert(institution.to_string());
+            }
+        }
+    }
+
+    let config = config_with_token().await?;
+    let res = timed("retrieve_all_requisitions", || {
+        gocardless::apis::requisitions_api::retrieve_all_requisitions(&config, None, None)
+    })
+    .await?;
+    let mut events = vec![];
+    for r in res.results.unwrap_or_default() {
+        let (Some(id), Some(agreement)) = (r.id, r.agreement) else {
+            continue;
+        };
+        let agreement = agreement.to_string();
+        let file = r
+            .accounts
+            .iter()
+            .flatten()
+            .find_map(|a| files.get(&a.to_string()));
+        let Some(file) = file else {
+            continue;
+        };
+        if recorded.contains(&agreement) {
+            continue;
+        }
+        let eua = timed("retrieve_eua_by_id", || {
+            gocardless::apis::agreements_api::retrieve_eua_by_id(&config, &agreement)
+        })
+        .await?;
+        // Not accepted yet.
+        let Some(accepted) = eua.accepted else {
+            continue;
+        };
+        let accepted = DateTime::parse_from_rfc3339(&accepted)
+            .with_context(|| format!("invalid acceptance date {:?}", accepted))?;
+        let mut metadata = HashMap::new();
+        metadata.insert("agreement".to_string(), MetadataValue::String(agreement));
+        metadata.insert(
+            "requisition".to_string(),
+            MetadataValue::String(id.to_string()),
+        );
+        if let Some(days) = eua.access_valid_for_days {
+            let expires = accepted + chrono::Duration::days(days.into());
+            metadata.insert(
+                "expires".to_string(),
+                MetadataValue::Date(expires.date_naive()),
+            );
+        }
+        events.push((file.clone(), accepted, r.institution_id, metadata));
+    }
+
+    // In the order of the acceptance, so the first agreement of an institution is the linked one.
+    events.sort_by_key(|(_, accepted, _, _)| *accepted);
+    let count = events.len();
+    for (file, accepted, institution, metadata) in events {
+        let verb = if linked.insert(institution.clone()) {
+            "linked"
+        } else {
+            "relinked"
+        };
+        info!("recording the {} agreement of {}", verb, institution);
+        let d = Directive {
+            date: accepted.date_naive(),
+            content: DirectiveContent::Event(Event {
+                name: EVENT_TYPE.to_string(),
+                value: format!("{} {}", verb, institution),
+            }),
+            metadata,
+        };
+        ledger
+            .files
+            .get_mut(&file)
+            .context("the file of the account is not in the ledger")?
+            .directives
+            .push(d);
+    }
+    Ok(count)
+}
@@ -0,0 +1,145 @@
+use crate::{
+    expiring_requisitions, import_ledger, ledger_config, ledger_io, notify::EXPIRY_WARNING_DAYS,
+    rules, ExpiringRequisition, ImportOptions,
+};
+use anyhow::Context;
+use chrono::{DateTime, Local, Utc};
+use serde::Serialize;
+use std::{
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+use tracing::{error, info, warn};
+
+/// State of the daemon, written to the status file after every sync.
+#[derive(Serialize, Default, Debug)]
+pub struct Status {
+    pub ledger: PathBuf,
+    pub started: Option<DateTime<Utc>>,
+    pub last_run: Option<DateTime<Utc>>,
+    pub last_success: Option<DateTime<Utc>>,
+    pub last_error: Option<String>,
+    pub next_run: Option<DateTime<Utc>>,
+    pub runs: u64,
+    pub failures: u64,
+    /// Requisitions whose agreement expired or expires soon.
+    pub expiring_requisitions: Vec<ExpiringRequisition>,
+}
+
+/// Parses the cron expression. The standard 5 fields (minute, hour, day of month, month, day of
+/// week) are accepted, as well as the 6 field variant with seconds.
+pub fn parse_schedule(expression: &str) -> anyhow::Result<cron::Schedule> {
+    let expression = if expression.split_whitespace().count() == 5 {
+        format!("0 {}", expression)
+    } else {
+        expression.to_string()
+    };
+    cron::Schedule::from_str(&expression)
+        .map_err(|e| anyhow::format_err!("invalid schedule {:?}: {}", expression, e))
+}
+
+async fn write_status(path: &Path, status: &Status) -> anyhow::Result<()> {
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    tokio::fs::write(path, serde_json::to_string_pretty(status)?)
+        .await
+        .with_context(|| format!("failed to write {}", path.display()))
+}
+
+/// How often the rules file is checked for changes between the syncs.
+const RULES_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The rules file of the imports, if the ledger can be read and configures one.
+async fn rules_watcher(beancount_path: &Path, options: &ImportOptions) -> Option<rules::Watcher> {
+    let (ledger, _) = ledger_io::read_ledger(beancount_path.to_path_buf())
+        .await
+        .ok()?;
+    let path = ledger_config::rules_path(options.rules.as_deref(), &ledger).ok()??;
+    Some(match rules::Rules::load(Some(&path)).await {
+        Ok(rules) => rules::Watcher::new(rules.files()),
+        Err(_) => rules::Watcher::new(&[path]),
+    })
+}
+
+/// Sleeps until the time. Meanwhile, a changed rules file is loaded right away, so the mistakes
+/// show up in the log before the next sync, which reads the rules again.
+async fn wait(until: DateTime<Utc>, mut watcher: Option<rules::Watcher>) {
+    loop {
+        let left = (until - Utc::now()).to_std().unwrap_or_default();
+        if left.is_zero() {
+            return;
+        }
+        tokio::time::sleep(left.min(RULES_POLL_INTERVAL)).await;
+        let Some(watcher) = &mut watcher else {
+            continue;
+        };
+        if watcher.changed() {
+            match rules::Rules::load(Some(watcher.path())).await {
+                Ok(rules) => {
+                    info!(
+                        rules = %watcher.path().display(),
+                        "reloaded the changed rules, the next sync uses them"
+                    );
+                    *watcher = rules::Watcher::new(rules.files());
+                }
+                Err(e) => warn!("the changed rules failed to load: {:#}", e),
+            }
+        }
+    }
+}
+
+/// Imports into the ledger on the schedule, until the process is stopped. Failed imports are
+/// logged and retried at the next scheduled time. To stay within the API limits (a few requests
+/// per account per day), scheduled times less than `24h / max_syncs_per_day` after the previous
+/// sync are skipped.
+pub async fn run(
+    beancount_path: &Path,
+    options: &ImportOptions,
+    schedule: &cron::Schedule,
+    max_syncs_per_day: u32,
+    status_path: &Path,
+) -> anyhow::Result<()> {
+    let min_interval = chrono::Duration::seconds(86400 / i64::from(max_syncs_per_day.max(1)));
+    let mut status = Status {
+        ledger: beancount_path.to_path_buf(),
+        started: Some(Utc::now()),
+        ..Default::default()
+    };
+    for next in schedule.upcoming(Local) {
+        let next = next.with_timezone(&Utc);
+        if let Some(last) = status.last_run {
+            if next - last < min_interval {
+                info!(%next, "skipping the scheduled sync, too soon after the previous one");
+                continue;
+            }
+        }
+        status.next_run = Some(next);
+        write_status(status_path, &status).await?;
+        info!(%next, "waiting for the next sync");
+        wait(next, rules_watcher(beancount_path, options).await).await;
+
+        status.last_run = Some(Utc::now());
+        status.runs += 1;
+        if !options.offline {
+            match expiring_requisitions(EXPIRY_WARNING_DAYS).await {
+                Ok(expiring) => status.expiring_requisitions = expiring,
+                Err(e) => warn!("failed to check the agreements: {:#}", e),
+            }
+        }
+        match import_ledger(beancount_path, options).await {
+            Ok(_) => {
+                info!(ledger = %beancount_path.display(), "sync finished");
+                status.last_success = status.last_run;
+                status.last_error = None;
+            }
+            Err(e) => {
+                error!(ledger = %beancount_path.display(), "sync failed: {:#}", e);
+                status.failures += 1;
+                status.last_error = Some(format!("{:#}", e));
+            }
+        }
+    }
+    anyhow::bail!("the schedule has no upcoming times")
+}
@@ -0,0 +1,173 @@
+use anyhow::Context;
+use beanru::types::{Account, Commodity, Currency, Directive, DirectiveContent, Ledger, Open};
+use chrono::NaiveDate;
+use rust_decimal::Decimal;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    path::{Path, PathBuf},
+};
+use tracing::info;
+
+fn currencies(d: &Directive<Decimal>) -> Vec<&Currency> {
+    match &d.content {
+        DirectiveContent::Transaction(t) => t
+            .postings
+            .iter()
+            .filter_map(|p| p.amount.as_ref().map(|a| &a.currency))
+            .collect(),
+        DirectiveContent::Balance(b) => vec![&b.amount.currency],
+        DirectiveContent::Price(p) => vec![&p.currency, &p.amount.currency],
+        _ => vec![],
+    }
+}
+
+fn same_file(a: &Path, b: &Path) -> bool {
+    match (std::fs::canonicalize(a), std::fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+/// Finds the key of the given file in the ledger.
+fn resolve_target(
+    ledger: &Ledger<Decimal>,
+    target: Option<&Path>,
+) -> anyhow::Result<Option<PathBuf>> {
+    let Some(target) = target else {
+        return Ok(None);
+    };
+    let path = ledger
+        .files
+        .keys()
+        .find(|p| same_file(p, target))
+        .cloned()
+        .ok_or_else(|| {
+            anyhow::format_err!(
+                "{} is not part of the ledger, include it from the main file",
+                target.display()
+            )
+        })?;
+    Ok(Some(path))
+}
+
+/// Adds `commodity` directives for the currencies used by the new directives (the ones after
+/// `original_len` in each file) that are not declared anywhere in the ledger.
+///
+/// The declarations are added to the `target` file (which has to be part of the ledger), or to
+/// the file where the currency was used for the first time.
+pub fn declare_commodities(
+    ledger: &mut Ledger<Decimal>,
+    original_len: &HashMap<PathBuf, usize>,
+    target: Option<&Path>,
+) -> anyhow::Result<()> {
+    let mut declared: HashSet<Currency> = HashSet::new();
+    for file in ledger.files.values() {
+        for d in &file.directives {
+            if let DirectiveContent::Commodity(c) = &d.content {
+                declared.insert(c.currency.clone());
+            }
+        }
+    }
+
+    // currency -> (first date of use, file)
+    let mut missing: BTreeMap<Currency, (NaiveDate, PathBuf)> = BTreeMap::new();
+    for (path, file) in &ledger.files {
+        let start = original_len.get(path).copied().unwrap_or_default();
+        for d in file.directives.iter().skip(start) {
+            for c in currencies(d) {
+                if declared.contains(c) {
+                    continue;
+                }
+                let e = missing.entry(c.clone()).or_insert((d.date, path.clone()));
+                if d.date < e.0 {
+                    e.0 = d.date;
+                }
+            }
+        }
+    }
+    if missing.is_empty() {
+        return Ok(());
+    }
+
+    let target = resolve_target(ledger, target)?;
+    for (currency, (date, path)) in missing {
+        info!("Declaring commodity {}", currency);
+        let path = target.clone().unwrap_or(path);
+        let file = ledger
+            .files
+            .get_mut(&path)
+            .with_context(|| format!("{} is not part of the ledger", path.display()))?;
+        file.directives.push(Directive {
+            date,
+            content: DirectiveContent::Commodity(Commodity { currency }),
+            metadata: Default::default(),
+        });
+    }
+    Ok(())
+}
+
+/// Adds `open` directives for the accounts used by the new directives (e.g. the counter accounts
+/// assigned by the rules) that are not opened anywhere in the ledger. The accounts are opened at
+/// the date of the first use.
+///
+/// The directives are added to the `target` file (which has to be part of the ledger), or to the
+/// file where the account was used for the first time.
+pub fn open_accounts(
+    ledger: &mut Ledger<Decimal>,
+    original_len: &HashMap<PathBuf, usize>,
+    target: Option<&Path>,
+) -> anyhow::Result<()> {
+    let mut opened: HashSet<Account> = HashSet::new();
+    for file in ledger.files.values() {
+        for d in &file.directives {
+            if let DirectiveContent::Open(o) = &d.content {
+                opened.insert(o.account.clone());
+            }
+        }
+    }
+
+    // account -> (first date of use, file)
+    let mut missing: BTreeMap<Account, (NaiveDate, PathBuf)> = BTreeMap::new();
+    for (path, file) in &ledger.files {
+        let start = original_len.get(path).copied().unwrap_or_default();
+        for d in file.directives.iter().skip(start) {
+            let DirectiveContent::Transaction(t) = &d.content else {
+                continue;
+            };
+            for p in &t.postings {
+                if opened.contains(&p.account) {
+                    continue;
+                }
+                let e = missing
+                    .entry(p.account.clone())
+                    .or_insert((d.date, path.clone()));
+                if d.date < e.0 {
+                    e.0 = d.date;
+                }
+            }
+        }
+    }
+    if missing.is_empty() {
+        return Ok(());
+    }
+
+    let target = resolve_target(ledger, target)?;
+    for (account, (date, path)) in missing {
+        info!("Opening account {}", account);
+        let path = target.clone().unwrap_or(path);
+        let file = ledger
+            .files
+            .get_mut(&path)
+            .with_context(|| format!("{} is not part of the ledger", path.display()))?;
+        file.directives.push(Directive {
+            date,
+            content: DirectiveContent::Open(Open {
+                account,
+                currencies: vec![],
+                booking_method: None,
+            }),
+            metadata: Default::default(),
+        });
+    }
+    Ok(())
+}
@@ -0,0 +1,315 @@
+//! Detection of the fetched transactions that are already in the ledger. By default, the
+//! transactions are matched by the internal transaction id, kept in the `^id-...` link or in the
+//! metadata key of the mapper; other strategies can be selected with `custom "gocardless" "dedup"`
+//! or provided by library users.
+
+use crate::{is_duplicate, mapper::TransactionMapper};
+use beanru::types::{Account, Directive, DirectiveContent, MetadataValue};
+use rust_decimal::Decimal;
+use std::{
+    collections::{HashMap, HashSet},
+    hash::{Hash, Hasher},
+};
+
+pub trait DedupStrategy: Send {
+    /// Remembers the transaction of the ledger. The transactions imported by the run are not
+    /// added, so they are not compared with each other.
+    fn add(&mut self, d: &Directive<Decimal>);
+
+    /// Whether the transaction converted for the account is already in the ledger.
+    fn is_duplicate(&self, d: &Directive<Decimal>, account: &Account) -> bool;
+}
+
+/// Matches the transactions by the link with the internal transaction id (the default).
+pub struct LinkId {
+    prefix: String,
+    ids: HashSet<String>,
+}
+
+impl LinkId {
+    pub fn new(prefix: &str) -> LinkId {
+        LinkId {
+            prefix: prefix.to_string(),
+            ids: HashSet::new(),
+        }
+    }
+}
+
+impl DedupStrategy for LinkId {
+    fn add(&mut self, d: &Directive<Decimal>) {
+        if let Some(t) = d.content.transaction_opt() {
+            self.ids.extend(
+                t.links
+                    .iter()
+                    .filter(|l| l.starts_with(&self.prefix))
+                    .cloned(),
+            );
+        }
+    }
+
+    fn is_duplicate(&self, d: &Directive<Decimal>, _: &Account) -> bool {
+        is_duplicate(d, &self.ids)
+    }
+}
+
+/// Matches the transactions by the value of a metadata key (e.g. an id kept in the metadata).
+pub struct MetadataKey {
+    key: String,
+    values: HashSet<String>,
+}
+
+impl MetadataKey {
+    pub fn new(key: &str) -> MetadataKey {
+        MetadataKey {
+            key: key.to_string(),
+            values: HashSet::new(),
+        }
+    }
+
+    fn value<'a>(&self, d: &'a Directive<Decimal>) -> Option<&'a str> {
+        match d.metadata.get(&self.key)? {
+            MetadataValue::String(s) => Some(s),
+            _ => None,
+        }
+    }
+}
+
+impl DedupStrategy for MetadataKey {
+    fn add(&mut self, d: &Directive<Decimal>) {
+        if let Some(v) = self.value(d) {
+            self.values.insert(v.to_string());
+        }
+    }
+
+    fn is_duplicate(&self, d: &Directive<Decimal>, _: &Account) -> bool {
+        self.value(d).is_some_and(|v| self.values.contains(v))
+    }
+}
+
+/// (account, amount) of each posting of the transaction with an amount.
+fn postings(d: &Directive<Decimal>) -> Vec<(&Account, String)> {
+    let DirectiveContent::Transaction(t) = &d.content else {
+        return vec![];
+    };
+    t.postings
+        .iter()
+        .filter_map(|p| {
+            let a = p.amount.as_ref()?;
+            Some((
+                &p.account,
+                format!("{} {}", a.value.normalize(), a.currency.0),
+            ))
+        })
+        .collect()
+}
+
+fn narration(d: &Directive<Decimal>) -> String {
+    d.content
+        .transaction_opt()
+        .and_then(|t| t.narration.as_deref())
+        .unwrap_or_default()
+        .to_lowercase()
+}
+
+/// Matches the transactions by a hash of the date, the amount of the account and the narration,
+/// for banks without stable transaction ids. Identical transactions on the same day (e.g. two
+/// coffees) are both imported when they are fetched together, but not when one of them is already
+/// in the ledger.
+#[derive(Default)]
+pub struct ContentHash {
+    hashes: HashSet<u64>,
+}
+
+impl ContentHash {
+    fn hash(d: &Directive<Decimal>, account: &Account, amount: &str) -> u64 {
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        (d.date, &account.0, amount, narration(d)).hash(&mut hasher);
+        hasher.finish()
+    }
+}
+
+impl DedupStrategy for ContentHash {
+    fn add(&mut self, d: &Directive<Decimal>) {
+        for (account, amount) in postings(d) {
+            self.hashes.insert(ContentHash::hash(d, account, &amount));
+        }
+    }
+
+    fn is_duplicate(&self, d: &Directive<Decimal>, account: &Account) -> bool {
+        postings(d)
+            .into_iter()
+            .filter(|(a, _)| *a == account)
+            .any(|(a, amount)| self.hashes.contains(&ContentHash::hash(d, a, &amount)))
+    }
+}
+
+/// Matches the transactions with the same amount of the account booked at most a few days apart
+/// (e.g. when the bank changes the booking date), ignoring the narration.
+pub struct Fuzzy {
+    days: i64,
+    dates: HashMap<(Account, String), Vec<chrono::NaiveDate>>,
+}
+
+impl Fuzzy {
+    pub fn new(days: i64) -> Fuzzy {
+        Fuzzy {
+            days,
+            dates: HashMap::new(),
+        }
+    }
+}
+
+impl DedupStrategy for Fuzzy {
+    fn add(&mut self, d: &Directive<Decimal>) {
+        for (account, amount) in postings(d) {
+            self.dates
+                .entry((account.clone(), amount))
+                .or_default()
+                .push(d.date);
+        }
+    }
+
+    fn is_duplicate(&self, d: &Directive<Decimal>, account: &Account) -> bool {
+        postings(d)
+            .into_iter()
+            .filter(|(a, _)| *a == account)
+            .any(|(a, amount)| {
+                self.dates.get(&(a.clone(), amount)).is_some_and(|dates| {
+                    dates
+                        .iter()
+                        .any(|date| (*date - d.date).num_days().abs() <= self.days)
+                })
+            })
+    }
+}
+
+/// Default window of the fuzzy matching.
+pub const DEFAULT_FUZZY_DAYS: i64 = 3;
+
+/// The strategy configured in the ledger: `link`, `hash`, `fuzzy` (or `fuzzy:<days>`) or
+/// `metadata:<key>`. Without one, the transactions are matched by the id where the mapper keeps it.
+pub fn from_config(
+    config: Option<&str>,
+    mapper: &TransactionMapper,
+) -> anyhow::Result<Box<dyn DedupStrategy>> {
+    let link_prefix = mapper.link_prefix();
+    let Some(config) = config else {
+        return Ok(match mapper.id_metadata() {
+            Some(key) => Box::new(MetadataKey::new(key)),
+            None => Box::new(LinkId::new(link_prefix)),
+        });
+    };
+    let (name, arg) = match config.split_once(':') {
+        Some((name, arg)) => (name, Some(arg)),
+        None => (config, None),
+    };
+    Ok(match (name, arg) {
+        ("link", None) => Box::new(LinkId::new(link_prefix)),
+        ("hash", None) => Box::new(ContentHash::default()),
+        ("fuzzy", None) => Box::new(Fuzzy::new(DEFAULT_FUZZY_DAYS)),
+        ("fuzzy", Some(days)) => {
+            Box::new(Fuzzy::new(days.parse().map_err(|_| {
+                anyhow::format_err!("invalid number of days {:?}", days)
+            })?))
+        }
+        ("metadata", Some(key)) if !key.is_empty() => Box::new(MetadataKey::new(key)),
+        _ => anyhow::bail!(
+            "unknown deduplication strategy {:?}, use link, hash, fuzzy[:<days>] or metadata:<key>",
+            config
+        ),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{bank as account, transaction};
+
+    fn directive(
+        mapper: &TransactionMapper,
+        id: &str,
+        date: &str,
+        amount: &str,
+        narration: &str,
+    ) -> Directive<Decimal> {
+        let fields = serde_json::json!({ "remittanceInformationUnstructured": narration });
+        mapper
+            .map(&transaction(id, date, amount, fields), &account())
+            .unwrap()
+    }
+
+    /// Whether the strategy takes the transactions for duplicates of the one in the ledger.
+    fn duplicates(
+        strategy: &mut dyn DedupStrategy,
+        mapper: &TransactionMapper,
+        fetched: &[(&str, &str, &str, &str)],
+    ) -> Vec<bool> {
+        strategy.add(&directive(mapper, "t1", "2024-03-05", "-3.20", "Coffee"));
+        fetched
+            .iter()
+            .map(|(id, date, amount, narration)| {
+                strategy.is_duplicate(&directive(mapper, id, date, amount, narration), &account())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn matches_by_id() {
+        let mapper = TransactionMapper::default();
+        let fetched = [
+            ("t1", "2024-03-06", "-3.50", "Coffee shop"),
+            ("t2", "2024-03-05", "-3.20", "Coffee"),
+        ];
+        let mut strategy = from_config(None, &mapper).unwrap();
+        assert_eq!(
+            duplicates(strategy.as_mut(), &mapper, &fetched),
+            vec![true, false]
+        );
+
+        // Where the mapper keeps the id.
+        let mapper = TransactionMapper::builder().id_metadata("bank_id").build();
+        let mut strategy = from_config(None, &mapper).unwrap();
+        assert_eq!(
+            duplicates(strategy.as_mut(), &mapper, &fetched),
+            vec![true, false]
+        );
+    }
+
+    #[test]
+    fn matches_by_content() {
+        let mapper = TransactionMapper::default();
+        let fetched = [
+            ("t2", "2024-03-05", "-3.20", "COFFEE"),
+            ("t3", "2024-03-06", "-3.20", "Coffee"),
+            ("t4", "2024-03-05", "-3.50", "Coffee"),
+        ];
+        let mut strategy = from_config(Some("hash"), &mapper).unwrap();
+        assert_eq!(
+            duplicates(strategy.as_mut(), &mapper, &fetched),
+            vec![true, false, false]
+        );
+    }
+
+    #[test]
+    fn matches_close_dates() {
+        let mapper = TransactionMapper::default();
+        let fetched = [
+            ("t2", "2024-03-07", "-3.20", "Card payment"),
+            ("t3", "2024-03-09", "-3.20", "Coffee"),
+            ("t4", "2024-03-05", "-3.50", "Coffee"),
+        ];
+        let mut strategy = from_config(Some("fuzzy:2"), &mapper).unwrap();
+        assert_eq!(
+            duplicates(strategy.as_mut(), &mapper, &fetched),
+            vec![true, false, false]
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_strategies() {
+        let mapper = TransactionMapper::default();
+        for config in ["fuzzy:soon", "metadata:", "metadata", "link:x", "exact"] {
+            assert!(from_config(Some(config), &mapper).is_err(), "{}", config);
+        }
+    }
+}
@@ -0,0 +1,257 @@
+//! The `doctor` command: checks the whole setup and suggests fixes for the problems.
+
+use crate::{
+    api_config, get_token, ledger_config, ledger_io, notify::EXPIRY_WARNING_DAYS, output,
+    requisitions, rules, settings, source, timed, ImportOptions,
+};
+use console::style;
+use std::{collections::HashSet, path::Path};
+
+#[derive(Default)]
+struct Report {
+    problems: usize,
+}
+
+impl Report {
+    fn ok(&self, check: &str) {
+        println!("{} {}", style("✓").green(), check);
+    }
+
+    fn warn(&self, check: &str, fix: &str) {
+        println!("{} {}\n    {}", style("!").yellow(), check, fix);
+    }
+
+    fn fail(&mut self, check: &str, fix: &str) {
+        self.problems += 1;
+        println!("{} {}\n    {}", style("✗").red(), check, fix);
+    }
+}
+
+/// Checks the token, the requisitions and, if given, the ledger and the rules. Fails when any
+/// problems were found.
+pub async fn run(beancount_path: Option<&Path>, rules_path: Option<&Path>) -> anyhow::Result<()> {
+    let mut report = Report::default();
+
+    // Account ids connected through the requisitions, if they could be listed.
+    let mut connected: Option<HashSet<String>> = None;
+    let mut cache = requisitions::Cache::default();
+    match get_token().await {
+        Err(e) => report.fail(
+            &format!("No valid access token: {:#}", e),
+            "Sign in with `sign-in <secret-id> <secret-key>` (or `init`).",
+        ),
+        Ok(token) => {
+            report.ok("Access token is valid");
+            let config = gocardless::apis::configuration::Configuration {
+                bearer_access_token: Some(token),
+                ..api_config()?
+            };
+            match timed("retrieve_all_requisitions", || {
+                gocardless::apis::requisitions_api::retrieve_all_requisitions(&config, None, None)
+            })
+            .await
+            {
+                Err(e) => report.fail(
+                    &format!("Failed to list the requisitions: {:#}", e),
+                    "Check the network connection and the `api_url` setting.",
+                ),
+                Ok(res) => {
+                    let mut ids = HashSet::new();
+                    let listed = res.results.unwrap_or_default();
+                    cache = requisitions::refresh(&config, &listed).await;
+                    for r in listed {
+                        let id = r.id.map(|id| id.to_string()).unwrap_or_default();
+                        let name = format!("Requisition {} ({})", id, r.institution_id);
+                        let renew = format!(
+                            "Renew it with `create-requisition {}` and remove it with \
+                             `delete-requisition {}`.",
+                            r.institution_id, id
+                        );
+                        let status = output::requisition_status(r.status);
+                        let expires = cache.agreement_expiry(&config, &r).await.ok().flatten();
+                        let days = expires.map(|e| (e - chrono::Utc::now()).num_days());
+                        match r.status.and_then(|s| serde_json::to_value(s).ok()) {
+                            Some(s) if s == "LN" => match days {
+                                Some(d) if d < 0 => {
+                                    report.fail(&format!("{}: the access expired", name), &renew)
+                                }
+                                Some(d) if d < EXPIRY_WARNING_DAYS => report.warn(
+                                    &format!("{}: the access expires in {} day(s)", name, d),
+                                    &renew,
+                                ),
+                                _ => report.ok(&format!("{}: {}", name, status)),
+                            },
+                            Some(s) if s == "EX" || s == "RJ" || s == "SU" => {
+                                report.fail(&format!("{}: {}", name, status), &renew)
+                            }
+                            _ => report.warn(
+                                &format!("{}: {}", name, status),
+                                "Finish the authorization with the link from \
+                                 `list-requisitions`, or delete the requisition.",
+                            ),
+                        }
+                        ids.extend(r.accounts.iter().flatten().map(|a| a.to_string()));
+                    }
+                    connected = Some(ids);
+                }
+            }
+        }
+    }
+
+    let Some(beancount_path) = beancount_path else {
+        check_rules(&mut report, rules_path).await;
+        return finish(report);
+    };
+    let ledger = match ledger_io::read_ledger(beancount_path.to_path_buf()).await {
+        Ok((ledger, _)) => {
+            report.ok(&format!("Ledger {} parses", beancount_path.display()));
+            ledger
+        }
+        Err(e) => {
+            report.fail(
+                &format!("Failed to read {}: {:#}", beancount_path.display(), e),
+                "Fix the syntax error (`bean-check` shows more details).",
+            );
+            check_rules(&mut report, rules_path).await;
+            return finish(report);
+        }
+    };
+
+    match ledger_config::configured_accounts(&ledger) {
+        Err(e) => report.fail(
+            &format!("Invalid importer configuration: {:#}", e),
+            "Fix the `custom \"gocardless\"` directives or the `open` metadata.",
+        ),
+        Ok(accounts) => {
+            let imported: HashSet<&str> = accounts
+                .iter()
+                .filter(|a| a.metadata_str("importer") == Some("gocardless"))
+                .filter_map(|a| a.metadata_str("account_id"))
+                .collect();
+            if connected.is_some() {
+                for a in cache.unimported(&imported) {
+                    report.warn(
+                        &format!("Linked account {} is not imported into the ledger", a),
+                        "Configure it with the `account_id` metadata, or delete its requisition \
+                         if it's no longer needed.",
+                    );
+                }
+            }
+            let mut options = ImportOptions::default();
+            settings::get().apply(&mut options);
+            let sources = match source::all(&options).await {
+                Ok(sources) => sources,
+                Err(e) => {
+                    report.fail(
+                        &format!("Failed to set up the importers: {:#}", e),
+                        "Check the CSV profiles file and the local store in `~/.gocardless`.",
+                    );
+                    vec![]
+                }
+            };
+            let names: Vec<&str> = sources.iter().map(|s| s.name()).collect();
+            for a in &accounts {
+                let importer = a.metadata_str("importer").unwrap_or_default();
+                let Some(source) = source::find(&sources, importer) else {
+                    report.fail(
+                        &format!("{}: unknown importer {:?}", a.account, importer),
+                        &format!("Use one of {}.", names.join(", ")),
+                    );
+                    continue;
+                };
+                if let Err(e) = ledger_config::target_file(&ledger, a) {
+                    report.fail(
+                        &format!("{}: {:#}", a.account, e),
+                        "Include the target file in the ledger, or fix `target_file`.",
+                    );
+                    continue;
+                }
+                if let Some((key, extensions)) = source.statement_files() {
+                    match source::statement::files(a, key, extensions) {
+                        Ok(files) if files.iter().all(|f| f.exists()) => {
+                            report.ok(&format!("{}: {} statement file(s)", a.account, files.len()))
+                        }
+                        Ok(_) => report.fail(
+                            &format!("{}: the {} doesn't exist", a.account, key),
+                            "Fix the path (relative to the ledger file).",
+                        ),
+                        Err(e) => report.fail(
+                            &format!("{}: {:#}", a.account, e),
+                            &format!("Add the `{}` metadata.", key),
+                        ),
+                    }
+                    continue;
+                }
+                match (a.metadata_str("account_id"), &connected) {
+                    (None, _) => report.fail(
+                        &format!("{}: no account_id", a.account),
+                        "Add the `account_id` metadata (see `list-requisitions`).",
+                    ),
+                    (Some(id), Some(connected)) if !connected.contains(id) => {
+                        // The bank may have issued a new account ID (e.g. after reconnecting).
+                        let same_iban: Vec<&str> = a
+                            .metadata_str("iban")
+                            .into_iter()
+                            .flat_map(|iban| cache.accounts_with_iban(iban))
+                            .map(|c| c.id.as_str())
+                            .collect();
+                        let hint = match same_iban.as_slice() {
+                            [new_id] => format!(
+                                "The account with the same IBAN is connected as {}, update the \
+                                 `account_id`.",
+                                new_id
+                            ),
+                            _ => "Fix the `account_id` (see `list-requisitions`) or connect the \
+                                  bank with `create-requisition`."
+                                .to_string(),
+                        };
+                        report.fail(
+                            &format!(
+                                "{}: account {} is not connected by any requisition",
+                                a.account, id
+                            ),
+                            &hint,
+                        )
+                    }
+                    (Some(id), Some(_)) => {
+                        report.ok(&format!("{}: account {} is connected", a.account, id))
+                    }
+                    (Some(_), None) => {}
+                }
+            }
+        }
+    }
+
+    match ledger_config::rules_path(rules_path, &ledger) {
+        Ok(path) => check_rules(&mut report, path.as_deref()).await,
+        Err(e) => report.fail(
+            &format!("Invalid importer configuration: {:#}", e),
+            "Fix the `custom \"gocardless\"` directives.",
+        ),
+    }
+    finish(report)
+}
+
+async fn check_rules(report: &mut Report, path: Option<&Path>) {
+    let path = path
+        .map(|p| p.to_path_buf())
+        .or_else(|| crate::settings::get().rules.clone());
+    let Some(path) = path else {
+        return;
+    };
+    match rules::Rules::load(Some(&path)).await {
+        Ok(_) => report.ok(&format!("Rules {} parse", path.display())),
+        Err(e) => report.fail(
+            &format!("Invalid rules {}: {:#}", path.display(), e),
+            "Fix the rules file (see README.md for the format).",
+        ),
+    }
+}
+
+fn finish(report: Report) -> anyhow::Result<()> {
+    if report.problems > 0 {
+        anyhow::bail!("found {} problem(s)", report.problems);
+    }
+    println!("No problems found");
+    Ok(())
+}
@@ -0,0 +1,85 @@
+//! `document` directives for the statements of the account (e.g. the monthly PDF statements
+//! downloaded from the online banking), kept in the directory of the `statements_dir` metadata.
+
+use crate::source::{statement, SourceAccount};
+use beanru::types::{Directive, DirectiveContent, Document};
+use chrono::NaiveDate;
+use regex::Regex;
+use rust_decimal::Decimal;
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+
+/// Extensions of the statement files.
+const EXTENSIONS: &[&str] = &["pdf"];
+
+/// The date in the file name, e.g. `2024-03-31` or `2024-03` (a monthly statement).
+fn date_re() -> &'static Regex {
+    static RE: OnceLock<Regex> = OnceLock::new();
+    RE.get_or_init(|| Regex::new(r"(\d{4})-(\d{2})(?:-(\d{2}))?").unwrap())
+}
+
+/// The date of the statement: from the file name (the last day of the month without the day), or
+/// the day it was last modified.
+fn date(path: &Path) -> anyhow::Result<NaiveDate> {
+    let name = path
+        .file_name()
+        .map(|n| n.to_string_lossy())
+        .unwrap_or_default();
+    if let Some(c) = date_re().captures(&name) {
+        let year = c[1].parse().unwrap_or_default();
+        let month = c[2].parse().unwrap_or_default();
+        let date = match c.get(3) {
+            Some(day) => NaiveDate::from_ymd_opt(year, month, day.as_str().parse()?),
+            None => NaiveDate::from_ymd_opt(year, month, 1)
+                .and_then(|d| d.checked_add_months(chrono::Months::new(1)))
+                .and_then(|d| d.pred_opt()),
+        };
+        if let Some(date) = date {
+            return Ok(date);
+        }
+    }
+    let modified: chrono::DateTime<chrono::Local> = std::fs::metadata(path)?.modified()?.into();
+    Ok(modified.date_naive())
+}
+
+/// The path written in the directive: relative to the ledger file receiving it, if it is in the
+/// same directory tree.
+fn relative(path: &Path, file: &Path) -> String {
+    let relative = file
+        .parent()
+        .and_then(|dir| path.strip_prefix(dir).ok())
+        .unwrap_or(path);
+    relative.to_string_lossy().to_string()
+}
+
+/// The `document` directives of the statements of the account that are not in the ledger yet
+/// (`existing` are the paths of the documents of the ledger, resolved as with
+/// `ledger_config::resolve`), for the ledger file `file`.
+pub fn new_documents(
+    account: &SourceAccount,
+    existing: &HashSet<PathBuf>,
+    file: &Path,
+) -> anyhow::Result<Vec<Directive<Decimal>>> {
+    if account.metadata_str("statements_dir").is_none() {
+        return Ok(vec![]);
+    }
+    let mut documents = vec![];
+    for path in statement::files(account, "statements_dir", EXTENSIONS)? {
+        if existing.contains(&path) || !path.is_file() {
+            continue;
+        }
+        documents.push(Directive {
+            date: date(&path)?,
+            content: DirectiveContent::Document(Document {
+                account: account.account.clone(),
+                path: relative(&path, file),
+            }),
+            metadata: Default::default(),
+        });
+    }
+    documents.sort_by_key(|d| d.date);
+    Ok(documents)
+}
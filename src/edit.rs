@@ -0,0 +1,84 @@
+//! `import --edit`: the new directives are reviewed in the editor before they are imported.
+
+use crate::render_directive;
+use anyhow::Context;
+use beanru::types::Ledger;
+use rust_decimal::Decimal;
+use std::{collections::HashMap, path::PathBuf};
+
+/// The editor of the user: `$VISUAL`, `$EDITOR` or `vi`.
+fn editor() -> String {
+    ["VISUAL", "EDITOR"]
+        .iter()
+        .filter_map(|v| std::env::var(v).ok())
+        .find(|e| !e.trim().is_empty())
+        .unwrap_or_else(|| "vi".to_string())
+}
+
+/// Writes the directives added to each file (after `original_len`) to temporary files, opens them
+/// in the editor and replaces the added directives with whatever remains in the files after the
+/// editor exits.
+pub async fn edit_new_directives(
+    ledger: &mut Ledger<Decimal>,
+    original_len: &HashMap<PathBuf, usize>,
+) -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let mut targets: Vec<&PathBuf> = ledger
+        .files
+        .iter()
+        .filter(|(p, f)| f.directives.len() > original_len[*p])
+        .map(|(p, _)| p)
+        .collect();
+    if targets.is_empty() {
+        return Ok(());
+    }
+    targets.sort();
+    let mut edited = vec![];
+    for (i, p) in targets.into_iter().enumerate() {
+        let name = p.file_name().unwrap_or_default().to_string_lossy();
+        let tmp = dir.path().join(format!("{}-{}", i + 1, name));
+        let mut content = format!(
+            "; New directives for {}.\n; Delete or edit them, then save and close the editor.\n",
+            p.display()
+        );
+        for d in &ledger.files[p].directives[original_len[p]..] {
+            content += &format!("\n{}\n", render_directive(d, &HashMap::new()));
+        }
+        tokio::fs::write(&tmp, content).await?;
+        edited.push((p.clone(), tmp));
+    }
+
+    let editor = editor();
+    let status = tokio::process::Command::new("sh")
+        .arg("-c")
+        .arg(format!("{} \"$@\"", editor))
+        .arg("sh")
+        .args(edited.iter().map(|(_, tmp)| tmp))
+        .status()
+        .await
+        .with_context(|| format!("failed to run the editor {:?}", editor))?;
+    if !status.success() {
+        anyhow::bail!(
+            "the editor {:?} exited with {}, the ledger was not modified",
+            editor,
+            status
+        );
+    }
+
+    for (p, tmp) in edited {
+        let reviewed = Ledger::<Decimal>::read(tmp.clone(), |f| async move {
+            Ok(tokio::fs::read_to_string(f).await?)
+        })
+        .await
+        .with_context(|| format!("failed to parse the edited directives for {}", p.display()))?;
+        let directives = reviewed
+            .files
+            .into_values()
+            .flat_map(|f| f.directives)
+            .collect::<Vec<_>>();
+        let file = ledger.files.get_mut(&p).unwrap();
+        file.directives.truncate(original_len[&p]);
+        file.directives.extend(directives);
+    }
+    Ok(())
+}
@@ -0,0 +1,140 @@
+//! Error type of the library entry points (`import_ledger`, `sign_in`), so applications embedding
+//! the importer can react to the kind of the failure. Internally, the errors are `anyhow` errors,
+//! classified by the [`Failure`] contexts and the marker errors below.
+
+use crate::failure::Failure;
+
+/// A failed API request: the error response, or the request that couldn't be sent.
+#[derive(Debug)]
+pub struct ApiError {
+    pub status: Option<reqwest::StatusCode>,
+    pub message: String,
+}
+
+impl std::fmt::Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+/// Context of a transaction that couldn't be converted: the account, the id and the start of the
+/// raw transaction.
+#[derive(Debug)]
+pub struct ConversionError(pub String);
+
+impl std::fmt::Display for ConversionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for ConversionError {}
+
+/// Context of a failure to read, lock or write the ledger.
+#[derive(Debug)]
+pub struct LedgerError(pub String);
+
+impl std::fmt::Display for LedgerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for LedgerError {}
+
+/// Kind of the failure, with the underlying error (displayed with all its context).
+#[derive(thiserror::Error, Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// The access token is missing, or the refresh token or the secrets expired.
+    #[error("{0:#}")]
+    Auth(anyhow::Error),
+    /// The API rate limit was exceeded, or the daily quota is used up.
+    #[error("{0:#}")]
+    RateLimited(anyhow::Error),
+    /// The end user agreement of the requisition expired or was revoked.
+    #[error("{0:#}")]
+    AgreementExpired(anyhow::Error),
+    /// Any other failed API request.
+    #[error("{0:#}")]
+    Api(anyhow::Error),
+    /// A fetched transaction couldn't be converted.
+    #[error("{0:#}")]
+    Conversion(anyhow::Error),
+    /// The ledger couldn't be read, locked or written.
+    #[error("{0:#}")]
+    Ledger(anyhow::Error),
+    /// The imported directives failed the validation.
+    #[error("{0:#}")]
+    Validation(anyhow::Error),
+    /// Some of the accounts failed to import, the others were imported.
+    #[error("{0:#}")]
+    PartialImport(anyhow::Error),
+    #[error("{0:#}")]
+    Other(anyhow::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    /// The underlying error, with all its context.
+    pub fn inner(&self) -> &anyhow::Error {
+        match self {
+            Error::Auth(e)
+            | Error::RateLimited(e)
+            | Error::AgreementExpired(e)
+            | Error::Api(e)
+            | Error::Conversion(e)
+            | Error::Ledger(e)
+            | Error::Validation(e)
+            | Error::PartialImport(e)
+            | Error::Other(e) => e,
+        }
+    }
+
+    pub fn into_inner(self) -> anyhow::Error {
+        match self {
+            Error::Auth(e)
+            | Error::RateLimited(e)
+            | Error::AgreementExpired(e)
+            | Error::Api(e)
+            | Error::Conversion(e)
+            | Error::Ledger(e)
+            | Error::Validation(e)
+            | Error::PartialImport(e)
+            | Error::Other(e) => e,
+        }
+    }
+
+    /// The status of the error response, if the API request failed.
+    pub fn status(&self) -> Option<reqwest::StatusCode> {
+        self.inner()
+            .downcast_ref::<ApiError>()
+            .and_then(|e| e.status)
+    }
+}
+
+impl From<anyhow::Error> for Error {
+    fn from(e: anyhow::Error) -> Self {
+        if let Some(failure) = Failure::of(&e) {
+            return match failure {
+                Failure::Auth => Error::Auth(e),
+                Failure::RateLimited => Error::RateLimited(e),
+                Failure::AgreementExpired => Error::AgreementExpired(e),
+                Failure::Validation => Error::Validation(e),
+                Failure::PartialImport => Error::PartialImport(e),
+            };
+        }
+        if e.is::<ConversionError>() {
+            Error::Conversion(e)
+        } else if e.is::<LedgerError>() {
+            Error::Ledger(e)
+        } else if e.is::<ApiError>() {
+            Error::Api(e)
+        } else {
+            Error::Other(e)
+        }
+    }
+}
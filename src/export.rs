@@ -0,0 +1,373 @@
+use crate::{booking_date, mapper::TransactionMapper};
+use anyhow::Context;
+use beanru::types::{Account, Directive, DirectiveContent, MetadataValue, Posting};
+use gocardless::models::{AccountBalance, AccountTransactions, TransactionSchema};
+use rust_decimal::Decimal;
+use std::{collections::HashSet, io::Write, path::Path};
+
+/// Format of the exported transactions, for tools other than beancount.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExportFormat {
+    /// Open Financial Exchange 2 (XML), supported by e.g. GnuCash and Banktivity.
+    Ofx,
+    /// Quicken Interchange Format.
+    Qif,
+    /// hledger journal entries.
+    Hledger,
+    /// ledger-cli journal entries.
+    Ledger,
+    /// Beancount entries following the conventions of the beancount-import reviewing tool.
+    BeancountImport,
+}
+
+impl ExportFormat {
+    /// Whether the format is a plain text accounting journal, produced from the categorized
+    /// directives instead of the raw transactions.
+    pub fn is_journal(self) -> bool {
+        matches!(
+            self,
+            ExportFormat::Hledger | ExportFormat::Ledger | ExportFormat::BeancountImport
+        )
+    }
+}
+
+fn payee(t: &TransactionSchema) -> Option<&str> {
+    let name = if t.transaction_amount.amount.starts_with('-') {
+        &t.creditor_name
+    } else {
+        &t.debtor_name
+    };
+    name.as_deref()
+}
+
+fn escape_xml(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+fn write_ofx(
+    account_id: &str,
+    transactions: &AccountTransactions,
+    balances: &AccountBalance,
+    out: &mut dyn Write,
+) -> anyhow::Result<()> {
+    let booked = &transactions.transactions.booked;
+    let balance = balances.balances.iter().flatten().next();
+    let currency = booked
+        .first()
+        .map(|t| t.transaction_amount.currency.clone())
+        .or_else(|| balance.map(|b| b.balance_amount.currency.clone()))
+        .unwrap_or_default();
+    let dates = booked
+        .iter()
+        .map(booking_date)
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let today = chrono::Local::now().date_naive();
+    let start = dates.iter().min().copied().unwrap_or(today);
+    let end = dates.iter().max().copied().unwrap_or(today);
+
+    writeln!(
+        out,
+        r#"<?xml version="1.0" encoding="UTF-8" standalone="no"?>"#
+    )?;
+    writeln!(
+        out,
+        r#"<?OFX OFXHEADER="200" VERSION="220" SECURITY="NONE" OLDFILEUID="NONE" NEWFILEUID="NONE"?>"#
+    )?;
+    writeln!(out, "<OFX>")?;
+    writeln!(out, "<SIGNONMSGSRSV1><SONRS>")?;
+    writeln!(
+        out,
+        "<STATUS><CODE>0</CODE><SEVERITY>INFO</SEVERITY></STATUS>"
+    )?;
+    writeln!(out, "<DTSERVER>{}</DTSERVER>", today.format("%Y%m%d"))?;
+    writeln!(out, "<LANGUAGE>ENG</LANGUAGE>")?;
+    writeln!(out, "</SONRS></SIGNONMSGSRSV1>")?;
+    writeln!(out, "<BANKMSGSRSV1><STMTTRNRS>")?;
+    writeln!(out, "<TRNUID>0</TRNUID>")?;
+    writeln!(
+        out,
+        "<STATUS><CODE>0</CODE><SEVERITY>INFO</SEVERITY></STATUS>"
+    )?;
+    writeln!(out, "<STMTRS>")?;
+    writeln!(out, "<CURDEF>{}</CURDEF>", escape_xml(&currency))?;
+    writeln!(
+        out,
+        "<BANKACCTFROM><BANKID>GOCARDLESS</BANKID><ACCTID>{}</ACCTID><ACCTTYPE>CHECKING</ACCTTYPE></BANKACCTFROM>",
+        escape_xml(account_id)
+    )?;
+    writeln!(out, "<BANKTRANLIST>")?;
+    writeln!(out, "<DTSTART>{}</DTSTART>", start.format("%Y%m%d"))?;
+    writeln!(out, "<DTEND>{}</DTEND>", end.format("%Y%m%d"))?;
+    for (t, date) in booked.iter().zip(dates) {
+        let amount = &t.transaction_amount.amount;
+        let fitid = t
+            .transaction_id
+            .as_ref()
+            .or(t.internal_transaction_id.as_ref())
+            .cloned()
+            .unwrap_or_else(|| format!("{}-{}", date.format("%Y%m%d"), amount));
+        writeln!(out, "<STMTTRN>")?;
+        let kind = if amount.starts_with('-') {
+            "DEBIT"
+        } else {
+            "CREDIT"
+        };
+        writeln!(out, "<TRNTYPE>{}</TRNTYPE>", kind)?;
+        writeln!(out, "<DTPOSTED>{}</DTPOSTED>", date.format("%Y%m%d"))?;
+        writeln!(out, "<TRNAMT>{}</TRNAMT>", escape_xml(amount))?;
+        writeln!(out, "<FITID>{}</FITID>", escape_xml(&fitid))?;
+        if let Some(payee) = payee(t) {
+            writeln!(out, "<NAME>{}</NAME>", escape_xml(payee))?;
+        }
+        if let Some(memo) = crate::narration(t) {
+            writeln!(out, "<MEMO>{}</MEMO>", escape_xml(&memo))?;
+        }
+        writeln!(out, "</STMTTRN>")?;
+    }
+    writeln!(out, "</BANKTRANLIST>")?;
+    if let Some(b) = balance {
+        let date = b
+            .reference_date
+            .as_deref()
+            .unwrap_or_default()
+            .replace('-', "");
+        writeln!(
+            out,
+            "<LEDGERBAL><BALAMT>{}</BALAMT><DTASOF>{}</DTASOF></LEDGERBAL>",
+            escape_xml(&b.balance_amount.amount),
+            if date.is_empty() {
+                today.format("%Y%m%d").to_string()
+            } else {
+                date
+            }
+        )?;
+    }
+    writeln!(out, "</STMTRS>")?;
+    writeln!(out, "</STMTTRNRS></BANKMSGSRSV1>")?;
+    writeln!(out, "</OFX>")?;
+    Ok(())
+}
+
+fn write_qif(transactions: &AccountTransactions, out: &mut dyn Write) -> anyhow::Result<()> {
+    writeln!(out, "!Type:Bank")?;
+    for t in &transactions.transactions.booked {
+        writeln!(out, "D{}", booking_date(t)?.format("%m/%d/%Y"))?;
+        writeln!(out, "T{}", t.transaction_amount.amount)?;
+        if let Some(payee) = payee(t) {
+            writeln!(out, "P{}", payee)?;
+        }
+        if let Some(memo) = crate::narration(t) {
+            writeln!(out, "M{}", memo)?;
+        }
+        if let Some(id) = &t.transaction_id {
+            writeln!(out, "N{}", id)?;
+        }
+        writeln!(out, "^")?;
+    }
+    Ok(())
+}
+
+fn metadata_value(v: &MetadataValue<Decimal>) -> String {
+    match v {
+        MetadataValue::String(s) => s.replace(['\n', ','], " "),
+        MetadataValue::Number(n) => n.to_string(),
+        MetadataValue::Currency(c) => c.0.clone(),
+        MetadataValue::Account(a) => a.to_string(),
+        MetadataValue::Bool(b) => b.to_string(),
+        MetadataValue::Date(d) => d.to_string(),
+    }
+}
+
+/// Journals have no links, so they are kept as `link` tags (which are also used to find the
+/// already exported transactions).
+const LINK_TAG: &str = "link";
+
+/// Returns the links of the transactions exported to the journal before.
+pub fn journal_links(format: ExportFormat, content: &str) -> HashSet<String> {
+    let re = match format {
+        ExportFormat::BeancountImport => regex::Regex::new(r"\^([^\s]+)").unwrap(),
+        _ => regex::Regex::new(&format!(r"\b{}:\s*([^,\s]+)", LINK_TAG)).unwrap(),
+    };
+    re.captures_iter(content)
+        .map(|c| c[1].to_string())
+        .collect()
+}
+
+/// Metadata key of the posting with the internal transaction id, in the beancount-import format.
+const BEANCOUNT_IMPORT_ID_KEY: &str = "gocardless_id";
+
+/// Account of the counter posting of the uncategorized transactions, left for beancount-import to
+/// predict.
+const BEANCOUNT_IMPORT_FIXME: &str = "Expenses:FIXME";
+
+/// Writes the transactions as beancount entries in the form beancount-import expects from its data
+/// sources: the bank posting carries the `date`, `source_desc` and id metadata used to match and
+/// classify it, and the uncategorized transactions go to `Expenses:FIXME`.
+fn write_beancount_import(
+    directives: &[Directive<Decimal>],
+    mapper: &TransactionMapper,
+    out: &mut dyn Write,
+) -> anyhow::Result<()> {
+    for d in directives {
+        let id = mapper.transaction_id(d).map(|id| id.to_string());
+        let mut d = d.clone();
+        let DirectiveContent::Transaction(t) = &mut d.content else {
+            continue;
+        };
+        let source_desc = t
+            .narration
+            .clone()
+            .or_else(|| t.payee.clone())
+            .unwrap_or_default();
+        if t.postings.len() == 1 {
+            t.postings.push(Posting {
+                flag: None,
+                account: Account(BEANCOUNT_IMPORT_FIXME.to_string()),
+                amount: None,
+                cost: None,
+                price: None,
+                metadata: Default::default(),
+                autocomputed: false,
+            });
+        }
+        if let Some(p) = t.postings.first_mut() {
+            p.metadata
+                .insert("date".into(), MetadataValue::Date(d.date));
+            p.metadata
+                .insert("source_desc".into(), MetadataValue::String(source_desc));
+            if let Some(id) = id {
+                p.metadata
+                    .insert(BEANCOUNT_IMPORT_ID_KEY.into(), MetadataValue::String(id));
+            }
+        }
+        writeln!(out, "{}", d)?;
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+/// Writes the transactions as hledger or ledger-cli journal entries. Metadata and links become
+/// `key: value` tags in the comments.
+pub fn write_journal(
+    format: ExportFormat,
+    directives: &[Directive<Decimal>],
+    mapper: &TransactionMapper,
+    out: &mut dyn Write,
+) -> anyhow::Result<()> {
+    if format == ExportFormat::BeancountImport {
+        return write_beancount_import(directives, mapper, out);
+    }
+    for d in directives {
+        let DirectiveContent::Transaction(t) = &d.content else {
+            continue;
+        };
+        let flag = match t.flag {
+            Some('*') => " *",
+            Some('!') => " !",
+            _ => "",
+        };
+        let narration = t.narration.as_deref().unwrap_or_default();
+        let mut comments = vec![];
+        match (format, &t.payee) {
+            (ExportFormat::Hledger, Some(payee)) => {
+                writeln!(out, "{}{} {} | {}", d.date, flag, payee, narration)?
+            }
+            (_, Some(payee)) => {
+                writeln!(out, "{}{} {}", d.date, flag, payee)?;
+                if !narration.is_empty() {
+                    comments.push(narration.to_string());
+                }
+            }
+            (_, None) => writeln!(out, "{}{} {}", d.date, flag, narration)?,
+        }
+
+        let mut tags: Vec<&String> = t.tags.iter().collect();
+        tags.sort();
+        if !tags.is_empty() {
+            match format {
+                ExportFormat::Ledger => comments.push(format!(
+                    ":{}:",
+                    tags.iter()
+                        .map(|t| t.as_str())
+                        .collect::<Vec<_>>()
+                        .join(":")
+                )),
+                _ => comments.push(
+                    tags.iter()
+                        .map(|t| format!("{}:", t))
+                        .collect::<Vec<_>>()
+                        .join(", "),
+                ),
+            }
+        }
+        let mut links: Vec<&String> = t.links.iter().collect();
+        links.sort();
+        for link in links {
+            comments.push(format!("{}: {}", LINK_TAG, link));
+        }
+        let mut metadata: Vec<_> = d.metadata.iter().collect();
+        metadata.sort_by_key(|(k, _)| k.as_str());
+        for (key, value) in metadata {
+            comments.push(format!("{}: {}", key, metadata_value(value)));
+        }
+        for c in comments {
+            writeln!(out, "    ; {}", c)?;
+        }
+
+        for p in &t.postings {
+            match &p.amount {
+                Some(a) => writeln!(out, "    {}  {} {}", p.account, a.value, a.currency.0)?,
+                None => writeln!(out, "    {}", p.account)?,
+            }
+        }
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+/// Appends the transactions that are not in the journal yet (by their links) to it, creating the
+/// file if needed. Returns the number of the appended transactions.
+pub fn append_journal(
+    format: ExportFormat,
+    path: &Path,
+    directives: &[Directive<Decimal>],
+    mapper: &TransactionMapper,
+) -> anyhow::Result<usize> {
+    let existing = match std::fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e).with_context(|| format!("failed to read {}", path.display())),
+    };
+    let ids = journal_links(format, &existing);
+    let directives: Vec<_> = directives
+        .iter()
+        .filter(|d| d.content.transaction_opt().is_some() && !crate::is_duplicate(d, &ids))
+        .cloned()
+        .collect();
+    let mut out = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("failed to open {}", path.display()))?;
+    write_journal(format, &directives, mapper, &mut out)?;
+    Ok(directives.len())
+}
+
+/// Writes the booked transactions of the account in the given format.
+pub fn export(
+    format: ExportFormat,
+    account_id: &str,
+    transactions: &AccountTransactions,
+    balances: &AccountBalance,
+    out: &mut dyn Write,
+) -> anyhow::Result<()> {
+    match format {
+        ExportFormat::Ofx => write_ofx(account_id, transactions, balances, out),
+        ExportFormat::Qif => write_qif(transactions, out),
+        ExportFormat::Hledger | ExportFormat::Ledger | ExportFormat::BeancountImport => {
+            anyhow::bail!("journal formats are written from the categorized directives")
+        }
+    }
+}
@@ -0,0 +1,122 @@
+//! The `extract` command: prints the beancount entries of saved API responses, like the `extract`
+//! of the classic beancount importers, so the importer fits into `bean-extract` style pipelines.
+
+use crate::{
+    categorized_directives, dedup, ledger_config, ledger_io, mapper, render_directive, roundups,
+    rules, source::SourceAccount, timezone,
+};
+use anyhow::Context;
+use beanru::types::{Account, DirectiveContent};
+use gocardless::models::AccountTransactions;
+use std::{
+    collections::{HashMap, HashSet},
+    io::Write,
+    path::{Path, PathBuf},
+};
+use tracing::warn;
+
+/// The response of the transactions request in the file (the importer protocol's `identify`).
+fn identify(path: &Path) -> Option<AccountTransactions> {
+    let content = std::fs::read_to_string(path).ok()?;
+    serde_json::from_str(&content).ok()
+}
+
+/// The account id the file is named after (as saved by `--record` and `dump-fixture`).
+fn file_account_id(path: &Path) -> Option<&str> {
+    path.file_stem()?.to_str()
+}
+
+/// The account of the file: the given one, or the account of the ledger configured with the
+/// account id of the file. Also the configuration of the account, if any.
+fn file_account<'a>(
+    path: &Path,
+    account: Option<&str>,
+    configured: &'a [SourceAccount],
+) -> Option<(Account, Option<&'a SourceAccount>)> {
+    let account_id = file_account_id(path);
+    let source = configured
+        .iter()
+        .find(|a| account_id.is_some() && a.metadata_str("account_id") == account_id);
+    match account {
+        Some(account) => Some((
+            Account(account.to_string()),
+            source.filter(|a| a.account.0 == account),
+        )),
+        None => source.map(|a| (a.account.clone(), Some(a))),
+    }
+}
+
+/// The institution of the file's account, from its configuration or the cached requisitions (the
+/// files are extracted without calling the API).
+fn file_institution(path: &Path, source: Option<&SourceAccount>) -> anyhow::Result<Option<String>> {
+    if let Some(id) = source.and_then(|a| a.metadata_str("institution_id")) {
+        return Ok(Some(id.to_string()));
+    }
+    match file_account_id(path) {
+        Some(account_id) => crate::source::gocardless::cached_institution(account_id),
+        None => Ok(None),
+    }
+}
+
+pub async fn run(
+    files: &[PathBuf],
+    account: Option<&str>,
+    existing: Option<&Path>,
+    rules: Option<&Path>,
+) -> anyhow::Result<()> {
+    let mut mapper = mapper::TransactionMapper::default();
+    let mut dedup = dedup::from_config(None, &mapper)?;
+    let mut configured = vec![];
+    let mut rules_path = rules
+        .map(|p| p.to_path_buf())
+        .or_else(|| crate::settings::get().rules.clone());
+    if let Some(path) = existing {
+        let (ledger, _) = ledger_io::read_ledger(path.to_path_buf()).await?;
+        let config = ledger_config::LedgerConfig::read(&ledger)?;
+        mapper = config.mapper();
+        dedup = dedup::from_config(config.dedup.as_deref(), &mapper)?;
+        for (_, file) in &ledger.files {
+            for d in &file.directives {
+                if let DirectiveContent::Transaction(_) = d.content {
+                    roundups::add_to_dedup(dedup.as_mut(), &mapper, d);
+                }
+            }
+        }
+        configured = ledger_config::configured_accounts(&ledger)?;
+        rules_path = ledger_config::rules_path(rules, &ledger)?;
+    }
+    let rules = rules::Rules::load(rules_path.as_deref()).await?;
+
+    // Internal ids of the printed transactions, for the ones repeated in the files.
+    let mut printed_ids = HashSet::new();
+    let mut out = std::io::stdout().lock();
+    writeln!(out, ";; -*- mode: beancount -*-")?;
+    for path in files {
+        let Some(mut res) = identify(path) else {
+            warn!("{}: not a transactions response, skipping", path.display());
+            continue;
+        };
+        let (account, source) = file_account(path, account, &configured).with_context(|| {
+            format!(
+                "{}: no account configured for it, use --account",
+                path.display()
+            )
+        })?;
+        timezone::fill_missing_dates(&mut res.transactions, source, || async {
+            file_institution(path, source)
+        })
+        .await?;
+        writeln!(out, "\n**** {}\n", path.display())?;
+        for d in categorized_directives(&res, &account, &mapper, &rules)? {
+            if dedup.is_duplicate(&d, &account)
+                || mapper
+                    .transaction_id(&d)
+                    .is_some_and(|id| !printed_ids.insert(id.to_string()))
+            {
+                continue;
+            }
+            writeln!(out, "{}\n", render_directive(&d, &HashMap::new()))?;
+        }
+    }
+    Ok(())
+}
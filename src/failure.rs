@@ -0,0 +1,51 @@
+//! Classes of failures with a stable exit code of the CLI, so wrapper scripts can react to them.
+
+use serde::{Deserialize, Serialize};
+
+/// Class of the failure, attached to the errors as context. Errors without a class exit with 1,
+/// invalid command line arguments with 2.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Failure {
+    /// The access token is missing, or the refresh token or the secrets expired (`sign-in` again).
+    Auth,
+    /// The API rate limit was exceeded.
+    RateLimited,
+    /// The end user agreement of the requisition expired or was revoked.
+    AgreementExpired,
+    /// Some of the accounts failed to import, the others were imported.
+    PartialImport,
+    /// The imported directives failed the validation (`--validate`, `--bean-check`).
+    Validation,
+}
+
+impl Failure {
+    pub fn exit_code(self) -> u8 {
+        match self {
+            Failure::Auth => 3,
+            Failure::RateLimited => 4,
+            Failure::AgreementExpired => 5,
+            Failure::PartialImport => 6,
+            Failure::Validation => 7,
+        }
+    }
+
+    /// The class attached to the error, as the error itself or as its context.
+    pub fn of(e: &anyhow::Error) -> Option<Failure> {
+        e.downcast_ref::<Failure>().copied()
+    }
+}
+
+impl std::fmt::Display for Failure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Failure::Auth => "authentication failed",
+            Failure::RateLimited => "rate limit exceeded",
+            Failure::AgreementExpired => "access to the account expired",
+            Failure::PartialImport => "some accounts failed to import",
+            Failure::Validation => "validation failed",
+        })
+    }
+}
+
+impl std::error::Error for Failure {}
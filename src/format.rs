@@ -0,0 +1,182 @@
+//! Rendering of the imported directives in the formatting style of the rest of the ledger, so
+//! `bean-format` isn't needed afterwards. The style is configured in the settings:
+//!
+//! ```toml
+//! [format]
+//! indent = 4
+//! amount_column = 70
+//! metadata_order = ["booking_date_time", "from_name", "to_name"]
+//! links_on_line = false
+//! ```
+
+use beanru::types::{Amount, Directive, DirectiveContent, MetadataValue, Posting};
+use rust_decimal::Decimal;
+use serde::Deserialize;
+use std::collections::HashMap;
+
+/// Indentation of the metadata and postings, unless configured otherwise.
+pub const DEFAULT_INDENT: usize = 2;
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields, default)]
+pub struct Style {
+    /// Spaces before the metadata and the postings (twice as many before the posting metadata).
+    pub indent: usize,
+    /// Column at which the amounts of the postings and balance assertions end (right-aligned, as
+    /// `bean-format` does). By default, they follow the account after two spaces.
+    pub amount_column: Option<usize>,
+    /// Metadata keys written first, in this order. The other keys follow in alphabetical order.
+    pub metadata_order: Vec<String>,
+    /// Whether the links are written on the directive line. Otherwise, they get their own line
+    /// after it.
+    pub links_on_line: bool,
+}
+
+impl Default for Style {
+    fn default() -> Self {
+        Style {
+            indent: DEFAULT_INDENT,
+            amount_column: None,
+            metadata_order: vec![],
+            links_on_line: true,
+        }
+    }
+}
+
+fn quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn metadata_value(v: &MetadataValue<Decimal>) -> String {
+    match v {
+        MetadataValue::String(s) => quote(s),
+        MetadataValue::Number(n) => n.to_string(),
+        MetadataValue::Currency(c) => c.0.clone(),
+        MetadataValue::Account(a) => a.0.clone(),
+        MetadataValue::Bool(true) => "TRUE".to_string(),
+        MetadataValue::Bool(false) => "FALSE".to_string(),
+        MetadataValue::Date(d) => d.to_string(),
+    }
+}
+
+impl Style {
+    /// The metadata keys in the configured order.
+    fn ordered<'a>(&self, metadata: &'a HashMap<String, MetadataValue<Decimal>>) -> Vec<&'a str> {
+        let rank = |key: &str| {
+            self.metadata_order
+                .iter()
+                .position(|k| k == key)
+                .unwrap_or(self.metadata_order.len())
+        };
+        let mut keys: Vec<&str> = metadata.keys().map(|k| k.as_str()).collect();
+        keys.sort_by(|a, b| (rank(a), a).cmp(&(rank(b), b)));
+        keys
+    }
+
+    fn metadata(
+        &self,
+        out: &mut String,
+        metadata: &HashMap<String, MetadataValue<Decimal>>,
+        indent: usize,
+    ) {
+        for key in self.ordered(metadata) {
+            out.push_str(&format!(
+                "\n{:indent$}{}: {}",
+                "",
+                key,
+                metadata_value(&metadata[key])
+            ));
+        }
+    }
+
+    /// Appends the amount to the line (e.g. `  Assets:Bank`), aligned to the amount column.
+    fn amount(&self, line: &mut String, number: &str, currency: &str) {
+        let width = line.chars().count();
+        let padding = match self.amount_column {
+            Some(column) => column.saturating_sub(width + number.chars().count()).max(2),
+            None => 2,
+        };
+        line.push_str(&format!("{:padding$}{} {}", "", number, currency));
+    }
+
+    fn posting(&self, out: &mut String, p: &Posting<Decimal>) {
+        let mut line = format!("\n{:indent$}", "", indent = self.indent);
+        if let Some(flag) = p.flag {
+            line.push_str(&format!("{} ", flag));
+        }
+        line.push_str(&p.account.0);
+        if let Some(a) = &p.amount {
+            // The leading newline doesn't count for the column.
+            let mut rest = line.split_off(1);
+            self.amount(&mut rest, &a.value.to_string(), &a.currency.0);
+            line.push_str(&rest);
+        }
+        let format_amount = |a: &Amount<Decimal>| format!("{} {}", a.value, a.currency.0);
+        if let Some(cost) = &p.cost {
+            line.push_str(&format!(" {{{}}}", format_amount(cost)));
+        }
+        if let Some(price) = &p.price {
+            line.push_str(&format!(" @ {}", format_amount(price)));
+        }
+        out.push_str(&line);
+        self.metadata(out, &p.metadata, self.indent * 2);
+    }
+
+    /// Renders the directive. The balance assertions get the tolerance, if any. Other directives
+    /// than transactions, balances and prices are rendered as usual.
+    pub fn render(&self, d: &Directive<Decimal>, tolerance: Option<Decimal>) -> String {
+        let mut out = match &d.content {
+            DirectiveContent::Transaction(t) => {
+                let mut line = format!("{} {}", d.date, t.flag.unwrap_or('*'));
+                if let Some(payee) = &t.payee {
+                    line.push_str(&format!(" {}", quote(payee)));
+                }
+                line.push_str(&format!(
+                    " {}",
+                    quote(t.narration.as_deref().unwrap_or_default())
+                ));
+                let mut tags: Vec<&String> = t.tags.iter().collect();
+                tags.sort();
+                for tag in tags {
+                    line.push_str(&format!(" #{}", tag));
+                }
+                let mut links: Vec<&String> = t.links.iter().collect();
+                links.sort();
+                let links: Vec<String> = links.iter().map(|l| format!("^{}", l)).collect();
+                if !links.is_empty() {
+                    if self.links_on_line {
+                        line.push_str(&format!(" {}", links.join(" ")));
+                    } else {
+                        line.push_str(&format!(
+                            "\n{:indent$}{}",
+                            "",
+                            links.join(" "),
+                            indent = self.indent
+                        ));
+                    }
+                }
+                self.metadata(&mut line, &d.metadata, self.indent);
+                for p in &t.postings {
+                    self.posting(&mut line, p);
+                }
+                return line;
+            }
+            DirectiveContent::Balance(b) => {
+                let mut line = format!("{} balance {}", d.date, b.account);
+                let number = match tolerance {
+                    Some(tolerance) => format!("{} ~ {}", b.amount.value, tolerance),
+                    None => b.amount.value.to_string(),
+                };
+                self.amount(&mut line, &number, &b.amount.currency.0);
+                line
+            }
+            DirectiveContent::Price(p) => format!(
+                "{} price {} {} {}",
+                d.date, p.currency, p.amount.value, p.amount.currency
+            ),
+            _ => return d.to_string(),
+        };
+        self.metadata(&mut out, &d.metadata, self.indent);
+        out
+    }
+}
@@ -0,0 +1,108 @@
+//! Append-only log of the imports (`~/.gocardless/history.jsonl`), shown by the `history` command.
+
+use crate::{base_config_dir, mapper::TransactionMapper, AccountSummary};
+use beanru::types::{Directive, DirectiveContent};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+/// Directives added to one ledger file by the import.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct FileChanges {
+    pub file: PathBuf,
+    pub directives: usize,
+    /// Internal ids of the added transactions (the `^id-...` links, or wherever the mapper keeps
+    /// them).
+    pub transaction_ids: Vec<String>,
+}
+
+impl FileChanges {
+    pub fn new(
+        file: &Path,
+        directives: &[Directive<Decimal>],
+        mapper: &TransactionMapper,
+    ) -> FileChanges {
+        let mut transaction_ids: Vec<String> = directives
+            .iter()
+            .filter(|d| matches!(d.content, DirectiveContent::Transaction(_)))
+            .filter_map(|d| mapper.transaction_id(d))
+            .map(|id| id.to_string())
+            .collect();
+        transaction_ids.sort();
+        FileChanges {
+            file: file.to_path_buf(),
+            directives: directives.len(),
+            transaction_ids,
+        }
+    }
+}
+
+/// A single import run.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Entry {
+    pub time: chrono::DateTime<chrono::Local>,
+    /// Version of the importer.
+    pub version: String,
+    pub ledger: PathBuf,
+    pub success: bool,
+    pub error: Option<String>,
+    #[serde(default)]
+    pub accounts: Vec<AccountSummary>,
+    #[serde(default)]
+    pub changes: Vec<FileChanges>,
+}
+
+impl Entry {
+    pub fn new_transactions(&self) -> usize {
+        self.accounts.iter().map(|a| a.new_transactions).sum()
+    }
+}
+
+pub fn default_path() -> anyhow::Result<PathBuf> {
+    Ok(base_config_dir()?.join("history.jsonl"))
+}
+
+/// Appends the import run to the history.
+pub fn record(
+    ledger: &Path,
+    result: &anyhow::Result<Vec<AccountSummary>>,
+    changes: Vec<FileChanges>,
+) -> anyhow::Result<()> {
+    let accounts = result.as_ref().cloned().unwrap_or_default();
+    let entry = Entry {
+        time: chrono::Local::now(),
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        ledger: std::fs::canonicalize(ledger).unwrap_or_else(|_| ledger.to_path_buf()),
+        success: result.is_ok() && accounts.iter().all(|a| a.error.is_none()),
+        error: result.as_ref().err().map(|e| format!("{:#}", e)),
+        accounts,
+        changes,
+    };
+    let path = default_path()?;
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)?;
+    writeln!(file, "{}", serde_json::to_string(&entry)?)?;
+    Ok(())
+}
+
+/// Reads the history, oldest first. Lines that can't be parsed (e.g. written by a newer version)
+/// are skipped.
+pub fn read(path: &Path) -> anyhow::Result<Vec<Entry>> {
+    let content = match std::fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e.into()),
+    };
+    Ok(content
+        .lines()
+        .filter_map(|l| serde_json::from_str(l).ok())
+        .collect())
+}
@@ -0,0 +1,120 @@
+//! Import directory mode: each import batch is written into a new dated file in the directory
+//! (listed in its `index.json`) for review, e.g. in Fava, instead of being appended to the ledger.
+//! The batches are merged into the ledger by hand and deleted afterwards; until then, their
+//! transactions are not imported again.
+
+use crate::{history::FileChanges, ledger_io};
+use beanru::types::{Directive, Ledger};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+const INDEX_FILE: &str = "index.json";
+
+/// A batch written to the import directory.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Batch {
+    /// Name of the batch file in the directory.
+    pub file: String,
+    pub time: chrono::DateTime<chrono::Local>,
+    pub ledger: PathBuf,
+    /// The directives of the batch, by the ledger file they are meant for.
+    pub changes: Vec<FileChanges>,
+}
+
+pub fn read_index(dir: &Path) -> anyhow::Result<Vec<Batch>> {
+    match std::fs::read_to_string(dir.join(INDEX_FILE)) {
+        Ok(content) => Ok(serde_json::from_str(&content)?),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(vec![]),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// The directives of the batches that are still in the directory (not merged yet).
+pub async fn pending(dir: &Path) -> anyhow::Result<Vec<Directive<Decimal>>> {
+    let mut directives = vec![];
+    for batch in read_index(dir)? {
+        let path = dir.join(&batch.file);
+        if !path.exists() {
+            continue;
+        }
+        let ledger = Ledger::read(path, |p| async move {
+            let content = tokio::fs::read_to_string(&p).await?;
+            Ok(ledger_io::strip_tolerances(&content).0)
+        })
+        .await?;
+        for (_, file) in ledger.files {
+            directives.extend(file.directives);
+        }
+    }
+    Ok(directives)
+}
+
+/// Writes the batch into a new dated file and adds it to the index. Returns the path of the file.
+pub fn write_batch(
+    dir: &Path,
+    ledger: &Path,
+    content: &str,
+    changes: Vec<FileChanges>,
+) -> anyhow::Result<PathBuf> {
+    std::fs::create_dir_all(dir)?;
+    let time = chrono::Local::now();
+    let stem = time.format("%Y-%m-%d-%H%M%S").to_string();
+    // Batches written in the same second (e.g. of several ledgers) get a numeric suffix.
+    let mut n = 1;
+    let (file, path, mut out) = loop {
+        let file = match n {
+            1 => format!("{}.beancount", stem),
+            n => format!("{}-{}.beancount", stem, n),
+        };
+        let path = dir.join(&file);
+        match std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+        {
+            Ok(out) => break (file, path, out),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => n += 1,
+            Err(e) => return Err(e.into()),
+        }
+    };
+    out.write_all(content.as_bytes())?;
+    let mut index: Vec<Batch> = read_index(dir)?
+        .into_iter()
+        .filter(|b| dir.join(&b.file).exists())
+        .collect();
+    index.push(Batch {
+        file,
+        time,
+        ledger: std::fs::canonicalize(ledger).unwrap_or_else(|_| ledger.to_path_buf()),
+        changes,
+    });
+    std::fs::write(dir.join(INDEX_FILE), serde_json::to_string_pretty(&index)?)?;
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn batches_of_the_same_second_are_kept() {
+        let dir = tempfile::tempdir().unwrap();
+        let ledger = Path::new("ledger.beancount");
+        let first = write_batch(dir.path(), ledger, "first\n", vec![]).unwrap();
+        let second = write_batch(dir.path(), ledger, "second\n", vec![]).unwrap();
+        assert_ne!(first, second);
+        assert_eq!(std::fs::read_to_string(&first).unwrap(), "first\n");
+        assert_eq!(std::fs::read_to_string(&second).unwrap(), "second\n");
+        let files: Vec<String> = read_index(dir.path())
+            .unwrap()
+            .into_iter()
+            .map(|b| b.file)
+            .collect();
+        assert_eq!(files.len(), 2);
+        assert_ne!(files[0], files[1]);
+    }
+}
@@ -0,0 +1,209 @@
+//! The `init` command: guided sign-in, first requisition and starter settings.
+
+use crate::{config_with_token, error::Error, get_token, settings, sign_in, timed, timed_once};
+use anyhow::Context;
+use chrono::Days;
+use dialoguer::{theme::ColorfulTheme, Confirm, FuzzySelect, Input, Password};
+use gocardless::apis::{configuration::Configuration, institutions_api};
+use std::path::Path;
+
+/// Settings written by `init`, when the settings file doesn't exist yet.
+fn starter_settings(country: &str) -> String {
+    format!(
+        r#"# Settings of beancount-gocardless-importer, see README.md
+country = "{}"
+# rules = "/path/to/rules.yaml"
+
+# [notify]
+# command = "notify-send GoCardless \"$GOCARDLESS_MESSAGE\""
+"#,
+        country
+    )
+}
+
+/// Beancount account name component from the institution name, e.g. "Revolut Ltd." -> "RevolutLtd".
+fn account_component(name: &str) -> String {
+    let component: String = name
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .map(|w| {
+            let mut chars = w.chars();
+            let first = chars.next().unwrap().to_uppercase();
+            first.chain(chars).collect::<String>()
+        })
+        .collect();
+    if component.is_empty() {
+        "Bank".into()
+    } else {
+        component
+    }
+}
+
+/// Lets the user pick the institution, creates the requisition and waits until the user finishes
+/// the authorization. Returns the institution and the connected account ids.
+async fn connect(
+    theme: &ColorfulTheme,
+    config: &Configuration,
+    country: &str,
+) -> anyhow::Result<(gocardless::models::Integration, Vec<String>)> {
+    let mut banks = timed(
+        "retrieve_all_supported_institutions_in_a_given_country",
+        || {
+            institutions_api::retrieve_all_supported_institutions_in_a_given_country(
+                config,
+                None,
+                None,
+                None,
+                None,
+                None,
+                Some(country),
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+            )
+        },
+    )
+    .await?;
+    if banks.is_empty() {
+        anyhow::bail!("no institutions found in {:?}", country);
+    }
+    let names: Vec<&str> = banks.iter().map(|b| b.name.as_str()).collect();
+    let selected = FuzzySelect::with_theme(theme)
+        .with_prompt("Institution")
+        .items(&names)
+        .interact()?;
+    let bank = banks.swap_remove(selected);
+
+    let req = gocardless::models::RequisitionRequest::new(
+        Some("https://example.com/".into()),
+        bank.id.clone(),
+    );
+    let requisition = timed_once("create_requisition", || {
+        gocardless::apis::requisitions_api::create_requisition(config, req)
+    })
+    .await?;
+    let id = requisition
+        .id
+        .context("requisition id is missing from the gocardless response")?
+        .to_string();
+    let link = requisition
+        .link
+        .context("setup link is missing from the gocardless response")?;
+    println!(
+        "\nFollow the link to authorize the access to your accounts:\n{}\n",
+        link
+    );
+    loop {
+        Confirm::with_theme(theme)
+            .with_prompt("Finished the authorization?")
+            .default(true)
+            .wait_for_newline(true)
+            .interact()?;
+        let requisition = timed("requisition_by_id", || {
+            gocardless::apis::requisitions_api::requisition_by_id(config, &id)
+        })
+        .await?;
+        let accounts: Vec<String> = requisition
+            .accounts
+            .iter()
+            .flatten()
+            .map(|a| a.to_string())
+            .collect();
+        if !accounts.is_empty() {
+            return Ok((bank, accounts));
+        }
+        println!("The requisition has no accounts yet, finish the authorization first.");
+    }
+}
+
+/// Runs the setup interactively. The starter settings are written to `settings_path` (from
+/// `--config` or `$GOCARDLESS_CONFIG`), or the default location.
+pub async fn run(settings_path: Option<&Path>) -> anyhow::Result<()> {
+    let theme = ColorfulTheme::default();
+
+    let signed_in = get_token().await.is_ok();
+    let sign_in_again = signed_in
+        && Confirm::with_theme(&theme)
+            .with_prompt("You are already signed in. Sign in again with new secrets?")
+            .default(false)
+            .interact()?;
+    if !signed_in || sign_in_again {
+        println!("Get the secrets at https://bankaccountdata.gocardless.com/user-secrets/");
+        let secret_id: String = Input::with_theme(&theme)
+            .with_prompt("Secret ID")
+            .interact_text()?;
+        let secret_key = Password::with_theme(&theme)
+            .with_prompt("Secret key")
+            .interact()?;
+        sign_in(secret_id.trim().to_string(), secret_key.trim().to_string())
+            .await
+            .map_err(Error::into_inner)?;
+        println!("Signed in");
+    }
+
+    let mut country_input = Input::<String>::with_theme(&theme)
+        .with_prompt("Country of your bank (ISO 3166 code, e.g. GB)");
+    if let Some(country) = &settings::get().country {
+        country_input = country_input.default(country.clone());
+    }
+    let country = country_input.interact_text()?.trim().to_uppercase();
+
+    let settings_path = match settings_path {
+        Some(path) => path.to_path_buf(),
+        None => settings::default_path()?,
+    };
+    if !settings_path.exists()
+        && Confirm::with_theme(&theme)
+            .with_prompt(format!(
+                "Write the starter settings to {}?",
+                settings_path.display()
+            ))
+            .default(true)
+            .interact()?
+    {
+        if let Some(dir) = settings_path.parent() {
+            tokio::fs::create_dir_all(dir).await?;
+        }
+        tokio::fs::write(&settings_path, starter_settings(&country)).await?;
+    }
+
+    if !Confirm::with_theme(&theme)
+        .with_prompt("Connect a bank now?")
+        .default(true)
+        .interact()?
+    {
+        println!("Connect a bank later with `create-requisition`.");
+        return Ok(());
+    }
+    let config = config_with_token().await?;
+    let (bank, accounts) = connect(&theme, &config, &country).await?;
+
+    // The bank returns transactions of the last `transaction_total_days` days.
+    let history_days: u64 = bank
+        .transaction_total_days
+        .as_deref()
+        .and_then(|d| d.parse().ok())
+        .unwrap_or(90);
+    let open_date = chrono::Local::now().date_naive() - Days::new(history_days);
+    println!("\nAdd the accounts to your ledger (and rename them as you like):\n");
+    for account_id in accounts {
+        let details = timed("retrieve_account_details", || {
+            gocardless::apis::accounts_api::retrieve_account_details(&config, &account_id)
+        })
+        .await?;
+        let mut name = format!("Assets:{}", account_component(&bank.name));
+        if let Some(currency) = &details.account.currency {
+            name += &format!(":{}", currency);
+        }
+        println!(
+            "{} open {}\n  importer: \"gocardless\"\n  account_id: \"{}\"\n",
+            open_date, name, account_id
+        );
+    }
+    println!("Then run `beancount-gocardless-importer import <ledger.beancount>`.");
+    Ok(())
+}
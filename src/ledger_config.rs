@@ -0,0 +1,290 @@
+//! Importer configuration kept in the ledger as `custom "gocardless"` directives, as an alternative
+//! to the metadata of the `open` directives:
+//!
+//! ```beancount
+//! 2020-01-01 custom "gocardless" "rules" "rules.yaml"
+//! 2020-01-01 custom "gocardless" "default_account" Expenses:Uncategorized
+//! 2020-01-01 custom "gocardless" "account" Assets:UK:Revolut:EUR
+//!   importer: "gocardless"
+//!   account_id: "<account-id>"
+//! ```
+
+use crate::{mapper::TransactionMapper, source::SourceAccount};
+use anyhow::Context;
+use beanru::types::{Account, Directive, DirectiveContent, Ledger, MetadataValue};
+use rust_decimal::Decimal;
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+};
+
+/// Name of the custom directives read by the importer.
+const CUSTOM_NAME: &str = "gocardless";
+
+#[derive(Default, Debug)]
+pub struct LedgerConfig {
+    /// Categorization rules file.
+    pub rules: Option<PathBuf>,
+    /// Counter account of the transactions not categorized by the rules.
+    pub default_account: Option<Account>,
+    /// Accounts configured with `custom "gocardless" "account"` directives.
+    pub accounts: Vec<SourceAccount>,
+    /// Directory receiving the import batches instead of the ledger.
+    pub import_dir: Option<PathBuf>,
+    /// Script run on every imported transaction.
+    pub script: Option<PathBuf>,
+    /// Payee directory (see `payees::Directory`).
+    pub payees: Option<PathBuf>,
+    /// Deduplication strategy (see `dedup::from_config`).
+    pub dedup: Option<String>,
+    /// Prefix of the links with the internal transaction id (`id-` by default).
+    pub link_prefix: Option<String>,
+    /// Metadata key with the internal transaction id, kept there instead of a link.
+    pub id_metadata: Option<String>,
+    /// Length of the longest narration, the longer ones are shortened.
+    pub max_narration_length: Option<usize>,
+}
+
+/// Resolves the path relative to the ledger file it is configured in.
+pub fn resolve(file: &Path, path: &str) -> PathBuf {
+    let path = Path::new(path);
+    match file.parent() {
+        Some(dir) if path.is_relative() => dir.join(path),
+        _ => path.to_path_buf(),
+    }
+}
+
+fn string(value: Option<&MetadataValue<Decimal>>) -> Option<&str> {
+    match value? {
+        MetadataValue::String(s) => Some(s),
+        MetadataValue::Account(a) => Some(&a.0),
+        _ => None,
+    }
+}
+
+/// Sets the setting, unless it was already configured.
+fn set_once<T>(setting: &mut Option<T>, value: T, what: &str, context: &str) -> anyhow::Result<()> {
+    if setting.is_some() {
+        anyhow::bail!("{}: {} is configured more than once", context, what);
+    }
+    *setting = Some(value);
+    Ok(())
+}
+
+impl LedgerConfig {
+    /// The transaction mapper with the `link_prefix`, `id_metadata` and `max_narration_length` of
+    /// the ledger.
+    pub fn mapper(&self) -> TransactionMapper {
+        let mut builder = TransactionMapper::builder();
+        if let Some(prefix) = &self.link_prefix {
+            builder = builder.link_prefix(prefix);
+        }
+        if let Some(key) = &self.id_metadata {
+            builder = builder.id_metadata(key);
+        }
+        if let Some(max) = self.max_narration_length {
+            builder = builder.max_narration(max);
+        }
+        builder.build()
+    }
+
+    pub fn read(ledger: &Ledger<Decimal>) -> anyhow::Result<LedgerConfig> {
+        let mut config = LedgerConfig::default();
+        for (path, file) in &ledger.files {
+            for d in &file.directives {
+                let DirectiveContent::Custom(custom) = &d.content else {
+                    continue;
+                };
+                if custom.name != CUSTOM_NAME {
+                    continue;
+                }
+                let context = || format!("{}: {} custom {:?}", path.display(), d.date, CUSTOM_NAME);
+                let key = string(custom.values.first()).with_context(|| {
+                    format!("{}: the first value has to be the setting", context())
+                })?;
+                let value = string(custom.values.get(1))
+                    .with_context(|| format!("{}: {:?} has no value", context(), key))?;
+                let context = context();
+                match key {
+                    "rules" => set_once(
+                        &mut config.rules,
+                        resolve(path, value),
+                        "the rules",
+                        &context,
+                    )?,
+                    "default_account" => set_once(
+                        &mut config.default_account,
+                        Account(value.to_string()),
+                        "the default account",
+                        &context,
+                    )?,
+                    "import_dir" => set_once(
+                        &mut config.import_dir,
+                        resolve(path, value),
+                        "the import directory",
+                        &context,
+                    )?,
+                    "script" => set_once(
+                        &mut config.script,
+                        resolve(path, value),
+                        "the script",
+                        &context,
+                    )?,
+                    "payees" => set_once(
+                        &mut config.payees,
+                        resolve(path, value),
+                        "the payee directory",
+                        &context,
+                    )?,
+                    "dedup" => set_once(
+                        &mut config.dedup,
+                        value.to_string(),
+                        "the deduplication",
+                        &context,
+                    )?,
+                    "link_prefix" => set_once(
+                        &mut config.link_prefix,
+                        value.to_string(),
+                        "the link prefix",
+                        &context,
+                    )?,
+                    "id_metadata" => set_once(
+                        &mut config.id_metadata,
+                        value.to_string(),
+                        "the id metadata",
+                        &context,
+                    )?,
+                    "max_narration_length" => {
+                        let length = value.parse().map_err(|_| {
+                            anyhow::format_err!("{}: invalid length {:?}", context, value)
+                        })?;
+                        set_once(
+                            &mut config.max_narration_length,
+                            length,
+                            "the narration length",
+                            &context,
+                        )?
+                    }
+                    "account" => config.accounts.push(SourceAccount {
+                        account: Account(value.to_string()),
+                        metadata: d.metadata.clone(),
+                        file: path.clone(),
+                    }),
+                    _ => anyhow::bail!("{}: unknown setting {:?}", context, key),
+                }
+            }
+        }
+        Ok(config)
+    }
+}
+
+/// The rules file: the one given on the command line takes precedence over the one configured in
+/// the ledger, and that over the global settings.
+pub fn rules_path(
+    command_line: Option<&Path>,
+    ledger: &Ledger<Decimal>,
+) -> anyhow::Result<Option<PathBuf>> {
+    if let Some(path) = command_line {
+        return Ok(Some(path.to_path_buf()));
+    }
+    Ok(LedgerConfig::read(ledger)?
+        .rules
+        .or_else(|| crate::settings::get().rules.clone()))
+}
+
+/// The accounts opened in the ledger.
+pub fn opened_accounts(ledger: &Ledger<Decimal>) -> HashSet<Account> {
+    ledger
+        .files
+        .values()
+        .flat_map(|f| &f.directives)
+        .filter_map(|d| match &d.content {
+            DirectiveContent::Open(open) => Some(open.account.clone()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// The accounts configured with an importer, in the `open` metadata or the custom directives.
+pub fn configured_accounts(ledger: &Ledger<Decimal>) -> anyhow::Result<Vec<SourceAccount>> {
+    let mut accounts = vec![];
+    for (path, file) in &ledger.files {
+        for d in &file.directives {
+            if let DirectiveContent::Open(open) = &d.content {
+                accounts.push(SourceAccount {
+                    account: open.account.clone(),
+                    metadata: d.metadata.clone(),
+                    file: path.clone(),
+                });
+            }
+        }
+    }
+    accounts.extend(LedgerConfig::read(ledger)?.accounts);
+    accounts.retain(|a| a.metadata_str("importer").is_some());
+    let mut configured = HashSet::new();
+    for a in &accounts {
+        if !configured.insert(&a.account) {
+            anyhow::bail!("account {} is configured more than once", a.account);
+        }
+    }
+    Ok(accounts)
+}
+
+/// The ledger file that receives the imported directives of the account: the file in the
+/// `target_file` metadata (relative to the file with the configuration), or the file with the
+/// configuration itself.
+pub fn target_file(ledger: &Ledger<Decimal>, account: &SourceAccount) -> anyhow::Result<PathBuf> {
+    let Some(target) = account.metadata_str("target_file") else {
+        return Ok(account.file.clone());
+    };
+    let target = resolve(&account.file, target);
+    let canonical = std::fs::canonicalize(&target).ok();
+    ledger
+        .files
+        .keys()
+        .find(|p| {
+            **p == target || (canonical.is_some() && std::fs::canonicalize(p).ok() == canonical)
+        })
+        .cloned()
+        .with_context(|| {
+            format!(
+                "the target file {} of {} is not included in the ledger",
+                target.display(),
+                account.account
+            )
+        })
+}
+
+/// Whether the account is configured with a `custom "gocardless" "account"` directive (instead of
+/// the `open` metadata).
+pub fn is_custom_account(ledger: &Ledger<Decimal>, account: &SourceAccount) -> bool {
+    let Some(file) = ledger.files.get(&account.file) else {
+        return false;
+    };
+    file.directives.iter().any(|d| match &d.content {
+        DirectiveContent::Custom(custom) => {
+            custom.name == CUSTOM_NAME
+                && string(custom.values.first()) == Some("account")
+                && string(custom.values.get(1)) == Some(account.account.0.as_str())
+        }
+        _ => false,
+    })
+}
+
+/// The directive holding the configuration of the account: its `open` directive or its
+/// `custom "gocardless" "account"` directive.
+pub fn config_directive_mut<'a>(
+    ledger: &'a mut Ledger<Decimal>,
+    account: &SourceAccount,
+) -> Option<&'a mut Directive<Decimal>> {
+    let file = ledger.files.get_mut(&account.file)?;
+    file.directives.iter_mut().find(|d| match &d.content {
+        DirectiveContent::Open(open) => open.account == account.account,
+        DirectiveContent::Custom(custom) => {
+            custom.name == CUSTOM_NAME
+                && string(custom.values.first()) == Some("account")
+                && string(custom.values.get(1)) == Some(account.account.0.as_str())
+        }
+        _ => false,
+    })
+}
@@ -0,0 +1,258 @@
+use crate::error::LedgerError;
+use anyhow::Context;
+use beanru::types::Ledger;
+use fs2::FileExt;
+use regex::Regex;
+use rust_decimal::Decimal;
+use std::{
+    collections::HashMap,
+    hash::{Hash, Hasher},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, OnceLock},
+};
+
+fn content_hash(content: &str) -> u64 {
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    content.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// The date, account and number of a balance assertion.
+const BALANCE: &str = r"^(\d{4}-\d{2}-\d{2})\s+balance\s+(\S+)\s+(-?[\d,.]+)";
+const CURRENCY: &str = r"([A-Z][A-Z0-9'._-]*)";
+
+/// A balance assertion with a tolerance, e.g. `2024-03-01 balance Assets:Bank 100.00 ~ 0.01 EUR`.
+fn tolerance_re() -> &'static Regex {
+    static RE: OnceLock<Regex> = OnceLock::new();
+    RE.get_or_init(|| Regex::new(&format!(r"{}\s*~\s*[\d.]+\s+{}", BALANCE, CURRENCY)).unwrap())
+}
+
+/// A balance assertion without a tolerance, as the beancount types render it.
+fn balance_re() -> &'static Regex {
+    static RE: OnceLock<Regex> = OnceLock::new();
+    RE.get_or_init(|| Regex::new(&format!(r"{}\s+{}", BALANCE, CURRENCY)).unwrap())
+}
+
+/// Identifies the balance assertion by its date, account and amount, however it is formatted.
+fn balance_key(c: &regex::Captures) -> String {
+    let number = c[3].replace(',', "");
+    let number = number
+        .parse::<Decimal>()
+        .map(|n| n.normalize().to_string())
+        .unwrap_or(number);
+    format!("{} {} {} {}", &c[1], &c[2], number, &c[4])
+}
+
+/// Removes the tolerances from the balance assertions, which the beancount types can't
+/// represent. Returns the content for the parser and the original lines of the balance assertions
+/// with a tolerance, by their key.
+pub fn strip_tolerances(content: &str) -> (String, HashMap<String, String>) {
+    let mut lines = HashMap::new();
+    let mut stripped = String::with_capacity(content.len());
+    for line in content.split_inclusive('\n') {
+        match tolerance_re().captures(line) {
+            Some(c) => {
+                lines.insert(
+                    balance_key(&c),
+                    line.trim_end_matches(['\r', '\n']).to_string(),
+                );
+                let rest = &line[c.get(0).unwrap().end()..];
+                stripped += &format!("{} balance {} {} {}{}", &c[1], &c[2], &c[3], &c[4], rest);
+            }
+            None => stripped += line,
+        }
+    }
+    (stripped, lines)
+}
+
+/// Puts the original lines (with the tolerance) of the balance assertions back into the rendered
+/// content.
+fn restore_tolerances(content: String, lines: &HashMap<String, String>) -> String {
+    if lines.is_empty() {
+        return content;
+    }
+    let mut restored = String::with_capacity(content.len());
+    for line in content.split_inclusive('\n') {
+        let original = balance_re()
+            .captures(line)
+            .and_then(|c| lines.get(&balance_key(&c)));
+        match original {
+            Some(original) => {
+                restored += original;
+                if line.ends_with('\n') {
+                    restored.push('\n');
+                }
+            }
+            None => restored += line,
+        }
+    }
+    restored
+}
+
+/// Hashes of the ledger files, as they were when the ledger was read, and the balance assertions
+/// with a tolerance in each.
+#[derive(Default)]
+pub struct Snapshot {
+    hashes: HashMap<PathBuf, u64>,
+    tolerances: HashMap<PathBuf, HashMap<String, String>>,
+}
+
+impl Snapshot {
+    /// Fails if any of the ledger files changed on disk since the ledger was read (e.g. because
+    /// they were edited while the import was running), so the changes are not overwritten.
+    pub async fn check_unmodified(&self) -> anyhow::Result<()> {
+        let mut modified = vec![];
+        for (path, hash) in &self.hashes {
+            let content = tokio::fs::read_to_string(path).await.unwrap_or_default();
+            if content_hash(&content) != *hash {
+                modified.push(path.display().to_string());
+            }
+        }
+        if !modified.is_empty() {
+            modified.sort();
+            return Err(anyhow::Error::new(LedgerError(format!(
+                "the ledger files were modified during the import, rerun the import to not overwrite \
+                 the changes: {}",
+                modified.join(", ")
+            ))));
+        }
+        Ok(())
+    }
+
+    /// Whether the content is the same as the file had when the ledger was read.
+    pub fn is_unchanged(&self, path: &Path, content: &str) -> bool {
+        self.hashes.get(path) == Some(&content_hash(content))
+    }
+
+    /// The rendered content of the file, with the balance assertions of the file that had a
+    /// tolerance kept as they were. Every write of the ledger goes through it, so the tolerances
+    /// survive.
+    pub fn restore(&self, path: &Path, content: String) -> String {
+        match self.tolerances.get(path) {
+            Some(lines) => restore_tolerances(content, lines),
+            None => content,
+        }
+    }
+}
+
+/// Reads the ledger, remembering the content of each file.
+pub async fn read_ledger(path: PathBuf) -> anyhow::Result<(Ledger<Decimal>, Snapshot)> {
+    let snapshot = Arc::new(Mutex::new(Snapshot::default()));
+    let context = LedgerError(format!("failed to read the ledger {}", path.display()));
+    let ledger = Ledger::read(path, |p| {
+        let snapshot = snapshot.clone();
+        async move {
+            let content = tokio::fs::read_to_string(&p).await?;
+            let (stripped, tolerances) = strip_tolerances(&content);
+            let mut snapshot = snapshot.lock().unwrap();
+            snapshot.hashes.insert(p.clone(), content_hash(&content));
+            snapshot.tolerances.insert(p, tolerances);
+            Ok(stripped)
+        }
+    })
+    .await
+    .context(context)?;
+    let snapshot = std::mem::take(&mut *snapshot.lock().unwrap());
+    Ok((ledger, snapshot))
+}
+
+/// Reads the ledger from the standard input. The ledger has to be in a single file, as the
+/// included files can't be read relative to it.
+pub async fn read_stdin(path: PathBuf) -> anyhow::Result<(Ledger<Decimal>, Snapshot)> {
+    let mut content = String::new();
+    tokio::io::AsyncReadExt::read_to_string(&mut tokio::io::stdin(), &mut content)
+        .await
+        .context(LedgerError(
+            "failed to read the ledger from stdin".to_string(),
+        ))?;
+    let hashes = HashMap::from([(path.clone(), content_hash(&content))]);
+    let (content, tolerances) = strip_tolerances(&content);
+    let tolerances = HashMap::from([(path.clone(), tolerances)]);
+    let root = path.clone();
+    let ledger = Ledger::read(path, |p| {
+        let content = content.clone();
+        let root = root.clone();
+        async move {
+            if p != root {
+                anyhow::bail!(
+                    "the ledger includes {}, which is not supported with --stdin",
+                    p.display()
+                );
+            }
+            Ok(content)
+        }
+    })
+    .await
+    .context(LedgerError(
+        "failed to parse the ledger from stdin".to_string(),
+    ))?;
+    Ok((ledger, Snapshot { hashes, tolerances }))
+}
+
+/// Exclusive lock on the ledger, released when dropped.
+pub struct LedgerLock {
+    _file: std::fs::File,
+}
+
+/// Takes the lock of the ledger, so two imports of the same ledger (e.g. an interactive one and
+/// one started by cron) can't run at the same time.
+pub fn lock_ledger(path: &Path) -> anyhow::Result<LedgerLock> {
+    let canonical = std::fs::canonicalize(path)
+        .with_context(|| LedgerError(format!("failed to read {}", path.display())))?;
+    let dir = crate::base_config_dir()?.join("locks");
+    std::fs::create_dir_all(&dir)?;
+    let name: String = canonical
+        .to_string_lossy()
+        .chars()
+        .map(|c| {
+            if c.is_alphanumeric() || c == '.' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    let lock_path = dir.join(format!("{}.lock", name));
+    let file = std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&lock_path)?;
+    file.try_lock_exclusive().map_err(|_| {
+        anyhow::Error::new(LedgerError(format!(
+            "another import of {} is running (lock file: {})",
+            path.display(),
+            lock_path.display()
+        )))
+    })?;
+    Ok(LedgerLock { _file: file })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tolerances_survive_rendering() {
+        let content = "2024-01-01 open Assets:Bank\n\n\
+                       2024-03-01 balance Assets:Bank  1,100.00 ~ 0.01 EUR\n\n\
+                       2024-03-02 balance Assets:Bank  90.00 EUR\n";
+        let (stripped, lines) = strip_tolerances(content);
+        assert_eq!(
+            stripped,
+            "2024-01-01 open Assets:Bank\n\n\
+             2024-03-01 balance Assets:Bank 1,100.00 EUR\n\n\
+             2024-03-02 balance Assets:Bank  90.00 EUR\n"
+        );
+        // Rendered differently by the beancount types.
+        let rendered = "2024-01-01 open Assets:Bank\n\n\
+                        2024-03-01 balance Assets:Bank 1100.0 EUR\n\n\
+                        2024-03-02 balance Assets:Bank 90.00 EUR\n";
+        assert_eq!(
+            restore_tolerances(rendered.to_string(), &lines),
+            "2024-01-01 open Assets:Bank\n\n\
+             2024-03-01 balance Assets:Bank  1,100.00 ~ 0.01 EUR\n\n\
+             2024-03-02 balance Assets:Bank 90.00 EUR\n"
+        );
+    }
+}
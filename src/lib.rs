@@ -0,0 +1,2035 @@
+//! Imports transactions and balances from GoCardless (bank account data) into a beancount
+//! ledger. The `beancount-gocardless-importer` binary is a thin CLI over this library.
+
+use anyhow::Context;
+use beanru::{
+    bag::Bag,
+    types::{
+        Account, Amount, Balance, Currency, Directive, DirectiveContent, Ledger, MetadataValue,
+        Price,
+    },
+};
+use chrono::{Days, NaiveDate};
+use gocardless::models::{
+    BalanceSchema, JwtRefreshRequest, SpectacularJwtObtain, SpectacularJwtRefresh,
+    TransactionSchema,
+};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+    time::{Duration, SystemTime},
+};
+use tokio::io::AsyncWriteExt;
+use tracing::{debug, info, trace, warn};
+
+pub mod account_details;
+pub mod amount;
+pub mod anonymize;
+pub mod cards;
+pub mod consents;
+pub mod daemon;
+pub mod declarations;
+pub mod dedup;
+pub mod doctor;
+pub mod documents;
+pub mod edit;
+pub mod error;
+pub mod export;
+pub mod extract;
+pub mod failure;
+pub mod format;
+pub mod history;
+pub mod import_dir;
+pub mod init;
+pub mod ledger_config;
+pub mod ledger_io;
+pub mod mapper;
+pub mod metrics;
+pub mod mutations;
+pub mod notify;
+pub mod output;
+pub mod payees;
+pub mod pending;
+pub mod pipe;
+pub mod preview;
+pub mod progress;
+pub mod quirks;
+pub mod quota;
+pub mod recording;
+pub mod recurring;
+pub mod refunds;
+pub mod requisitions;
+pub mod review;
+pub mod roundups;
+pub mod rule_test;
+pub mod rules;
+pub mod sanity;
+pub mod script;
+pub mod settings;
+pub mod snapshot;
+pub mod source;
+pub mod store;
+#[cfg(test)]
+pub(crate) mod test_util;
+pub mod timezone;
+pub mod validate;
+pub mod verify_config;
+
+/// Sends the API request, logging how long it took. Requests that failed because of a network or
+/// server error are retried, as configured in the settings.
+pub async fn timed<T, E, Fut>(request: &str, send: impl Fn() -> Fut) -> anyhow::Result<T>
+where
+    Fut: std::future::Future<Output = Result<T, gocardless::apis::Error<E>>>,
+{
+    let retry = &settings::get().retry;
+    let mut attempt = 1;
+    loop {
+        let start = std::time::Instant::now();
+        let res = send().await;
+        debug!(
+            request,
+            attempt,
+            elapsed_ms = start.elapsed().as_millis() as u64,
+            ok = res.is_ok(),
+            "API request"
+        );
+        let transient = match &res {
+            Err(gocardless::apis::Error::Reqwest(_)) => true,
+            Err(gocardless::apis::Error::ResponseError(c)) => c.status.is_server_error(),
+            _ => false,
+        };
+        if !transient || attempt >= retry.attempts {
+            return res.map_err(gocardless_err);
+        }
+        let delay = retry.backoff(attempt);
+        if let Err(e) = res {
+            warn!(request, "{:#}, retrying in {:?}", gocardless_err(e), delay);
+        }
+        tokio::time::sleep(delay).await;
+        attempt += 1;
+    }
+}
+
+/// Sends the API request that isn't safe to repeat (e.g. creating a requisition) once, logging how
+/// long it took.
+pub async fn timed_once<T, E, Fut>(request: &str, send: impl FnOnce() -> Fut) -> anyhow::Result<T>
+where
+    Fut: std::future::Future<Output = Result<T, gocardless::apis::Error<E>>>,
+{
+    let start = std::time::Instant::now();
+    let res = send().await;
+    debug!(
+        request,
+        elapsed_ms = start.elapsed().as_millis() as u64,
+        ok = res.is_ok(),
+        "API request"
+    );
+    res.map_err(gocardless_err)
+}
+
+/// Options of the import. All the features that change the imported directives are opt-in.
+#[derive(clap::Args, Clone, Debug)]
+pub struct ImportOptions {
+    #[arg(long)]
+    pub filter_account_re: Option<regex::Regex>,
+    /// Path to the YAML file with categorization rules. For more information, see README.md
+    #[arg(long)]
+    pub rules: Option<PathBuf>,
+    /// Tag added to every imported transaction (can be repeated). `{date}` is replaced with the
+    /// date of the import, e.g. `import-{date}`.
+    #[arg(long = "tag")]
+    pub tags: Vec<String>,
+    /// Instead of adding the tags to each transaction, wrap the imported directives in each file
+    /// with `pushtag`/`poptag`.
+    #[arg(long)]
+    pub pushtag: bool,
+    /// Flag of the imported transactions, e.g. `!` to mark them for review.
+    #[arg(long)]
+    pub flag: Option<char>,
+    /// Flag of the imported transactions that were fully categorized by one of the rules.
+    #[arg(long, default_value_t = '*')]
+    pub categorized_flag: char,
+    /// Link refunds and reversals with the original transaction.
+    #[arg(long)]
+    pub link_refunds: bool,
+    /// With --link-refunds, how many days before the refund the original transaction can be.
+    #[arg(long, default_value_t = 30)]
+    pub refund_window_days: u64,
+    /// Tag new payments that continue a regular series of payments (e.g. monthly subscriptions)
+    /// with `#subscription` and the detected `recurrence` metadata.
+    #[arg(long)]
+    pub detect_recurring: bool,
+    /// After the import, print how many transactions each rule categorized and the
+    /// counterparties of the uncategorized ones, the most frequent first.
+    #[arg(long)]
+    pub rule_stats: bool,
+    /// Flag the suspicious transactions with `!` for review: in a currency new to the account,
+    /// with the same amount as another transaction of the day, or above `--suspicious-amount`.
+    #[arg(long)]
+    pub sanity_checks: bool,
+    /// Amount above which the transactions are suspicious, unless the account configures its own
+    /// with the `suspicious_amount` metadata.
+    #[arg(long, value_name = "AMOUNT", requires = "sanity_checks")]
+    pub suspicious_amount: Option<Decimal>,
+    /// Add `price` directives for the exchange rates of foreign currency transactions.
+    #[arg(long)]
+    pub prices: bool,
+    /// Add `commodity` directives for the imported currencies that are not declared in the
+    /// ledger.
+    #[arg(long)]
+    pub declare_commodities: bool,
+    /// File (part of the ledger) that receives the new `commodity` directives. By default, they
+    /// are added to the file where the currency is used.
+    #[arg(long, requires = "declare_commodities")]
+    pub commodities_file: Option<PathBuf>,
+    /// Add `document` directives for the statements in the `statements_dir` of the accounts that
+    /// are not in the ledger yet.
+    #[arg(long)]
+    pub documents: bool,
+    /// Add `open` directives for the accounts used by the imported transactions (e.g. assigned by
+    /// the categorization rules) that are not opened in the ledger.
+    #[arg(long)]
+    pub open_accounts: bool,
+    /// File (part of the ledger) that receives the new `open` directives. By default, they are
+    /// added to the file where the account is used.
+    #[arg(long, requires = "open_accounts")]
+    pub accounts_file: Option<PathBuf>,
+    /// Check the new directives before writing the ledger (accounts are opened, transactions
+    /// balance) and abort the import if any problems are found.
+    #[arg(long)]
+    pub validate: bool,
+    /// Additionally run `bean-check` on the resulting ledger and abort the import if it reports
+    /// more errors than for the ledger on disk.
+    #[arg(long)]
+    pub bean_check: bool,
+    /// Do not fetch anything from GoCardless, import only the transactions and balances saved in
+    /// the local store by the previous imports.
+    #[arg(long)]
+    pub offline: bool,
+    /// YAML file with the profiles (column layouts) of the CSV statements, for the accounts
+    /// imported with `importer: "csv"`. For more information, see README.md
+    #[arg(long)]
+    pub csv_profiles: Option<PathBuf>,
+    /// Command run (with `sh -c`) after the import, with the JSON summary on the standard input.
+    #[arg(long)]
+    pub notify_command: Option<String>,
+    /// URL the JSON summary is POSTed to after the import (e.g. ntfy, Slack or Matrix webhook).
+    #[arg(long)]
+    pub notify_url: Option<reqwest::Url>,
+    /// File updated with the metrics of the imports, in the Prometheus text format (e.g. for the
+    /// node_exporter textfile collector).
+    #[arg(long)]
+    pub metrics_file: Option<PathBuf>,
+    /// Request the longest transaction history the bank provides for the account (e.g. right
+    /// after connecting it), instead of the default period.
+    #[arg(long, value_name = "ACCOUNT", value_parser = |s: &str| anyhow::Ok(Account(s.to_string())), conflicts_with = "offline")]
+    pub backfill: Option<Account>,
+    /// Import only the balance assertions, without fetching the transactions.
+    #[arg(long, conflicts_with = "no_balance")]
+    pub balances_only: bool,
+    /// Import only the transactions, without the balance assertions.
+    #[arg(long)]
+    pub no_balance: bool,
+    /// Add the balance assertion even when the balance didn't change since the last one, if that
+    /// is at least this many days old (0 to always add it).
+    #[arg(long, value_name = "DAYS")]
+    pub balance_interval_days: Option<u64>,
+    /// Log and skip the transactions that can't be converted (e.g. with an invalid date or
+    /// amount) instead of failing the import.
+    #[arg(long)]
+    pub skip_bad_transactions: bool,
+    /// Print the directives that would be added to each file, without modifying the ledger.
+    #[arg(long)]
+    pub dry_run: bool,
+    /// Print the directives added to each file.
+    #[arg(long, conflicts_with = "dry_run")]
+    pub diff: bool,
+    /// Open the new directives in `$EDITOR` and import whatever remains after the file is saved
+    /// and the editor is closed.
+    #[arg(long, conflicts_with = "dry_run")]
+    pub edit: bool,
+    /// Record the date, amount and narration of the transactions changed by the bank after they
+    /// were imported in their metadata (`bank_date`, `bank_amount`, `bank_narration`). The changes
+    /// are reported either way. Not available with an import directory, as the ledger isn't written.
+    #[arg(long, conflicts_with = "import_dir")]
+    pub record_bank_changes: bool,
+    /// Record `event "gocardless"` directives for the agreements (granting the access to the
+    /// accounts of the ledger) accepted since the last import.
+    #[arg(long, conflicts_with = "offline")]
+    pub record_consents: bool,
+    /// Fetch from GoCardless even if the daily API quota of some accounts seems to be used up.
+    #[arg(long)]
+    pub force: bool,
+    /// Rhai script run on every imported transaction after the categorization rules, which can
+    /// change or drop it. For more information, see README.md
+    #[arg(long)]
+    pub script: Option<PathBuf>,
+    /// Command run (with `sh -c`) on every imported transaction after the rules and the script,
+    /// with the transaction as JSON on the standard input. Its output replaces the transaction,
+    /// and an empty output drops it. For more information, see README.md
+    #[arg(long)]
+    pub transform_command: Option<String>,
+    /// YAML file with the known payees (default account, tags and aliases), also updated with
+    /// the number of imported transactions of each. For more information, see README.md
+    #[arg(long)]
+    pub payees: Option<PathBuf>,
+    /// Write the imported directives into a new dated file in the directory (listed in its
+    /// `index.json`) for review, instead of adding them to the ledger.
+    #[arg(long, value_name = "DIR")]
+    pub import_dir: Option<PathBuf>,
+    /// Also append the imported transactions to the journal of another plain text accounting tool
+    /// (in the `--journal-format`), skipping the ones already in it.
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["dry_run", "stdout", "import_dir"])]
+    pub journal: Option<PathBuf>,
+    /// Format of the `--journal`: hledger, ledger or beancount-import.
+    #[arg(long, value_enum, default_value_t = export::ExportFormat::Hledger)]
+    pub journal_format: export::ExportFormat,
+    /// Read the ledger (a single file, without includes) from the standard input. Requires
+    /// `--stdout`.
+    #[arg(long, requires = "stdout")]
+    pub stdin: bool,
+    /// Print the resulting ledger (a single file) to the standard output instead of writing it.
+    #[arg(long, conflicts_with_all = ["dry_run", "diff", "edit", "import_dir"])]
+    pub stdout: bool,
+}
+
+impl Default for ImportOptions {
+    /// The same options as `import` without any flags.
+    fn default() -> Self {
+        #[derive(clap::Parser)]
+        struct Cli {
+            #[command(flatten)]
+            options: ImportOptions,
+        }
+        <Cli as clap::Parser>::parse_from(["import"]).options
+    }
+}
+
+impl ImportOptions {
+    /// The tags added to the imported transactions, with the placeholders replaced.
+    pub fn tags(&self, today: NaiveDate) -> Vec<String> {
+        self.tags
+            .iter()
+            .map(|t| {
+                t.trim_start_matches('#')
+                    .replace("{date}", &today.format("%Y-%m-%d").to_string())
+            })
+            .collect()
+    }
+}
+
+/// The GoCardless API tokens, saved in `~/.gocardless/token.yml` by `sign-in`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Tokens {
+    pub access_token: String,
+    pub access_expires: SystemTime,
+    pub refresh_token: String,
+    pub refresh_expires: SystemTime,
+}
+
+impl Tokens {
+    pub fn from_jwt(now: SystemTime, jwt: &SpectacularJwtObtain) -> anyhow::Result<Tokens> {
+        Ok(Tokens {
+            access_token: jwt.access.clone().context("access token is missing")?,
+            access_expires: now
+                + Duration::from_secs(
+                    jwt.access_expires
+                        .context("access token expiration is missing")?
+                        .try_into()?,
+                ),
+            refresh_token: jwt.refresh.clone().context("refresh token is missing")?,
+            refresh_expires: now
+                + Duration::from_secs(
+                    jwt.refresh_expires
+                        .context("refresh token expiration is missing")?
+                        .try_into()?,
+                ),
+        })
+    }
+
+    pub fn update_access_token(
+        &mut self,
+        now: SystemTime,
+        jwt: &SpectacularJwtRefresh,
+    ) -> anyhow::Result<()> {
+        self.access_token = jwt.access.clone().context("access token is missing")?;
+        self.access_expires = now
+            + Duration::from_secs(
+                jwt.access_expires
+                    .context("access token expiration is missing")?
+                    .try_into()?,
+            );
+        Ok(())
+    }
+}
+
+/// Directory with the tokens and the other state of the importer (`~/.gocardless`).
+pub fn base_config_dir() -> anyhow::Result<PathBuf> {
+    Ok(PathBuf::from(std::env::var("HOME")?).join(".gocardless"))
+}
+
+/// Returns a valid access token, refreshing it if needed.
+pub async fn get_token() -> anyhow::Result<String> {
+    let path = base_config_dir()?.join("token.yml");
+    let now = SystemTime::now();
+    let content = tokio::fs::read_to_string(&path)
+        .await
+        .context(failure::Failure::Auth)?;
+    let mut tokens: Tokens = serde_yaml::from_str(&content).context(failure::Failure::Auth)?;
+    if now < tokens.access_expires {
+        return Ok(tokens.access_token);
+    }
+    if now > tokens.refresh_expires {
+        return Err(anyhow::format_err!("refresh token exipred").context(failure::Failure::Auth));
+    }
+    let config = api_config()?;
+    let jwt = timed("get_a_new_access_token", || {
+        gocardless::apis::token_api::get_a_new_access_token(
+            &config,
+            JwtRefreshRequest::new(tokens.refresh_token.clone()),
+        )
+    })
+    .await?;
+    tokens.update_access_token(now, &jwt)?;
+    tokio::fs::write(&path, serde_yaml::to_string(&tokens)?.as_bytes()).await?;
+    Ok(tokens.access_token)
+}
+
+static HTTP_CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+
+/// HTTP client with the proxy and the TLS settings, shared by all the requests.
+pub fn http_client() -> anyhow::Result<reqwest::Client> {
+    if let Some(client) = HTTP_CLIENT.get() {
+        return Ok(client.clone());
+    }
+    let http = &settings::get().http;
+    let mut builder = reqwest::Client::builder()
+        .timeout(http.timeout())
+        .user_agent(http.user_agent());
+    if let Some(proxy) = &http.proxy {
+        builder = builder.proxy(reqwest::Proxy::all(proxy.clone())?);
+    }
+    if let Some(path) = &http.ca_bundle {
+        let pem = std::fs::read(path)
+            .with_context(|| format!("failed to read the CA bundle {}", path.display()))?;
+        for cert in pem_certificates(&pem) {
+            let cert = reqwest::Certificate::from_pem(cert.as_bytes())
+                .with_context(|| format!("invalid certificate in {}", path.display()))?;
+            builder = builder.add_root_certificate(cert);
+        }
+    }
+    if http.insecure {
+        warn!("the TLS certificates are not verified");
+        builder = builder.danger_accept_invalid_certs(true);
+    }
+    let client = builder.build()?;
+    Ok(HTTP_CLIENT.get_or_init(|| client).clone())
+}
+
+/// Splits the PEM bundle into the single certificates.
+fn pem_certificates(pem: &[u8]) -> Vec<String> {
+    const END: &str = "-----END CERTIFICATE-----";
+    String::from_utf8_lossy(pem)
+        .split_inclusive(END)
+        .filter(|c| c.contains(END))
+        .map(|c| c.trim_start().to_string())
+        .collect()
+}
+
+/// API configuration with the base URL and the HTTP client from the settings.
+pub fn api_config() -> anyhow::Result<gocardless::apis::configuration::Configuration> {
+    let mut config = gocardless::apis::configuration::Configuration {
+        client: http_client()?,
+        user_agent: Some(settings::get().http.user_agent()),
+        ..Default::default()
+    };
+    if let Some(url) = &settings::get().api_url {
+        config.base_path = url.trim_end_matches('/').to_string();
+    }
+    Ok(config)
+}
+
+/// API configuration authenticated with the saved access token.
+pub async fn config_with_token() -> anyhow::Result<gocardless::apis::configuration::Configuration> {
+    let token = get_token()
+        .await
+        .context("Failed to get the access token, please first run `sign-in` command")?;
+    Ok(gocardless::apis::configuration::Configuration {
+        bearer_access_token: Some(token),
+        ..api_config()?
+    })
+}
+
+/// Narration of the imported transaction: the remittance information or the creditor name.
+pub fn narration(t: &TransactionSchema) -> Option<String> {
+    if let Some(inf) = &t.remittance_information_unstructured_array {
+        if !inf.is_empty() {
+            return Some(inf.join(", "));
+        }
+    }
+    if let Some(inf) = &t.remittance_information_unstructured {
+        return Some(inf.clone());
+    }
+    t.creditor_name.clone()
+}
+
+/// Date of the imported transaction. Not every bank fills the booking date, so the first of the
+/// booking date, the booking date-time, the value date and the value date-time is used.
+pub fn booking_date(t: &TransactionSchema) -> anyhow::Result<NaiveDate> {
+    let dates = [
+        ("booking_date", &t.booking_date),
+        ("booking_date_time", &t.booking_date_time),
+        ("value_date", &t.value_date),
+        ("value_date_time", &t.value_date_time),
+    ];
+    for (field, value) in dates {
+        let Some(value) = value else {
+            continue;
+        };
+        let (date, _) = NaiveDate::parse_and_remainder(value, "%Y-%m-%d")
+            .with_context(|| format!("invalid {} {:?}", field, value))?;
+        if field != "booking_date" {
+            debug!("the booking date is missing, using the {}", field);
+        }
+        return Ok(date);
+    }
+    anyhow::bail!(
+        "transaction {} has no booking or value date",
+        t.internal_transaction_id
+            .as_deref()
+            .or(t.transaction_id.as_deref())
+            .unwrap_or("without id")
+    )
+}
+
+/// Converts the GoCardless transaction to a beancount transaction with a single posting to the
+/// account, with the default [`mapper::TransactionMapper`].
+pub fn gocardless_transaction_to_beancount(
+    t: &TransactionSchema,
+    account: &Account,
+) -> anyhow::Result<Directive<Decimal>> {
+    mapper::TransactionMapper::default().map(t, account)
+}
+
+/// Converts the booked transactions with the mapper and applies the categorization rules, without
+/// looking at the ledger (used by the previews and exports).
+pub fn categorized_directives(
+    res: &gocardless::models::AccountTransactions,
+    account: &Account,
+    mapper: &mapper::TransactionMapper,
+    rules: &rules::Rules,
+) -> anyhow::Result<Vec<Directive<Decimal>>> {
+    let mut directives = vec![];
+    for t in &res.transactions.booked {
+        let mut d = mapper.map(t, account)?;
+        if let Some(c) = rules.categorize(t) {
+            apply_category(&c.category, &mut d, t);
+        }
+        directives.push(d);
+    }
+    sort_transactions(&mut directives);
+    Ok(directives)
+}
+
+/// Applies the category to the transaction. A category that doesn't fit it (e.g. a salary template
+/// matching a payment, or split postings that don't balance it) leaves the transaction
+/// uncategorized with a warning, as it depends on the data of the bank. Returns whether it was
+/// applied.
+fn apply_category(
+    category: &rules::Category,
+    d: &mut Directive<Decimal>,
+    t: &TransactionSchema,
+) -> bool {
+    let mut categorized = d.clone();
+    match category.apply(&mut categorized) {
+        Ok(()) => {
+            *d = categorized;
+            true
+        }
+        Err(e) => {
+            warn!(
+                "leaving the transaction {:?} uncategorized: {:#}",
+                t.internal_transaction_id, e
+            );
+            false
+        }
+    }
+}
+
+/// Booking time of the imported transaction, if the bank reported it.
+fn booking_time(d: &Directive<Decimal>) -> Option<chrono::DateTime<chrono::Utc>> {
+    let dt = metadata_str(d, "booking_date_time")?;
+    match chrono::DateTime::parse_from_rfc3339(dt) {
+        Ok(dt) => Some(dt.to_utc()),
+        Err(_) => chrono::NaiveDateTime::parse_from_str(dt, "%Y-%m-%dT%H:%M:%S%.f")
+            .ok()
+            .map(|dt| dt.and_utc()),
+    }
+}
+
+/// Sorts the converted transactions (in the order returned by the bank, newest first) by date.
+/// Within a day, they are ordered by the booking time when the bank reports it, and in the
+/// reverse order of the bank otherwise, so the order doesn't change between the imports.
+pub fn sort_transactions(directives: &mut [Directive<Decimal>]) {
+    directives.reverse();
+    directives.sort_by_cached_key(|d| (d.date, booking_time(d)));
+}
+
+pub fn metadata_str<'a>(d: &'a Directive<Decimal>, key: &str) -> Option<&'a str> {
+    match d.metadata.get(key) {
+        Some(MetadataValue::String(s)) => Some(s),
+        _ => None,
+    }
+}
+
+/// Returns the amount of the transaction posted to the given account.
+pub fn posting_amount(d: &Directive<Decimal>, account: &Account) -> Option<Amount<Decimal>> {
+    let DirectiveContent::Transaction(t) = &d.content else {
+        return None;
+    };
+    t.postings
+        .iter()
+        .find(|p| &p.account == account)
+        .and_then(|p| p.amount.clone())
+}
+
+/// Creates the price directive from the exchange rate used by the bank for the transaction.
+pub fn price_directive(
+    t: &TransactionSchema,
+    date: NaiveDate,
+) -> anyhow::Result<Option<Directive<Decimal>>> {
+    let Some(ce) = &t.currency_exchange else {
+        return Ok(None);
+    };
+    let (Some(source), Some(target), Some(rate)) =
+        (&ce.source_currency, &ce.target_currency, &ce.exchange_rate)
+    else {
+        return Ok(None);
+    };
+    if source == target {
+        return Ok(None);
+    }
+    // The rate is expressed as 1 unit currency = rate of the other currency. Without the unit
+    // currency, the rate converts from the source currency.
+    let (base, quote) = match &ce.unit_currency {
+        Some(unit) if unit == target => (target, source),
+        _ => (source, target),
+    };
+    let rate: Decimal = rate
+        .parse()
+        .with_context(|| format!("invalid exchange rate {:?}", rate))?;
+    Ok(Some(Directive {
+        date,
+        content: DirectiveContent::Price(Price {
+            currency: Currency(base.clone()),
+            amount: Amount {
+                value: rate,
+                currency: Currency(quote.clone()),
+            },
+        }),
+        metadata: Default::default(),
+    }))
+}
+
+/// Creates the price directives from a balance the bank reports in more than one currency: one
+/// price of the currency of the balance per other currency of the same balance type.
+pub fn balance_prices(
+    balances: &[BalanceSchema],
+    balance: &BalanceSchema,
+    date: NaiveDate,
+) -> anyhow::Result<Vec<Directive<Decimal>>> {
+    let base = amount::parse(&balance.balance_amount.amount, amount::API_SEPARATOR)?;
+    if base.is_zero() {
+        return Ok(vec![]);
+    }
+    let mut currencies = HashSet::from([&balance.balance_amount.currency]);
+    let mut prices = vec![];
+    for b in balances {
+        if b.balance_type != balance.balance_type || !currencies.insert(&b.balance_amount.currency)
+        {
+            continue;
+        }
+        let value = amount::parse(&b.balance_amount.amount, amount::API_SEPARATOR)?;
+        prices.push(Directive {
+            date,
+            content: DirectiveContent::Price(Price {
+                currency: Currency(balance.balance_amount.currency.clone()),
+                amount: Amount {
+                    value: (value / base).round_dp(6),
+                    currency: Currency(b.balance_amount.currency.clone()),
+                },
+            }),
+            metadata: Default::default(),
+        });
+    }
+    Ok(prices)
+}
+
+/// Adds the price, unless the ledger (or the import) already has a price of the currency pair on
+/// that date.
+fn add_price(
+    prices: &mut HashSet<(NaiveDate, Currency, Currency)>,
+    new_prices: &mut Vec<Directive<Decimal>>,
+    p: Directive<Decimal>,
+) {
+    if let DirectiveContent::Price(price) = &p.content {
+        if prices.insert((
+            p.date,
+            price.currency.clone(),
+            price.amount.currency.clone(),
+        )) {
+            new_prices.push(p);
+        }
+    }
+}
+
+/// Whether the directive has one of the links of the already imported transactions.
+pub fn is_duplicate(d: &Directive<Decimal>, ids: &HashSet<String>) -> bool {
+    let Some(t) = d.content.transaction_opt() else {
+        return false;
+    };
+    for link in &t.links {
+        if ids.contains(link) {
+            return true;
+        }
+    }
+    false
+}
+
+/// Describes the transaction that failed to convert: the account, the id and the start of the
+/// raw transaction.
+fn conversion_context(account: &Account, t: &TransactionSchema) -> error::ConversionError {
+    const SNIPPET_LEN: usize = 200;
+    let mut raw = serde_json::to_string(t).unwrap_or_default();
+    if let Some((i, _)) = raw.char_indices().nth(SNIPPET_LEN) {
+        raw.truncate(i);
+        raw += "...";
+    }
+    error::ConversionError(format!(
+        "{}: failed to convert transaction {}: {}",
+        account,
+        t.internal_transaction_id
+            .as_deref()
+            .or(t.transaction_id.as_deref())
+            .unwrap_or("without id"),
+        raw
+    ))
+}
+
+/// Result of the import of a single account.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AccountSummary {
+    pub account: String,
+    pub importer: String,
+    pub account_id: Option<String>,
+    pub new_transactions: usize,
+    /// Why the account failed to import (none of its directives are imported then).
+    pub error: Option<String>,
+    pub failure: Option<failure::Failure>,
+}
+
+impl AccountSummary {
+    fn imported(importer: &str, account: &source::SourceAccount, new_transactions: usize) -> Self {
+        AccountSummary {
+            account: account.account.to_string(),
+            importer: importer.to_string(),
+            account_id: account.metadata_str("account_id").map(|s| s.to_string()),
+            new_transactions,
+            error: None,
+            failure: None,
+        }
+    }
+
+    fn failed(importer: &str, account: &source::SourceAccount, e: &anyhow::Error) -> Self {
+        AccountSummary {
+            account: account.account.to_string(),
+            importer: importer.to_string(),
+            account_id: account.metadata_str("account_id").map(|s| s.to_string()),
+            new_transactions: 0,
+            error: Some(format!("{:#}", e)),
+            failure: failure::Failure::of(e),
+        }
+    }
+}
+
+/// Warns about the configured account ids that are not attached to any linked requisition, and
+/// the linked accounts that nothing in the ledger imports, according to the requisitions cache.
+fn check_linked(configured: &[(Account, String)]) {
+    let cache = match requisitions::Cache::load() {
+        Ok(cache) => cache,
+        Err(e) => {
+            warn!("{:#}", e);
+            return;
+        }
+    };
+    if cache.requisitions.is_empty() {
+        return;
+    }
+    for (account, id) in configured {
+        if cache.account(id).is_none() {
+            warn!(
+                "{}: account {} is not attached to any linked requisition, run `doctor` for details",
+                account, id
+            );
+        }
+    }
+    let imported: HashSet<&str> = configured.iter().map(|(_, id)| id.as_str()).collect();
+    for a in cache.unimported(&imported) {
+        warn!("the linked account {} is not imported into the ledger", a);
+    }
+}
+
+/// The state updated by the import besides the ledger, saved once the imported directives are
+/// written.
+pub struct ImportState {
+    /// The pending holds that were reported.
+    pub pending: pending::State,
+    /// The payee directory, with the counts of the payees.
+    pub payees: Option<payees::Directory>,
+}
+
+impl ImportState {
+    pub fn save(&self) -> anyhow::Result<()> {
+        self.pending.save()?;
+        if let Some(payees) = &self.payees {
+            payees.save()?;
+        }
+        Ok(())
+    }
+}
+
+/// What the import needs to know about the ledger it imports into.
+#[derive(Default)]
+pub struct LedgerIndex {
+    /// The date and amount of the last balance assertion of each account.
+    pub last_balance: HashMap<Account, (NaiveDate, Amount<Decimal>)>,
+    /// The date of the last transaction of each account.
+    pub last_transaction: HashMap<Account, NaiveDate>,
+    /// (date, currency, quote currency) of the prices already present in the ledger.
+    pub prices: HashSet<(NaiveDate, Currency, Currency)>,
+    /// Paths of the documents already present in the ledger.
+    pub documents: HashSet<PathBuf>,
+    /// The imported transactions, to detect the ones changed by the bank.
+    pub recorded: HashMap<u64, mutations::Recorded>,
+}
+
+impl LedgerIndex {
+    /// Indexes the ledger in a single pass, adding its transactions to the dedup strategy. The
+    /// accounts and amounts are cloned only when they are first seen or newer, as large ledgers
+    /// have many postings per account. The documents are indexed only if `documents` is set.
+    pub fn build(
+        ledger: &Ledger<Decimal>,
+        mapper: &mapper::TransactionMapper,
+        dedup: &mut dyn dedup::DedupStrategy,
+        documents: bool,
+    ) -> LedgerIndex {
+        let mut index = LedgerIndex::default();
+        let start = std::time::Instant::now();
+        let mut directives = 0;
+        for (path, file) in &ledger.files {
+            directives += file.directives.len();
+            for d in &file.directives {
+                match &d.content {
+                    DirectiveContent::Transaction(t) => {
+                        roundups::add_to_dedup(dedup, mapper, d);
+                        mutations::add_recorded(&mut index.recorded, mapper, d);
+                        for p in &t.postings {
+                            match index.last_transaction.get_mut(&p.account) {
+                                Some(date) => *date = (*date).max(d.date),
+                                None => {
+                                    index.last_transaction.insert(p.account.clone(), d.date);
+                                }
+                            }
+                        }
+                    }
+                    DirectiveContent::Balance(b) => match index.last_balance.get_mut(&b.account) {
+                        Some(e) if e.0 < d.date => *e = (d.date, b.amount.clone()),
+                        Some(_) => {}
+                        None => {
+                            index
+                                .last_balance
+                                .insert(b.account.clone(), (d.date, b.amount.clone()));
+                        }
+                    },
+                    DirectiveContent::Price(p) => {
+                        index.prices.insert((
+                            d.date,
+                            p.currency.clone(),
+                            p.amount.currency.clone(),
+                        ));
+                    }
+                    DirectiveContent::Document(doc) if documents => {
+                        index
+                            .documents
+                            .insert(ledger_config::resolve(path, &doc.path));
+                    }
+                    _ => {}
+                }
+            }
+        }
+        debug!(directives, elapsed = ?start.elapsed(), "indexed the ledger");
+        index
+    }
+}
+
+/// Imports the new transactions and balances of all accounts configured in the ledger.
+pub async fn import(
+    ledger: &mut Ledger<Decimal>,
+    options: &ImportOptions,
+    rules: &rules::Rules,
+    sources: &[Box<dyn source::ImportSource>],
+    mapper: &mapper::TransactionMapper,
+    dedup: &mut dyn dedup::DedupStrategy,
+    state: &mut ImportState,
+) -> anyhow::Result<Vec<AccountSummary>> {
+    let pending_state = &mut state.pending;
+    let mut payees = state.payees.as_mut();
+    let tags = if options.pushtag {
+        vec![]
+    } else {
+        options.tags(chrono::Local::now().date_naive())
+    };
+
+    let LedgerIndex {
+        last_balance,
+        mut last_transaction,
+        mut prices,
+        documents,
+        recorded,
+    } = LedgerIndex::build(ledger, mapper, dedup, options.documents);
+    let mut summary = vec![];
+    let mut mutated = vec![];
+    // Internal ids of the transactions imported by this run. The dedup strategy only knows the
+    // ledger, so e.g. two identical coffees of one day are not taken for the same transaction.
+    let mut imported_ids = HashSet::new();
+    let mut rule_stats = rules::Stats::default();
+
+    // The accounts configured with one of the sources, grouped by the file that receives their
+    // directives.
+    let mut targets: HashMap<PathBuf, Vec<(&dyn source::ImportSource, source::SourceAccount)>> =
+        HashMap::new();
+    // The configured GoCardless accounts (also the ones filtered out), by their account id.
+    let mut gocardless_ids: Vec<(Account, String)> = vec![];
+    for account in ledger_config::configured_accounts(ledger)? {
+        let Some(importer) = account.metadata_str("importer") else {
+            continue;
+        };
+        let Some(source) = source::find(sources, importer) else {
+            continue;
+        };
+        if importer == "gocardless" {
+            // Not connected to GoCardless yet.
+            let Some(id) = account.metadata_str("account_id") else {
+                debug!(account = %account.account, "no account_id, skipping");
+                continue;
+            };
+            gocardless_ids.push((account.account.clone(), id.to_string()));
+        }
+        if let Some(ref filter) = options.filter_account_re {
+            if !filter.is_match(&account.account.0) {
+                continue;
+            }
+        }
+        let target = ledger_config::target_file(ledger, &account)?;
+        targets.entry(target).or_default().push((source, account));
+    }
+    if let Some(backfill) = &options.backfill {
+        if !targets
+            .values()
+            .flatten()
+            .any(|(s, a)| s.name() == "gocardless" && a.account == *backfill)
+        {
+            anyhow::bail!(
+                "{} is not imported from GoCardless, it can't be backfilled",
+                backfill
+            );
+        }
+    }
+    if !options.offline {
+        let planned: Vec<quota::Planned> = targets
+            .values()
+            .flatten()
+            .filter(|(s, _)| s.name() == "gocardless")
+            .filter_map(|(_, a)| {
+                let mut endpoints = vec![];
+                if !options.balances_only {
+                    endpoints.push(quota::TRANSACTIONS);
+                }
+                if !options.no_balance {
+                    endpoints.push(quota::BALANCES);
+                }
+                Some(quota::Planned {
+                    account: &a.account.0,
+                    account_id: a.metadata_str("account_id")?,
+                    endpoints,
+                })
+            })
+            .collect();
+        quota::check(&planned, options.force)?;
+        check_linked(&gocardless_ids);
+    }
+    let config = ledger_config::LedgerConfig::read(ledger)?;
+    let default_category = config.default_account.map(|a| rules::Category {
+        account: Some(a.0),
+        ..Default::default()
+    });
+    let script = options
+        .script
+        .clone()
+        .or(config.script)
+        .map(|p| script::Script::load(&p))
+        .transpose()?;
+    // Only the new directives are written into the import directory, so the ones already in the
+    // ledger can't be changed.
+    let new_only = options.import_dir.is_some() || config.import_dir.is_some();
+
+    for (path, file) in &mut ledger.files {
+        let to_import = targets.remove(path).unwrap_or_default();
+        // Add new transactions (and collect the pending ones, used later for balance assertions).
+        let mut pending_bag: HashMap<Account, Bag<Decimal>> = HashMap::new();
+        let pb = progress::bar(to_import.len(), "Fetching transactions");
+        for (source, source_account) in &to_import {
+            let account = &source_account.account;
+            pb.set_message(account.to_string());
+            if options.balances_only {
+                summary.push(AccountSummary::imported(source.name(), source_account, 0));
+                pb.inc(1);
+                continue;
+            }
+            let res = match source.transactions(source_account).await {
+                Ok(res) => res,
+                Err(e) => {
+                    warn!("{}: failed to fetch the transactions: {:#}", account, e);
+                    summary.push(AccountSummary::failed(source.name(), source_account, &e));
+                    pb.inc(1);
+                    continue;
+                }
+            };
+
+            let config = source_account.exclusions().and_then(|e| {
+                Ok((
+                    e,
+                    cards::pattern(source_account)?,
+                    source_account.metadata_decimal("suspicious_amount")?,
+                    roundups::Config::of(source_account)?,
+                ))
+            });
+            let (exclusions, card_pattern, suspicious_amount, round_ups) = match config {
+                Ok(config) => config,
+                Err(e) => {
+                    warn!("{:#}", e);
+                    summary.push(AccountSummary::failed(source.name(), source_account, &e));
+                    pb.inc(1);
+                    continue;
+                }
+            };
+            let mut new_directives = Vec::new();
+            let mut new_prices = Vec::new();
+            let mut transactions = *res.transactions;
+            transactions
+                .booked
+                .retain(|t| source_account.imports_currency(&t.transaction_amount.currency));
+            if let Some(pending) = &mut transactions.pending {
+                pending.retain(|t| source_account.imports_currency(&t.transaction_amount.currency));
+            }
+            let booked = transactions.booked.clone();
+            for t in transactions.booked {
+                if exclusions.excludes(&t) || rules.is_ignored(&t, account) {
+                    debug!(id = ?t.internal_transaction_id, "ignoring transaction");
+                    continue;
+                }
+                let mut d = match mapper
+                    .map(&t, account)
+                    .with_context(|| conversion_context(account, &t))
+                {
+                    Ok(d) => d,
+                    Err(e) if options.skip_bad_transactions => {
+                        warn!("skipping the transaction: {:#}", e);
+                        continue;
+                    }
+                    Err(e) => return Err(e),
+                };
+                if let Some(card) = cards::card_id(&t, &card_pattern) {
+                    d.metadata
+                        .insert("card".into(), MetadataValue::String(card));
+                }
+                if dedup.is_duplicate(&d, account) {
+                    trace!(id = ?t.internal_transaction_id, "skipping duplicate transaction");
+                    if let Some(m) = mutations::detect(&recorded, mapper, &d, account) {
+                        mutated.push(m);
+                    }
+                } else if mapper
+                    .transaction_id(&d)
+                    .is_some_and(|id| !imported_ids.insert(id.to_string()))
+                {
+                    // Some banks return the same transaction twice in one response.
+                    trace!(id = ?t.internal_transaction_id, "skipping repeated transaction");
+                } else {
+                    if options.prices {
+                        // A bad rate doesn't make the transaction bad.
+                        match price_directive(&t, d.date) {
+                            Ok(Some(p)) => add_price(&mut prices, &mut new_prices, p),
+                            Ok(None) => {}
+                            Err(e) => warn!(
+                                "{}: skipping the price of transaction {:?}: {:#}",
+                                account, t.internal_transaction_id, e
+                            ),
+                        }
+                    }
+                    let raw_narration = mapper.raw_narration(&d).map(String::from);
+                    let categorization = rules.categorize(&t);
+                    rule_stats.record(&t, categorization.as_ref());
+                    let categorization = categorization.filter(|c| {
+                        debug!(
+                            id = ?t.internal_transaction_id,
+                            source = ?c.source,
+                            account = ?c.category.account,
+                            "categorized transaction"
+                        );
+                        apply_category(&c.category, &mut d, &t)
+                    });
+                    let payee_account = match &mut payees {
+                        Some(payees) => payees.apply(&t, &mut d, categorization.as_ref()),
+                        None => false,
+                    };
+                    if categorization.is_none() && !payee_account {
+                        if let Some(c) = &default_category {
+                            apply_category(c, &mut d, &t);
+                        }
+                    }
+                    if let DirectiveContent::Transaction(t) = &mut d.content {
+                        t.tags.extend(tags.iter().cloned());
+                        t.flag = match categorization {
+                            Some(c) if c.is_complete() => Some(options.categorized_flag),
+                            _ => options.flag.or(t.flag),
+                        };
+                    }
+                    if let Some(script) = &script {
+                        let keep = script.apply(&mut d, account).with_context(|| {
+                            format!("{}: transaction {:?}", account, t.internal_transaction_id)
+                        })?;
+                        if !keep {
+                            debug!(id = ?t.internal_transaction_id, "dropped by the script");
+                            continue;
+                        }
+                    }
+                    if let Some(command) = &options.transform_command {
+                        let keep = pipe::transform(command, &mut d, account)
+                            .await
+                            .with_context(|| {
+                                format!("{}: transaction {:?}", account, t.internal_transaction_id)
+                            })?;
+                        if !keep {
+                            debug!(
+                                id = ?t.internal_transaction_id,
+                                "dropped by the transform command"
+                            );
+                            continue;
+                        }
+                    }
+                    // The narration of the bank, for detecting its changes.
+                    mapper.keep_raw_narration(&mut d, raw_narration);
+                    new_directives.push(d);
+                }
+            }
+            let mut holds = vec![];
+            for t in transactions.pending.unwrap_or_default() {
+                let value = match amount::transaction(&t)
+                    .with_context(|| conversion_context(account, &t))
+                {
+                    Ok(value) => value,
+                    Err(e) if options.skip_bad_transactions => {
+                        warn!("skipping the pending transaction: {:#}", e);
+                        continue;
+                    }
+                    Err(e) => return Err(e),
+                };
+                *pending_bag.entry(account.clone()).or_default() += Amount {
+                    value,
+                    currency: Currency(t.transaction_amount.currency.clone()),
+                };
+                holds.push(pending::Hold::new(&t, value));
+            }
+            pending_state.reconcile(account, holds, &booked);
+
+            sort_transactions(&mut new_directives);
+
+            if let Some(round_ups) = &round_ups {
+                // A round-up merged into a purchase in the ledger would be lost with the ledger
+                // not written, so it's imported on its own.
+                let existing: &mut [Directive<Decimal>] = if new_only {
+                    &mut []
+                } else {
+                    &mut file.directives
+                };
+                roundups::pair(existing, &mut new_directives, account, mapper, round_ups);
+            }
+
+            if options.link_refunds {
+                let existing: &mut [Directive<Decimal>] = if new_only {
+                    &mut []
+                } else {
+                    &mut file.directives
+                };
+                refunds::link_refunds(
+                    existing,
+                    &mut new_directives,
+                    account,
+                    mapper,
+                    Days::new(options.refund_window_days),
+                );
+            }
+            if options.detect_recurring {
+                recurring::tag_recurring(&file.directives, &mut new_directives, account, mapper);
+            }
+            if options.sanity_checks {
+                sanity::check(
+                    &file.directives,
+                    &mut new_directives,
+                    account,
+                    suspicious_amount.or(options.suspicious_amount),
+                );
+            }
+
+            if let Some(d) = new_directives.last() {
+                last_transaction
+                    .entry(account.clone())
+                    .and_modify(|t| {
+                        if *t < d.date {
+                            *t = d.date;
+                        }
+                    })
+                    .or_insert(d.date);
+            }
+
+            summary.push(AccountSummary::imported(
+                source.name(),
+                source_account,
+                new_directives.len(),
+            ));
+            for d in &mut new_directives {
+                cards::route(d, source_account);
+            }
+            file.directives.append(&mut new_directives);
+            new_prices.sort_by_key(|d| d.date);
+            file.directives.append(&mut new_prices);
+            pb.inc(1);
+        }
+        pb.finish_and_clear();
+        if options.documents {
+            for (_, source_account) in &to_import {
+                let mut new_documents =
+                    match documents::new_documents(source_account, &documents, path) {
+                        Ok(documents) => documents,
+                        Err(e) => {
+                            warn!(
+                                "{}: failed to read the statements: {:#}",
+                                source_account.account, e
+                            );
+                            continue;
+                        }
+                    };
+                if !new_documents.is_empty() {
+                    info!(
+                        "{}: adding {} document(s)",
+                        source_account.account,
+                        new_documents.len()
+                    );
+                }
+                file.directives.append(&mut new_documents);
+            }
+        }
+        if options.no_balance {
+            continue;
+        }
+        // Add balances to the accounts
+        let pb = progress::bar(to_import.len(), "Fetching balances");
+        for (source, source_account) in &to_import {
+            let account = &source_account.account;
+            pb.set_message(account.to_string());
+            pb.inc(1);
+            if summary
+                .iter()
+                .any(|s| s.account == account.0 && s.error.is_some())
+            {
+                continue;
+            }
+            let res = match source.balances(source_account).await {
+                Ok(res) => res,
+                Err(e) => {
+                    warn!("{}: failed to fetch the balance: {:#}", account, e);
+                    if let Some(s) = summary.iter_mut().find(|s| s.account == account.0) {
+                        *s = AccountSummary::failed(source.name(), source_account, &e);
+                    }
+                    continue;
+                }
+            };
+            let Some(balances) = res.balances else {
+                continue;
+            };
+            let Some(b) = balances
+                .iter()
+                .find(|b| source_account.imports_currency(&b.balance_amount.currency))
+            else {
+                continue;
+            };
+
+            let mut amount = Amount {
+                value: amount::parse(&b.balance_amount.amount, amount::API_SEPARATOR)
+                    .with_context(|| format!("balance of {}", account))?,
+                currency: Currency(b.balance_amount.currency.clone()),
+            };
+            if let Some(bag) = pending_bag.get(account) {
+                if let Some(a) = bag.commodities().get(&amount.currency) {
+                    amount.value -= a;
+                }
+            }
+
+            let today = chrono::Local::now().date_naive();
+            let mut date = balance_date(
+                b.reference_date.as_deref(),
+                last_transaction.get(account).copied(),
+                today,
+            )?;
+            if options.prices {
+                match balance_prices(&balances, b, date) {
+                    Ok(new_prices) => {
+                        let mut added = vec![];
+                        for p in new_prices {
+                            add_price(&mut prices, &mut added, p);
+                        }
+                        file.directives.append(&mut added);
+                    }
+                    Err(e) => warn!("{}: skipping the prices of the balance: {:#}", account, e),
+                }
+            }
+            if let Some((previous_date, previous_balance)) = last_balance.get(account) {
+                if amount == previous_balance.clone() {
+                    // Without new transactions, the unchanged balance is asserted today.
+                    date = date.max(today);
+                    let due = options.balance_interval_days.is_some_and(|days| {
+                        date > *previous_date && date >= *previous_date + Days::new(days)
+                    });
+                    if !due {
+                        info!(
+                            "{}: previous balance matches the new one, skipping balance directive",
+                            account
+                        );
+                        continue;
+                    }
+                }
+            }
+
+            let d = Directive {
+                date,
+                content: DirectiveContent::Balance(Balance {
+                    account: account.clone(),
+                    amount,
+                }),
+                metadata: Default::default(),
+            };
+            file.directives.push(d);
+        }
+        pb.finish_and_clear();
+    }
+    mutations::describe(ledger, mapper, &mut mutated);
+    for m in &mutated {
+        let changes: Vec<String> = m.changes.iter().map(|c| c.to_string()).collect();
+        warn!(
+            "{}: the bank changed the imported transaction {}: {}",
+            m.account,
+            m.id,
+            changes.join(", ")
+        );
+    }
+    if options.record_bank_changes {
+        if new_only {
+            // The ledger isn't written, so the changes would be reported again.
+            warn!("the bank changes can't be recorded in the import directory mode");
+        } else {
+            mutations::record(ledger, mapper, &mutated);
+        }
+    }
+    if options.rule_stats {
+        // On stderr, as the ledger can be written to stdout.
+        eprint!("{}", rules.report(&rule_stats));
+    }
+    Ok(summary)
+}
+
+/// Date of the balance assertion: the reference date of the balance, or the day after the last
+/// transaction of the account. Accounts without any transactions (e.g. freshly connected) are
+/// asserted today.
+pub(crate) fn balance_date(
+    reference_date: Option<&str>,
+    last_transaction: Option<NaiveDate>,
+    today: NaiveDate,
+) -> anyhow::Result<NaiveDate> {
+    if let Some(rd) = reference_date {
+        let (date, _) = NaiveDate::parse_and_remainder(rd, "%Y-%m-%d")
+            .with_context(|| format!("invalid balance reference date {:?}", rd))?;
+        return Ok(date);
+    }
+    Ok(last_transaction
+        .and_then(|d| d.checked_add_days(Days::new(1)))
+        .unwrap_or(today))
+}
+
+/// Renders the directive in the formatting style of the settings, adding the tolerance of the
+/// account to the balance assertions (which the beancount types can't represent). The metadata is
+/// always written in the same order, so importing the same transactions gives the same output.
+pub fn render_directive(d: &Directive<Decimal>, tolerances: &HashMap<Account, Decimal>) -> String {
+    let tolerance = match &d.content {
+        DirectiveContent::Balance(b) => tolerances.get(&b.account).copied(),
+        _ => None,
+    };
+    settings::get().format.render(d, tolerance)
+}
+
+/// Renders the directives wrapped with `pushtag`/`poptag` of the tags.
+pub fn pushtag_batch(
+    tags: &[String],
+    directives: &[Directive<Decimal>],
+    tolerances: &HashMap<Account, Decimal>,
+) -> String {
+    let mut out = String::new();
+    for tag in tags {
+        out += &format!("\npushtag #{}\n", tag);
+    }
+    for d in directives {
+        out += &format!("\n{}\n", render_directive(d, tolerances));
+    }
+    out += "\n";
+    for tag in tags.iter().rev() {
+        out += &format!("poptag #{}\n", tag);
+    }
+    out
+}
+
+/// Returns when the access granted by the end user agreement expires (if it was accepted).
+pub async fn agreement_expiry(
+    config: &gocardless::apis::configuration::Configuration,
+    agreement_id: &str,
+) -> anyhow::Result<Option<chrono::DateTime<chrono::Utc>>> {
+    let agreement = timed("retrieve_eua_by_id", || {
+        gocardless::apis::agreements_api::retrieve_eua_by_id(config, agreement_id)
+    })
+    .await?;
+    let (Some(accepted), Some(days)) = (agreement.accepted, agreement.access_valid_for_days) else {
+        return Ok(None);
+    };
+    let accepted = chrono::DateTime::parse_from_rfc3339(&accepted)
+        .with_context(|| format!("invalid acceptance date {:?}", accepted))?;
+    Ok(Some(
+        accepted.with_timezone(&chrono::Utc) + chrono::Duration::days(days.into()),
+    ))
+}
+
+/// A requisition whose end user agreement expired or expires soon, so the access to its accounts
+/// has to be renewed with `create-requisition`.
+#[derive(Serialize, Clone, Debug)]
+pub struct ExpiringRequisition {
+    pub requisition: String,
+    pub institution: String,
+    pub expires: chrono::DateTime<chrono::Utc>,
+    pub account_ids: Vec<String>,
+}
+
+/// Returns the requisitions whose agreement expired or expires within the days, logging a warning
+/// for each of them.
+pub async fn expiring_requisitions(within_days: i64) -> anyhow::Result<Vec<ExpiringRequisition>> {
+    let config = config_with_token().await?;
+    let res = timed("retrieve_all_requisitions", || {
+        gocardless::apis::requisitions_api::retrieve_all_requisitions(&config, None, None)
+    })
+    .await?;
+    let listed = res.results.unwrap_or_default();
+    let cache = requisitions::refresh(&config, &listed).await;
+    let mut expiring = vec![];
+    for r in listed {
+        let Some(id) = r.id else {
+            continue;
+        };
+        let expires = match cache.agreement_expiry(&config, &r).await {
+            Ok(Some(expires)) => expires,
+            Ok(None) => continue,
+            Err(e) => {
+                warn!(requisition = %id, "failed to retrieve the agreement: {:#}", e);
+                continue;
+            }
+        };
+        let days = (expires - chrono::Utc::now()).num_days();
+        if days < 0 {
+            warn!(requisition = %id, %expires, "the agreement expired, renew the requisition");
+        } else if days < within_days {
+            warn!(requisition = %id, %expires, "the agreement expires soon, renew the requisition");
+        } else {
+            continue;
+        }
+        expiring.push(ExpiringRequisition {
+            requisition: id.to_string(),
+            institution: r.institution_id,
+            expires,
+            account_ids: r.accounts.iter().flatten().map(|a| a.to_string()).collect(),
+        });
+    }
+    Ok(expiring)
+}
+
+/// Converts the API error, logging the error responses.
+pub fn gocardless_err<T>(e: gocardless::apis::Error<T>) -> anyhow::Error {
+    if let gocardless::apis::Error::ResponseError(ref c) = e {
+        metrics::record_api_error(c.status == reqwest::StatusCode::TOO_MANY_REQUESTS);
+        if c.status == reqwest::StatusCode::TOO_MANY_REQUESTS {
+            warn!(status = %c.status, "rate limit exceeded: {}", c.content);
+        } else {
+            debug!(status = %c.status, "error response: {}", c.content);
+        }
+        let e = anyhow::Error::new(error::ApiError {
+            status: Some(c.status),
+            message: format!("error in response, {}", c.content),
+        });
+        let content = c.content.to_lowercase();
+        match c.status {
+            reqwest::StatusCode::TOO_MANY_REQUESTS => e.context(failure::Failure::RateLimited),
+            reqwest::StatusCode::UNAUTHORIZED if content.contains("token") => {
+                e.context(failure::Failure::Auth)
+            }
+            // Expired, revoked or suspended access to the account.
+            reqwest::StatusCode::UNAUTHORIZED
+            | reqwest::StatusCode::FORBIDDEN
+            | reqwest::StatusCode::CONFLICT => e.context(failure::Failure::AgreementExpired),
+            _ => e,
+        }
+    } else {
+        metrics::record_api_error(false);
+        anyhow::Error::new(error::ApiError {
+            status: None,
+            message: e.to_string(),
+        })
+    }
+}
+
+/// Obtains new API tokens with the secrets and saves them in the config directory.
+pub async fn sign_in(secret_id: String, secret_key: String) -> error::Result<()> {
+    let config = api_config()?;
+    let secrets = gocardless::models::jwt_obtain_pair_request::JwtObtainPairRequest::new(
+        secret_id, secret_key,
+    );
+    let jwt = timed_once("obtain_new_access_slash_refresh_token_pair", || {
+        gocardless::apis::token_api::obtain_new_access_slash_refresh_token_pair(&config, secrets)
+    })
+    .await?;
+
+    let tokens = Tokens::from_jwt(SystemTime::now(), &jwt)?;
+    save_tokens(&tokens).await?;
+    Ok(())
+}
+
+async fn save_tokens(tokens: &Tokens) -> anyhow::Result<()> {
+    let token_yaml_dir = base_config_dir()?;
+    tokio::fs::create_dir_all(&token_yaml_dir).await?;
+    let dir_permissions = std::fs::Permissions::from_mode(0o700);
+    tokio::fs::set_permissions(&token_yaml_dir, dir_permissions).await?;
+
+    let token_yaml_path = token_yaml_dir.join("token.yml");
+    let mut options = tokio::fs::OpenOptions::new();
+    options.write(true).create(true).mode(0o600);
+    let mut file = options.open(token_yaml_path).await?;
+    file.write_all(serde_yaml::to_string(tokens)?.as_bytes())
+        .await?;
+    Ok(())
+}
+
+/// Imports into the ledger at the path: locks the ledger, imports the new transactions and
+/// balances, optionally validates the result, and writes the changed files. Afterwards, the
+/// notification hooks are run (also when the import failed).
+pub async fn import_ledger(
+    beancount_path: &Path,
+    options: &ImportOptions,
+) -> error::Result<Vec<AccountSummary>> {
+    let (result, changes) = match write_import(beancount_path, options).await {
+        Ok((summary, changes)) => (Ok(summary), changes),
+        Err(e) => (Err(e), vec![]),
+    };
+    // A dry run is not a real import, so it is not reported.
+    if !options.dry_run {
+        // A pipeline doesn't touch the files next to the ledger.
+        if !options.stdout {
+            if let Err(e) = history::record(beancount_path, &result, changes) {
+                warn!("failed to record the import in the history: {:#}", e);
+            }
+        }
+        if let Some(path) = &options.metrics_file {
+            if let Err(e) = metrics::write(path, beancount_path, &result) {
+                warn!("failed to write the metrics: {:#}", e);
+            }
+        }
+        notify::notify(beancount_path, options, &result).await;
+    }
+    let summary = result?;
+    // When all the failed accounts failed for the same reason (e.g. the agreement expired), that
+    // is the reason of the failure.
+    let failures: HashSet<Option<failure::Failure>> = summary
+        .iter()
+        .filter(|a| a.error.is_some())
+        .map(|a| a.failure)
+        .collect();
+    if !failures.is_empty() {
+        let failed: Vec<&str> = summary
+            .iter()
+            .filter(|a| a.error.is_some())
+            .map(|a| a.account.as_str())
+            .collect();
+        let kind = match failures.into_iter().collect::<Vec<_>>()[..] {
+            [Some(kind)] => kind,
+            _ => failure::Failure::PartialImport,
+        };
+        return Err(anyhow::format_err!(
+            "failed to import {} account(s): {}",
+            failed.len(),
+            failed.join(", ")
+        )
+        .context(kind)
+        .into());
+    }
+    Ok(summary)
+}
+
+/// Imports into the ledger, returning the summary and the directives added to each file.
+async fn write_import(
+    beancount_path: &Path,
+    options: &ImportOptions,
+) -> anyhow::Result<(Vec<AccountSummary>, Vec<history::FileChanges>)> {
+    let (_lock, (mut ledger, snapshot)) = if options.stdin {
+        (
+            None,
+            ledger_io::read_stdin(beancount_path.to_path_buf()).await?,
+        )
+    } else {
+        (
+            Some(ledger_io::lock_ledger(beancount_path)?),
+            ledger_io::read_ledger(beancount_path.to_path_buf()).await?,
+        )
+    };
+    if options.journal.is_some() && !options.journal_format.is_journal() {
+        anyhow::bail!("--journal-format has to be hledger, ledger or beancount-import");
+    }
+    if options.stdout && ledger.files.len() > 1 {
+        anyhow::bail!("--stdout supports only ledgers in a single file");
+    }
+    let rules_path = ledger_config::rules_path(options.rules.as_deref(), &ledger)?;
+    let mut rules = rules::Rules::load(rules_path.as_deref()).await?;
+    if !options.open_accounts {
+        rules.require_opened_atm_account(&ledger_config::opened_accounts(&ledger));
+    }
+    let original_len: HashMap<PathBuf, usize> = ledger
+        .files
+        .iter()
+        .map(|(p, f)| (p.clone(), f.directives.len()))
+        .collect();
+
+    let sources = source::all(options).await?;
+    let config = ledger_config::LedgerConfig::read(&ledger)?;
+    let mapper = config.mapper();
+    let mut dedup = dedup::from_config(config.dedup.as_deref(), &mapper)?;
+    let import_dir = options
+        .import_dir
+        .clone()
+        .or_else(|| config.import_dir.clone());
+    if let Some(dir) = &import_dir {
+        // The batches not merged into the ledger yet.
+        for d in import_dir::pending(dir).await? {
+            roundups::add_to_dedup(dedup.as_mut(), &mapper, &d);
+        }
+    }
+    let mut state = ImportState {
+        pending: pending::State::load()?,
+        payees: options
+            .payees
+            .clone()
+            .or_else(|| config.payees.clone())
+            .map(|p| payees::Directory::load(&p))
+            .transpose()?,
+    };
+    let summary = import(
+        &mut ledger,
+        options,
+        &rules,
+        &sources,
+        &mapper,
+        dedup.as_mut(),
+        &mut state,
+    )
+    .await?;
+    if options.record_consents {
+        // The import succeeded without it.
+        if let Err(e) = consents::record(&mut ledger).await {
+            warn!("failed to record the consents: {:#}", e);
+        }
+    }
+    if options.declare_commodities {
+        declarations::declare_commodities(
+            &mut ledger,
+            &original_len,
+            options.commodities_file.as_deref(),
+        )?;
+    }
+    if options.open_accounts {
+        declarations::open_accounts(&mut ledger, &original_len, options.accounts_file.as_deref())?;
+    }
+    if options.edit {
+        edit::edit_new_directives(&mut ledger, &original_len).await?;
+    }
+    if options.validate || options.bean_check {
+        let problems =
+            validate::check_new_directives(&ledger, beancount_path, &original_len).await?;
+        for p in &problems {
+            match &p.directive {
+                Some(d) => warn!("{}: {}\n{}", p.path.display(), p.message, d),
+                None => warn!("{}: {}", p.path.display(), p.message),
+            }
+        }
+        if !problems.is_empty() {
+            return Err(anyhow::format_err!(
+                "the import would introduce {} problem(s), the ledger was not modified",
+                problems.len()
+            )
+            .context(failure::Failure::Validation));
+        }
+    }
+    if options.bean_check {
+        if let Some(output) = validate::bean_check(&ledger, &snapshot, beancount_path).await? {
+            warn!("bean-check output:\n{}", output);
+            return Err(anyhow::format_err!(
+                "bean-check reported new errors, the ledger was not modified"
+            )
+            .context(failure::Failure::Validation));
+        }
+    }
+
+    let mut changes: Vec<history::FileChanges> = ledger
+        .files
+        .iter()
+        .filter(|(p, f)| f.directives.len() > original_len[*p])
+        .map(|(p, f)| history::FileChanges::new(p, &f.directives[original_len[p]..], &mapper))
+        .collect();
+    changes.sort_by(|a, b| a.file.cmp(&b.file));
+
+    // Tolerance of the balance assertions, from the `balance_tolerance` metadata.
+    let mut tolerances: HashMap<Account, Decimal> = HashMap::new();
+    for account in ledger_config::configured_accounts(&ledger)? {
+        if let Some(tolerance) = account.metadata_decimal("balance_tolerance")? {
+            tolerances.insert(account.account, tolerance);
+        }
+    }
+
+    // The new directives are rendered separately (with the metadata in a stable order), so they
+    // can be wrapped in pushtag/poptag and appended to the file.
+    let tags = if options.pushtag {
+        options.tags(chrono::Local::now().date_naive())
+    } else {
+        vec![]
+    };
+    let mut batches: HashMap<PathBuf, String> = HashMap::new();
+    let mut journal_directives = vec![];
+    for (p, f) in &mut ledger.files {
+        let new_directives = f.directives.split_off(original_len[p]);
+        if options.journal.is_some() {
+            journal_directives.extend(new_directives.iter().cloned());
+        }
+        if !new_directives.is_empty() {
+            batches.insert(
+                p.clone(),
+                pushtag_batch(&tags, &new_directives, &tolerances),
+            );
+        }
+    }
+
+    if options.dry_run || options.diff {
+        let mut paths: Vec<&PathBuf> = ledger.files.keys().collect();
+        paths.sort();
+        for p in paths {
+            let added = batches.get(p).map(|b| b.as_str()).unwrap_or_default();
+            if !added.trim().is_empty() {
+                println!("{}", preview::diff(p, added));
+            }
+        }
+        if options.dry_run {
+            return Ok((summary, vec![]));
+        }
+    }
+
+    if let Some(dir) = &import_dir {
+        let mut paths: Vec<&PathBuf> = ledger.files.keys().collect();
+        paths.sort();
+        let mut content = String::new();
+        for p in paths {
+            let added = batches.get(p).map(|b| b.as_str()).unwrap_or_default();
+            if !added.trim().is_empty() {
+                content += &format!("; {}\n{}\n", p.display(), added);
+            }
+        }
+        if !changes.is_empty() {
+            let path = import_dir::write_batch(dir, beancount_path, &content, changes)?;
+            info!("the imported directives were written to {}", path.display());
+        }
+        state.save()?;
+        return Ok((summary, vec![]));
+    }
+
+    // Only the files that received new directives are written (and, with --record-bank-changes,
+    // the ones with updated transactions), so the others keep their modification time.
+    let added: HashSet<&PathBuf> = batches.keys().collect();
+    if options.stdout {
+        let batches = &batches;
+        let snapshot = &snapshot;
+        ledger
+            .write(|p, content| async move {
+                let content = snapshot.restore(&p, content);
+                let batch = batches.get(&p).map(|b| b.as_str()).unwrap_or_default();
+                let mut out = std::io::stdout().lock();
+                std::io::Write::write_all(&mut out, (content + batch).as_bytes())?;
+                Ok(())
+            })
+            .await?;
+        state.save()?;
+        return Ok((summary, changes));
+    }
+
+    snapshot.check_unmodified().await?;
+    let pb = progress::spinner("Writing ledger");
+    ledger
+        .write(|p, content| {
+            let content = snapshot.restore(&p, content);
+            let write = added.contains(&p)
+                || (options.record_bank_changes && !snapshot.is_unchanged(&p, &content));
+            let batch = batches.get(&p).cloned().unwrap_or_default();
+            async move {
+                if write {
+                    tokio::fs::write(p, content + batch.as_str()).await?;
+                }
+                Ok(())
+            }
+        })
+        .await
+        .with_context(|| {
+            error::LedgerError(format!(
+                "failed to write the ledger {}",
+                beancount_path.display()
+            ))
+        })?;
+    pb.finish_and_clear();
+    state.save()?;
+    if let Some(journal) = &options.journal {
+        journal_directives.sort_by_key(|d| d.date);
+        let count = export::append_journal(
+            options.journal_format,
+            journal,
+            &journal_directives,
+            &mapper,
+        )?;
+        info!(count, "appended the transactions to {}", journal.display());
+    }
+    Ok((summary, changes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(s: &str) -> NaiveDate {
+        s.parse().unwrap()
+    }
+
+    fn balance(balance_type: &str, amount: &str, currency: &str) -> BalanceSchema {
+        serde_json::from_value(serde_json::json!({
+            "balanceType": balance_type,
+            "balanceAmount": { "amount": amount, "currency": currency },
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn balance_prices_per_currency() {
+        let balances = [
+            balance("closingBooked", "200.00", "EUR"),
+            balance("closingBooked", "216.50", "USD"),
+            balance("closingBooked", "171.00", "GBP"),
+            balance("closingBooked", "216.60", "USD"),
+            balance("interimAvailable", "1000.00", "CHF"),
+        ];
+        let prices: Vec<(NaiveDate, String, Decimal, String)> =
+            balance_prices(&balances, &balances[0], date("2024-03-01"))
+                .unwrap()
+                .into_iter()
+                .map(|d| match d.content {
+                    DirectiveContent::Price(p) => {
+                        (d.date, p.currency.0, p.amount.value, p.amount.currency.0)
+                    }
+                    _ => panic!("not a price"),
+                })
+                .collect();
+        let eur = |value: &str, currency: &str| {
+            (
+                date("2024-03-01"),
+                "EUR".to_string(),
+                value.parse().unwrap(),
+                currency.to_string(),
+            )
+        };
+        // One price per currency, of the same balance type.
+        assert_eq!(prices, [eur("1.0825", "USD"), eur("0.855", "GBP")]);
+        let empty = [balance("closingBooked", "0.00", "EUR"), balances[1].clone()];
+        assert!(balance_prices(&empty, &empty[0], date("2024-03-01"))
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn balance_date_uses_the_reference_date() {
+        let today = date("2024-03-10");
+        assert_eq!(
+            balance_date(Some("2024-03-08"), Some(date("2024-03-05")), today).unwrap(),
+            date("2024-03-08")
+        );
+        // Some banks send a date-time.
+        assert_eq!(
+            balance_date(Some("2024-03-08T23:59:59Z"), None, today).unwrap(),
+            date("2024-03-08")
+        );
+        assert!(balance_date(Some("yesterday"), None, today).is_err());
+    }
+
+    #[test]
+    fn balance_date_falls_back_to_the_day_after_the_last_transaction() {
+        assert_eq!(
+            balance_date(None, Some(date("2024-03-05")), date("2024-03-10")).unwrap(),
+            date("2024-03-06")
+        );
+    }
+
+    #[test]
+    fn balance_date_falls_back_to_today() {
+        assert_eq!(
+            balance_date(None, None, date("2024-03-10")).unwrap(),
+            date("2024-03-10")
+        );
+    }
+
+    /// The imported directives are rendered with the metadata in a stable order, whatever order it
+    /// was inserted in, and read back as they were.
+    #[tokio::test]
+    async fn rendering_round_trips() {
+        let imported = test_util::directive(
+            "c3",
+            "2024-03-05",
+            "-12.40",
+            serde_json::json!({
+                "bookingDateTime": "2024-03-05T08:12:31Z",
+                "valueDate": "2024-03-06",
+                "creditorName": "Coffee Shop",
+                "creditorAccount": { "iban": "GB33BUKB20201555555555" },
+                "remittanceInformationUnstructured": "Card payment Coffee Shop",
+            }),
+        );
+        let mut metadata: Vec<_> = imported.metadata.clone().into_iter().collect();
+        metadata.sort_by(|a, b| a.0.cmp(&b.0));
+        assert!(metadata.len() > 2);
+
+        let path = PathBuf::from("imported.beancount");
+        let mut rendered = vec![];
+        for order in 0..metadata.len() {
+            let mut d = imported.clone();
+            d.metadata = Default::default();
+            // Rotated, and every other order reversed.
+            metadata.rotate_left(1);
+            let entries: Vec<_> = match order % 2 {
+                0 => metadata.iter().collect(),
+                _ => metadata.iter().rev().collect(),
+            };
+            for (key, value) in entries {
+                d.metadata.insert(key.clone(), value.clone());
+            }
+            let content = render_directive(&d, &HashMap::new()) + "\n";
+            let ledger = Ledger::<Decimal>::read(path.clone(), |_| {
+                let content = content.clone();
+                async move { Ok(content) }
+            })
+            .await
+            .unwrap();
+            assert_eq!(
+                ledger.files[&path].directives,
+                std::slice::from_ref(&imported)
+            );
+            rendered.push(content);
+        }
+        assert!(rendered.iter().all(|r| *r == rendered[0]), "{:?}", rendered);
+    }
+
+    #[test]
+    fn unfit_category_leaves_the_transaction_uncategorized() {
+        let salary: rules::Category = serde_yaml::from_str(
+            r#"
+            salary:
+              income: "Income:Salary"
+              deductions: [{ account: "Expenses:Taxes", percent: 20 }]
+            "#,
+        )
+        .unwrap();
+        // A payment back to the employer.
+        let t = test_util::transaction("t1", "2024-03-05", "-100.00", serde_json::json!({}));
+        let mut d = test_util::directive("t1", "2024-03-05", "-100.00", serde_json::json!({}));
+        let imported = d.clone();
+        assert!(!apply_category(&salary, &mut d, &t));
+        assert_eq!(d, imported);
+
+        let t = test_util::transaction("t2", "2024-03-05", "2000.00", serde_json::json!({}));
+        let mut d = test_util::directive("t2", "2024-03-05", "2000.00", serde_json::json!({}));
+        assert!(apply_category(&salary, &mut d, &t));
+        assert_eq!(d.content.transaction_opt().unwrap().postings.len(), 3);
+    }
+
+    /// Imports the balance assertion into the ledger, the way `write_import` writes it.
+    async fn import_balance(path: &Path, date: &str, value: &str) {
+        let (ledger, snapshot) = ledger_io::read_ledger(path.to_path_buf()).await.unwrap();
+        let account = test_util::bank();
+        let d = Directive {
+            date: date.parse().unwrap(),
+            content: DirectiveContent::Balance(Balance {
+                account: account.clone(),
+                amount: Amount {
+                    value: value.parse().unwrap(),
+                    currency: Currency("EUR".to_string()),
+                },
+            }),
+            metadata: Default::default(),
+        };
+        let tolerances = HashMap::from([(account, "0.01".parse().unwrap())]);
+        let batch = pushtag_batch(&[], &[d], &tolerances);
+        let snapshot = &snapshot;
+        ledger
+            .write(|p, content| {
+                let content = snapshot.restore(&p, content) + batch.as_str();
+                async move { Ok(tokio::fs::write(p, content).await?) }
+            })
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn tolerances_survive_the_next_import() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ledger.beancount");
+        std::fs::write(&path, "2024-01-01 open Assets:Bank\n").unwrap();
+        import_balance(&path, "2024-03-01", "100.00").await;
+        import_balance(&path, "2024-04-01", "120.00").await;
+        let content = std::fs::read_to_string(&path).unwrap();
+        assert!(content.contains("2024-03-01 balance Assets:Bank  100.00 ~ 0.01 EUR"));
+        assert!(content.contains("2024-04-01 balance Assets:Bank  120.00 ~ 0.01 EUR"));
+    }
+}
@@ -1,48 +1,169 @@
 use anyhow::Context;
-use beanru::{
-    bag::Bag,
-    types::{
-        Account, Amount, Balance, Currency, Directive, DirectiveContent, Ledger, MetadataValue,
-        Posting, Transaction,
-    },
-};
-use chrono::{Days, NaiveDate};
-use clap::{Parser, Subcommand};
-use gocardless::models::{
-    JwtRefreshRequest, SpectacularJwtObtain, SpectacularJwtRefresh, Status1c5Enum,
-    TransactionSchema,
-};
-use rust_decimal::Decimal;
-use serde::{Deserialize, Serialize};
-use std::{
-    collections::{HashMap, HashSet},
-    os::unix::fs::PermissionsExt,
-    path::PathBuf,
-    time::{Duration, SystemTime},
+use beancount_gocardless_importer::{
+    account_details, anonymize, base_config_dir, categorized_directives, config_with_token, daemon,
+    doctor, error::Error, export, extract, failure::Failure, history, import_ledger, init,
+    is_duplicate, mapper, narration, output, preview, progress, quota, recording, requisitions,
+    review, rule_test, rules, settings, settings::Settings, sign_in, snapshot, source, store,
+    timed, timed_once, timezone, verify_config, ImportOptions,
 };
-use tokio::io::AsyncWriteExt;
+use beanru::types::Account;
+use clap::{CommandFactory, Parser, Subcommand};
+use output::{OutputFormat, Table};
+use preview::ColorChoice;
+use std::collections::HashSet;
+use std::io::Write;
+use std::path::PathBuf;
+use tracing::{info, trace, warn};
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 struct Args {
     #[command(subcommand)]
     command: Commands,
+    /// Do not show progress bars and messages (warnings and errors are still reported).
+    #[arg(long, short, global = true)]
+    quiet: bool,
+    /// Show more details: -v shows API requests, deduplication and categorization decisions,
+    /// -vv shows everything.
+    #[arg(long, short, action = clap::ArgAction::Count, global = true)]
+    verbose: u8,
+    /// Write the logs as JSON, one object per line.
+    #[arg(long, global = true)]
+    log_json: bool,
+    /// Save the API responses of the import in the directory.
+    #[arg(long, global = true, conflicts_with = "replay")]
+    record: Option<PathBuf>,
+    /// Run the import against the API responses saved with `--record`, without network access.
+    #[arg(long, global = true)]
+    replay: Option<PathBuf>,
+    /// Settings file, by default `~/.config/gocardless-importer/config.toml` (or
+    /// `$GOCARDLESS_CONFIG`).
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+    /// When to color the output (`auto` respects `NO_COLOR`).
+    #[arg(long, global = true, value_enum, default_value_t = ColorChoice::Auto)]
+    color: ColorChoice,
+}
+
+#[derive(Subcommand)]
+enum CacheCommand {
+    /// Remove the stored transactions and balances, so the next import uses only fresh data from
+    /// the bank.
+    Clear {
+        /// Remove only the data of this account.
+        #[arg(long)]
+        account_id: Option<String>,
+        /// Remove only the data last fetched before this age, e.g. `30d`, `12h` or `2w`.
+        #[arg(long, value_parser = parse_age)]
+        older_than: Option<chrono::Duration>,
+    },
+    /// Show what is stored for each account.
+    Stats {
+        /// [default: table]
+        #[arg(long, value_enum)]
+        format: Option<OutputFormat>,
+    },
+}
+
+#[derive(Subcommand)]
+enum RulesCommand {
+    /// Show what the rules do with the transactions saved in the local store (the counter
+    /// account, payee and tags), without contacting GoCardless or writing the ledger.
+    Test {
+        #[command(flatten)]
+        query: store::Query,
+        /// Ledger with the configured accounts and rules.
+        #[arg(long)]
+        ledger: Option<PathBuf>,
+        /// Categorization rules (by default the ones configured in the ledger or the settings).
+        #[arg(long)]
+        rules: Option<PathBuf>,
+        /// [default: table]
+        #[arg(long, value_enum)]
+        format: Option<OutputFormat>,
+    },
+}
+
+/// Parses an age like `30d` (hours, days and weeks are supported).
+fn parse_age(s: &str) -> Result<chrono::Duration, String> {
+    let (n, unit) = s.split_at(s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len()));
+    let n: i64 = n.parse().map_err(|_| format!("invalid age {:?}", s))?;
+    match unit {
+        "h" => Ok(chrono::Duration::hours(n)),
+        "d" => Ok(chrono::Duration::days(n)),
+        "w" => Ok(chrono::Duration::weeks(n)),
+        _ => Err(format!("invalid age {:?}, use e.g. 12h, 30d or 2w", s)),
+    }
+}
+
+/// Warns when the calls to the account would exceed today's quota. Unlike the import, the commands
+/// try anyway.
+fn check_quota(account_id: &str, endpoints: &[&'static str]) -> anyhow::Result<()> {
+    let planned = quota::Planned {
+        account: account_id,
+        account_id,
+        endpoints: endpoints.to_vec(),
+    };
+    quota::check(&[planned], true)
+}
+
+fn init_logging(args: &Args) {
+    let level = match (args.quiet, args.verbose) {
+        (true, _) => "warn",
+        (false, 0) => "info",
+        (false, 1) => "debug",
+        _ => "trace",
+    };
+    let filter = tracing_subscriber::EnvFilter::try_from_default_env().unwrap_or_else(|_| {
+        tracing_subscriber::EnvFilter::new(format!("warn,beancount_gocardless_importer={}", level))
+    });
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(progress::MakeLogWriter)
+        .with_ansi(console::colors_enabled_stderr())
+        .with_target(false);
+    if args.log_json {
+        builder.json().init();
+    } else if args.verbose == 0 {
+        builder.without_time().init();
+    } else {
+        builder.init();
+    }
+    progress::set_enabled(!args.quiet && !args.log_json && args.verbose == 0);
 }
 
 #[derive(Subcommand)]
 enum Commands {
+    /// Guided setup: sign in, connect the first bank and print the ledger configuration.
+    Init,
+    /// Print the completion script for the shell.
+    Completions {
+        shell: clap_complete::Shell,
+    },
+    /// Write the man pages of the command and its subcommands to the directory.
+    Manpages {
+        dir: PathBuf,
+    },
     SignIn {
         secret_id: String,
         secret_key: String,
     },
     ListInstitutions {
-        #[arg(long)]
-        country: Option<String>,
+        /// Country code of the institutions, can be repeated or given as a comma separated list.
+        #[arg(long, value_delimiter = ',')]
+        country: Vec<String>,
+        /// [default: table]
+        #[arg(long, value_enum)]
+        format: Option<OutputFormat>,
     },
     CreateRequisition {
         institution_id: String,
     },
-    ListRequisitions,
+    ListRequisitions {
+        /// [default: table]
+        #[arg(long, value_enum)]
+        format: Option<OutputFormat>,
+    },
     DeleteRequisition {
         requisition_id: String,
     },
@@ -50,438 +171,278 @@ enum Commands {
         /// The ID of the account to list transactions for.
         /// The IDs can be seen through `list-requisitions` command.
         account_id: String,
+        /// [default: yaml]
+        #[arg(long, value_enum)]
+        format: Option<OutputFormat>,
+        /// Write the output to the file instead of stdout.
+        #[arg(long)]
+        output: Option<PathBuf>,
+        /// The beancount account used in the preview of the imported directives (with
+        /// `--format beancount`).
+        #[arg(long, required_if_eq("format", "beancount"))]
+        account_name: Option<String>,
+        /// Categorization rules applied in the preview (with `--format beancount`).
+        #[arg(long)]
+        rules: Option<PathBuf>,
     },
     Balance {
         /// The ID of the account to list transactions for.
         /// The IDs can be seen through `list-requisitions` command.
         account_id: String,
+        /// [default: yaml]
+        #[arg(long, value_enum)]
+        format: Option<OutputFormat>,
+    },
+    /// Export the transactions of the account for tools other than beancount.
+    Export {
+        /// The ID of the account to export transactions of.
+        /// The IDs can be seen through `list-requisitions` command.
+        account_id: String,
+        #[arg(long, value_enum, default_value_t = export::ExportFormat::Ofx)]
+        format: export::ExportFormat,
+        /// Write the output to the file instead of stdout.
+        #[arg(long)]
+        output: Option<PathBuf>,
+        /// The account used in the journal entries (with `--format hledger`, `--format ledger` or
+        /// `--format beancount-import`).
+        #[arg(long, required_if_eq_any([
+            ("format", "hledger"),
+            ("format", "ledger"),
+            ("format", "beancount-import"),
+        ]))]
+        account_name: Option<String>,
+        /// Categorization rules applied to the journal entries.
+        #[arg(long)]
+        rules: Option<PathBuf>,
+        /// Existing journal: the transactions already in it are skipped and the new ones are
+        /// appended to it.
+        #[arg(long, conflicts_with = "output")]
+        journal: Option<PathBuf>,
+    },
+    /// Print the beancount entries of the transactions in saved API responses (e.g. recorded with
+    /// `--record` or `dump-fixture`), like the `extract` of the beancount importers.
+    Extract {
+        /// Files with the responses of the transactions request. The other files are skipped.
+        #[arg(required = true)]
+        files: Vec<PathBuf>,
+        /// The account of the entries. By default, the account of the existing ledger configured
+        /// with the account id the file is named after.
+        #[arg(long)]
+        account: Option<String>,
+        /// Existing ledger: the transactions already in it are skipped.
+        #[arg(long, short = 'e')]
+        existing: Option<PathBuf>,
+        /// Categorization rules (by default the ones configured in the existing ledger or the
+        /// settings).
+        #[arg(long)]
+        rules: Option<PathBuf>,
+    },
+    /// Save the API responses for the account in the directory, in the format used by `--replay`
+    /// (e.g. to attach them to a bug report).
+    DumpFixture {
+        /// The ID of the account.
+        account_id: String,
+        dir: PathBuf,
+        /// Replace IBANs, names and references with fake values.
+        #[arg(long)]
+        anonymize: bool,
+    },
+    /// Search the transactions saved in the local store, without contacting GoCardless.
+    Query {
+        #[command(flatten)]
+        query: store::Query,
+        /// [default: table]
+        #[arg(long, value_enum)]
+        format: Option<OutputFormat>,
+    },
+    /// Manage the local store of the fetched transactions and balances.
+    Cache {
+        #[command(subcommand)]
+        command: CacheCommand,
+    },
+    /// Work on the categorization rules.
+    Rules {
+        #[command(subcommand)]
+        command: RulesCommand,
+    },
+    /// Check the token, the requisitions, the ledger configuration and the rules.
+    Doctor {
+        beancount_path: Option<PathBuf>,
+        /// Categorization rules to check (by default the ones configured in the ledger or the
+        /// settings).
+        #[arg(long)]
+        rules: Option<PathBuf>,
+    },
+    /// Check only the importer configuration of the ledger (metadata of the accounts, account ids,
+    /// target files), without contacting the API.
+    VerifyConfig {
+        beancount_path: PathBuf,
+    },
+    /// Write the IBAN, the owner and the product of the GoCardless accounts as metadata of their
+    /// `open` directives.
+    UpdateAccounts {
+        beancount_path: PathBuf,
+    },
+    /// Append the current balance of every configured account to the ledger, even when it didn't
+    /// change (e.g. monthly, as an audit trail).
+    SnapshotBalances {
+        beancount_path: PathBuf,
+        #[arg(long)]
+        filter_account_re: Option<regex::Regex>,
+        /// YAML file with the profiles of the CSV statements.
+        #[arg(long)]
+        csv_profiles: Option<PathBuf>,
+    },
+    /// Show the log of the previous imports.
+    History {
+        /// Show only the imports of this ledger.
+        #[arg(long)]
+        ledger: Option<PathBuf>,
+        /// Number of the most recent imports to show.
+        #[arg(long, default_value_t = 20)]
+        limit: usize,
+        /// [default: table]
+        #[arg(long, value_enum)]
+        format: Option<OutputFormat>,
+    },
+    /// Review the transactions flagged with `!` or without a counter posting: assign the counter
+    /// accounts and accept them.
+    Review {
+        beancount_path: PathBuf,
+        /// Categorization rules used for the suggestions (by default the ones configured in the
+        /// ledger or the settings).
+        #[arg(long)]
+        rules: Option<PathBuf>,
+        /// Instead of opening the interface, apply the rules to the transactions flagged with `!`
+        /// and accept the ones they assign a counter account to.
+        #[arg(long)]
+        apply_rules: bool,
+        /// Only print what `--apply-rules` would change.
+        #[arg(long, requires = "apply_rules")]
+        dry_run: bool,
+    },
+    /// Keep running and import into the ledger on a schedule.
+    Daemon {
+        beancount_path: PathBuf,
+        /// Cron expression (minute, hour, day of month, month, day of week) of the syncs.
+        #[arg(long, default_value = "0 6 * * *")]
+        schedule: String,
+        /// Maximum number of syncs per day, to stay within the API limits.
+        #[arg(long, default_value_t = 4)]
+        max_syncs_per_day: u32,
+        /// File with the status of the daemon (JSON), updated after every sync. Defaults to
+        /// `~/.gocardless/daemon-status.json`.
+        #[arg(long)]
+        status_file: Option<PathBuf>,
+        #[command(flatten)]
+        options: ImportOptions,
     },
     Import {
-        /// Import transactions based on configuration in given beancount ledger.
+        /// Import transactions based on configuration in given beancount ledgers (by default the
+        /// `ledgers` of the settings), one after another.
         ///
         /// The ledger is expected to have special metadata on the account that configures the
         /// importer. For more information, see README.md
-        beancount_path: PathBuf,
-        #[arg(long)]
-        filter_account_re: Option<regex::Regex>,
+        beancount_paths: Vec<PathBuf>,
+        #[command(flatten)]
+        options: ImportOptions,
     },
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct Tokens {
-    access_token: String,
-    access_expires: SystemTime,
-    refresh_token: String,
-    refresh_expires: SystemTime,
-}
-
-impl Tokens {
-    fn from_jwt(now: SystemTime, jwt: &SpectacularJwtObtain) -> anyhow::Result<Tokens> {
-        Ok(Tokens {
-            access_token: jwt.access.clone().context("access token is missing")?,
-            access_expires: now
-                + Duration::from_secs(
-                    jwt.access_expires
-                        .context("access token expiration is missing")?
-                        .try_into()?,
-                ),
-            refresh_token: jwt.refresh.clone().context("refresh token is missing")?,
-            refresh_expires: now
-                + Duration::from_secs(
-                    jwt.refresh_expires
-                        .context("refresh token expiration is missing")?
-                        .try_into()?,
-                ),
-        })
-    }
-
-    fn update_access_token(
-        &mut self,
-        now: SystemTime,
-        jwt: &SpectacularJwtRefresh,
-    ) -> anyhow::Result<()> {
-        self.access_token = jwt.access.clone().context("access token is missing")?;
-        self.access_expires = now
-            + Duration::from_secs(
-                jwt.access_expires
-                    .context("access token expiration is missing")?
-                    .try_into()?,
-            );
-        Ok(())
-    }
-}
-
-fn base_config_dir() -> anyhow::Result<PathBuf> {
-    Ok(PathBuf::from(std::env::var("HOME")?).join(".gocardless"))
-}
-
-async fn get_token() -> anyhow::Result<String> {
-    let path = base_config_dir()?.join("token.yml");
-    let now = SystemTime::now();
-    let mut tokens: Tokens = serde_yaml::from_str(&tokio::fs::read_to_string(&path).await?)?;
-    if now < tokens.access_expires {
-        return Ok(tokens.access_token);
-    }
-    if now > tokens.refresh_expires {
-        anyhow::bail!("refresh token exipred")
-    }
-    let config = gocardless::apis::configuration::Configuration::default();
-    let jwt = gocardless::apis::token_api::get_a_new_access_token(
-        &config,
-        JwtRefreshRequest::new(tokens.refresh_token.clone()),
-    )
-    .await
-    .map_err(gocardless_err)?;
-    tokens.update_access_token(now, &jwt)?;
-    tokio::fs::write(&path, serde_yaml::to_string(&tokens)?.as_bytes()).await?;
-    Ok(tokens.access_token)
-}
-
-async fn config_with_token() -> anyhow::Result<gocardless::apis::configuration::Configuration> {
-    let token = get_token()
-        .await
-        .context("Failed to get the access token, please first run `sign-in` command")?;
-    Ok(gocardless::apis::configuration::Configuration {
-        bearer_access_token: Some(token),
-        ..Default::default()
-    })
-}
-
-fn narration(t: &TransactionSchema) -> Option<String> {
-    if let Some(inf) = &t.remittance_information_unstructured_array {
-        if !inf.is_empty() {
-            return Some(inf.join(", "));
+#[tokio::main]
+async fn main() -> std::process::ExitCode {
+    let args = Args::parse();
+    match run(args).await {
+        Ok(()) => std::process::ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {:?}", e);
+            std::process::ExitCode::from(Failure::of(&e).map_or(1, Failure::exit_code))
         }
     }
-    if let Some(inf) = &t.remittance_information_unstructured {
-        return Some(inf.clone());
-    }
-    t.creditor_name.clone()
 }
 
-fn gocardless_transaction_to_beancount(
-    t: &TransactionSchema,
-    account: &Account,
-) -> anyhow::Result<Directive<Decimal>> {
-    let (date, _) = chrono::NaiveDate::parse_and_remainder(
-        t.booking_date.as_ref().context("booking date is missing")?,
-        "%Y-%m-%d",
-    )?;
-    let mut metadata: HashMap<String, MetadataValue<Decimal>> = HashMap::new();
-    if let Some(dt) = &t.booking_date_time {
-        metadata.insert(
-            "booking_date_time".into(),
-            MetadataValue::String(dt.clone()),
-        );
-    }
-    if let Some(dt) = &t.value_date_time {
-        metadata.insert("value_date_time".into(), MetadataValue::String(dt.clone()));
-    }
-    if let Some(debtor_name) = &t.debtor_name {
-        metadata.insert(
-            "from_name".into(),
-            MetadataValue::String(debtor_name.clone()),
-        );
-    }
-    if let Some(d) = &t.debtor_account {
-        if let Some(iban) = &d.iban {
-            metadata.insert("from_iban".into(), MetadataValue::String(iban.clone()));
-        }
-    }
-    if let Some(creditor_name) = &t.creditor_name {
-        metadata.insert(
-            "to_name".into(),
-            MetadataValue::String(creditor_name.clone()),
-        );
-    }
-    if let Some(d) = &t.creditor_account {
-        if let Some(iban) = &d.iban {
-            metadata.insert("to_iban".into(), MetadataValue::String(iban.clone()));
-        }
-    }
-    if let Some(ce) = &t.currency_exchange {
-        if let Some(sc) = &ce.source_currency {
-            metadata.insert("source_currency".into(), MetadataValue::String(sc.clone()));
-        }
-        if let Some(sc) = &ce.exchange_rate {
-            metadata.insert("exchange_rate".into(), MetadataValue::String(sc.clone()));
+async fn run(args: Args) -> anyhow::Result<()> {
+    preview::set_color(args.color);
+    init_logging(&args);
+    recording::set_mode(match (&args.record, &args.replay) {
+        (Some(dir), _) => recording::Mode::Record(dir.clone()),
+        (_, Some(dir)) => recording::Mode::Replay(dir.clone()),
+        _ => recording::Mode::Live,
+    });
+    let config_path = args
+        .config
+        .clone()
+        .or_else(|| std::env::var_os("GOCARDLESS_CONFIG").map(PathBuf::from));
+    // `init` creates the missing settings file.
+    let settings = match &config_path {
+        Some(path) if matches!(args.command, Commands::Init) && !path.exists() => {
+            Settings::default()
         }
-        if let Some(sc) = &ce.target_currency {
-            metadata.insert("target_currency".into(), MetadataValue::String(sc.clone()));
-        }
-    }
-    if let Some(tc) = &t.proprietary_bank_transaction_code {
-        metadata.insert("transaction_code".into(), MetadataValue::String(tc.clone()));
-    }
-
-    let mut links = HashSet::new();
-    if let Some(id) = &t.internal_transaction_id {
-        links.insert(format!("id-{}", id));
-    }
-
-    let transaction = Transaction {
-        flag: None,
-        payee: None,
-        narration: narration(t),
-        tags: Default::default(),
-        links,
-        postings: vec![Posting {
-            flag: None,
-            account: account.clone(),
-            amount: Some(Amount {
-                value: t.transaction_amount.amount.parse()?,
-                currency: Currency(t.transaction_amount.currency.clone()),
-            }),
-            cost: None,
-            price: None,
-            metadata: Default::default(),
-            autocomputed: false,
-        }],
-        balanced: false,
-    };
-    let d = Directive {
-        date,
-        content: DirectiveContent::Transaction(transaction),
-        metadata,
+        _ => Settings::load(config_path.clone())?,
     };
-    Ok(d)
-}
-
-fn is_duplicate(d: &Directive<Decimal>, ids: &HashSet<String>) -> bool {
-    let Some(t) = d.content.transaction_opt() else {
-        return false;
+    settings::set(settings);
+    let default_format = |format: Option<OutputFormat>, default| {
+        format.or(settings::get().output).unwrap_or(default)
     };
-    for link in &t.links {
-        if ids.contains(link) {
-            return true;
-        }
-    }
-    false
-}
-
-async fn import(
-    ledger: &mut Ledger<Decimal>,
-    filter_account_re: Option<regex::Regex>,
-) -> anyhow::Result<()> {
-    let config = config_with_token().await?;
-
-    let mut ids: HashSet<String> = HashSet::new();
-    let mut last_balance: HashMap<Account, (NaiveDate, Amount<Decimal>)> = HashMap::new();
-    let mut last_transaction: HashMap<Account, NaiveDate> = HashMap::new();
-
-    for (_, file) in &mut ledger.files {
-        for d in &file.directives {
-            match &d.content {
-                DirectiveContent::Transaction(t) => {
-                    for link in &t.links {
-                        if link.starts_with("id-") {
-                            ids.insert(link.clone());
-                        }
-                    }
-                    for p in &t.postings {
-                        last_transaction
-                            .entry(p.account.clone())
-                            .and_modify(|t| {
-                                if *t < d.date {
-                                    *t = d.date;
-                                }
-                            })
-                            .or_insert(d.date);
-                    }
-                }
-                DirectiveContent::Balance(b) => {
-                    last_balance
-                        .entry(b.account.clone())
-                        .and_modify(|e| {
-                            if e.0 < d.date {
-                                *e = (d.date, b.amount.clone())
-                            }
-                        })
-                        .or_insert((d.date, b.amount.clone()));
-                }
-                _ => {}
-            }
-        }
-    }
-
-    for (_, file) in &mut ledger.files {
-        // (gocardless_account_id, account)
-        let mut to_import: Vec<(String, Account)> = vec![];
-        // Scan the file for the list of configured accounts with gocardless importer.
-        for d in &file.directives {
-            if let DirectiveContent::Open(ref open) = d.content {
-                let Some(importer) = d.metadata.get("importer") else {
-                    continue;
-                };
-                let MetadataValue::String(importer) = importer else {
-                    continue;
-                };
-                if importer != "gocardless" {
-                    continue;
-                }
-
-                let Some(account_id) = d.metadata.get("account_id") else {
-                    continue;
-                };
-                let MetadataValue::String(account_id) = account_id else {
-                    continue;
-                };
-                if let Some(ref filter) = filter_account_re {
-                    if !filter.is_match(&open.account.0) {
-                        continue;
-                    }
-                }
-                to_import.push((account_id.clone(), open.account.clone()));
-            }
-        }
-        // Add new transactions (and collect the pending ones, used later for balance assertions).
-        let mut pending_bag: HashMap<Account, Bag<Decimal>> = HashMap::new();
-        for (account_id, account) in &to_import {
-            println!("Retrieving transactions for {} ...", account);
-            let res = gocardless::apis::accounts_api::retrieve_account_transactions(
-                &config, account_id, None, None,
-            )
-            .await
-            .map_err(gocardless_err)?;
-
-            let mut new_directives = Vec::new();
-            for t in res.transactions.booked {
-                let d = gocardless_transaction_to_beancount(&t, account)?;
-                if !is_duplicate(&d, &ids) {
-                    new_directives.push(d);
-                }
-            }
-            for t in res.transactions.pending.unwrap_or_default() {
-                *pending_bag.entry(account.clone()).or_default() += Amount {
-                    value: t.transaction_amount.amount.parse()?,
-                    currency: Currency(t.transaction_amount.currency.clone()),
-                };
-            }
-
-            new_directives.reverse();
-            new_directives.sort_by_key(|d| d.date);
-
-            if let Some(d) = new_directives.last() {
-                last_transaction
-                    .entry(account.clone())
-                    .and_modify(|t| {
-                        if *t < d.date {
-                            *t = d.date;
-                        }
-                    })
-                    .or_insert(d.date);
-            }
 
-            file.directives.append(&mut new_directives);
+    match args.command {
+        Commands::Init => init::run(config_path.as_deref()).await?,
+        Commands::Completions { shell } => {
+            let mut command = Args::command();
+            let name = command.get_name().to_string();
+            clap_complete::generate(shell, &mut command, name, &mut std::io::stdout());
         }
-        // Add balances to the accounts
-        for (account_id, account) in &to_import {
-            println!("Balancing {} ...", account);
-            let res =
-                gocardless::apis::accounts_api::retrieve_account_balances(&config, account_id)
-                    .await
-                    .map_err(gocardless_err)?;
-            let Some(b) = res.balances else {
-                continue;
-            };
-            let Some(b) = b.first() else {
-                continue;
-            };
-
-            let mut amount = Amount {
-                value: Decimal::from_str_exact(&b.balance_amount.amount)?,
-                currency: Currency(b.balance_amount.currency.clone()),
-            };
-            if let Some(bag) = pending_bag.get(account) {
-                if let Some(a) = bag.commodities().get(&amount.currency) {
-                    amount.value -= a;
-                }
-            }
-
-            let previous_balance = last_balance.get(account);
-            if let Some((_, previous_balance)) = previous_balance {
-                if amount == previous_balance.clone() {
-                    println!("Previous balance matches the new one, skipping balance directive");
-                    continue;
-                }
-            }
-
-            let date = b
-                .reference_date
-                .as_ref()
-                .map(|rd| {
-                    let (date, _) = chrono::NaiveDate::parse_and_remainder(rd, "%Y-%m-%d").unwrap();
-                    date
-                })
-                .unwrap_or_else(|| {
-                    (*last_transaction.get(account).unwrap())
-                        .checked_add_days(Days::new(1))
-                        .unwrap()
-                });
-
-            let d = Directive {
-                date,
-                content: DirectiveContent::Balance(Balance {
-                    account: account.clone(),
-                    amount,
-                }),
-                metadata: Default::default(),
-            };
-            file.directives.push(d);
+        Commands::Manpages { dir } => {
+            std::fs::create_dir_all(&dir)?;
+            clap_mangen::generate_to(Args::command(), &dir)
+                .with_context(|| format!("failed to write the man pages to {}", dir.display()))?;
         }
-    }
-    Ok(())
-}
-
-fn gocardless_err<T>(e: gocardless::apis::Error<T>) -> anyhow::Error {
-    if let gocardless::apis::Error::ResponseError(ref c) = e {
-        anyhow::format_err!("error in response, {}", c.content)
-    } else {
-        anyhow::format_err!("{}", e)
-    }
-}
-
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    let args = Args::parse();
-
-    match args.command {
         Commands::SignIn {
             secret_id,
             secret_key,
         } => {
-            let config = gocardless::apis::configuration::Configuration::default();
-            let secrets = gocardless::models::jwt_obtain_pair_request::JwtObtainPairRequest::new(
-                secret_id, secret_key,
-            );
-            let jwt = gocardless::apis::token_api::obtain_new_access_slash_refresh_token_pair(
-                &config, secrets,
-            )
-            .await
-            .map_err(gocardless_err)?;
-
-            let tokens = Tokens::from_jwt(SystemTime::now(), &jwt)?;
-
-            let token_yaml_dir = base_config_dir()?;
-            tokio::fs::create_dir_all(&token_yaml_dir).await?;
-            let dir_permissions = std::fs::Permissions::from_mode(0o700);
-            tokio::fs::set_permissions(&token_yaml_dir, dir_permissions).await?;
-
-            let token_yaml_path = token_yaml_dir.join("token.yml");
-            let mut options = tokio::fs::OpenOptions::new();
-            options.write(true).create(true).mode(0o600);
-            let mut file = options.open(token_yaml_path).await?;
-            file.write_all(serde_yaml::to_string(&tokens)?.as_bytes())
-                .await?;
+            sign_in(secret_id, secret_key)
+                .await
+                .map_err(Error::into_inner)?;
             println!("Signed in");
         }
-        Commands::ListInstitutions { country } => {
+        Commands::ListInstitutions { country, format } => {
+            let format = default_format(format, OutputFormat::Table);
+            let mut countries: Vec<Option<String>> = country.into_iter().map(Some).collect();
+            if countries.is_empty() {
+                countries = match &settings::get().country {
+                    Some(c) => c.split(',').map(|c| Some(c.trim().to_string())).collect(),
+                    None => vec![None],
+                };
+            }
             let config = config_with_token().await?;
-            let banks = gocardless::apis::institutions_api::retrieve_all_supported_institutions_in_a_given_country(&config, None, None, None, None, None, country.as_deref(), None, None, None, None, None, None, None).await.map_err(gocardless_err)?;
-            println!("ID: NAME");
+            let mut banks: Vec<gocardless::models::Integration> = vec![];
+            for country in countries {
+                let res = timed("retrieve_all_supported_institutions_in_a_given_country", || {
+                    gocardless::apis::institutions_api::retrieve_all_supported_institutions_in_a_given_country(&config, None, None, None, None, None, country.as_deref(), None, None, None, None, None, None, None)
+                })
+                .await?;
+                // Institutions operating in several countries are returned for each of them.
+                for bank in res {
+                    if !banks.iter().any(|b| b.id == bank.id) {
+                        banks.push(bank);
+                    }
+                }
+            }
+            if output::print_structured(format, &banks)? {
+                return Ok(());
+            }
+            let mut table = Table::new(&["ID", "NAME", "BIC", "HISTORY DAYS", "COUNTRIES"]);
             for bank in banks {
-                println!("{}: {}", bank.id, bank.name);
+                table.row(vec![
+                    bank.id,
+                    bank.name,
+                    bank.bic.unwrap_or_default(),
+                    bank.transaction_total_days.unwrap_or_default(),
+                    bank.countries.join(","),
+                ]);
             }
+            table.print();
         }
         Commands::CreateRequisition { institution_id } => {
             let config = config_with_token().await?;
@@ -489,82 +450,473 @@ async fn main() -> anyhow::Result<()> {
                 Some("https://example.com/".into()),
                 institution_id,
             );
-            let res = gocardless::apis::requisitions_api::create_requisition(&config, req)
-                .await
-                .map_err(gocardless_err)?;
+            let res = timed_once("create_requisition", || {
+                gocardless::apis::requisitions_api::create_requisition(&config, req)
+            })
+            .await?;
             let link = res
                 .link
                 .context("setup link is missing from the gocardless response")?;
             println!("Follow the link to finish the instituion setup:\n{}", link);
         }
-        Commands::ListRequisitions => {
+        Commands::ListRequisitions { format } => {
+            let format = default_format(format, OutputFormat::Table);
             let config = config_with_token().await?;
-            let res =
+            let res = timed("retrieve_all_requisitions", || {
                 gocardless::apis::requisitions_api::retrieve_all_requisitions(&config, None, None)
-                    .await
-                    .map_err(gocardless_err)?;
-            let Some(requisitions) = res.results else {
+            })
+            .await?;
+            let requisitions = res.results.unwrap_or_default();
+            if output::print_structured(format, &requisitions)? {
                 return Ok(());
-            };
+            }
+            let cache = requisitions::refresh(&config, &requisitions).await;
+            let mut table =
+                Table::new(&["ID", "INSTITUTION", "STATUS", "EXPIRES", "ACCOUNTS", "LINK"]);
             for r in requisitions {
-                println!("ID: {:?}", r.id);
-                println!("Institution ID: {}", r.institution_id);
-                println!("Agreement: {:?}", r.agreement);
-                match r.status {
-                    Some(Status1c5Enum::Cr) => println!("Status: Created (not set up yet)"),
-                    Some(Status1c5Enum::Ln) => println!("Status: Linked"),
-                    _ => println!("Status: {:?}", r.status),
+                let expires = match cache.agreement_expiry(&config, &r).await {
+                    Ok(expires) => output::expiry(expires),
+                    Err(e) => {
+                        warn!(requisition = ?r.id, "failed to retrieve the agreement: {:#}", e);
+                        "unknown".to_string()
+                    }
+                };
+                let accounts: Vec<String> = r
+                    .accounts
+                    .iter()
+                    .flatten()
+                    .map(|a| {
+                        let id = a.to_string();
+                        match cache.account(&id) {
+                            Some(cached) => cached.to_string(),
+                            None => id,
+                        }
+                    })
+                    .collect();
+                table.row(vec![
+                    r.id.map(|id| id.to_string()).unwrap_or_default(),
+                    r.institution_id,
+                    output::requisition_status(r.status),
+                    expires,
+                    accounts.join(", "),
+                    r.link.unwrap_or_default(),
+                ]);
+            }
+            table.print();
+        }
+        Commands::DeleteRequisition { requisition_id } => {
+            let config = config_with_token().await?;
+            timed_once("delete_requisition_by_id", || {
+                gocardless::apis::requisitions_api::delete_requisition_by_id(
+                    &config,
+                    &requisition_id,
+                )
+            })
+            .await?;
+        }
+        Commands::ListTransactions {
+            account_id,
+            format,
+            output,
+            account_name,
+            rules,
+        } => {
+            let format = default_format(format, OutputFormat::Yaml);
+            let rules = rules.or_else(|| settings::get().rules.clone());
+            let config = config_with_token().await?;
+            check_quota(&account_id, &[quota::TRANSACTIONS])?;
+            let mut res =
+                source::gocardless::retrieve_transactions(&config, &account_id, &[]).await?;
+            timezone::fill_missing_dates(&mut res.transactions, None, || {
+                source::gocardless::retrieve_institution(&config, &account_id)
+            })
+            .await?;
+            let mut out: Box<dyn std::io::Write> = match &output {
+                Some(path) => {
+                    console::set_colors_enabled(false);
+                    Box::new(std::fs::File::create(path)?)
                 }
-                println!("Link: {:?}", r.link);
-                if let Some(ref accounts) = r.accounts {
-                    println!("Accounts:");
-                    for a in accounts {
-                        println!("- {}", a);
+                None => Box::new(std::io::stdout()),
+            };
+            if output::write_structured(format, &res, &mut out)? {
+                return Ok(());
+            }
+            match format {
+                OutputFormat::Csv => output::write_transactions_csv(&res, &mut out)?,
+                OutputFormat::Beancount => {
+                    let account = Account(account_name.context("--account-name is required")?);
+                    let rules = rules::Rules::load(rules.as_deref()).await?;
+                    for d in categorized_directives(
+                        &res,
+                        &account,
+                        &mapper::TransactionMapper::default(),
+                        &rules,
+                    )? {
+                        for line in d.to_string().lines() {
+                            writeln!(out, "{}", preview::highlight(line))?;
+                        }
+                        writeln!(out)?;
                     }
                 }
-                println!();
+                _ => output::transactions_table(&res).write(&mut out)?,
             }
         }
-        Commands::DeleteRequisition { requisition_id } => {
+        Commands::Balance { account_id, format } => {
+            let format = default_format(format, OutputFormat::Yaml);
             let config = config_with_token().await?;
-            gocardless::apis::requisitions_api::delete_requisition_by_id(&config, &requisition_id)
-                .await
-                .map_err(gocardless_err)?;
+            check_quota(&account_id, &[quota::BALANCES])?;
+            let res = source::gocardless::retrieve_balances(&config, &account_id).await?;
+            if output::print_structured(format, &res)? {
+                return Ok(());
+            }
+            let mut table = Table::new(&["TYPE", "AMOUNT", "CURRENCY", "REFERENCE DATE"]);
+            for b in res.balances.iter().flatten() {
+                table.row(vec![
+                    b.balance_type.clone(),
+                    b.balance_amount.amount.clone(),
+                    b.balance_amount.currency.clone(),
+                    b.reference_date.clone().unwrap_or_default(),
+                ]);
+            }
+            table.print();
         }
-        Commands::ListTransactions { account_id } => {
+        Commands::Export {
+            account_id,
+            format,
+            output,
+            account_name,
+            rules,
+            journal,
+        } => {
+            let rules = rules.or_else(|| settings::get().rules.clone());
+            let existing = match &journal {
+                Some(path) if path.exists() => tokio::fs::read_to_string(path).await?,
+                _ => String::new(),
+            };
             let config = config_with_token().await?;
-            let res = gocardless::apis::accounts_api::retrieve_account_transactions(
-                &config,
-                &account_id,
-                None,
-                None,
+            // The journals are written from the transactions only.
+            if format.is_journal() {
+                check_quota(&account_id, &[quota::TRANSACTIONS])?;
+            } else {
+                check_quota(&account_id, &[quota::TRANSACTIONS, quota::BALANCES])?;
+            }
+            let mut transactions =
+                source::gocardless::retrieve_transactions(&config, &account_id, &[]).await?;
+            timezone::fill_missing_dates(&mut transactions.transactions, None, || {
+                source::gocardless::retrieve_institution(&config, &account_id)
+            })
+            .await?;
+            let balances = if format.is_journal() {
+                None
+            } else {
+                Some(source::gocardless::retrieve_balances(&config, &account_id).await?)
+            };
+            let mut out: Box<dyn std::io::Write> = match (&output, &journal) {
+                (Some(path), _) => Box::new(std::fs::File::create(path)?),
+                (_, Some(path)) => Box::new(
+                    std::fs::OpenOptions::new()
+                        .create(true)
+                        .append(true)
+                        .open(path)?,
+                ),
+                _ => Box::new(std::io::stdout()),
+            };
+            if let Some(balances) = balances {
+                export::export(format, &account_id, &transactions, &balances, &mut out)?;
+                return Ok(());
+            }
+            let account = Account(account_name.context("--account-name is required")?);
+            let rules = rules::Rules::load(rules.as_deref()).await?;
+            let ids = export::journal_links(format, &existing);
+            let mapper = mapper::TransactionMapper::default();
+            let directives: Vec<_> =
+                categorized_directives(&transactions, &account, &mapper, &rules)?
+                    .into_iter()
+                    .filter(|d| {
+                        let duplicate = is_duplicate(d, &ids);
+                        if duplicate {
+                            trace!(date = %d.date, "skipping transaction already in the journal");
+                        }
+                        !duplicate
+                    })
+                    .collect();
+            info!(count = directives.len(), "exporting transactions");
+            export::write_journal(format, &directives, &mapper, &mut out)?;
+        }
+        Commands::Extract {
+            files,
+            account,
+            existing,
+            rules,
+        } => {
+            extract::run(
+                &files,
+                account.as_deref(),
+                existing.as_deref(),
+                rules.as_deref(),
             )
-            .await
-            .map_err(gocardless_err)?;
-            println!("{}", serde_yaml::to_string(&res)?);
+            .await?
         }
-        Commands::Balance { account_id } => {
+        Commands::DumpFixture {
+            account_id,
+            dir,
+            anonymize,
+        } => {
             let config = config_with_token().await?;
-            let res =
-                gocardless::apis::accounts_api::retrieve_account_balances(&config, &account_id)
-                    .await
-                    .map_err(gocardless_err)?;
-            println!("{}", serde_yaml::to_string(&res)?);
+            check_quota(&account_id, &[quota::TRANSACTIONS, quota::BALANCES])?;
+            let transactions =
+                source::gocardless::retrieve_transactions(&config, &account_id, &[]).await?;
+            let balances = source::gocardless::retrieve_balances(&config, &account_id).await?;
+            // The responses are saved as the bank returned them: the replayed fixtures go through
+            // the import, which fills the missing dates itself.
+            let mut anonymizer = anonymize::Anonymizer::default();
+            for (request, mut res) in [
+                (
+                    "retrieve_account_transactions",
+                    serde_json::to_value(&transactions)?,
+                ),
+                (
+                    "retrieve_account_balances",
+                    serde_json::to_value(&balances)?,
+                ),
+            ] {
+                if anonymize {
+                    anonymizer.anonymize(&mut res);
+                }
+                recording::save(&dir, request, &account_id, &res).await?;
+            }
+            info!(dir = %dir.display(), "saved the API responses");
         }
-        Commands::Import {
+        Commands::Cache {
+            command:
+                CacheCommand::Clear {
+                    account_id,
+                    older_than,
+                },
+        } => {
+            let cleared = store::Store::open_default()?.clear(
+                account_id.as_deref(),
+                older_than.map(|age| chrono::Utc::now() - age),
+            )?;
+            println!(
+                "Removed {} transaction(s), {} pending transaction(s) and {} balance(s)",
+                cleared.transactions, cleared.pending_transactions, cleared.balances
+            );
+        }
+        Commands::Cache {
+            command: CacheCommand::Stats { format },
+        } => {
+            let format = default_format(format, OutputFormat::Table);
+            let stats = store::Store::open_default()?.stats()?;
+            if output::print_structured(format, &stats)? {
+                return Ok(());
+            }
+            let mut table = Table::new(&[
+                "ACCOUNT",
+                "TRANSACTIONS",
+                "FIRST DATE",
+                "LAST DATE",
+                "BALANCES",
+                "LAST FETCHED",
+            ]);
+            for s in stats {
+                table.row(vec![
+                    s.account_id,
+                    s.transactions.to_string(),
+                    s.first_booking_date.unwrap_or_default(),
+                    s.last_booking_date.unwrap_or_default(),
+                    s.balances.to_string(),
+                    s.last_fetched.unwrap_or_default(),
+                ]);
+            }
+            table.print();
+            let path = base_config_dir()?.join("store.sqlite");
+            if let Ok(metadata) = std::fs::metadata(&path) {
+                println!("\n{}: {} KiB", path.display(), metadata.len() / 1024);
+            }
+        }
+        Commands::Rules {
+            command:
+                RulesCommand::Test {
+                    query,
+                    ledger,
+                    rules,
+                    format,
+                },
+        } => {
+            let format = default_format(format, OutputFormat::Table);
+            rule_test::run(&query, ledger.as_deref(), rules.as_deref(), format).await?;
+        }
+        Commands::Query { query, format } => {
+            let format = default_format(format, OutputFormat::Table);
+            let res = store::Store::open_default()?.query(&query)?;
+            if output::print_structured(format, &res)? {
+                return Ok(());
+            }
+            let mut table = Table::new(&[
+                "ACCOUNT",
+                "DATE",
+                "AMOUNT",
+                "CURRENCY",
+                "COUNTERPARTY",
+                "NARRATION",
+            ]);
+            for s in &res {
+                let t = &s.transaction;
+                table.row(vec![
+                    s.account_id.clone(),
+                    t.booking_date.clone().unwrap_or_default(),
+                    t.transaction_amount.amount.clone(),
+                    t.transaction_amount.currency.clone(),
+                    t.creditor_name
+                        .clone()
+                        .or_else(|| t.debtor_name.clone())
+                        .unwrap_or_default(),
+                    narration(t).unwrap_or_default(),
+                ]);
+            }
+            table.print();
+        }
+        Commands::Doctor {
+            beancount_path,
+            rules,
+        } => doctor::run(beancount_path.as_deref(), rules.as_deref()).await?,
+        Commands::VerifyConfig { beancount_path } => verify_config::run(&beancount_path).await?,
+        Commands::UpdateAccounts { beancount_path } => {
+            let updated = account_details::update(&beancount_path).await?;
+            println!("Updated {} account(s)", updated);
+        }
+        Commands::SnapshotBalances {
             beancount_path,
             filter_account_re,
+            csv_profiles,
         } => {
-            let mut ledger: Ledger<Decimal> = Ledger::read(beancount_path, |p| async {
-                Ok(tokio::fs::read_to_string(p).await?)
-            })
+            let options = ImportOptions {
+                filter_account_re,
+                csv_profiles,
+                ..Default::default()
+            };
+            let added = snapshot::run(&beancount_path, &options).await?;
+            println!("Added {} balance assertion(s)", added);
+        }
+        Commands::History {
+            ledger,
+            limit,
+            format,
+        } => {
+            let format = default_format(format, OutputFormat::Table);
+            let mut entries = history::read(&history::default_path()?)?;
+            if let Some(ledger) = ledger {
+                let ledger = std::fs::canonicalize(&ledger).unwrap_or(ledger);
+                entries.retain(|e| e.ledger == ledger);
+            }
+            let entries = &entries[entries.len().saturating_sub(limit)..];
+            if output::print_structured(format, &entries)? {
+                return Ok(());
+            }
+            let mut table = Table::new(&["TIME", "LEDGER", "STATUS", "NEW", "DIRECTIVES"]);
+            for e in entries {
+                table.row(vec![
+                    e.time.format("%Y-%m-%d %H:%M:%S").to_string(),
+                    e.ledger.display().to_string(),
+                    match &e.error {
+                        None if e.success => "ok".to_string(),
+                        None => "partial".to_string(),
+                        Some(error) => format!("failed: {}", error),
+                    },
+                    e.new_transactions().to_string(),
+                    e.changes
+                        .iter()
+                        .map(|c| c.directives)
+                        .sum::<usize>()
+                        .to_string(),
+                ]);
+            }
+            table.print();
+        }
+        Commands::Review {
+            beancount_path,
+            rules,
+            apply_rules: true,
+            dry_run,
+        } => review::apply_rules(&beancount_path, rules.as_deref(), dry_run).await?,
+        Commands::Review {
+            beancount_path,
+            rules,
+            ..
+        } => review::run(&beancount_path, rules.as_deref()).await?,
+        Commands::Daemon {
+            beancount_path,
+            schedule,
+            max_syncs_per_day,
+            status_file,
+            mut options,
+        } => {
+            settings::get().apply(&mut options);
+            let schedule = daemon::parse_schedule(&schedule)?;
+            let status_file = match status_file {
+                Some(path) => path,
+                None => base_config_dir()?.join("daemon-status.json"),
+            };
+            daemon::run(
+                &beancount_path,
+                &options,
+                &schedule,
+                max_syncs_per_day,
+                &status_file,
+            )
             .await?;
-
-            import(&mut ledger, filter_account_re).await?;
-
-            ledger
-                .write(|p, content| async { Ok(tokio::fs::write(p, content).await?) })
-                .await?;
+        }
+        Commands::Import {
+            beancount_paths,
+            mut options,
+        } => {
+            settings::get().apply(&mut options);
+            if options.stdin && beancount_paths.len() > 1 {
+                anyhow::bail!("only one ledger can be read from stdin");
+            }
+            let paths = if options.stdin && beancount_paths.is_empty() {
+                vec![PathBuf::from("-")]
+            } else if beancount_paths.is_empty() {
+                settings::get().ledgers.clone()
+            } else {
+                beancount_paths
+            };
+            if paths.is_empty() {
+                anyhow::bail!(
+                    "no ledger to import, give one or configure `ledgers` in the settings"
+                );
+            }
+            if let [path] = &paths[..] {
+                import_ledger(path, &options)
+                    .await
+                    .map_err(Error::into_inner)?;
+                return Ok(());
+            }
+            // The ledgers are imported one after another, so they share the token and the daily
+            // API quota of the accounts imported into several of them.
+            let mut failures = vec![];
+            for path in &paths {
+                if let Err(e) = import_ledger(path, &options).await {
+                    let e = e.into_inner();
+                    warn!("{}: {:#}", path.display(), e);
+                    failures.push((path.display().to_string(), Failure::of(&e)));
+                }
+            }
+            if !failures.is_empty() {
+                let kinds: HashSet<Option<Failure>> = failures.iter().map(|(_, f)| *f).collect();
+                let kind = match kinds.into_iter().collect::<Vec<_>>()[..] {
+                    [Some(kind)] => kind,
+                    _ => Failure::PartialImport,
+                };
+                let failed: Vec<&str> = failures.iter().map(|(p, _)| p.as_str()).collect();
+                return Err(anyhow::format_err!(
+                    "failed to import {} ledger(s): {}",
+                    failed.len(),
+                    failed.join(", ")
+                )
+                .context(kind));
+            }
         }
     }
     Ok(())
@@ -0,0 +1,408 @@
+//! Conversion of the GoCardless transactions to beancount transactions, configurable for library
+//! users:
+//!
+//! ```ignore
+//! let mapper = TransactionMapper::builder()
+//!     .narration(Narration::Counterparty)
+//!     .payee(Payee::Counterparty)
+//!     .rename_metadata("from_name", "payer")
+//!     .skip_metadata("value_date_time")
+//!     .hook(|t, d| {
+//!         d.metadata.insert("source".into(), MetadataValue::String("bank".into()));
+//!         Ok(())
+//!     })
+//!     .build();
+//! let d = mapper.map(&transaction, &account)?;
+//! ```
+
+use crate::{amount, booking_date, narration};
+use beanru::types::{
+    Account, Amount, Currency, Directive, DirectiveContent, MetadataValue, Posting, Transaction,
+};
+use chrono::NaiveDate;
+use gocardless::models::TransactionSchema;
+use rust_decimal::Decimal;
+use std::collections::{HashMap, HashSet};
+
+/// Link prefix of the internal transaction id, used for deduplication.
+pub const DEFAULT_LINK_PREFIX: &str = "id-";
+
+/// Metadata key with the narration reported by the bank, when it was shortened or changed.
+pub const RAW_NARRATION_KEY: &str = "raw_narration";
+
+/// Source of the narration.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Narration {
+    /// The remittance information, or the creditor name.
+    #[default]
+    Remittance,
+    /// The other party of the transaction: the creditor of outgoing and the debtor of incoming
+    /// payments, or the remittance information.
+    Counterparty,
+    /// The additional information, or the remittance information.
+    AdditionalInformation,
+}
+
+/// Source of the payee.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Payee {
+    #[default]
+    None,
+    /// The creditor of outgoing and the debtor of incoming payments.
+    Counterparty,
+    Creditor,
+    Debtor,
+}
+
+type Hook =
+    Box<dyn Fn(&TransactionSchema, &mut Directive<Decimal>) -> anyhow::Result<()> + Send + Sync>;
+
+/// Converts the GoCardless transactions to beancount transactions with a single posting to the
+/// account. The internal transaction id is kept as a link (`^id-...`) or in a metadata key, used
+/// for deduplication.
+pub struct TransactionMapper {
+    narration: Narration,
+    payee: Payee,
+    flag: Option<char>,
+    link_prefix: String,
+    id_metadata: Option<String>,
+    max_narration: Option<usize>,
+    /// Default metadata key -> the key used instead, or nothing to skip the metadata.
+    metadata_keys: HashMap<&'static str, Option<String>>,
+    hooks: Vec<Hook>,
+}
+
+impl Default for TransactionMapper {
+    fn default() -> Self {
+        TransactionMapper::builder().build()
+    }
+}
+
+/// Builder of the [`TransactionMapper`].
+pub struct TransactionMapperBuilder {
+    mapper: TransactionMapper,
+}
+
+/// Shortens the narration to at most `max` characters: the runs of whitespace are collapsed and, if
+/// it is still too long, it is cut at the last word boundary near the limit and ends with `…`.
+fn shorten(narration: &str, max: usize) -> Option<String> {
+    if narration.chars().count() <= max {
+        return None;
+    }
+    let collapsed = narration.split_whitespace().collect::<Vec<_>>().join(" ");
+    if collapsed.chars().count() <= max {
+        return Some(collapsed);
+    }
+    let cut: String = collapsed.chars().take(max.saturating_sub(1)).collect();
+    // Don't drop more than a third of the allowed length to end at a word boundary.
+    let cut = match cut.rfind(' ') {
+        Some(i) if cut[..i].chars().count() >= max * 2 / 3 => &cut[..i],
+        _ => cut.as_str(),
+    };
+    Some(format!("{}…", cut.trim_end()))
+}
+
+impl TransactionMapper {
+    pub fn builder() -> TransactionMapperBuilder {
+        TransactionMapperBuilder {
+            mapper: TransactionMapper {
+                narration: Narration::default(),
+                payee: Payee::default(),
+                flag: None,
+                link_prefix: DEFAULT_LINK_PREFIX.to_string(),
+                id_metadata: None,
+                max_narration: None,
+                metadata_keys: HashMap::new(),
+                hooks: vec![],
+            },
+        }
+    }
+
+    pub fn link_prefix(&self) -> &str {
+        &self.link_prefix
+    }
+
+    /// Metadata key with the internal transaction id, if it is not kept as a link.
+    pub fn id_metadata(&self) -> Option<&str> {
+        self.id_metadata.as_deref()
+    }
+
+    /// The internal transaction id of an imported transaction, from wherever the mapper keeps it.
+    pub fn transaction_id<'a>(&self, d: &'a Directive<Decimal>) -> Option<&'a str> {
+        match &self.id_metadata {
+            Some(key) => match d.metadata.get(key)? {
+                MetadataValue::String(s) => Some(s),
+                _ => None,
+            },
+            None => d
+                .content
+                .transaction_opt()?
+                .links
+                .iter()
+                .find_map(|l| l.strip_prefix(self.link_prefix.as_str())),
+        }
+    }
+
+    /// A transaction without postings with just the id, kept where the mapper keeps it, e.g. for
+    /// the dedup strategy to know the id of a transaction merged into another one.
+    pub fn id_only(&self, date: NaiveDate, id: &str) -> Directive<Decimal> {
+        let mut metadata = HashMap::new();
+        let mut links = HashSet::new();
+        match &self.id_metadata {
+            Some(key) => {
+                metadata.insert(key.clone(), MetadataValue::String(id.to_string()));
+            }
+            None => {
+                links.insert(format!("{}{}", self.link_prefix, id));
+            }
+        }
+        Directive {
+            date,
+            content: DirectiveContent::Transaction(Transaction {
+                flag: self.flag,
+                payee: None,
+                narration: None,
+                tags: Default::default(),
+                links,
+                postings: vec![],
+                balanced: false,
+            }),
+            metadata,
+        }
+    }
+
+    /// The value of the default metadata key (e.g. `raw_narration`) written by the mapper, under
+    /// the key it uses instead.
+    pub fn metadata_str<'a>(
+        &self,
+        d: &'a Directive<Decimal>,
+        key: &'static str,
+    ) -> Option<&'a str> {
+        let key = match self.metadata_keys.get(key) {
+            Some(renamed) => renamed.as_deref()?,
+            None => key,
+        };
+        match d.metadata.get(key)? {
+            MetadataValue::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    /// The narration reported by the bank: the `raw_narration` metadata, or the narration of the
+    /// transaction without it.
+    pub fn raw_narration<'a>(&self, d: &'a Directive<Decimal>) -> Option<&'a str> {
+        match self.metadata_str(d, RAW_NARRATION_KEY) {
+            Some(raw) => Some(raw),
+            None => d.content.transaction_opt()?.narration.as_deref(),
+        }
+    }
+
+    /// Keeps the narration reported by the bank in the `raw_narration` metadata, if the narration
+    /// of the converted transaction was changed since (e.g. by a script).
+    pub fn keep_raw_narration(&self, d: &mut Directive<Decimal>, raw: Option<String>) {
+        let Some(raw) = raw else {
+            return;
+        };
+        let narration = d
+            .content
+            .transaction_opt()
+            .and_then(|t| t.narration.as_ref());
+        if narration == Some(&raw) || self.metadata_str(d, RAW_NARRATION_KEY).is_some() {
+            return;
+        }
+        let key = match self.metadata_keys.get(RAW_NARRATION_KEY) {
+            Some(Some(renamed)) => renamed.clone(),
+            Some(None) => return,
+            None => RAW_NARRATION_KEY.to_string(),
+        };
+        d.metadata.insert(key, MetadataValue::String(raw));
+    }
+
+    fn narration(&self, t: &TransactionSchema, outgoing: bool) -> Option<String> {
+        let counterparty = if outgoing {
+            &t.creditor_name
+        } else {
+            &t.debtor_name
+        };
+        match self.narration {
+            Narration::Remittance => narration(t),
+            Narration::Counterparty => counterparty.clone().or_else(|| narration(t)),
+            Narration::AdditionalInformation => {
+                t.additional_information.clone().or_else(|| narration(t))
+            }
+        }
+    }
+
+    fn payee(&self, t: &TransactionSchema, outgoing: bool) -> Option<String> {
+        match self.payee {
+            Payee::None => None,
+            Payee::Counterparty if outgoing => t.creditor_name.clone(),
+            Payee::Counterparty => t.debtor_name.clone(),
+            Payee::Creditor => t.creditor_name.clone(),
+            Payee::Debtor => t.debtor_name.clone(),
+        }
+    }
+
+    /// Converts the transaction of the account.
+    pub fn map(
+        &self,
+        t: &TransactionSchema,
+        account: &Account,
+    ) -> anyhow::Result<Directive<Decimal>> {
+        let date = booking_date(t)?;
+        let value = amount::transaction(t)?;
+        let outgoing = value.is_sign_negative();
+        let mut narration = self.narration(t, outgoing);
+        let mut raw_narration = None;
+        if let (Some(n), Some(max)) = (&narration, self.max_narration) {
+            if let Some(short) = shorten(n, max) {
+                raw_narration = narration.replace(short);
+            }
+        }
+
+        let mut metadata: HashMap<String, MetadataValue<Decimal>> = HashMap::new();
+        let mut insert = |key: &'static str, value: Option<&String>| {
+            let Some(value) = value else {
+                return;
+            };
+            let key = match self.metadata_keys.get(key) {
+                Some(Some(renamed)) => renamed.clone(),
+                Some(None) => return,
+                None => key.to_string(),
+            };
+            metadata.insert(key, MetadataValue::String(value.clone()));
+        };
+        insert("booking_date_time", t.booking_date_time.as_ref());
+        insert("value_date_time", t.value_date_time.as_ref());
+        insert("from_name", t.debtor_name.as_ref());
+        insert(
+            "from_iban",
+            t.debtor_account.as_ref().and_then(|a| a.iban.as_ref()),
+        );
+        insert("to_name", t.creditor_name.as_ref());
+        insert(
+            "to_iban",
+            t.creditor_account.as_ref().and_then(|a| a.iban.as_ref()),
+        );
+        if let Some(ce) = &t.currency_exchange {
+            insert("source_currency", ce.source_currency.as_ref());
+            insert("exchange_rate", ce.exchange_rate.as_ref());
+            insert("target_currency", ce.target_currency.as_ref());
+        }
+        insert(
+            "transaction_code",
+            t.proprietary_bank_transaction_code.as_ref(),
+        );
+        insert(RAW_NARRATION_KEY, raw_narration.as_ref());
+
+        let mut links = HashSet::new();
+        if let Some(id) = &t.internal_transaction_id {
+            match &self.id_metadata {
+                Some(key) => {
+                    metadata.insert(key.clone(), MetadataValue::String(id.clone()));
+                }
+                None => {
+                    links.insert(format!("{}{}", self.link_prefix, id));
+                }
+            }
+        }
+
+        let transaction = Transaction {
+            flag: self.flag,
+            payee: self.payee(t, outgoing),
+            narration,
+            tags: Default::default(),
+            links,
+            postings: vec![Posting {
+                flag: None,
+                account: account.clone(),
+                amount: Some(Amount {
+                    value,
+                    currency: Currency(t.transaction_amount.currency.clone()),
+                }),
+                cost: None,
+                price: None,
+                metadata: Default::default(),
+                autocomputed: false,
+            }],
+            balanced: false,
+        };
+        let mut d = Directive {
+            date,
+            content: DirectiveContent::Transaction(transaction),
+            metadata,
+        };
+        for hook in &self.hooks {
+            hook(t, &mut d)?;
+        }
+        Ok(d)
+    }
+}
+
+impl TransactionMapperBuilder {
+    pub fn narration(mut self, narration: Narration) -> Self {
+        self.mapper.narration = narration;
+        self
+    }
+
+    pub fn payee(mut self, payee: Payee) -> Self {
+        self.mapper.payee = payee;
+        self
+    }
+
+    /// Flag of the converted transactions, used by the import unless `--flag` is given or the
+    /// rules categorized the transaction.
+    pub fn flag(mut self, flag: Option<char>) -> Self {
+        self.mapper.flag = flag;
+        self
+    }
+
+    pub fn link_prefix(mut self, prefix: &str) -> Self {
+        self.mapper.link_prefix = prefix.to_string();
+        self
+    }
+
+    /// Keeps the internal transaction id in the metadata key (e.g. `gocardless_id`) instead of a
+    /// link.
+    pub fn id_metadata(mut self, key: &str) -> Self {
+        self.mapper.id_metadata = Some(key.to_string());
+        self
+    }
+
+    /// Shortens the longer narrations to the number of characters, keeping the full narration in
+    /// the `raw_narration` metadata.
+    pub fn max_narration(mut self, max: usize) -> Self {
+        self.mapper.max_narration = Some(max);
+        self
+    }
+
+    /// Stores the metadata (e.g. `from_name`) under another key.
+    pub fn rename_metadata(mut self, key: &'static str, renamed: &str) -> Self {
+        self.mapper
+            .metadata_keys
+            .insert(key, Some(renamed.to_string()));
+        self
+    }
+
+    /// Leaves out the metadata (e.g. `value_date_time`).
+    pub fn skip_metadata(mut self, key: &'static str) -> Self {
+        self.mapper.metadata_keys.insert(key, None);
+        self
+    }
+
+    /// Adds a function run on every converted transaction, in the order they were added.
+    pub fn hook(
+        mut self,
+        hook: impl Fn(&TransactionSchema, &mut Directive<Decimal>) -> anyhow::Result<()>
+            + Send
+            + Sync
+            + 'static,
+    ) -> Self {
+        self.mapper.hooks.push(Box::new(hook));
+        self
+    }
+
+    pub fn build(self) -> TransactionMapper {
+        self.mapper
+    }
+}
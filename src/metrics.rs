@@ -0,0 +1,172 @@
+//! Metrics of the imports in the Prometheus text format, for the node_exporter textfile collector.
+//! The counters and timestamps are kept across the imports by reading the previous file.
+
+use crate::AccountSummary;
+use anyhow::Context;
+use std::{
+    collections::BTreeMap,
+    io::Write,
+    path::Path,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+static API_ERRORS: AtomicU64 = AtomicU64::new(0);
+static RATE_LIMIT_HITS: AtomicU64 = AtomicU64::new(0);
+
+/// (name, type, help) of the metrics, in the order they are written.
+const METRICS: &[(&str, &str, &str)] = &[
+    (
+        "gocardless_import_last_run_timestamp_seconds",
+        "gauge",
+        "Time of the last import of the ledger.",
+    ),
+    (
+        "gocardless_import_last_success_timestamp_seconds",
+        "gauge",
+        "Time of the last successful import of the ledger.",
+    ),
+    (
+        "gocardless_import_success",
+        "gauge",
+        "Whether the last import of the ledger succeeded.",
+    ),
+    (
+        "gocardless_account_last_success_timestamp_seconds",
+        "gauge",
+        "Time of the last successful import of the account.",
+    ),
+    (
+        "gocardless_transactions_imported_total",
+        "counter",
+        "Number of transactions imported into the account.",
+    ),
+    (
+        "gocardless_api_errors_total",
+        "counter",
+        "Number of failed API requests.",
+    ),
+    (
+        "gocardless_rate_limit_hits_total",
+        "counter",
+        "Number of API requests rejected because of the rate limit.",
+    ),
+];
+
+/// Counts a failed API request.
+pub fn record_api_error(rate_limited: bool) {
+    API_ERRORS.fetch_add(1, Ordering::Relaxed);
+    if rate_limited {
+        RATE_LIMIT_HITS.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+fn series(name: &str, label: Option<(&str, &str)>) -> String {
+    match label {
+        Some((key, value)) => format!(
+            "{}{{{}=\"{}\"}}",
+            name,
+            key,
+            value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n")
+        ),
+        None => name.to_string(),
+    }
+}
+
+/// Reads the samples of the previous file (series -> value).
+fn read(path: &Path) -> BTreeMap<String, f64> {
+    let content = std::fs::read_to_string(path).unwrap_or_default();
+    content
+        .lines()
+        .filter(|l| !l.starts_with('#'))
+        .filter_map(|l| {
+            let (series, value) = l.rsplit_once(' ')?;
+            Some((series.to_string(), value.parse().ok()?))
+        })
+        .collect()
+}
+
+/// Updates the metrics file with the result of the import of the ledger. The file is replaced
+/// atomically, so the collector never reads a partial file.
+pub fn write(
+    path: &Path,
+    ledger: &Path,
+    result: &anyhow::Result<Vec<AccountSummary>>,
+) -> anyhow::Result<()> {
+    let mut samples = read(path);
+    let now = chrono::Utc::now().timestamp() as f64;
+    let ledger = ledger.display().to_string();
+    let ledger_label = Some(("ledger", ledger.as_str()));
+    let mut set = |name: &str, label: Option<(&str, &str)>, value: f64| {
+        samples.insert(series(name, label), value);
+    };
+    set(
+        "gocardless_import_last_run_timestamp_seconds",
+        ledger_label,
+        now,
+    );
+    let success = result
+        .as_ref()
+        .is_ok_and(|accounts| accounts.iter().all(|a| a.error.is_none()));
+    set(
+        "gocardless_import_success",
+        ledger_label,
+        if success { 1. } else { 0. },
+    );
+    if success {
+        set(
+            "gocardless_import_last_success_timestamp_seconds",
+            ledger_label,
+            now,
+        );
+    }
+    if let Ok(accounts) = result {
+        for a in accounts.iter().filter(|a| a.error.is_none()) {
+            set(
+                "gocardless_account_last_success_timestamp_seconds",
+                Some(("account", &a.account)),
+                now,
+            );
+        }
+    }
+    let mut add = |name: &str, label: Option<(&str, &str)>, value: u64| {
+        *samples.entry(series(name, label)).or_default() += value as f64;
+    };
+    for a in result.iter().flatten() {
+        add(
+            "gocardless_transactions_imported_total",
+            Some(("account", &a.account)),
+            a.new_transactions as u64,
+        );
+    }
+    add(
+        "gocardless_api_errors_total",
+        None,
+        API_ERRORS.swap(0, Ordering::Relaxed),
+    );
+    add(
+        "gocardless_rate_limit_hits_total",
+        None,
+        RATE_LIMIT_HITS.swap(0, Ordering::Relaxed),
+    );
+
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let mut file = tempfile::NamedTempFile::new_in(dir)?;
+    for (name, kind, help) in METRICS {
+        writeln!(file, "# HELP {} {}", name, help)?;
+        writeln!(file, "# TYPE {} {}", name, kind)?;
+        for (series, value) in &samples {
+            if series == name || series.starts_with(&format!("{}{{", name)) {
+                writeln!(file, "{} {}", series, value)?;
+            }
+        }
+    }
+    file.persist(path)
+        .with_context(|| format!("failed to write {}", path.display()))?;
+    Ok(())
+}
@@ -0,0 +1,340 @@
+//! Detection of the transactions changed by the bank after they were imported (e.g. a corrected
+//! amount or narration). Such transactions are recognized by their internal transaction id, so they
+//! are not imported again, but the differences from the values the bank reported before are
+//! reported. The narration and the date edited in the ledger are not changes of the bank, so the
+//! raw values kept in the metadata (`raw_narration`, `booking_date_time`) are compared instead,
+//! when the transaction has them. The narration is compared only with the raw one, kept when it
+//! was shortened or changed (e.g. by a script) during the import.
+
+use crate::mapper::{TransactionMapper, RAW_NARRATION_KEY};
+use beanru::types::{Account, Directive, DirectiveContent, Ledger, MetadataValue};
+use chrono::NaiveDate;
+use rust_decimal::Decimal;
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+};
+
+/// Metadata keys with the values reported by the bank after the transaction was imported, added
+/// by `--record-bank-changes`.
+const DATE_KEY: &str = "bank_date";
+const AMOUNT_KEY: &str = "bank_amount";
+const NARRATION_KEY: &str = "bank_narration";
+
+/// Metadata key with the booking date-time reported by the bank.
+const DATE_TIME_KEY: &str = "booking_date_time";
+
+fn hash(value: impl Hash) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// The imported transaction as the bank reported it. Large ledgers have many imported
+/// transactions, so only the hashes of the compared values are kept; the values themselves are
+/// read from the ledger by [`describe`] for the changed transactions.
+pub struct Recorded {
+    date: NaiveDate,
+    date_time: Option<u64>,
+    /// The raw narration, if it was kept.
+    narration: Option<u64>,
+    /// The account and the amount of the postings with an amount.
+    amounts: Vec<(u64, u64)>,
+    /// The changes recorded in the metadata before, by their key.
+    changes: Vec<(&'static str, u64)>,
+}
+
+impl Recorded {
+    /// The value in the ledger, or the last change recorded in the metadata.
+    fn value(&self, key: &str, value: u64) -> u64 {
+        self.recorded(key).unwrap_or(value)
+    }
+
+    fn recorded(&self, key: &str) -> Option<u64> {
+        self.changes
+            .iter()
+            .find(|(k, _)| *k == key)
+            .map(|(_, h)| *h)
+    }
+}
+
+/// A field of the transaction that the bank changed.
+#[derive(Clone, Debug)]
+pub struct Change {
+    pub key: &'static str,
+    pub recorded: Option<String>,
+    pub fetched: Option<String>,
+}
+
+impl std::fmt::Display for Change {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let field = self.key.trim_start_matches("bank_");
+        write!(f, "{} {:?} -> {:?}", field, self.recorded, self.fetched)
+    }
+}
+
+/// The changes of the transaction with the internal id.
+#[derive(Clone, Debug)]
+pub struct Mutation {
+    pub id: String,
+    pub account: Account,
+    pub changes: Vec<Change>,
+}
+
+fn amount<'a>(d: &'a Directive<Decimal>, account: &Account) -> Option<(Decimal, &'a str)> {
+    let t = d.content.transaction_opt()?;
+    t.postings
+        .iter()
+        .find(|p| p.account == *account)
+        .and_then(|p| p.amount.as_ref())
+        .map(|a| (a.value, a.currency.0.as_str()))
+}
+
+fn amount_str((value, currency): (Decimal, &str)) -> String {
+    format!("{} {}", value, currency)
+}
+
+/// The value of a change recorded in the metadata (e.g. `-42.10 EUR`), hashed like the fetched
+/// one. An empty value is a missing one.
+fn change_hash(key: &str, value: &str) -> u64 {
+    let value = Some(value).filter(|v| !v.is_empty());
+    match key {
+        DATE_KEY => hash(value.and_then(|v| v.parse::<NaiveDate>().ok())),
+        AMOUNT_KEY => hash(value.and_then(|v| {
+            let (value, currency) = v.split_once(' ')?;
+            Some((value.parse::<Decimal>().ok()?, currency))
+        })),
+        _ => hash(value),
+    }
+}
+
+/// Adds the transaction of the ledger to the imported ones (by their internal id), if it was
+/// imported.
+pub fn add_recorded(
+    recorded: &mut HashMap<u64, Recorded>,
+    mapper: &TransactionMapper,
+    d: &Directive<Decimal>,
+) {
+    let DirectiveContent::Transaction(t) = &d.content else {
+        return;
+    };
+    let Some(id) = mapper.transaction_id(d) else {
+        return;
+    };
+    let amounts = t
+        .postings
+        .iter()
+        .filter_map(|p| {
+            let a = p.amount.as_ref()?;
+            Some((
+                hash(&p.account.0),
+                hash(Some((a.value, a.currency.0.as_str()))),
+            ))
+        })
+        .collect();
+    let changes = [DATE_KEY, AMOUNT_KEY, NARRATION_KEY]
+        .into_iter()
+        .filter_map(|key| match d.metadata.get(key)? {
+            MetadataValue::String(s) => Some((key, change_hash(key, s))),
+            _ => None,
+        })
+        .collect();
+    recorded.insert(
+        hash(id),
+        Recorded {
+            date: d.date,
+            date_time: mapper.metadata_str(d, DATE_TIME_KEY).map(hash),
+            narration: mapper
+                .metadata_str(d, RAW_NARRATION_KEY)
+                .map(|n| hash(Some(n))),
+            amounts,
+            changes,
+        },
+    );
+}
+
+/// Compares the transaction fetched again from the bank with the one imported into the account
+/// before. Returns nothing when it is new or unchanged. The recorded values of the changes are
+/// filled by [`describe`].
+pub fn detect(
+    recorded: &HashMap<u64, Recorded>,
+    mapper: &TransactionMapper,
+    fetched: &Directive<Decimal>,
+    account: &Account,
+) -> Option<Mutation> {
+    let id = mapper.transaction_id(fetched)?;
+    let r = recorded.get(&hash(id))?;
+    let mut changes = vec![];
+    let mut changed = |key, fetched| {
+        changes.push(Change {
+            key,
+            recorded: None,
+            fetched,
+        })
+    };
+    // The date moved in the ledger is not compared when the bank keeps the same date-time.
+    let date_time = mapper.metadata_str(fetched, DATE_TIME_KEY).map(hash);
+    if (r.date_time.is_none() || r.date_time != date_time)
+        && r.value(DATE_KEY, hash(Some(r.date))) != hash(Some(fetched.date))
+    {
+        changed(DATE_KEY, Some(fetched.date.to_string()));
+    }
+    // Postings without an amount (e.g. edited by hand) are not compared.
+    let account_hash = hash(&account.0);
+    if let Some((_, amount_hash)) = r.amounts.iter().find(|(a, _)| *a == account_hash) {
+        let fetched_amount = amount(fetched, account);
+        if r.value(AMOUNT_KEY, *amount_hash) != hash(fetched_amount) {
+            changed(AMOUNT_KEY, fetched_amount.map(amount_str));
+        }
+    }
+    // The narration in the ledger may be edited, so it's compared only with the raw one.
+    let fetched_narration = mapper.raw_narration(fetched);
+    if r.recorded(NARRATION_KEY)
+        .or(r.narration)
+        .is_some_and(|h| h != hash(fetched_narration))
+    {
+        changed(NARRATION_KEY, fetched_narration.map(String::from));
+    }
+    if changes.is_empty() {
+        return None;
+    }
+    Some(Mutation {
+        id: id.to_string(),
+        account: account.clone(),
+        changes,
+    })
+}
+
+/// Fills the recorded values of the changes from the transactions in the ledger: the last change
+/// recorded in the metadata, or the value of the transaction.
+pub fn describe(ledger: &Ledger<Decimal>, mapper: &TransactionMapper, mutations: &mut [Mutation]) {
+    if mutations.is_empty() {
+        return;
+    }
+    let by_id: HashMap<String, usize> = mutations
+        .iter()
+        .enumerate()
+        .map(|(i, m)| (m.id.clone(), i))
+        .collect();
+    for file in ledger.files.values() {
+        for d in &file.directives {
+            let Some(&i) = mapper.transaction_id(d).and_then(|id| by_id.get(id)) else {
+                continue;
+            };
+            let m = &mut mutations[i];
+            for c in &mut m.changes {
+                c.recorded = match d.metadata.get(c.key) {
+                    Some(MetadataValue::String(s)) => Some(s.clone()).filter(|s| !s.is_empty()),
+                    _ => match c.key {
+                        DATE_KEY => Some(d.date.to_string()),
+                        AMOUNT_KEY => amount(d, &m.account).map(amount_str),
+                        _ => mapper.raw_narration(d).map(String::from),
+                    },
+                };
+            }
+        }
+    }
+}
+
+/// Records the values reported by the bank in the metadata of the changed transactions, so they
+/// are reported only once.
+pub fn record(ledger: &mut Ledger<Decimal>, mapper: &TransactionMapper, mutations: &[Mutation]) {
+    let by_id: HashMap<&str, &Mutation> = mutations.iter().map(|m| (m.id.as_str(), m)).collect();
+    for (_, file) in &mut ledger.files {
+        for d in &mut file.directives {
+            let Some(m) = mapper
+                .transaction_id(d)
+                .and_then(|id| by_id.get(id).copied())
+            else {
+                continue;
+            };
+            for c in &m.changes {
+                d.metadata.insert(
+                    c.key.to_string(),
+                    MetadataValue::String(c.fetched.clone().unwrap_or_default()),
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{self, bank};
+    use gocardless::models::TransactionSchema;
+
+    fn transaction(narration: &str, amount: &str) -> TransactionSchema {
+        test_util::transaction(
+            "t1",
+            "2024-03-05",
+            amount,
+            serde_json::json!({
+                "bookingDateTime": "2024-03-05T10:00:00Z",
+                "remittanceInformationUnstructured": narration,
+            }),
+        )
+    }
+
+    fn detect_changes(
+        mapper: &TransactionMapper,
+        imported: Directive<Decimal>,
+        t: &TransactionSchema,
+    ) -> Vec<&'static str> {
+        let account = bank();
+        let mut recorded = HashMap::new();
+        add_recorded(&mut recorded, mapper, &imported);
+        let fetched = mapper.map(t, &account).unwrap();
+        detect(&recorded, mapper, &fetched, &account)
+            .map(|m| m.changes.iter().map(|c| c.key).collect())
+            .unwrap_or_default()
+    }
+
+    #[test]
+    fn compares_raw_values() {
+        let mapper = TransactionMapper::builder().max_narration(20).build();
+        let account = bank();
+        let t = transaction("Card payment at the grocery store on the corner", "-12.40");
+        let mut imported = mapper.map(&t, &account).unwrap();
+        // Edited by hand after the import.
+        imported.date = imported.date.succ_opt().unwrap();
+        if let DirectiveContent::Transaction(tx) = &mut imported.content {
+            tx.narration = Some("Groceries".to_string());
+        }
+        assert!(detect_changes(&mapper, imported.clone(), &t).is_empty());
+
+        let changed = transaction("Card payment at the grocery store", "-12.50");
+        assert_eq!(
+            detect_changes(&mapper, imported, &changed),
+            vec![AMOUNT_KEY, NARRATION_KEY]
+        );
+    }
+
+    #[test]
+    fn compares_only_raw_narrations() {
+        let mapper = TransactionMapper::default();
+        let account = bank();
+        let t = transaction("Card payment at the grocery store", "-12.40");
+        let edit = |d: &mut Directive<Decimal>| {
+            if let DirectiveContent::Transaction(tx) = &mut d.content {
+                tx.narration = Some("Groceries".to_string());
+            }
+        };
+        let changed = transaction("Card payment at the corner shop", "-12.40");
+        // Edited by hand: the narration of the bank is unknown.
+        let mut imported = mapper.map(&t, &account).unwrap();
+        edit(&mut imported);
+        assert!(detect_changes(&mapper, imported.clone(), &t).is_empty());
+        assert!(detect_changes(&mapper, imported, &changed).is_empty());
+        // Edited during the import (e.g. by a script), keeping the raw narration.
+        let mut imported = mapper.map(&t, &account).unwrap();
+        let raw = mapper.raw_narration(&imported).map(String::from);
+        edit(&mut imported);
+        mapper.keep_raw_narration(&mut imported, raw);
+        assert!(detect_changes(&mapper, imported.clone(), &t).is_empty());
+        assert_eq!(
+            detect_changes(&mapper, imported, &changed),
+            vec![NARRATION_KEY]
+        );
+    }
+}
@@ -0,0 +1,155 @@
+//! Hooks run after the import, so automated imports can be monitored.
+
+use crate::{expiring_requisitions, recording, AccountSummary, ExpiringRequisition, ImportOptions};
+use anyhow::Context;
+use serde::Serialize;
+use std::{path::Path, process::Stdio};
+use tokio::io::AsyncWriteExt;
+use tracing::{debug, warn};
+
+/// Agreements expiring within this many days are reported as needing re-authorization.
+pub const EXPIRY_WARNING_DAYS: i64 = 7;
+
+/// Summary of the import, passed to the hooks as JSON.
+#[derive(Serialize, Debug)]
+pub struct Summary {
+    pub ledger: String,
+    pub success: bool,
+    pub error: Option<String>,
+    pub new_transactions: usize,
+    pub accounts: Vec<AccountSummary>,
+    /// Requisitions whose access expired or expires soon.
+    pub reauthorization: Vec<ExpiringRequisition>,
+    /// Human readable message (used by Slack and similar webhooks).
+    pub text: String,
+}
+
+impl Summary {
+    pub fn new(
+        ledger: &Path,
+        result: &anyhow::Result<Vec<AccountSummary>>,
+        reauthorization: Vec<ExpiringRequisition>,
+    ) -> Summary {
+        let accounts = result.as_ref().cloned().unwrap_or_default();
+        let new_transactions = accounts.iter().map(|a| a.new_transactions).sum();
+        let failed: Vec<&str> = accounts
+            .iter()
+            .filter(|a| a.error.is_some())
+            .map(|a| a.account.as_str())
+            .collect();
+        let error = match result {
+            Err(e) => Some(format!("{:#}", e)),
+            Ok(_) if !failed.is_empty() => Some(format!(
+                "failed to import {} account(s): {}",
+                failed.len(),
+                failed.join(", ")
+            )),
+            Ok(_) => None,
+        };
+        let mut text = match &error {
+            None => format!(
+                "{}: imported {} new transaction(s)",
+                ledger.display(),
+                new_transactions
+            ),
+            Some(e) => format!("{}: import failed: {}", ledger.display(), e),
+        };
+        for r in &reauthorization {
+            let accounts: Vec<&str> = accounts
+                .iter()
+                .filter(|a| {
+                    a.account_id
+                        .as_ref()
+                        .is_some_and(|id| r.account_ids.contains(id))
+                })
+                .map(|a| a.account.as_str())
+                .collect();
+            text += &format!(
+                "\nAccess to {} ({}) expires {}, renew it with create-requisition",
+                r.institution,
+                if accounts.is_empty() {
+                    r.requisition.clone()
+                } else {
+                    accounts.join(", ")
+                },
+                r.expires.format("%Y-%m-%d")
+            );
+        }
+        Summary {
+            ledger: ledger.display().to_string(),
+            success: error.is_none(),
+            error,
+            new_transactions,
+            accounts,
+            reauthorization,
+            text,
+        }
+    }
+}
+
+/// Runs the hooks configured in the options. Failures of the hooks are only logged.
+pub async fn notify(
+    ledger: &Path,
+    options: &ImportOptions,
+    result: &anyhow::Result<Vec<AccountSummary>>,
+) {
+    if options.notify_command.is_none() && options.notify_url.is_none() {
+        return;
+    }
+    let mut reauthorization = vec![];
+    if !options.offline && !recording::is_replay() {
+        match expiring_requisitions(EXPIRY_WARNING_DAYS).await {
+            Ok(r) => reauthorization = r,
+            Err(e) => warn!("failed to check the agreements: {:#}", e),
+        }
+    }
+    let summary = Summary::new(ledger, result, reauthorization);
+    if let Some(command) = &options.notify_command {
+        if let Err(e) = run_command(command, &summary).await {
+            warn!("notification command failed: {:#}", e);
+        }
+    }
+    if let Some(url) = &options.notify_url {
+        if let Err(e) = post(url, &summary).await {
+            warn!("notification webhook failed: {:#}", e);
+        }
+    }
+}
+
+async fn run_command(command: &str, summary: &Summary) -> anyhow::Result<()> {
+    debug!(command, "running the notification command");
+    let mut child = tokio::process::Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .env("GOCARDLESS_IMPORT_SUCCESS", summary.success.to_string())
+        .env(
+            "GOCARDLESS_NEW_TRANSACTIONS",
+            summary.new_transactions.to_string(),
+        )
+        .env("GOCARDLESS_MESSAGE", &summary.text)
+        .stdin(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("failed to run {:?}", command))?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin
+            .write_all(serde_json::to_string(summary)?.as_bytes())
+            .await?;
+    }
+    let status = child.wait().await?;
+    if !status.success() {
+        anyhow::bail!("{:?} exited with {}", command, status);
+    }
+    Ok(())
+}
+
+async fn post(url: &reqwest::Url, summary: &Summary) -> anyhow::Result<()> {
+    debug!(%url, "posting the notification");
+    crate::http_client()?
+        .post(url.clone())
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .body(serde_json::to_string(summary)?)
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(())
+}
@@ -0,0 +1,199 @@
+use console::{measure_text_width, pad_str, style, Alignment};
+use gocardless::models::{AccountTransactions, Status1c5Enum, TransactionSchema};
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+
+/// Output format of the listing commands.
+#[derive(clap::ValueEnum, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    Json,
+    Yaml,
+    Table,
+    /// Supported only by `list-transactions`.
+    Csv,
+    /// Supported only by `list-transactions`, requires `--account-name`.
+    Beancount,
+}
+
+/// Writes the value as JSON or YAML. Returns false for the other formats, which have to be
+/// rendered by the caller.
+pub fn write_structured<T: Serialize>(
+    format: OutputFormat,
+    value: &T,
+    out: &mut dyn Write,
+) -> anyhow::Result<bool> {
+    match format {
+        OutputFormat::Json => writeln!(out, "{}", serde_json::to_string_pretty(value)?)?,
+        OutputFormat::Yaml => write!(out, "{}", serde_yaml::to_string(value)?)?,
+        OutputFormat::Table | OutputFormat::Csv | OutputFormat::Beancount => return Ok(false),
+    }
+    Ok(true)
+}
+
+/// Prints the value as JSON or YAML. Returns false for the table format, which has to be rendered
+/// by the caller.
+pub fn print_structured<T: Serialize>(format: OutputFormat, value: &T) -> anyhow::Result<bool> {
+    if matches!(format, OutputFormat::Csv | OutputFormat::Beancount) {
+        anyhow::bail!("{:?} output is supported only by list-transactions", format);
+    }
+    write_structured(format, value, &mut std::io::stdout())
+}
+
+/// Booked and pending transactions, with their status.
+fn all_transactions(res: &AccountTransactions) -> impl Iterator<Item = (&str, &TransactionSchema)> {
+    let booked = res.transactions.booked.iter().map(|t| ("booked", t));
+    let pending = res.transactions.pending.iter().flatten();
+    booked.chain(pending.map(|t| ("pending", t)))
+}
+
+pub fn transactions_table(res: &AccountTransactions) -> Table {
+    let mut table = Table::new(&[
+        "STATUS",
+        "DATE",
+        "AMOUNT",
+        "CURRENCY",
+        "COUNTERPARTY",
+        "NARRATION",
+    ]);
+    for (status, t) in all_transactions(res) {
+        table.row(vec![
+            status.to_string(),
+            t.booking_date.clone().unwrap_or_default(),
+            t.transaction_amount.amount.clone(),
+            t.transaction_amount.currency.clone(),
+            t.creditor_name
+                .clone()
+                .or_else(|| t.debtor_name.clone())
+                .unwrap_or_default(),
+            crate::narration(t).unwrap_or_default(),
+        ]);
+    }
+    table
+}
+
+/// Writes the transactions as CSV. The columns are stable, so the output can be consumed by other
+/// tools.
+pub fn write_transactions_csv(
+    res: &AccountTransactions,
+    out: &mut dyn Write,
+) -> anyhow::Result<()> {
+    let mut w = csv::Writer::from_writer(out);
+    w.write_record([
+        "date",
+        "value_date",
+        "amount",
+        "currency",
+        "creditor",
+        "debtor",
+        "narration",
+        "transaction_id",
+        "internal_transaction_id",
+        "status",
+    ])?;
+    for (status, t) in all_transactions(res) {
+        w.write_record([
+            t.booking_date.as_deref().unwrap_or_default(),
+            t.value_date.as_deref().unwrap_or_default(),
+            &t.transaction_amount.amount,
+            &t.transaction_amount.currency,
+            t.creditor_name.as_deref().unwrap_or_default(),
+            t.debtor_name.as_deref().unwrap_or_default(),
+            &crate::narration(t).unwrap_or_default(),
+            t.transaction_id.as_deref().unwrap_or_default(),
+            t.internal_transaction_id.as_deref().unwrap_or_default(),
+            status,
+        ])?;
+    }
+    w.flush()?;
+    Ok(())
+}
+
+/// A simple table with aligned columns. The cells can be styled (colors are shown only when
+/// printing to a terminal).
+pub struct Table {
+    header: Vec<String>,
+    rows: Vec<Vec<String>>,
+}
+
+impl Table {
+    pub fn new(header: &[&str]) -> Table {
+        Table {
+            header: header.iter().map(|h| h.to_string()).collect(),
+            rows: vec![],
+        }
+    }
+
+    pub fn row(&mut self, cells: Vec<String>) {
+        self.rows.push(cells);
+    }
+
+    pub fn print(&self) {
+        // Printing to stdout can only fail if it was closed.
+        let _ = self.write(&mut std::io::stdout());
+    }
+
+    pub fn write(&self, out: &mut dyn Write) -> std::io::Result<()> {
+        let mut widths: Vec<usize> = self.header.iter().map(|h| h.chars().count()).collect();
+        for row in &self.rows {
+            for (i, cell) in row.iter().enumerate() {
+                if i < widths.len() {
+                    widths[i] = widths[i].max(measure_text_width(cell));
+                }
+            }
+        }
+        let header: Vec<String> = self
+            .header
+            .iter()
+            .map(|h| style(h).bold().to_string())
+            .collect();
+        for row in std::iter::once(&header).chain(self.rows.iter()) {
+            let line: Vec<String> = row
+                .iter()
+                .zip(&widths)
+                .map(|(cell, width)| pad_str(cell, *width, Alignment::Left, None).to_string())
+                .collect();
+            writeln!(out, "{}", line.join("  ").trim_end())?;
+        }
+        Ok(())
+    }
+}
+
+/// Human readable, colored status of the requisition.
+pub fn requisition_status(status: Option<Status1c5Enum>) -> String {
+    let Some(status) = status else {
+        return String::new();
+    };
+    let code = serde_json::to_value(status)
+        .ok()
+        .and_then(|v| v.as_str().map(|s| s.to_string()))
+        .unwrap_or_else(|| format!("{:?}", status));
+    match code.as_str() {
+        "CR" => style("Created (not set up yet)").yellow().to_string(),
+        "GC" => style("Giving consent").yellow().to_string(),
+        "UA" => style("Undergoing authentication").yellow().to_string(),
+        "SA" => style("Selecting accounts").yellow().to_string(),
+        "GA" => style("Granting access").yellow().to_string(),
+        "LN" => style("Linked").green().to_string(),
+        "RJ" => style("Rejected").red().to_string(),
+        "SU" => style("Suspended").red().to_string(),
+        "EX" => style("Expired").red().to_string(),
+        _ => code,
+    }
+}
+
+/// Formats the expiry date of the access, highlighting the expired ones.
+pub fn expiry(expires: Option<chrono::DateTime<chrono::Utc>>) -> String {
+    let Some(expires) = expires else {
+        return String::new();
+    };
+    let now = chrono::Utc::now();
+    let date = expires.format("%Y-%m-%d").to_string();
+    if expires < now {
+        style(date).red().to_string()
+    } else if expires < now + chrono::Duration::days(7) {
+        style(date).yellow().to_string()
+    } else {
+        date
+    }
+}
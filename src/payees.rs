@@ -0,0 +1,153 @@
+//! Payee directory: the known counterparties with their default account and tags, in a YAML file
+//! meant to be edited by hand and shared with other importers:
+//!
+//! ```yaml
+//! payees:
+//!   - name: "Tesco"
+//!     account: "Expenses:Groceries"
+//!     tags: ["groceries"]
+//!     aliases: ["TESCO STORES 3012", "TESCO EXPRESS"]
+//!     count: 12
+//! ```
+//!
+//! The import counts how many transactions each payee got in `count`. Fields added by other tools
+//! are kept when the file is written back.
+
+use crate::rules::{counter_posting, Categorization};
+use anyhow::Context;
+use beanru::types::{Directive, DirectiveContent};
+use gocardless::models::TransactionSchema;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+};
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Payee {
+    /// The payee of the transactions.
+    pub name: String,
+    /// The counter account of the transactions not categorized by the rules.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub account: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    /// Other names of the counterparty in the bank transactions.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub aliases: Vec<String>,
+    /// Number of the imported transactions of the payee.
+    #[serde(default)]
+    pub count: u64,
+    #[serde(flatten)]
+    pub other: BTreeMap<String, serde_yaml::Value>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct File {
+    #[serde(default)]
+    payees: Vec<Payee>,
+    #[serde(flatten)]
+    other: BTreeMap<String, serde_yaml::Value>,
+}
+
+pub struct Directory {
+    path: PathBuf,
+    file: File,
+    /// Index of the payee by its normalized name and aliases.
+    names: HashMap<String, usize>,
+    changed: bool,
+}
+
+/// The name compared case-insensitively, with the whitespace collapsed.
+fn normalize(name: &str) -> String {
+    name.split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_lowercase()
+}
+
+/// The creditor of outgoing and the debtor of incoming payments.
+fn counterparty(t: &TransactionSchema) -> Option<&str> {
+    if t.transaction_amount.amount.starts_with('-') {
+        t.creditor_name.as_deref()
+    } else {
+        t.debtor_name.as_deref()
+    }
+}
+
+impl Directory {
+    /// Reads the directory. A missing file is an empty directory, created on the first save.
+    pub fn load(path: &Path) -> anyhow::Result<Directory> {
+        let file: File = match std::fs::read_to_string(path) {
+            Ok(content) => serde_yaml::from_str(&content)
+                .with_context(|| format!("failed to parse {}", path.display()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => File::default(),
+            Err(e) => {
+                return Err(e).with_context(|| format!("failed to read {}", path.display()));
+            }
+        };
+        let mut names = HashMap::new();
+        for (i, p) in file.payees.iter().enumerate() {
+            for name in std::iter::once(&p.name).chain(&p.aliases) {
+                if let Some(j) = names.insert(normalize(name), i) {
+                    if j != i {
+                        anyhow::bail!(
+                            "{}: {:?} is a name of both {:?} and {:?}",
+                            path.display(),
+                            name,
+                            file.payees[j].name,
+                            p.name
+                        );
+                    }
+                }
+            }
+        }
+        Ok(Directory {
+            path: path.to_path_buf(),
+            file,
+            names,
+            changed: false,
+        })
+    }
+
+    /// Applies the payee of the counterparty to the transaction: its name (unless the rules set
+    /// the payee), tags and account (unless the transaction has a counter posting already).
+    /// Returns whether the counter account was added.
+    pub fn apply(
+        &mut self,
+        t: &TransactionSchema,
+        d: &mut Directive<Decimal>,
+        categorization: Option<&Categorization>,
+    ) -> bool {
+        let Some(&i) = counterparty(t).and_then(|name| self.names.get(&normalize(name))) else {
+            return false;
+        };
+        let DirectiveContent::Transaction(tx) = &mut d.content else {
+            return false;
+        };
+        let payee = &mut self.file.payees[i];
+        payee.count += 1;
+        self.changed = true;
+        if categorization.is_none_or(|c| c.category.payee.is_none()) {
+            tx.payee = Some(payee.name.clone());
+        }
+        tx.tags.extend(payee.tags.iter().cloned());
+        match &payee.account {
+            Some(account) if tx.postings.len() == 1 => {
+                tx.postings.push(counter_posting(account, None));
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Writes the updated counts back, if any changed.
+    pub fn save(&self) -> anyhow::Result<()> {
+        if !self.changed {
+            return Ok(());
+        }
+        std::fs::write(&self.path, serde_yaml::to_string(&self.file)?)
+            .with_context(|| format!("failed to write {}", self.path.display()))
+    }
+}
@@ -0,0 +1,139 @@
+//! Pending transactions (e.g. card holds) seen by the previous imports, kept in
+//! `~/.gocardless/pending.json`. They are subtracted from the asserted balances, so the pending
+//! transactions that changed or disappeared without being booked are reported, as they explain
+//! unexpected jumps of the balance.
+
+use crate::{base_config_dir, narration, recording};
+use anyhow::Context;
+use beanru::types::Account;
+use gocardless::models::TransactionSchema;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use tracing::{info, warn};
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Hold {
+    /// The id of the transaction, or its date and narration when the bank doesn't provide one.
+    pub key: String,
+    pub date: Option<String>,
+    pub amount: Decimal,
+    pub currency: String,
+    pub narration: Option<String>,
+}
+
+impl Hold {
+    pub fn new(t: &TransactionSchema, amount: Decimal) -> Hold {
+        let narration = narration(t);
+        let key = t
+            .internal_transaction_id
+            .clone()
+            .or_else(|| t.transaction_id.clone())
+            .unwrap_or_else(|| {
+                format!(
+                    "{}|{}",
+                    t.booking_date
+                        .as_deref()
+                        .or(t.value_date.as_deref())
+                        .unwrap_or_default(),
+                    narration.as_deref().unwrap_or_default()
+                )
+            });
+        Hold {
+            key,
+            date: t.booking_date.clone().or_else(|| t.value_date.clone()),
+            amount,
+            currency: t.transaction_amount.currency.clone(),
+            narration,
+        }
+    }
+
+    fn describe(&self) -> String {
+        format!(
+            "{} {} {} {}",
+            self.date.as_deref().unwrap_or("undated"),
+            self.narration.as_deref().unwrap_or("(no narration)"),
+            self.amount,
+            self.currency
+        )
+    }
+}
+
+/// The pending transactions of each account, from the last import.
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct State {
+    accounts: BTreeMap<String, Vec<Hold>>,
+}
+
+fn path() -> anyhow::Result<std::path::PathBuf> {
+    Ok(base_config_dir()?.join("pending.json"))
+}
+
+impl State {
+    /// Reads the state of the previous import (nothing when replaying the recorded responses).
+    pub fn load() -> anyhow::Result<State> {
+        if recording::is_replay() {
+            return Ok(State::default());
+        }
+        let path = path()?;
+        match std::fs::read_to_string(&path) {
+            Ok(content) => serde_json::from_str(&content)
+                .with_context(|| format!("failed to parse {}", path.display())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(State::default()),
+            Err(e) => Err(e).with_context(|| format!("failed to read {}", path.display())),
+        }
+    }
+
+    pub fn save(&self) -> anyhow::Result<()> {
+        if recording::is_replay() {
+            return Ok(());
+        }
+        let path = path()?;
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(&path, serde_json::to_string_pretty(self)?)
+            .with_context(|| format!("failed to write {}", path.display()))
+    }
+
+    /// Replaces the pending transactions of the account, reporting the ones that changed their
+    /// amount, and the ones that disappeared without a booked transaction of the same amount.
+    pub fn reconcile(&mut self, account: &Account, holds: Vec<Hold>, booked: &[TransactionSchema]) {
+        let previous = self.accounts.remove(&account.0).unwrap_or_default();
+        for old in &previous {
+            match holds.iter().find(|h| h.key == old.key) {
+                Some(new) if new.amount != old.amount || new.currency != old.currency => warn!(
+                    "{}: pending transaction {} changed to {} {}",
+                    account,
+                    old.describe(),
+                    new.amount,
+                    new.currency
+                ),
+                Some(_) => {}
+                None => {
+                    let was_booked = booked.iter().any(|t| {
+                        t.transaction_amount.currency == old.currency
+                            && crate::amount::transaction(t).is_ok_and(|a| a == old.amount)
+                    });
+                    if was_booked {
+                        info!(
+                            "{}: pending transaction {} was booked",
+                            account,
+                            old.describe()
+                        );
+                    } else {
+                        warn!(
+                            "{}: pending transaction {} disappeared without being booked (e.g. \
+                             the hold was released)",
+                            account,
+                            old.describe()
+                        );
+                    }
+                }
+            }
+        }
+        if !holds.is_empty() {
+            self.accounts.insert(account.0.clone(), holds);
+        }
+    }
+}
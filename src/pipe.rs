@@ -0,0 +1,132 @@
+//! External command run on every imported transaction, e.g. a classifier written in Python. The
+//! command (run with `sh -c`) gets the proposed transaction as JSON on the standard input and prints
+//! the transaction that replaces it, or nothing to drop it:
+//!
+//! ```json
+//! {"date": "2024-01-31", "account": "Assets:Bank", "amount": "-12.50", "currency": "EUR",
+//!  "payee": null, "narration": "COFFEE", "flag": "*", "tags": [], "links": ["id-123"],
+//!  "metadata": {"to_name": "Coffee Shop"}, "counter_account": "Expenses:Food"}
+//! ```
+
+use crate::{rules::counter_posting, script::counter_account};
+use anyhow::Context;
+use beanru::types::{Account, Directive, DirectiveContent, MetadataValue};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, process::Stdio};
+use tokio::io::AsyncWriteExt;
+use tracing::trace;
+
+/// The transaction passed to the command. `account`, `amount` and `currency` describe the imported
+/// posting and are ignored in the output.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Proposed {
+    pub date: chrono::NaiveDate,
+    pub account: String,
+    pub amount: Option<Decimal>,
+    pub currency: Option<String>,
+    pub payee: Option<String>,
+    pub narration: Option<String>,
+    pub flag: Option<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub links: Vec<String>,
+    /// The string values of the metadata.
+    #[serde(default)]
+    pub metadata: BTreeMap<String, String>,
+    pub counter_account: Option<String>,
+}
+
+impl Proposed {
+    pub fn new(d: &Directive<Decimal>, account: &Account) -> Proposed {
+        let t = d.content.transaction_opt();
+        let amount = t
+            .and_then(|t| t.postings.iter().find(|p| p.account == *account))
+            .and_then(|p| p.amount.as_ref());
+        let sorted = |set: Option<_>| {
+            let mut v: Vec<String> = set.into_iter().flatten().cloned().collect();
+            v.sort();
+            v
+        };
+        Proposed {
+            date: d.date,
+            account: account.0.clone(),
+            amount: amount.map(|a| a.value),
+            currency: amount.map(|a| a.currency.0.clone()),
+            payee: t.and_then(|t| t.payee.clone()),
+            narration: t.and_then(|t| t.narration.clone()),
+            flag: t.and_then(|t| t.flag).map(|f| f.to_string()),
+            tags: sorted(t.map(|t| &t.tags)),
+            links: sorted(t.map(|t| &t.links)),
+            metadata: d
+                .metadata
+                .iter()
+                .filter_map(|(k, v)| match v {
+                    MetadataValue::String(s) => Some((k.clone(), s.clone())),
+                    _ => None,
+                })
+                .collect(),
+            counter_account: counter_account(d),
+        }
+    }
+
+    /// Applies the changes made by the command to the directive.
+    pub fn apply(self, d: &mut Directive<Decimal>) {
+        let counter = counter_account(d);
+        d.date = self.date;
+        d.metadata
+            .retain(|k, v| !matches!(v, MetadataValue::String(_)) || self.metadata.contains_key(k));
+        for (key, value) in self.metadata {
+            d.metadata.insert(key, MetadataValue::String(value));
+        }
+        let DirectiveContent::Transaction(t) = &mut d.content else {
+            return;
+        };
+        t.payee = self.payee;
+        t.narration = self.narration;
+        t.flag = self.flag.and_then(|f| f.chars().next());
+        t.tags = self.tags.into_iter().collect();
+        t.links = self.links.into_iter().collect();
+        if self.counter_account != counter {
+            t.postings.truncate(1);
+            if let Some(account) = &self.counter_account {
+                t.postings.push(counter_posting(account, None));
+            }
+        }
+    }
+}
+
+/// Pipes the transaction imported into the account through the command. Returns false if the
+/// command dropped it.
+pub async fn transform(
+    command: &str,
+    d: &mut Directive<Decimal>,
+    account: &Account,
+) -> anyhow::Result<bool> {
+    let input = serde_json::to_string(&Proposed::new(d, account))?;
+    trace!(command, input, "running the transform command");
+    let mut child = tokio::process::Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("failed to run {:?}", command))?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(input.as_bytes()).await?;
+    }
+    let output = child.wait_with_output().await?;
+    if !output.status.success() {
+        anyhow::bail!("{:?} exited with {}", command, output.status);
+    }
+    let output = String::from_utf8(output.stdout)
+        .with_context(|| format!("{:?} printed invalid UTF-8", command))?;
+    if output.trim().is_empty() || output.trim() == "null" {
+        return Ok(false);
+    }
+    let proposed: Proposed = serde_json::from_str(&output)
+        .with_context(|| format!("{:?} printed an invalid transaction", command))?;
+    proposed.apply(d);
+    Ok(true)
+}
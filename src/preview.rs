@@ -0,0 +1,87 @@
+//! Colored, diff-style rendering of the directives added by the import.
+
+use console::style;
+use regex::Regex;
+use std::{path::Path, sync::OnceLock};
+
+/// When to color the output.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ColorChoice {
+    /// Color the output when it is a terminal and `NO_COLOR` is not set.
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+/// Enables or disables the colors of stdout and stderr.
+pub fn set_color(choice: ColorChoice) {
+    let enabled = match choice {
+        ColorChoice::Auto => {
+            if std::env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty()) {
+                false
+            } else {
+                return;
+            }
+        }
+        ColorChoice::Always => true,
+        ColorChoice::Never => false,
+    };
+    console::set_colors_enabled(enabled);
+    console::set_colors_enabled_stderr(enabled);
+}
+
+fn token_re() -> &'static Regex {
+    static RE: OnceLock<Regex> = OnceLock::new();
+    RE.get_or_init(|| {
+        Regex::new(concat!(
+            r#"(?P<string>"(?:[^"\\]|\\.)*")"#,
+            r"|(?P<date>\b\d{4}-\d{2}-\d{2}\b)",
+            r"|(?P<account>\b(?:Assets|Liabilities|Equity|Income|Expenses)(?::[\p{Lu}\d][\w-]*)+)",
+            r"|(?P<tag>[#^][\w\-/.]+)",
+            r"|(?P<key>^\s+[a-z][\w-]*:)",
+            r"|(?P<amount>-?\d[\d,]*(?:\.\d+)?(?:\s+[A-Z][A-Z0-9'._-]*)?)",
+        ))
+        .unwrap()
+    })
+}
+
+/// Highlights the line of beancount syntax (if the colors are enabled).
+pub fn highlight(line: &str) -> String {
+    if !console::colors_enabled() {
+        return line.to_string();
+    }
+    token_re()
+        .replace_all(line, |c: &regex::Captures| {
+            let text = &c[0];
+            let styled = if c.name("string").is_some() {
+                style(text).yellow()
+            } else if c.name("date").is_some() {
+                style(text).blue()
+            } else if c.name("account").is_some() {
+                style(text).cyan()
+            } else if c.name("tag").is_some() {
+                style(text).magenta()
+            } else if c.name("key").is_some() {
+                style(text).dim()
+            } else {
+                style(text).bold()
+            };
+            styled.to_string()
+        })
+        .into_owned()
+}
+
+/// Renders the text added to the file as a diff: a header with the path and a green `+` in front
+/// of each highlighted line.
+pub fn diff(path: &Path, added: &str) -> String {
+    let mut out = format!(
+        "{}\n{}\n",
+        style(format!("--- {}", path.display())).bold(),
+        style(format!("+++ {}", path.display())).bold()
+    );
+    for line in added.trim_matches('\n').lines() {
+        out += &format!("{}{}\n", style("+").green(), highlight(line));
+    }
+    out
+}
@@ -0,0 +1,85 @@
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use std::{
+    io::Write,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        OnceLock,
+    },
+    time::Duration,
+};
+use tracing_subscriber::fmt::MakeWriter;
+
+static ENABLED: AtomicBool = AtomicBool::new(true);
+
+fn multi() -> &'static MultiProgress {
+    static MULTI: OnceLock<MultiProgress> = OnceLock::new();
+    MULTI.get_or_init(MultiProgress::new)
+}
+
+/// Disables the progress bars (e.g. in the quiet mode, or when verbose logs are enabled).
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Progress bar of a single stage of the import, e.g. fetching transactions of all accounts.
+pub fn bar(len: usize, stage: &str) -> ProgressBar {
+    if !is_enabled() {
+        return ProgressBar::hidden();
+    }
+    let pb = multi().add(ProgressBar::new(len as u64));
+    pb.set_style(
+        ProgressStyle::with_template("{prefix:>22} [{bar:30}] {pos}/{len} {wide_msg}")
+            .unwrap()
+            .progress_chars("=> "),
+    );
+    pb.set_prefix(stage.to_string());
+    pb
+}
+
+pub fn spinner(msg: &str) -> ProgressBar {
+    if !is_enabled() {
+        return ProgressBar::hidden();
+    }
+    let pb = multi().add(ProgressBar::new_spinner());
+    pb.set_message(msg.to_string());
+    pb.enable_steady_tick(Duration::from_millis(100));
+    pb
+}
+
+/// Writer for the logs that hides the progress bars while writing, so they don't interleave.
+pub struct LogWriter {
+    buf: Vec<u8>,
+}
+
+impl Write for LogWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for LogWriter {
+    fn drop(&mut self) {
+        multi().suspend(|| {
+            let _ = std::io::stderr().write_all(&self.buf);
+        });
+    }
+}
+
+pub struct MakeLogWriter;
+
+impl<'a> MakeWriter<'a> for MakeLogWriter {
+    type Writer = LogWriter;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        LogWriter { buf: vec![] }
+    }
+}
@@ -0,0 +1,106 @@
+//! Normalization of the known quirks of some institutions, applied to the fetched transactions
+//! before they are converted. The quirks of each institution are configured in the settings:
+//!
+//! ```toml
+//! [quirks.EXAMPLE_BANK_XXXXXX]
+//! swap_parties = true
+//! narration_field = "additional_information"
+//! ```
+
+use crate::narration;
+use anyhow::Context;
+use gocardless::models::{BankTransaction, TransactionSchema};
+use serde::Deserialize;
+
+/// Field of the transaction that holds the narration.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum NarrationField {
+    AdditionalInformation,
+    RemittanceInformationStructured,
+    EntryReference,
+    CreditorName,
+    DebtorName,
+}
+
+/// The quirks of an institution.
+#[derive(Deserialize, Default, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    /// The bank reports the debtor as the creditor and vice versa.
+    #[serde(default)]
+    pub swap_parties: bool,
+    /// The bank puts the narration in another field than the remittance information.
+    pub narration_field: Option<NarrationField>,
+    /// The bank keeps returning the booked transactions as pending, which would be subtracted
+    /// from the balance twice.
+    #[serde(default)]
+    pub drop_booked_pending: bool,
+    /// Format of the booking and value dates (e.g. `%d.%m.%Y`), when the bank doesn't use ISO
+    /// 8601.
+    pub date_format: Option<String>,
+}
+
+impl NarrationField {
+    fn get(self, t: &TransactionSchema) -> Option<&String> {
+        match self {
+            NarrationField::AdditionalInformation => t.additional_information.as_ref(),
+            NarrationField::RemittanceInformationStructured => {
+                t.remittance_information_structured.as_ref()
+            }
+            NarrationField::EntryReference => t.entry_reference.as_ref(),
+            NarrationField::CreditorName => t.creditor_name.as_ref(),
+            NarrationField::DebtorName => t.debtor_name.as_ref(),
+        }
+    }
+}
+
+fn normalize_date(date: &mut Option<String>, format: &str) -> anyhow::Result<()> {
+    if let Some(d) = date {
+        let parsed = chrono::NaiveDate::parse_from_str(d, format)
+            .with_context(|| format!("date {:?} doesn't match {:?}", d, format))?;
+        *d = parsed.format("%Y-%m-%d").to_string();
+    }
+    Ok(())
+}
+
+impl Profile {
+    fn normalize(&self, t: &mut TransactionSchema) -> anyhow::Result<()> {
+        if let Some(format) = &self.date_format {
+            normalize_date(&mut t.booking_date, format)?;
+            normalize_date(&mut t.value_date, format)?;
+        }
+        if self.swap_parties {
+            std::mem::swap(&mut t.debtor_name, &mut t.creditor_name);
+            std::mem::swap(&mut t.debtor_account, &mut t.creditor_account);
+            std::mem::swap(&mut t.ultimate_debtor, &mut t.ultimate_creditor);
+        }
+        if let Some(field) = self.narration_field {
+            if let Some(narration) = field.get(t).cloned() {
+                t.remittance_information_unstructured_array = Some(vec![narration]);
+            }
+        }
+        Ok(())
+    }
+
+    /// Normalizes the booked and pending transactions.
+    pub fn apply(&self, transactions: &mut BankTransaction) -> anyhow::Result<()> {
+        for t in &mut transactions.booked {
+            self.normalize(t)?;
+        }
+        for t in transactions.pending.iter_mut().flatten() {
+            self.normalize(t)?;
+        }
+        if self.drop_booked_pending {
+            let booked = &transactions.booked;
+            if let Some(pending) = &mut transactions.pending {
+                pending.retain(|p| {
+                    !booked.iter().any(|b| {
+                        b.transaction_amount == p.transaction_amount && narration(b) == narration(p)
+                    })
+                });
+            }
+        }
+        Ok(())
+    }
+}
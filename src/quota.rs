@@ -0,0 +1,327 @@
+//! Daily quota of the account API calls. GoCardless allows only a few calls a day to each of the
+//! account endpoints (transactions, balances, details), so the calls made by the previous runs are
+//! counted in `~/.gocardless/quota.json`, together with the remaining calls reported by the
+//! `HTTP_X_RATELIMIT_*` headers of the responses, and the import warns before it would exceed the
+//! quota.
+
+use crate::{base_config_dir, recording, settings};
+use anyhow::Context;
+use chrono::{DateTime, NaiveDate, Utc};
+use gocardless::apis::{configuration::Configuration, Error, ResponseContent};
+use reqwest::header::HeaderMap;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+};
+use tracing::warn;
+
+/// Calls a day to each account endpoint, unless configured otherwise (some banks allow fewer).
+pub const DEFAULT_DAILY_CALLS: u32 = 4;
+
+pub const TRANSACTIONS: &str = "transactions";
+pub const BALANCES: &str = "balances";
+pub const DETAILS: &str = "details";
+
+/// Serializes the updates of the state file.
+static LOCK: Mutex<()> = Mutex::new(());
+
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct State {
+    /// The (UTC) day of the counts, the quota is reset every day.
+    pub date: Option<NaiveDate>,
+    /// Account ID -> endpoint -> number of the calls made on the day.
+    #[serde(default)]
+    pub calls: BTreeMap<String, BTreeMap<String, u32>>,
+    /// Account ID -> endpoint -> number of the calls left on the day, as reported by the last
+    /// response.
+    #[serde(default)]
+    pub remaining: BTreeMap<String, BTreeMap<String, u32>>,
+    /// Account ID -> time when the API accepts the calls again, reported by the rate limited
+    /// responses.
+    #[serde(default)]
+    pub throttled_until: BTreeMap<String, DateTime<Utc>>,
+}
+
+fn path() -> anyhow::Result<std::path::PathBuf> {
+    Ok(base_config_dir()?.join("quota.json"))
+}
+
+impl State {
+    /// Reads the state, dropping the counts of the previous days.
+    pub fn load() -> anyhow::Result<State> {
+        let path = path()?;
+        let mut state: State = match std::fs::read_to_string(&path) {
+            Ok(content) => serde_json::from_str(&content)
+                .with_context(|| format!("failed to parse {}", path.display()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => State::default(),
+            Err(e) => return Err(e).with_context(|| format!("failed to read {}", path.display())),
+        };
+        let now = Utc::now();
+        if state.date != Some(now.date_naive()) {
+            state.date = Some(now.date_naive());
+            state.calls.clear();
+            state.remaining.clear();
+        }
+        state.throttled_until.retain(|_, until| *until > now);
+        Ok(state)
+    }
+
+    fn save(&self) -> anyhow::Result<()> {
+        let path = path()?;
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(&path, serde_json::to_string_pretty(self)?)
+            .with_context(|| format!("failed to write {}", path.display()))
+    }
+
+    pub fn used(&self, account_id: &str, endpoint: &str) -> u32 {
+        self.calls
+            .get(account_id)
+            .and_then(|c| c.get(endpoint))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    pub fn remaining(&self, account_id: &str, endpoint: &str) -> Option<u32> {
+        self.remaining
+            .get(account_id)
+            .and_then(|c| c.get(endpoint))
+            .copied()
+    }
+}
+
+fn update(f: impl FnOnce(&mut State)) {
+    if recording::is_replay() {
+        return;
+    }
+    let _lock = LOCK.lock().unwrap();
+    let res = State::load().and_then(|mut state| {
+        f(&mut state);
+        state.save()
+    });
+    if let Err(e) = res {
+        warn!("failed to update the API quota: {:#}", e);
+    }
+}
+
+/// Headers of the last response of an account endpoint, kept by [`get`] for [`counted`].
+#[derive(Clone, Default)]
+pub struct Headers(Arc<Mutex<Option<HeaderMap>>>);
+
+impl Headers {
+    /// The number in the rate limit header (e.g. `HTTP_X_RATELIMIT_ACCOUNT_SUCCESS_REMAINING`).
+    fn number(&self, name: &str) -> Option<i64> {
+        let headers = self.0.lock().unwrap();
+        headers
+            .as_ref()?
+            .get(name)?
+            .to_str()
+            .ok()?
+            .trim()
+            .parse()
+            .ok()
+    }
+
+    /// The calls to the endpoint of the account left on the day.
+    fn remaining(&self) -> Option<u32> {
+        self.number("HTTP_X_RATELIMIT_ACCOUNT_SUCCESS_REMAINING")
+            .and_then(|n| n.try_into().ok())
+    }
+
+    /// Seconds until the rate limit that rejected the call is reset: the one of the account, if
+    /// its calls are used up, otherwise the general one.
+    fn reset(&self) -> Option<i64> {
+        if self.remaining() == Some(0) {
+            self.number("HTTP_X_RATELIMIT_ACCOUNT_SUCCESS_RESET")
+        } else {
+            self.number("HTTP_X_RATELIMIT_RESET")
+        }
+    }
+}
+
+/// Requests the endpoint of the account (`transactions`, `balances` or `details`) like the API
+/// client, but keeps the headers of the response with the rate limits.
+pub async fn get<T: DeserializeOwned>(
+    config: &Configuration,
+    account_id: &str,
+    endpoint: &str,
+    query: &[(&str, String)],
+    headers: &Headers,
+) -> Result<T, Error<serde_json::Value>> {
+    let url = format!(
+        "{}/api/v2/accounts/{}/{}/",
+        config.base_path, account_id, endpoint
+    );
+    let mut req = config.client.get(url).query(query);
+    if let Some(user_agent) = &config.user_agent {
+        req = req.header(reqwest::header::USER_AGENT, user_agent);
+    }
+    if let Some(token) = &config.bearer_access_token {
+        req = req.bearer_auth(token);
+    }
+    let res = req.send().await.map_err(Error::Reqwest)?;
+    *headers.0.lock().unwrap() = Some(res.headers().clone());
+    let status = res.status();
+    let content = res.text().await.map_err(Error::Reqwest)?;
+    if !status.is_success() {
+        return Err(Error::ResponseError(ResponseContent {
+            status,
+            entity: serde_json::from_str(&content).ok(),
+            content,
+        }));
+    }
+    serde_json::from_str(&content).map_err(Error::Serde)
+}
+
+/// Counts a successful call to the account endpoint, with the calls left reported by the API.
+pub fn record(account_id: &str, endpoint: &str, remaining: Option<u32>) {
+    update(|state| {
+        *state
+            .calls
+            .entry(account_id.to_string())
+            .or_default()
+            .entry(endpoint.to_string())
+            .or_default() += 1;
+        if let Some(remaining) = remaining {
+            state
+                .remaining
+                .entry(account_id.to_string())
+                .or_default()
+                .insert(endpoint.to_string(), remaining);
+        }
+    });
+}
+
+/// Records that the calls to the account were rejected because of the rate limit, until the
+/// reset given by the headers of the response (`reset`, in seconds) or the error message of the
+/// API ("expected available in N seconds"), otherwise for the rest of the day.
+pub fn record_throttled(account_id: &str, error: &anyhow::Error, reset: Option<i64>) {
+    let message = format!("{:#}", error);
+    let seconds = reset.or_else(|| {
+        regex::Regex::new(r"(?i)available in (\d+) seconds")
+            .unwrap()
+            .captures(&message)
+            .and_then(|c| c[1].parse::<i64>().ok())
+    });
+    let now = Utc::now();
+    let until = match seconds {
+        Some(seconds) => now + chrono::Duration::seconds(seconds),
+        None => (now.date_naive() + chrono::Duration::days(1))
+            .and_hms_opt(0, 0, 0)
+            .unwrap()
+            .and_utc(),
+    };
+    update(|state| {
+        state.throttled_until.insert(account_id.to_string(), until);
+    });
+}
+
+/// Counts the call to the account endpoint made by `call` (which sends the request with [`get`]),
+/// or the rejection because of the rate limit, reading the limits from the response headers.
+pub async fn counted<T, Fut>(
+    account_id: &str,
+    endpoint: &str,
+    call: impl FnOnce(Headers) -> Fut,
+) -> anyhow::Result<T>
+where
+    Fut: std::future::Future<Output = anyhow::Result<T>>,
+{
+    let headers = Headers::default();
+    let res = call(headers.clone()).await;
+    match &res {
+        Ok(_) => record(account_id, endpoint, headers.remaining()),
+        Err(e) if crate::failure::Failure::of(e) == Some(crate::failure::Failure::RateLimited) => {
+            record_throttled(account_id, e, headers.reset())
+        }
+        Err(_) => {}
+    }
+    res
+}
+
+/// An account the import is going to call, with the endpoints it needs.
+pub struct Planned<'a> {
+    pub account: &'a str,
+    pub account_id: &'a str,
+    pub endpoints: Vec<&'static str>,
+}
+
+/// Checks that the planned calls fit into today's quota. Each problem is logged, and unless
+/// forced, the import is aborted before any call is made.
+pub fn check(planned: &[Planned], force: bool) -> anyhow::Result<()> {
+    if recording::is_replay() {
+        return Ok(());
+    }
+    let state = State::load()?;
+    let limit = settings::get()
+        .daily_account_calls
+        .unwrap_or(DEFAULT_DAILY_CALLS);
+    let mut problems = 0;
+    for p in planned {
+        if let Some(until) = state.throttled_until.get(p.account_id) {
+            warn!(account = p.account, %until, "the API rejects the calls to the account until later");
+            problems += 1;
+            continue;
+        }
+        for endpoint in &p.endpoints {
+            let used = state.used(p.account_id, endpoint);
+            // The calls left reported by the API take precedence over the counted ones.
+            let remaining = state.remaining(p.account_id, endpoint);
+            if remaining.map_or(used >= limit, |r| r == 0) {
+                warn!(
+                    account = p.account,
+                    endpoint,
+                    used,
+                    limit,
+                    ?remaining,
+                    "the daily API quota of the account is used up"
+                );
+                problems += 1;
+            }
+        }
+    }
+    if problems > 0 && !force {
+        return Err(anyhow::format_err!(
+            "the import would exceed the daily API quota of some accounts, run it with --force \
+             to try anyway, or with --offline"
+        )
+        .context(crate::failure::Failure::RateLimited));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&'static str, &str)]) -> Headers {
+        let mut map = HeaderMap::new();
+        for (name, value) in pairs {
+            map.insert(*name, value.parse().unwrap());
+        }
+        Headers(Arc::new(Mutex::new(Some(map))))
+    }
+
+    #[test]
+    fn rate_limit_headers() {
+        let h = headers(&[
+            ("http_x_ratelimit_reset", "60"),
+            ("http_x_ratelimit_account_success_remaining", "2"),
+            ("http_x_ratelimit_account_success_reset", "86000"),
+        ]);
+        assert_eq!(h.remaining(), Some(2));
+        assert_eq!(h.reset(), Some(60));
+
+        let h = headers(&[
+            ("http_x_ratelimit_reset", "60"),
+            ("http_x_ratelimit_account_success_remaining", "0"),
+            ("http_x_ratelimit_account_success_reset", "86000"),
+        ]);
+        assert_eq!(h.remaining(), Some(0));
+        assert_eq!(h.reset(), Some(86000));
+
+        assert_eq!(Headers::default().remaining(), None);
+        assert_eq!(Headers::default().reset(), None);
+    }
+}
@@ -0,0 +1,69 @@
+use anyhow::Context;
+use serde::{de::DeserializeOwned, Serialize};
+use std::{future::Future, path::PathBuf, sync::OnceLock};
+
+/// What happens with the API responses.
+#[derive(Debug)]
+pub enum Mode {
+    /// The API is called as usual.
+    Live,
+    /// The API is called and the responses are saved in the directory.
+    Record(PathBuf),
+    /// The API is not called, the responses are read from the directory.
+    Replay(PathBuf),
+}
+
+static MODE: OnceLock<Mode> = OnceLock::new();
+
+pub fn set_mode(mode: Mode) {
+    MODE.set(mode).expect("recording mode is already set");
+}
+
+fn mode() -> &'static Mode {
+    MODE.get_or_init(|| Mode::Live)
+}
+
+pub fn is_replay() -> bool {
+    matches!(mode(), Mode::Replay(_))
+}
+
+fn fixture_path(dir: &std::path::Path, request: &str, id: &str) -> PathBuf {
+    dir.join(request).join(format!("{}.json", id))
+}
+
+/// Saves the response of the API request, in the layout expected by `--replay`.
+pub async fn save<T: Serialize>(
+    dir: &std::path::Path,
+    request: &str,
+    id: &str,
+    res: &T,
+) -> anyhow::Result<()> {
+    let path = fixture_path(dir, request, id);
+    tokio::fs::create_dir_all(path.parent().unwrap()).await?;
+    tokio::fs::write(&path, serde_json::to_string_pretty(res)?).await?;
+    Ok(())
+}
+
+/// Sends the API request (identified by its name and the requested resource), recording its
+/// response or replaying the recorded one, depending on the mode.
+pub async fn call<T, E, Fut>(request: &str, id: &str, send: impl Fn() -> Fut) -> anyhow::Result<T>
+where
+    T: Serialize + DeserializeOwned,
+    Fut: Future<Output = Result<T, gocardless::apis::Error<E>>>,
+{
+    match mode() {
+        Mode::Live => crate::timed(request, send).await,
+        Mode::Record(dir) => {
+            let res = crate::timed(request, send).await?;
+            save(dir, request, id, &res).await?;
+            Ok(res)
+        }
+        Mode::Replay(dir) => {
+            let path = fixture_path(dir, request, id);
+            let content = tokio::fs::read_to_string(&path)
+                .await
+                .with_context(|| format!("no recorded response in {}", path.display()))?;
+            Ok(serde_json::from_str(&content)?)
+        }
+    }
+}
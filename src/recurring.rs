@@ -0,0 +1,181 @@
+use crate::{mapper::TransactionMapper, posting_amount};
+use beanru::types::{Account, Directive, DirectiveContent, MetadataValue};
+use chrono::NaiveDate;
+use rust_decimal::Decimal;
+
+pub const SUBSCRIPTION_TAG: &str = "subscription";
+
+/// Minimum number of payments (including the new one) to consider them recurring.
+const MIN_OCCURRENCES: usize = 3;
+/// Number of most recent payments used to detect the interval.
+const MAX_OCCURRENCES: usize = 6;
+
+/// (name, minimum days, maximum days) between two consecutive payments.
+const PERIODS: [(&str, i64, i64); 4] = [
+    ("weekly", 6, 8),
+    ("monthly", 27, 33),
+    ("quarterly", 85, 97),
+    ("yearly", 355, 375),
+];
+
+/// Amounts are considered similar if they differ by at most 10%.
+fn is_similar(a: Decimal, b: Decimal) -> bool {
+    (a - b).abs() * Decimal::TEN <= a.abs().max(b.abs())
+}
+
+fn period(dates: &[NaiveDate]) -> Option<&'static str> {
+    let intervals: Vec<i64> = dates.windows(2).map(|w| (w[1] - w[0]).num_days()).collect();
+    PERIODS
+        .iter()
+        .find(|(_, min, max)| intervals.iter().all(|i| min <= i && i <= max))
+        .map(|(name, _, _)| *name)
+}
+
+/// A payment that can be part of a recurring series: (creditor, date, amount).
+fn payment(
+    d: &Directive<Decimal>,
+    account: &Account,
+    mapper: &TransactionMapper,
+) -> Option<(String, NaiveDate, Decimal)> {
+    let amount = posting_amount(d, account)?;
+    if !amount.value.is_sign_negative() {
+        return None;
+    }
+    let creditor = mapper.metadata_str(d, "to_name")?.to_lowercase();
+    Some((creditor, d.date, amount.value))
+}
+
+/// Tags the new payments that continue a regular series of payments to the same creditor with
+/// similar amounts, and records the detected interval in the `recurrence` metadata.
+pub fn tag_recurring(
+    existing: &[Directive<Decimal>],
+    new: &mut [Directive<Decimal>],
+    account: &Account,
+    mapper: &TransactionMapper,
+) {
+    let mut history: Vec<(String, NaiveDate, Decimal)> = existing
+        .iter()
+        .filter_map(|d| payment(d, account, mapper))
+        .collect();
+    for d in new.iter_mut() {
+        let Some(current) = payment(d, account, mapper) else {
+            continue;
+        };
+        let mut dates: Vec<NaiveDate> = history
+            .iter()
+            .filter(|(creditor, date, value)| {
+                *creditor == current.0 && *date < current.1 && is_similar(*value, current.2)
+            })
+            .map(|(_, date, _)| *date)
+            .collect();
+        dates.sort();
+        dates.dedup();
+        dates.push(current.1);
+        let dates = &dates[dates.len().saturating_sub(MAX_OCCURRENCES)..];
+        history.push(current);
+        if dates.len() < MIN_OCCURRENCES {
+            continue;
+        }
+        let Some(period) = period(dates) else {
+            continue;
+        };
+        if let DirectiveContent::Transaction(t) = &mut d.content {
+            t.tags.insert(SUBSCRIPTION_TAG.to_string());
+        }
+        d.metadata.insert(
+            "recurrence".into(),
+            MetadataValue::String(period.to_string()),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        metadata_str,
+        test_util::{bank, directive, transaction},
+    };
+
+    fn payment(date: &str, amount: &str, creditor: &str) -> Directive<Decimal> {
+        let id = format!("{}-{}", creditor, date);
+        directive(
+            &id,
+            date,
+            amount,
+            serde_json::json!({ "creditorName": creditor }),
+        )
+    }
+
+    /// The recurrence detected for the new payment, if any.
+    fn recurrence(existing: &[Directive<Decimal>], new: Directive<Decimal>) -> Option<String> {
+        let mut new = [new];
+        tag_recurring(existing, &mut new, &bank(), &TransactionMapper::default());
+        let tagged = new[0]
+            .content
+            .transaction_opt()
+            .is_some_and(|t| t.tags.contains(SUBSCRIPTION_TAG));
+        let recurrence = metadata_str(&new[0], "recurrence").map(|r| r.to_string());
+        assert_eq!(tagged, recurrence.is_some());
+        recurrence
+    }
+
+    #[test]
+    fn tags_a_monthly_subscription() {
+        let existing = [
+            payment("2024-01-15", "-9.99", "Streaming Co"),
+            payment("2024-02-14", "-9.99", "Streaming Co"),
+        ];
+        // The price went up a little.
+        let new = payment("2024-03-15", "-10.49", "STREAMING CO");
+        assert_eq!(recurrence(&existing, new).as_deref(), Some("monthly"));
+    }
+
+    #[test]
+    fn ignores_irregular_payments() {
+        let existing = [
+            payment("2024-01-15", "-9.99", "Streaming Co"),
+            payment("2024-02-14", "-9.99", "Streaming Co"),
+        ];
+        // Too few payments.
+        assert_eq!(
+            recurrence(
+                &existing[1..],
+                payment("2024-03-15", "-9.99", "Streaming Co")
+            ),
+            None
+        );
+        // Not at a regular interval.
+        assert_eq!(
+            recurrence(&existing, payment("2024-04-20", "-9.99", "Streaming Co")),
+            None
+        );
+        // A different amount.
+        assert_eq!(
+            recurrence(&existing, payment("2024-03-15", "-19.99", "Streaming Co")),
+            None
+        );
+        // Another creditor.
+        assert_eq!(
+            recurrence(&existing, payment("2024-03-15", "-9.99", "Grocery")),
+            None
+        );
+    }
+
+    #[test]
+    fn reads_the_creditor_where_the_mapper_keeps_it() {
+        let mapper = TransactionMapper::builder()
+            .rename_metadata("to_name", "creditor")
+            .build();
+        let payment = |date: &str| {
+            let fields = serde_json::json!({ "creditorName": "Streaming Co" });
+            mapper
+                .map(&transaction(date, date, "-9.99", fields), &bank())
+                .unwrap()
+        };
+        let existing = [payment("2024-01-15"), payment("2024-02-14")];
+        let mut new = [payment("2024-03-15")];
+        tag_recurring(&existing, &mut new, &bank(), &mapper);
+        assert_eq!(metadata_str(&new[0], "recurrence"), Some("monthly"));
+    }
+}
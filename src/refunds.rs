@@ -0,0 +1,182 @@
+use crate::{mapper::TransactionMapper, posting_amount};
+use beanru::types::{Account, Directive, DirectiveContent};
+use chrono::Days;
+use rust_decimal::Decimal;
+
+const REFUND_LINK_PREFIX: &str = "refund-";
+
+fn is_linked(d: &Directive<Decimal>) -> bool {
+    let DirectiveContent::Transaction(t) = &d.content else {
+        return false;
+    };
+    t.links.iter().any(|l| l.starts_with(REFUND_LINK_PREFIX))
+}
+
+fn is_reversal(d: &Directive<Decimal>, mapper: &TransactionMapper) -> bool {
+    mapper
+        .metadata_str(d, "transaction_code")
+        .map(|c| {
+            let c = c.to_uppercase();
+            c.contains("REVERSAL") || c.contains("RRTN")
+        })
+        .unwrap_or(false)
+}
+
+/// The merchant or person on the other side of the transaction.
+fn counterparty(
+    d: &Directive<Decimal>,
+    value: Decimal,
+    mapper: &TransactionMapper,
+) -> Option<String> {
+    let key = if value.is_sign_negative() {
+        "to_name"
+    } else {
+        "from_name"
+    };
+    mapper
+        .metadata_str(d, key)
+        .or_else(|| mapper.raw_narration(d))
+        .map(|s| s.to_lowercase())
+}
+
+fn is_refund_of(
+    refund: &Directive<Decimal>,
+    original: &Directive<Decimal>,
+    account: &Account,
+    mapper: &TransactionMapper,
+    window: Days,
+) -> bool {
+    if is_linked(original) || original.date > refund.date {
+        return false;
+    }
+    if original.date.checked_add_days(window) < Some(refund.date) {
+        return false;
+    }
+    let (Some(r), Some(o)) = (
+        posting_amount(refund, account),
+        posting_amount(original, account),
+    ) else {
+        return false;
+    };
+    if r.currency != o.currency || r.value != -o.value || r.value.is_zero() {
+        return false;
+    }
+    if is_reversal(refund, mapper) {
+        return true;
+    }
+    // Transactions without a counterparty can't be told apart.
+    matches!(
+        (
+            counterparty(refund, r.value, mapper),
+            counterparty(original, o.value, mapper)
+        ),
+        (Some(r), Some(o)) if r == o
+    )
+}
+
+/// The internal transaction id of the transaction (where the mapper keeps it), or its date.
+pub(crate) fn link_id(d: &Directive<Decimal>, mapper: &TransactionMapper) -> String {
+    match mapper.transaction_id(d) {
+        Some(id) => id.to_string(),
+        None => d.date.format("%Y-%m-%d").to_string(),
+    }
+}
+
+fn refund_link(original: &Directive<Decimal>, mapper: &TransactionMapper) -> String {
+    format!("{}{}", REFUND_LINK_PREFIX, link_id(original, mapper))
+}
+
+fn add_link(d: &mut Directive<Decimal>, link: &str) {
+    if let DirectiveContent::Transaction(t) = &mut d.content {
+        t.links.insert(link.to_string());
+    }
+}
+
+/// Finds refunds and reversals among the new directives and links them with the original
+/// transaction, which can be either one of the new directives or already present in the file.
+///
+/// A transaction is considered a refund when it has the opposite amount of an earlier
+/// transaction (within the window) on the same account, with the same counterparty, or when the
+/// bank marked it as a reversal.
+pub fn link_refunds(
+    existing: &mut [Directive<Decimal>],
+    new: &mut [Directive<Decimal>],
+    account: &Account,
+    mapper: &TransactionMapper,
+    window: Days,
+) {
+    for i in 0..new.len() {
+        if is_linked(&new[i]) {
+            continue;
+        }
+        let (before, rest) = new.split_at_mut(i);
+        let refund = &mut rest[0];
+        let original = before
+            .iter_mut()
+            .rev()
+            .chain(existing.iter_mut().rev())
+            .find(|o| is_refund_of(refund, o, account, mapper, window));
+        if let Some(original) = original {
+            let link = refund_link(original, mapper);
+            add_link(original, &link);
+            add_link(refund, &link);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{self, bank};
+
+    fn directive(
+        id: &str,
+        date: &str,
+        amount: &str,
+        counterparty: Option<&str>,
+    ) -> Directive<Decimal> {
+        let mut fields = serde_json::json!({});
+        if let Some(name) = counterparty {
+            let key = if amount.starts_with('-') {
+                "creditorName"
+            } else {
+                "debtorName"
+            };
+            fields[key] = name.into();
+        }
+        test_util::directive(id, date, amount, fields)
+    }
+
+    fn link(existing: Directive<Decimal>, refund: Directive<Decimal>) -> bool {
+        let mut existing = [existing];
+        let mut new = [refund];
+        link_refunds(
+            &mut existing,
+            &mut new,
+            &bank(),
+            &TransactionMapper::default(),
+            Days::new(30),
+        );
+        is_linked(&existing[0]) && is_linked(&new[0])
+    }
+
+    #[test]
+    fn links_the_refund_of_the_counterparty() {
+        assert!(link(
+            directive("p1", "2024-03-01", "-20.00", Some("Shop")),
+            directive("r1", "2024-03-05", "20.00", Some("SHOP")),
+        ));
+        assert!(!link(
+            directive("p1", "2024-03-01", "-20.00", Some("Shop")),
+            directive("r1", "2024-03-05", "20.00", Some("Other shop")),
+        ));
+    }
+
+    #[test]
+    fn needs_a_counterparty() {
+        assert!(!link(
+            directive("p1", "2024-03-01", "-20.00", None),
+            directive("r1", "2024-03-05", "20.00", None),
+        ));
+    }
+}
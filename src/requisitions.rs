@@ -0,0 +1,220 @@
+//! Cache of the linked requisitions (`~/.gocardless/requisitions.json`): the accounts of each
+//! requisition with their IBAN and currency, and the expiry of its agreement. Both don't change
+//! once the requisition is linked, so they are fetched only for the new requisitions and the
+//! listing commands don't spend API calls on them every run.
+
+use crate::{agreement_expiry, base_config_dir, quota, recording, timed};
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use gocardless::{
+    apis::configuration::Configuration,
+    models::{AccountDetail, Requisition},
+};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use tracing::{debug, warn};
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CachedAccount {
+    pub id: String,
+    pub iban: Option<String>,
+    pub currency: Option<String>,
+    pub owner_name: Option<String>,
+}
+
+impl std::fmt::Display for CachedAccount {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.iban {
+            Some(iban) => write!(
+                f,
+                "{} ({} {})",
+                self.id,
+                iban,
+                self.currency.as_deref().unwrap_or_default()
+            ),
+            None => f.write_str(&self.id),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CachedRequisition {
+    pub institution_id: String,
+    pub agreement_expires: DateTime<Utc>,
+    pub accounts: Vec<CachedAccount>,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct Cache {
+    /// Requisition ID -> the requisition.
+    pub requisitions: BTreeMap<String, CachedRequisition>,
+}
+
+fn path() -> anyhow::Result<std::path::PathBuf> {
+    Ok(base_config_dir()?.join("requisitions.json"))
+}
+
+impl Cache {
+    /// Reads the cache (empty when replaying the recorded responses).
+    pub fn load() -> anyhow::Result<Cache> {
+        if recording::is_replay() {
+            return Ok(Cache::default());
+        }
+        let path = path()?;
+        match std::fs::read_to_string(&path) {
+            Ok(content) => serde_json::from_str(&content)
+                .with_context(|| format!("failed to parse {}", path.display())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Cache::default()),
+            Err(e) => Err(e).with_context(|| format!("failed to read {}", path.display())),
+        }
+    }
+
+    fn save(&self) -> anyhow::Result<()> {
+        if recording::is_replay() {
+            return Ok(());
+        }
+        let path = path()?;
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(&path, serde_json::to_string_pretty(self)?)
+            .with_context(|| format!("failed to write {}", path.display()))
+    }
+
+    /// The cached account with the ID.
+    pub fn account(&self, account_id: &str) -> Option<&CachedAccount> {
+        self.accounts().find(|a| a.id == account_id)
+    }
+
+    /// The cached accounts with the IBAN (ignoring spaces and case).
+    pub fn accounts_with_iban<'a>(
+        &'a self,
+        iban: &'a str,
+    ) -> impl Iterator<Item = &'a CachedAccount> + 'a {
+        let normalize = |iban: &str| iban.replace(' ', "").to_uppercase();
+        self.accounts()
+            .filter(move |a| a.iban.as_deref().map(normalize) == Some(normalize(iban)))
+    }
+
+    /// The institution of the cached account.
+    pub fn institution(&self, account_id: &str) -> Option<&str> {
+        self.requisitions
+            .values()
+            .find(|r| r.accounts.iter().any(|a| a.id == account_id))
+            .map(|r| r.institution_id.as_str())
+    }
+
+    /// The cached accounts that none of the account ids imports.
+    pub fn unimported<'a>(
+        &'a self,
+        imported: &'a HashSet<&str>,
+    ) -> impl Iterator<Item = &'a CachedAccount> + 'a {
+        self.accounts()
+            .filter(move |a| !imported.contains(a.id.as_str()))
+    }
+
+    fn accounts(&self) -> impl Iterator<Item = &CachedAccount> {
+        self.requisitions.values().flat_map(|r| &r.accounts)
+    }
+
+    /// When the agreement of the requisition expires, if it was accepted. Only the requisitions
+    /// that are not cached yet are looked up.
+    pub async fn agreement_expiry(
+        &self,
+        config: &Configuration,
+        requisition: &Requisition,
+    ) -> anyhow::Result<Option<DateTime<Utc>>> {
+        let id = requisition.id.map(|id| id.to_string()).unwrap_or_default();
+        if let Some(cached) = self.requisitions.get(&id) {
+            return Ok(Some(cached.agreement_expires));
+        }
+        match requisition.agreement {
+            Some(agreement) => agreement_expiry(config, &agreement.to_string()).await,
+            None => Ok(None),
+        }
+    }
+}
+
+/// Updates the cache with the listed requisitions: the requisitions that were deleted are
+/// forgotten, and the accounts and the agreement expiry of the newly linked ones are fetched.
+pub async fn refresh(config: &Configuration, requisitions: &[Requisition]) -> Cache {
+    let mut cache = match Cache::load() {
+        Ok(cache) => cache,
+        Err(e) => {
+            warn!("{:#}, discarding the cache", e);
+            Cache::default()
+        }
+    };
+    let listed: Vec<String> = requisitions
+        .iter()
+        .filter_map(|r| r.id.map(|id| id.to_string()))
+        .collect();
+    let mut changed = false;
+    cache.requisitions.retain(|id, _| {
+        let keep = listed.contains(id);
+        changed |= !keep;
+        keep
+    });
+    for r in requisitions {
+        let (Some(id), Some(agreement)) = (r.id, r.agreement) else {
+            continue;
+        };
+        let id = id.to_string();
+        let accounts: Vec<String> = r.accounts.iter().flatten().map(|a| a.to_string()).collect();
+        if cache.requisitions.contains_key(&id) || accounts.is_empty() {
+            continue;
+        }
+        match discover(config, &r.institution_id, &agreement.to_string(), accounts).await {
+            Ok(Some(cached)) => {
+                debug!(requisition = %id, "cached the requisition");
+                cache.requisitions.insert(id, cached);
+                changed = true;
+            }
+            Ok(None) => {}
+            Err(e) => warn!(requisition = %id, "failed to discover the accounts: {:#}", e),
+        }
+    }
+    if changed {
+        if let Err(e) = cache.save() {
+            warn!("failed to save the requisitions: {:#}", e);
+        }
+    }
+    cache
+}
+
+/// Fetches the agreement expiry and the accounts of the requisition (nothing while the agreement
+/// is not accepted).
+async fn discover(
+    config: &Configuration,
+    institution_id: &str,
+    agreement: &str,
+    accounts: Vec<String>,
+) -> anyhow::Result<Option<CachedRequisition>> {
+    let Some(agreement_expires) = agreement_expiry(config, agreement).await? else {
+        return Ok(None);
+    };
+    let mut cached = vec![];
+    for id in accounts {
+        let account_id = id.as_str();
+        let details: AccountDetail =
+            quota::counted(account_id, quota::DETAILS, |headers| async move {
+                timed("retrieve_account_details", || {
+                    quota::get(config, account_id, quota::DETAILS, &[], &headers)
+                })
+                .await
+            })
+            .await?;
+        let details = details.account;
+        cached.push(CachedAccount {
+            id,
+            iban: details.iban,
+            currency: details.currency,
+            owner_name: details.owner_name,
+        });
+    }
+    Ok(Some(CachedRequisition {
+        institution_id: institution_id.to_string(),
+        agreement_expires,
+        accounts: cached,
+    }))
+}
@@ -0,0 +1,638 @@
+//! The `review` command: a terminal interface to go through the transactions that need attention
+//! (flagged with `!` or without a counter posting), assign the counter accounts and accept them.
+
+use crate::{ledger_config, ledger_io, mapper::TransactionMapper, rules};
+use beanru::types::{Account, Directive, DirectiveContent, Ledger, MetadataValue};
+use fuzzy_matcher::{skim::SkimMatcherV2, FuzzyMatcher};
+use gocardless::models::{AccountSchema, TransactionSchema};
+use ratatui::{
+    crossterm::event::{self, Event, KeyCode, KeyEventKind},
+    layout::{Constraint, Layout, Rect},
+    style::{Color, Modifier, Style},
+    text::{Line, Span},
+    widgets::{Block, Borders, Clear, List, ListItem, ListState, Paragraph, Wrap},
+    DefaultTerminal, Frame,
+};
+use rust_decimal::Decimal;
+use std::{
+    collections::{BTreeSet, HashSet},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tracing::warn;
+
+#[derive(Clone, Debug)]
+enum Decision {
+    Pending,
+    Skip,
+    /// Accept the transaction, optionally with the counter account (or the suggested category).
+    Accept(Option<rules::Category>),
+}
+
+struct Item {
+    path: PathBuf,
+    index: usize,
+    directive: Directive<Decimal>,
+    /// Category suggested by the rules, with its source.
+    suggestion: Option<(rules::Category, rules::Source)>,
+    decision: Decision,
+}
+
+/// Fuzzy search of the counter account.
+struct Search {
+    query: String,
+    selected: usize,
+}
+
+struct App {
+    items: Vec<Item>,
+    /// The rules file, reloaded when it changes.
+    rules_watcher: Option<rules::Watcher>,
+    /// The mapper of the ledger, to read the imported transactions.
+    mapper: TransactionMapper,
+    accounts: Vec<String>,
+    list: ListState,
+    search: Option<Search>,
+    message: String,
+    /// Set after the first `q` when there are unsaved decisions.
+    confirm_quit: bool,
+}
+
+/// Whether the transaction needs to be reviewed: flagged with `!` or with only the bank posting.
+fn needs_review(d: &Directive<Decimal>) -> bool {
+    match &d.content {
+        DirectiveContent::Transaction(t) => t.flag == Some('!') || t.postings.len() == 1,
+        _ => false,
+    }
+}
+
+/// The account of the counterparty with the IBAN kept in the metadata.
+fn account_schema(
+    mapper: &TransactionMapper,
+    d: &Directive<Decimal>,
+    key: &'static str,
+) -> Option<Box<AccountSchema>> {
+    Some(Box::new(AccountSchema {
+        iban: Some(mapper.metadata_str(d, key)?.to_string()),
+        ..Default::default()
+    }))
+}
+
+/// Rebuilds the fields used by the rules from the imported transaction: the narration reported by
+/// the bank and the metadata, under the keys the mapper of the ledger writes them.
+fn rule_input(mapper: &TransactionMapper, d: &Directive<Decimal>) -> Option<TransactionSchema> {
+    let DirectiveContent::Transaction(t) = &d.content else {
+        return None;
+    };
+    let amount = t.postings.first()?.amount.clone()?;
+    let metadata = |key: &'static str| mapper.metadata_str(d, key).map(str::to_string);
+    let mut schema = TransactionSchema {
+        remittance_information_unstructured: mapper.raw_narration(d).map(str::to_string),
+        debtor_name: metadata("from_name"),
+        creditor_name: metadata("to_name"),
+        proprietary_bank_transaction_code: metadata("transaction_code"),
+        debtor_account: account_schema(mapper, d, "from_iban"),
+        creditor_account: account_schema(mapper, d, "to_iban"),
+        ..Default::default()
+    };
+    schema.transaction_amount.amount = amount.value.to_string();
+    schema.transaction_amount.currency = amount.currency.0;
+    Some(schema)
+}
+
+/// The category the rules suggest for the transaction, with its source.
+fn suggest(
+    rules: &rules::Rules,
+    mapper: &TransactionMapper,
+    d: &Directive<Decimal>,
+) -> Option<(rules::Category, rules::Source)> {
+    let t = rule_input(mapper, d)?;
+    rules
+        .categorize(&t)
+        .map(|c| (c.category.into_owned(), c.source))
+}
+
+fn accounts(ledger: &Ledger<Decimal>) -> Vec<String> {
+    let mut accounts = BTreeSet::new();
+    for f in ledger.files.values() {
+        for d in &f.directives {
+            match &d.content {
+                DirectiveContent::Open(o) => {
+                    accounts.insert(o.account.0.clone());
+                }
+                DirectiveContent::Transaction(t) => {
+                    accounts.extend(t.postings.iter().map(|p| p.account.0.clone()))
+                }
+                _ => {}
+            }
+        }
+    }
+    accounts.into_iter().collect()
+}
+
+/// Applies the decision to the directive in the ledger.
+fn apply(d: &mut Directive<Decimal>, category: Option<&rules::Category>) -> anyhow::Result<()> {
+    let DirectiveContent::Transaction(t) = &mut d.content else {
+        return Ok(());
+    };
+    t.flag = Some('*');
+    let Some(category) = category else {
+        return Ok(());
+    };
+    if t.postings.len() == 2
+        && category.postings.is_empty()
+        && category.share.is_none()
+        && category.salary.is_none()
+    {
+        if let Some(account) = &category.account {
+            t.postings[1].account = Account(account.clone());
+        }
+        if let Some(payee) = &category.payee {
+            t.payee = Some(payee.clone());
+        }
+        t.tags.extend(category.tags.iter().cloned());
+        for (key, value) in &category.metadata {
+            d.metadata
+                .insert(key.clone(), MetadataValue::String(value.clone()));
+        }
+        return Ok(());
+    }
+    if t.postings.len() > 1 {
+        anyhow::bail!("the transaction already has counter postings");
+    }
+    category.apply(d)
+}
+
+/// `review --apply-rules`: applies the current rules to the transactions still flagged with `!`,
+/// without the interface. The ones an explicit rule assigns the counter account to get it (with the
+/// payee, tags and metadata of the rule) and the `*` flag, so an improved rule also fixes the
+/// transactions imported before.
+pub async fn apply_rules(
+    beancount_path: &Path,
+    rules_path: Option<&Path>,
+    dry_run: bool,
+) -> anyhow::Result<()> {
+    let _lock = ledger_io::lock_ledger(beancount_path)?;
+    let (mut ledger, snapshot) = ledger_io::read_ledger(beancount_path.to_path_buf()).await?;
+    let rules_path = ledger_config::rules_path(rules_path, &ledger)?;
+    let rules = rules::Rules::load(rules_path.as_deref()).await?;
+    let mapper = ledger_config::LedgerConfig::read(&ledger)?.mapper();
+
+    let mut changed = HashSet::new();
+    let mut recategorized = 0;
+    for (path, file) in &mut ledger.files {
+        for d in &mut file.directives {
+            if !matches!(&d.content, DirectiveContent::Transaction(t) if t.flag == Some('!')) {
+                continue;
+            }
+            let Some(input) = rule_input(&mapper, d) else {
+                continue;
+            };
+            let Some(c) = rules.categorize(&input).filter(|c| c.is_complete()) else {
+                continue;
+            };
+            let mut updated = d.clone();
+            if let Err(e) = apply(&mut updated, Some(&c.category)) {
+                warn!("{}: {}: {:#}", path.display(), d.date, e);
+                continue;
+            }
+            let narration = updated
+                .content
+                .transaction_opt()
+                .and_then(|t| t.narration.clone())
+                .unwrap_or_default();
+            println!(
+                "{}: {} {:?} -> {}",
+                path.display(),
+                d.date,
+                narration,
+                c.category.account.as_deref().unwrap_or_default()
+            );
+            *d = updated;
+            changed.insert(path.clone());
+            recategorized += 1;
+        }
+    }
+    if recategorized == 0 {
+        println!("No flagged transactions matched the rules");
+        return Ok(());
+    }
+    if dry_run {
+        println!("{} transaction(s) would be recategorized", recategorized);
+        return Ok(());
+    }
+    snapshot.check_unmodified().await?;
+    let changed = &changed;
+    let snapshot = &snapshot;
+    ledger
+        .write(|p, content| async move {
+            let content = snapshot.restore(&p, content);
+            if changed.contains(&p) {
+                tokio::fs::write(p, content).await?;
+            }
+            Ok(())
+        })
+        .await?;
+    println!("Recategorized {} transaction(s)", recategorized);
+    Ok(())
+}
+
+pub async fn run(beancount_path: &Path, rules_path: Option<&Path>) -> anyhow::Result<()> {
+    let _lock = ledger_io::lock_ledger(beancount_path)?;
+    let (mut ledger, snapshot) = ledger_io::read_ledger(beancount_path.to_path_buf()).await?;
+    let rules_path = ledger_config::rules_path(rules_path, &ledger)?;
+    let rules = rules::Rules::load(rules_path.as_deref()).await?;
+    let mapper = ledger_config::LedgerConfig::read(&ledger)?.mapper();
+
+    let mut items = vec![];
+    for (path, file) in &ledger.files {
+        for (index, d) in file.directives.iter().enumerate() {
+            if !needs_review(d) {
+                continue;
+            }
+            items.push(Item {
+                path: path.clone(),
+                index,
+                directive: d.clone(),
+                suggestion: suggest(&rules, &mapper, d),
+                decision: Decision::Pending,
+            });
+        }
+    }
+    if items.is_empty() {
+        println!("No transactions to review");
+        return Ok(());
+    }
+    items.sort_by(|a, b| {
+        (a.directive.date, &a.path, a.index).cmp(&(b.directive.date, &b.path, b.index))
+    });
+
+    let mut app = App {
+        items,
+        rules_watcher: rules_path.map(|_| rules::Watcher::new(rules.files())),
+        mapper,
+        accounts: accounts(&ledger),
+        list: ListState::default().with_selected(Some(0)),
+        search: None,
+        message: String::new(),
+        confirm_quit: false,
+    };
+    let mut terminal = ratatui::init();
+    let save = app.run(&mut terminal);
+    ratatui::restore();
+    if !save? {
+        println!("The ledger was not modified");
+        return Ok(());
+    }
+
+    let mut changed = HashSet::new();
+    let mut accepted = 0;
+    for item in &app.items {
+        let Decision::Accept(category) = &item.decision else {
+            continue;
+        };
+        let d = &mut ledger.files.get_mut(&item.path).unwrap().directives[item.index];
+        // Validated when accepted, but a failure mustn't discard the other decisions.
+        if let Err(e) = apply(d, category.as_ref()) {
+            warn!("{}: {}: {:#}", item.path.display(), d.date, e);
+            continue;
+        }
+        changed.insert(item.path.clone());
+        accepted += 1;
+    }
+    if accepted == 0 {
+        println!("No transactions accepted, the ledger was not modified");
+        return Ok(());
+    }
+    snapshot.check_unmodified().await?;
+    let changed = &changed;
+    let snapshot = &snapshot;
+    ledger
+        .write(|p, content| async move {
+            let content = snapshot.restore(&p, content);
+            if changed.contains(&p) {
+                tokio::fs::write(p, content).await?;
+            }
+            Ok(())
+        })
+        .await?;
+    println!("Accepted {} transaction(s)", accepted);
+    Ok(())
+}
+
+/// How often the rules file is checked for changes while waiting for a key.
+const RULES_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+impl App {
+    /// Reloads the rules if the file changed and updates the suggestions.
+    fn reload_rules(&mut self) {
+        let Some(watcher) = &mut self.rules_watcher else {
+            return;
+        };
+        if !watcher.changed() {
+            return;
+        }
+        match rules::Rules::load_blocking(Some(watcher.path())) {
+            Ok(rules) => {
+                // The rules can include other files now.
+                *watcher = rules::Watcher::new(rules.files());
+                for item in &mut self.items {
+                    item.suggestion = suggest(&rules, &self.mapper, &item.directive);
+                }
+                let suggestions = self.items.iter().filter(|i| i.suggestion.is_some()).count();
+                self.message = format!(
+                    "Reloaded the rules, {} transaction(s) have a suggestion",
+                    suggestions
+                );
+            }
+            Err(e) => self.message = format!("Failed to reload the rules: {:#}", e),
+        }
+    }
+
+    /// Runs the interface until the user saves (true) or quits (false).
+    fn run(&mut self, terminal: &mut DefaultTerminal) -> anyhow::Result<bool> {
+        loop {
+            terminal.draw(|f| self.draw(f))?;
+            if !event::poll(RULES_POLL_INTERVAL)? {
+                self.reload_rules();
+                continue;
+            }
+            let Event::Key(key) = event::read()? else {
+                continue;
+            };
+            if key.kind != KeyEventKind::Press {
+                continue;
+            }
+            if self.search.is_some() {
+                self.search_key(key.code);
+                continue;
+            }
+            let quit = matches!(key.code, KeyCode::Char('q') | KeyCode::Esc);
+            if !quit {
+                self.confirm_quit = false;
+            }
+            let selected = self.list.selected().unwrap_or(0);
+            match key.code {
+                KeyCode::Down | KeyCode::Char('j') => self.list.select_next(),
+                KeyCode::Up | KeyCode::Char('k') => self.list.select_previous(),
+                KeyCode::Enter | KeyCode::Char('a') => {
+                    let item = &mut self.items[selected];
+                    item.decision = match &item.decision {
+                        Decision::Accept(category) => Decision::Accept(category.clone()),
+                        _ => Decision::Accept(None),
+                    };
+                    self.list.select_next();
+                }
+                KeyCode::Tab => match &self.items[selected].suggestion {
+                    Some((category, _)) => {
+                        let category = category.clone();
+                        if self.accept(selected, Some(category)) {
+                            self.list.select_next();
+                        }
+                    }
+                    None => self.message = "No suggestion for this transaction".into(),
+                },
+                KeyCode::Char('s') => {
+                    self.items[selected].decision = Decision::Skip;
+                    self.list.select_next();
+                }
+                KeyCode::Char('u') => self.items[selected].decision = Decision::Pending,
+                KeyCode::Char('e') | KeyCode::Char('/') => {
+                    self.search = Some(Search {
+                        query: String::new(),
+                        selected: 0,
+                    })
+                }
+                KeyCode::Char('w') => return Ok(true),
+                KeyCode::Char('q') | KeyCode::Esc => {
+                    let unsaved = self
+                        .items
+                        .iter()
+                        .any(|i| matches!(i.decision, Decision::Accept(_)));
+                    if !unsaved || self.confirm_quit {
+                        return Ok(false);
+                    }
+                    self.confirm_quit = true;
+                    self.message = "Unsaved decisions, press q again to quit without saving, \
+                                    w to save"
+                        .into();
+                }
+                _ => {}
+            }
+            if let Some(i) = self.list.selected() {
+                self.list.select(Some(i.min(self.items.len() - 1)));
+            }
+        }
+    }
+
+    /// Accepts the item with the category, if it can be applied to the transaction.
+    fn accept(&mut self, i: usize, category: Option<rules::Category>) -> bool {
+        let item = &mut self.items[i];
+        if let Err(e) = apply(&mut item.directive.clone(), category.as_ref()) {
+            self.message = format!("Can't accept the transaction: {:#}", e);
+            return false;
+        }
+        item.decision = Decision::Accept(category);
+        true
+    }
+
+    /// The accounts matching the query, best matches first.
+    fn matches(&self, query: &str) -> Vec<&str> {
+        let matcher = SkimMatcherV2::default();
+        let mut matches: Vec<(i64, &str)> = self
+            .accounts
+            .iter()
+            .filter_map(|a| Some((matcher.fuzzy_match(a, query)?, a.as_str())))
+            .collect();
+        matches.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(b.1)));
+        matches.into_iter().map(|(_, a)| a).collect()
+    }
+
+    fn search_key(&mut self, code: KeyCode) {
+        let Some(search) = &mut self.search else {
+            return;
+        };
+        match code {
+            KeyCode::Esc => self.search = None,
+            KeyCode::Char(c) => {
+                search.query.push(c);
+                search.selected = 0;
+            }
+            KeyCode::Backspace => {
+                search.query.pop();
+                search.selected = 0;
+            }
+            KeyCode::Down => search.selected += 1,
+            KeyCode::Up => search.selected = search.selected.saturating_sub(1),
+            KeyCode::Enter => {
+                let query = search.query.clone();
+                let selected = search.selected;
+                let matches = self.matches(&query);
+                // A new account can be entered in full.
+                let account = match matches.get(selected.min(matches.len().saturating_sub(1))) {
+                    Some(a) => a.to_string(),
+                    None if query.contains(':') => query,
+                    None => return,
+                };
+                let i = self.list.selected().unwrap_or(0);
+                self.search = None;
+                let category = rules::Category {
+                    account: Some(account),
+                    ..Default::default()
+                };
+                if !self.accept(i, Some(category)) {
+                    return;
+                }
+                self.list.select_next();
+                if let Some(i) = self.list.selected() {
+                    self.list.select(Some(i.min(self.items.len() - 1)));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn draw(&mut self, f: &mut Frame) {
+        let [list_area, detail_area, help_area] = Layout::vertical([
+            Constraint::Percentage(60),
+            Constraint::Fill(1),
+            Constraint::Length(2),
+        ])
+        .areas(f.area());
+
+        let rows: Vec<ListItem> = self
+            .items
+            .iter()
+            .map(|item| {
+                let (mark, style) = match &item.decision {
+                    Decision::Pending => (" ", Style::default()),
+                    Decision::Skip => ("-", Style::default().fg(Color::DarkGray)),
+                    Decision::Accept(_) => ("✓", Style::default().fg(Color::Green)),
+                };
+                let DirectiveContent::Transaction(t) = &item.directive.content else {
+                    return ListItem::new("");
+                };
+                let amount = t
+                    .postings
+                    .first()
+                    .and_then(|p| p.amount.as_ref())
+                    .map(|a| format!("{} {}", a.value, a.currency))
+                    .unwrap_or_default();
+                let counter = match &item.decision {
+                    Decision::Accept(Some(c)) => c.account.clone().unwrap_or_default(),
+                    _ => t
+                        .postings
+                        .get(1)
+                        .map(|p| p.account.0.clone())
+                        .unwrap_or_default(),
+                };
+                ListItem::new(Line::from(vec![
+                    Span::raw(format!("{} {} ", mark, item.directive.date)),
+                    Span::styled(
+                        format!("{:>14} ", amount),
+                        Style::default().add_modifier(Modifier::BOLD),
+                    ),
+                    Span::raw(format!("{:<40} ", t.narration.clone().unwrap_or_default())),
+                    Span::styled(counter, Style::default().fg(Color::Cyan)),
+                ]))
+                .style(style)
+            })
+            .collect();
+        let done = self
+            .items
+            .iter()
+            .filter(|i| !matches!(i.decision, Decision::Pending))
+            .count();
+        let list = List::new(rows)
+            .block(Block::default().borders(Borders::ALL).title(format!(
+                " Review ({}/{}) ",
+                done,
+                self.items.len()
+            )))
+            .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+        f.render_stateful_widget(list, list_area, &mut self.list);
+
+        let selected = &self.items[self.list.selected().unwrap_or(0)];
+        let mut detail = vec![Line::from(format!("{}", selected.path.display()))];
+        detail.extend(
+            selected
+                .directive
+                .to_string()
+                .lines()
+                .map(|l| Line::from(l.to_string())),
+        );
+        detail.push(Line::from(""));
+        detail.push(match &selected.suggestion {
+            Some((category, source)) => Line::from(vec![
+                Span::raw("Suggestion (Tab): "),
+                Span::styled(
+                    category.account.clone().unwrap_or_default(),
+                    Style::default().fg(Color::Cyan),
+                ),
+                Span::raw(format!(" from {:?}", source)),
+            ]),
+            None => Line::from("No rule matches"),
+        });
+        f.render_widget(
+            Paragraph::new(detail)
+                .wrap(Wrap { trim: false })
+                .block(Block::default().borders(Borders::ALL)),
+            detail_area,
+        );
+
+        let help = if self.message.is_empty() {
+            "enter/a accept  tab accept suggestion  e or / choose account  s skip  u undo  \
+             w save  q quit"
+                .to_string()
+        } else {
+            std::mem::take(&mut self.message)
+        };
+        f.render_widget(Paragraph::new(help).wrap(Wrap { trim: true }), help_area);
+
+        if let Some(search) = &self.search {
+            let area = popup(f.area());
+            let matches = self.matches(&search.query);
+            let selected = search.selected.min(matches.len().saturating_sub(1));
+            let mut lines = vec![Line::from(format!("> {}", search.query)), Line::from("")];
+            lines.extend(
+                matches
+                    .iter()
+                    .enumerate()
+                    .take(area.height as usize)
+                    .map(|(i, a)| {
+                        let style = if i == selected {
+                            Style::default().add_modifier(Modifier::REVERSED)
+                        } else {
+                            Style::default()
+                        };
+                        Line::styled(a.to_string(), style)
+                    }),
+            );
+            f.render_widget(Clear, area);
+            f.render_widget(
+                Paragraph::new(lines).block(
+                    Block::default()
+                        .borders(Borders::ALL)
+                        .title(" Counter account (esc to cancel) "),
+                ),
+                area,
+            );
+        }
+    }
+}
+
+fn popup(area: Rect) -> Rect {
+    let [_, area, _] = Layout::vertical([
+        Constraint::Percentage(20),
+        Constraint::Percentage(60),
+        Constraint::Percentage(20),
+    ])
+    .areas(area);
+    let [_, area, _] = Layout::horizontal([
+        Constraint::Percentage(20),
+        Constraint::Percentage(60),
+        Constraint::Percentage(20),
+    ])
+    .areas(area);
+    area
+}
@@ -0,0 +1,352 @@
+//! Pairing of the round-up transfers some banks make for every card purchase (e.g. 0.37 EUR to a
+//! savings vault for a purchase of 4.63 EUR) with the purchase. It is configured with the
+//! `round_ups` metadata of the account: `link` gives both transactions a shared `^roundup-<id>`
+//! link, `merge` moves the postings of the round-up into the purchase, keeping the id of the
+//! round-up in the `roundup_id` metadata.
+
+use crate::{dedup::DedupStrategy, metadata_str, posting_amount, refunds};
+use crate::{mapper::TransactionMapper, source::SourceAccount};
+use anyhow::Context;
+use beanru::types::{Account, Amount, Directive, DirectiveContent, MetadataValue};
+use regex::Regex;
+use rust_decimal::Decimal;
+use std::collections::HashSet;
+
+/// Narration of the round-up transfers, unless configured with the `round_up_pattern` metadata.
+pub const DEFAULT_PATTERN: &str = "(?i)round[- ]?up";
+
+const LINK_PREFIX: &str = "roundup-";
+
+/// Metadata key of the purchase with the id of the round-up merged into it.
+pub const ROUND_UP_ID_KEY: &str = "roundup_id";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    Link,
+    Merge,
+}
+
+pub struct Config {
+    mode: Mode,
+    pattern: Regex,
+}
+
+impl Config {
+    /// The configuration of the account, if it pairs the round-ups.
+    pub fn of(account: &SourceAccount) -> anyhow::Result<Option<Config>> {
+        let mode = match account.metadata_str("round_ups") {
+            None => return Ok(None),
+            Some("link") => Mode::Link,
+            Some("merge") => Mode::Merge,
+            Some(mode) => anyhow::bail!(
+                "{}: unknown round_ups {:?}, use link or merge",
+                account.account,
+                mode
+            ),
+        };
+        let pattern = account
+            .metadata_str("round_up_pattern")
+            .unwrap_or(DEFAULT_PATTERN);
+        let pattern = Regex::new(pattern)
+            .with_context(|| format!("{}: invalid round_up_pattern", account.account))?;
+        Ok(Some(Config { mode, pattern }))
+    }
+
+    fn is_round_up(&self, d: &Directive<Decimal>) -> bool {
+        d.content
+            .transaction_opt()
+            .and_then(|t| t.narration.as_deref())
+            .is_some_and(|n| self.pattern.is_match(n))
+    }
+
+    /// The amount of the round-up transfer: a payment of less than one unit of the currency.
+    fn round_up(&self, d: &Directive<Decimal>, account: &Account) -> Option<Amount<Decimal>> {
+        if !self.is_round_up(d) || is_linked(d) {
+            return None;
+        }
+        let a = posting_amount(d, account)?;
+        (a.value.is_sign_negative() && !a.value.is_zero() && a.value > -Decimal::ONE).then_some(a)
+    }
+
+    /// How many days the purchase is apart from the round-up, if the round-up rounds it up to a
+    /// whole amount.
+    fn distance(
+        &self,
+        purchase: &Directive<Decimal>,
+        round_up: &Directive<Decimal>,
+        amount: &Amount<Decimal>,
+        account: &Account,
+    ) -> Option<i64> {
+        if self.is_round_up(purchase) || is_linked(purchase) {
+            return None;
+        }
+        let p = posting_amount(purchase, account)?;
+        let days = (round_up.date - purchase.date).num_days().abs();
+        (p.currency == amount.currency
+            && p.value.is_sign_negative()
+            && !p.value.fract().is_zero()
+            && (p.value + amount.value).fract().is_zero()
+            && days <= 1)
+            .then_some(days)
+    }
+}
+
+fn is_linked(d: &Directive<Decimal>) -> bool {
+    d.content
+        .transaction_opt()
+        .is_some_and(|t| t.links.iter().any(|l| l.starts_with(LINK_PREFIX)))
+}
+
+fn add_link(d: &mut Directive<Decimal>, link: &str) {
+    if let DirectiveContent::Transaction(t) = &mut d.content {
+        t.links.insert(link.to_string());
+    }
+}
+
+/// Moves the postings of the round-up into the purchase. The counter posting of the round-up (e.g.
+/// to the savings account) gets the amount, so it doesn't compete with the one of the purchase.
+fn merge(
+    purchase: &mut Directive<Decimal>,
+    round_up: Directive<Decimal>,
+    amount: Amount<Decimal>,
+    mapper: &TransactionMapper,
+) {
+    // Keeps the id of the round-up apart from the one of the purchase, so the round-up is
+    // recognized as imported and the purchase keeps a single id.
+    let id = mapper.transaction_id(&round_up).map(String::from);
+    let id_link = id
+        .as_ref()
+        .map(|id| format!("{}{}", mapper.link_prefix(), id));
+    let (DirectiveContent::Transaction(p), DirectiveContent::Transaction(r)) =
+        (&mut purchase.content, round_up.content)
+    else {
+        return;
+    };
+    p.links
+        .extend(r.links.into_iter().filter(|l| id_link.as_ref() != Some(l)));
+    if let Some(id) = id {
+        purchase
+            .metadata
+            .insert(ROUND_UP_ID_KEY.to_string(), MetadataValue::String(id));
+    }
+    for mut posting in r.postings {
+        if posting.amount.is_none() {
+            posting.amount = Some(Amount {
+                value: -amount.value,
+                currency: amount.currency.clone(),
+            });
+        }
+        p.postings.push(posting);
+    }
+}
+
+/// Adds the transaction of the ledger to the dedup strategy, with the round-up merged into it.
+pub fn add_to_dedup(
+    dedup: &mut dyn DedupStrategy,
+    mapper: &TransactionMapper,
+    d: &Directive<Decimal>,
+) {
+    dedup.add(d);
+    if let Some(id) = metadata_str(d, ROUND_UP_ID_KEY) {
+        dedup.add(&mapper.id_only(d.date, id));
+    }
+}
+
+/// Pairs the new round-ups with the purchase they round up, among the new transactions or the ones
+/// already in the file. The purchase has to be booked at most a day apart, in the same currency,
+/// and the two amounts have to add up to a whole amount.
+pub fn pair(
+    existing: &mut [Directive<Decimal>],
+    new: &mut Vec<Directive<Decimal>>,
+    account: &Account,
+    mapper: &TransactionMapper,
+    config: &Config,
+) {
+    let mut merged = HashSet::new();
+    for i in 0..new.len() {
+        let Some(amount) = config.round_up(&new[i], account) else {
+            continue;
+        };
+        let round_up = new[i].clone();
+        // The closest purchase, the earlier one of the same distance.
+        let purchase = new
+            .iter()
+            .enumerate()
+            .filter_map(|(j, p)| Some((config.distance(p, &round_up, &amount, account)?, j)))
+            .min();
+        let purchase = match purchase {
+            Some((_, j)) => &mut new[j],
+            None => match existing
+                .iter_mut()
+                .rev()
+                .find(|p| config.distance(p, &round_up, &amount, account).is_some())
+            {
+                Some(p) => p,
+                None => continue,
+            },
+        };
+        let link = format!("{}{}", LINK_PREFIX, refunds::link_id(purchase, mapper));
+        add_link(purchase, &link);
+        match config.mode {
+            Mode::Link => add_link(&mut new[i], &link),
+            Mode::Merge => {
+                merge(purchase, round_up, amount, mapper);
+                merged.insert(i);
+            }
+        }
+    }
+    let mut i = 0;
+    new.retain(|_| {
+        i += 1;
+        !merged.contains(&(i - 1))
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{bank, directive, transaction};
+    use crate::{dedup, mutations};
+    use std::collections::HashMap;
+
+    fn config(metadata: &[(&str, &str)]) -> anyhow::Result<Option<Config>> {
+        Config::of(&SourceAccount {
+            account: bank(),
+            metadata: metadata
+                .iter()
+                .map(|(k, v)| (k.to_string(), MetadataValue::String(v.to_string())))
+                .collect(),
+            file: "main.beancount".into(),
+        })
+    }
+
+    fn payment(id: &str, date: &str, amount: &str, narration: &str) -> Directive<Decimal> {
+        let fields = serde_json::json!({ "remittanceInformationUnstructured": narration });
+        directive(id, date, amount, fields)
+    }
+
+    fn links(d: &Directive<Decimal>) -> Vec<&str> {
+        let mut links: Vec<&str> = d
+            .content
+            .transaction_opt()
+            .unwrap()
+            .links
+            .iter()
+            .map(|l| l.as_str())
+            .filter(|l| l.starts_with(LINK_PREFIX))
+            .collect();
+        links.sort();
+        links
+    }
+
+    #[test]
+    fn configured_by_the_metadata() {
+        assert!(config(&[]).unwrap().is_none());
+        let merge = config(&[("round_ups", "merge")]).unwrap().unwrap();
+        assert_eq!(merge.mode, Mode::Merge);
+        assert!(merge.is_round_up(&payment("1", "2024-03-01", "-0.37", "Round-up")));
+        let custom = config(&[("round_ups", "link"), ("round_up_pattern", "Vault")])
+            .unwrap()
+            .unwrap();
+        assert_eq!(custom.mode, Mode::Link);
+        assert!(!custom.is_round_up(&payment("1", "2024-03-01", "-0.37", "Round-up")));
+        assert!(config(&[("round_ups", "both")]).is_err());
+        assert!(config(&[("round_ups", "link"), ("round_up_pattern", "(")]).is_err());
+    }
+
+    #[test]
+    fn links_the_round_up_to_the_purchase() {
+        let config = config(&[("round_ups", "link")]).unwrap().unwrap();
+        let mut new = vec![
+            payment("p1", "2024-03-01", "-4.63", "Bakery"),
+            payment("p2", "2024-03-01", "-5.00", "Kiosk"),
+            payment("r1", "2024-03-02", "-0.37", "Round-up"),
+            // Doesn't round up any of the purchases.
+            payment("r2", "2024-03-02", "-0.50", "Round-up"),
+        ];
+        pair(
+            &mut [],
+            &mut new,
+            &bank(),
+            &TransactionMapper::default(),
+            &config,
+        );
+        assert_eq!(new.len(), 4);
+        assert_eq!(links(&new[0]), ["roundup-p1"]);
+        assert!(links(&new[1]).is_empty());
+        assert_eq!(links(&new[2]), ["roundup-p1"]);
+        assert!(links(&new[3]).is_empty());
+    }
+
+    #[test]
+    fn merges_the_round_up_into_the_purchase() {
+        let config = config(&[("round_ups", "merge")]).unwrap().unwrap();
+        let mut existing = [payment("p1", "2024-03-01", "-4.63", "Bakery")];
+        let mut new = vec![
+            payment("r1", "2024-03-02", "-0.37", "Round-up"),
+            payment("p2", "2024-03-02", "-12.00", "Books"),
+        ];
+        let mapper = TransactionMapper::default();
+        pair(&mut existing, &mut new, &bank(), &mapper, &config);
+        assert_eq!(new.len(), 1);
+        assert_eq!(mapper.transaction_id(&new[0]), Some("p2"));
+        let purchase = existing[0].content.transaction_opt().unwrap();
+        assert_eq!(purchase.postings.len(), 2);
+        let total: Decimal = purchase
+            .postings
+            .iter()
+            .filter_map(|p| p.amount.as_ref())
+            .map(|a| a.value)
+            .sum();
+        assert_eq!(total, Decimal::new(-500, 2));
+        assert_eq!(links(&existing[0]), ["roundup-p1"]);
+        // The id of the round-up is kept apart, so it isn't imported again.
+        assert_eq!(mapper.transaction_id(&existing[0]), Some("p1"));
+        assert_eq!(metadata_str(&existing[0], ROUND_UP_ID_KEY), Some("r1"));
+    }
+
+    #[test]
+    fn merged_round_up_is_a_duplicate_on_the_next_import() {
+        let config = config(&[("round_ups", "merge")]).unwrap().unwrap();
+        let fields =
+            |narration: &str| serde_json::json!({ "remittanceInformationUnstructured": narration });
+        let purchase = transaction("p1", "2024-03-01", "-4.63", fields("Bakery"));
+        let round_up = transaction("r1", "2024-03-02", "-0.37", fields("Round-up"));
+        for mapper in [
+            TransactionMapper::default(),
+            TransactionMapper::builder().id_metadata("bank_id").build(),
+        ] {
+            let mut imported = vec![
+                mapper.map(&purchase, &bank()).unwrap(),
+                mapper.map(&round_up, &bank()).unwrap(),
+            ];
+            pair(&mut [], &mut imported, &bank(), &mapper, &config);
+            assert_eq!(imported.len(), 1);
+
+            // The next import, with the merged transaction in the ledger.
+            let mut dedup = dedup::from_config(None, &mapper).unwrap();
+            let mut recorded = HashMap::new();
+            for d in &imported {
+                add_to_dedup(dedup.as_mut(), &mapper, d);
+                mutations::add_recorded(&mut recorded, &mapper, d);
+            }
+            for t in [&purchase, &round_up] {
+                let fetched = mapper.map(t, &bank()).unwrap();
+                assert!(dedup.is_duplicate(&fetched, &bank()));
+                assert!(mutations::detect(&recorded, &mapper, &fetched, &bank()).is_none());
+            }
+        }
+    }
+
+    #[test]
+    fn keeps_the_round_up_without_the_purchase() {
+        // In the import directory mode, the purchase in the ledger can't be changed.
+        let config = config(&[("round_ups", "merge")]).unwrap().unwrap();
+        let mut new = vec![payment("r1", "2024-03-02", "-0.37", "Round-up")];
+        let mapper = TransactionMapper::default();
+        pair(&mut [], &mut new, &bank(), &mapper, &config);
+        assert_eq!(new.len(), 1);
+        assert_eq!(mapper.transaction_id(&new[0]), Some("r1"));
+        assert!(links(&new[0]).is_empty());
+    }
+}
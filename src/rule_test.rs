@@ -0,0 +1,138 @@
+//! The `rules test` command: runs the categorization rules over the transactions saved in the
+//! local store, without contacting GoCardless or touching the ledger, to try out changes of the
+//! rules before the next import.
+
+use crate::{
+    ledger_config, ledger_io, narration,
+    output::{self, OutputFormat, Table},
+    rules::{self, Source},
+    store,
+};
+use beanru::types::Account;
+use serde::Serialize;
+use std::path::Path;
+
+/// What the rules do with a stored transaction.
+#[derive(Serialize)]
+pub struct Outcome {
+    /// The account of the ledger configured with the account id, or the account id.
+    pub account: String,
+    pub date: String,
+    pub amount: String,
+    pub currency: String,
+    pub counterparty: String,
+    pub narration: String,
+    pub ignored: bool,
+    pub counter_account: Option<String>,
+    pub payee: Option<String>,
+    pub tags: Vec<String>,
+    /// The rule (its patterns) or the code mapping that categorized the transaction.
+    pub matched_by: Option<String>,
+}
+
+fn matched_by(rules: &rules::Rules, c: &rules::Categorization) -> String {
+    let mut sources: Vec<String> = c
+        .rules
+        .iter()
+        .map(|i| format!("rule {}", rules.describe_rule(*i)))
+        .collect();
+    match c.source {
+        Source::Rule => {}
+        Source::AtmWithdrawal => sources.push("ATM withdrawal".to_string()),
+        Source::MerchantCategoryCode => sources.push("merchant category code".to_string()),
+        Source::BankTransactionCode => sources.push("bank transaction code".to_string()),
+    }
+    sources.join("; ")
+}
+
+/// Categorizes the stored transactions matching the query. With the ledger, the rules configured
+/// in it are used (unless `rules` is given) and the ignore entries limited to an account apply.
+pub async fn run(
+    query: &store::Query,
+    ledger: Option<&Path>,
+    rules: Option<&Path>,
+    format: OutputFormat,
+) -> anyhow::Result<()> {
+    let mut configured = vec![];
+    let mut rules_path = rules
+        .map(|p| p.to_path_buf())
+        .or_else(|| crate::settings::get().rules.clone());
+    if let Some(path) = ledger {
+        let (ledger, _) = ledger_io::read_ledger(path.to_path_buf()).await?;
+        configured = ledger_config::configured_accounts(&ledger)?;
+        rules_path = ledger_config::rules_path(rules, &ledger)?;
+    }
+    let rules = rules::Rules::load(rules_path.as_deref()).await?;
+
+    let mut outcomes = vec![];
+    for s in store::Store::open_default()?.query(query)? {
+        let t = &s.transaction;
+        let account = configured
+            .iter()
+            .find(|a| a.metadata_str("account_id") == Some(s.account_id.as_str()))
+            .map(|a| a.account.clone())
+            .unwrap_or_else(|| Account(s.account_id.clone()));
+        let counterparty = if t.transaction_amount.amount.starts_with('-') {
+            &t.creditor_name
+        } else {
+            &t.debtor_name
+        };
+        let mut outcome = Outcome {
+            account: account.0.clone(),
+            date: t.booking_date.clone().unwrap_or_default(),
+            amount: t.transaction_amount.amount.clone(),
+            currency: t.transaction_amount.currency.clone(),
+            counterparty: counterparty.clone().unwrap_or_default(),
+            narration: narration(t).unwrap_or_default(),
+            ignored: rules.is_ignored(t, &account),
+            counter_account: None,
+            payee: None,
+            tags: vec![],
+            matched_by: None,
+        };
+        if !outcome.ignored {
+            if let Some(c) = rules.categorize(t) {
+                outcome.counter_account = c.category.account.clone();
+                outcome.payee = c.category.payee.clone();
+                outcome.tags = c.category.tags.clone();
+                outcome.matched_by = Some(matched_by(&rules, &c));
+            }
+        }
+        outcomes.push(outcome);
+    }
+
+    if output::print_structured(format, &outcomes)? {
+        return Ok(());
+    }
+    let mut table = Table::new(&[
+        "ACCOUNT",
+        "DATE",
+        "AMOUNT",
+        "COUNTERPARTY",
+        "NARRATION",
+        "COUNTER ACCOUNT",
+        "PAYEE",
+        "TAGS",
+        "MATCHED BY",
+    ]);
+    for o in outcomes {
+        let counter_account = if o.ignored {
+            "(ignored)".to_string()
+        } else {
+            o.counter_account.unwrap_or_default()
+        };
+        table.row(vec![
+            o.account,
+            o.date,
+            format!("{} {}", o.amount, o.currency),
+            o.counterparty,
+            o.narration,
+            counter_account,
+            o.payee.unwrap_or_default(),
+            o.tags.join(" "),
+            o.matched_by.unwrap_or_default(),
+        ]);
+    }
+    table.print();
+    Ok(())
+}
@@ -0,0 +1,1055 @@
+use anyhow::Context;
+use beanru::types::{
+    Account, Amount, Directive, DirectiveContent, MetadataValue, Posting, Transaction,
+};
+use gocardless::models::{AccountSchema, TransactionSchema};
+use regex::Regex;
+use rust_decimal::Decimal;
+use serde::Deserialize;
+use serde_with::{serde_as, DisplayFromStr};
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashMap, HashSet},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+use tracing::warn;
+
+/// Mapping of merchant category codes and bank transaction codes shipped with the importer, used
+/// when a rules file sets `include_defaults`.
+const DEFAULT_CODES: &str = include_str!("../data/codes.yaml");
+
+/// The result of categorization, applied to the imported transaction.
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct Category {
+    /// The account of the counter posting. When split postings are present, this account receives
+    /// the remainder.
+    pub account: Option<String>,
+    pub payee: Option<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Additional counter postings with fixed or percentage amounts.
+    #[serde(default)]
+    pub postings: Vec<SplitPosting>,
+    /// Share of a shared expense: a posting with the percentage of the amount to the account of
+    /// the other person (e.g. `Assets:Receivable:Bob`).
+    pub share: Option<Share>,
+    /// Expansion of a salary credit into the gross salary, the deductions and the net amount.
+    pub salary: Option<Box<Salary>>,
+    /// Metadata added to the transaction, e.g. for the expense sharing plugins.
+    #[serde(default)]
+    pub metadata: BTreeMap<String, String>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct Share {
+    pub account: String,
+    pub percent: Decimal,
+}
+
+/// Payroll template of the salary credits, e.g. the gross salary posted to `Income:Salary` and
+/// the income tax and social security deducted from it.
+#[derive(Deserialize, Clone, Debug)]
+pub struct Salary {
+    /// Account of the gross salary.
+    pub income: String,
+    /// Fixed gross salary. Without it, the gross salary is computed from the net amount and the
+    /// deductions.
+    pub gross: Option<Decimal>,
+    /// Deductions from the gross salary, with fixed amounts or percentages of the gross salary.
+    #[serde(default)]
+    pub deductions: Vec<SplitPosting>,
+}
+
+impl Salary {
+    /// Adds the postings of the gross salary and the deductions to the net salary received. With
+    /// a fixed gross salary, a different net amount (e.g. with a bonus) is posted to `account`,
+    /// if any, or to the income account.
+    fn apply(
+        &self,
+        t: &mut Transaction<Decimal>,
+        net: Amount<Decimal>,
+        account: Option<&str>,
+    ) -> anyhow::Result<()> {
+        if net.value.is_sign_negative() {
+            anyhow::bail!("the salary template applies only to incoming payments");
+        }
+        let scale = net.value.scale();
+        let mut fixed = Decimal::ZERO;
+        let mut percent = Decimal::ZERO;
+        for d in &self.deductions {
+            match (d.amount, d.percent) {
+                (Some(amount), None) => fixed += amount,
+                (None, Some(p)) => percent += p,
+                _ => anyhow::bail!(
+                    "deduction {} must have exactly one of amount or percent",
+                    d.account
+                ),
+            }
+        }
+        let gross = match self.gross {
+            Some(gross) => gross,
+            None => {
+                let share = Decimal::ONE - percent / Decimal::ONE_HUNDRED;
+                if share <= Decimal::ZERO {
+                    anyhow::bail!("the deductions take the whole gross salary");
+                }
+                ((net.value + fixed) / share).round_dp(scale)
+            }
+        };
+        let deductions: Vec<(&str, Decimal)> = self
+            .deductions
+            .iter()
+            .map(|d| {
+                let value = d.amount.unwrap_or_else(|| {
+                    (gross * d.percent.unwrap_or_default() / Decimal::ONE_HUNDRED).round_dp(scale)
+                });
+                (d.account.as_str(), value)
+            })
+            .collect();
+        let received = net.value + deductions.iter().map(|(_, v)| v).sum::<Decimal>();
+        let posting = |account: &str, value| {
+            counter_posting(
+                account,
+                Some(Amount {
+                    value,
+                    currency: net.currency.clone(),
+                }),
+            )
+        };
+        match account {
+            Some(account) if self.gross.is_some() => {
+                t.postings.push(posting(&self.income, -gross));
+                if received != gross {
+                    t.postings.push(posting(account, gross - received));
+                }
+            }
+            // Also takes the rounding of the computed gross salary.
+            _ => t.postings.push(posting(&self.income, -received)),
+        }
+        for (account, value) in deductions {
+            t.postings.push(posting(account, value));
+        }
+        Ok(())
+    }
+}
+
+/// A counter posting with an amount computed from the amount of the bank transaction.
+#[derive(Deserialize, Clone, Debug)]
+pub struct SplitPosting {
+    pub account: String,
+    /// Fixed amount of the posting. The sign is taken from the counter side of the transaction.
+    pub amount: Option<Decimal>,
+    /// Percentage of the bank transaction amount.
+    pub percent: Option<Decimal>,
+}
+
+impl SplitPosting {
+    fn value(&self, bank_amount: Decimal) -> anyhow::Result<Decimal> {
+        match (self.amount, self.percent) {
+            (Some(amount), None) if bank_amount.is_sign_negative() => Ok(amount),
+            (Some(amount), None) => Ok(-amount),
+            (None, Some(percent)) => {
+                Ok((-bank_amount * percent / Decimal::ONE_HUNDRED).round_dp(bank_amount.scale()))
+            }
+            _ => anyhow::bail!(
+                "posting to {} must have exactly one of amount or percent",
+                self.account
+            ),
+        }
+    }
+}
+
+pub(crate) fn counter_posting(account: &str, amount: Option<Amount<Decimal>>) -> Posting<Decimal> {
+    Posting {
+        flag: None,
+        account: Account(account.to_string()),
+        amount,
+        cost: None,
+        price: None,
+        metadata: Default::default(),
+        autocomputed: false,
+    }
+}
+
+impl Category {
+    /// Whether the category posts the transaction to a counter account, directly, with split
+    /// postings or with the salary template.
+    pub fn has_counter_account(&self) -> bool {
+        self.account.is_some()
+            || !self.postings.is_empty()
+            || self.share.is_some()
+            || self.salary.is_some()
+    }
+
+    /// Fills what this category leaves out from the category of a later rule. The tags and the
+    /// metadata are combined.
+    fn merge(mut self, other: &Category) -> Category {
+        self.account = self.account.or_else(|| other.account.clone());
+        self.payee = self.payee.or_else(|| other.payee.clone());
+        for tag in &other.tags {
+            if !self.tags.contains(tag) {
+                self.tags.push(tag.clone());
+            }
+        }
+        if self.postings.is_empty() {
+            self.postings = other.postings.clone();
+        }
+        self.share = self.share.or_else(|| other.share.clone());
+        self.salary = self.salary.or_else(|| other.salary.clone());
+        for (key, value) in &other.metadata {
+            self.metadata
+                .entry(key.clone())
+                .or_insert_with(|| value.clone());
+        }
+        self
+    }
+
+    pub fn apply(&self, d: &mut Directive<Decimal>) -> anyhow::Result<()> {
+        let DirectiveContent::Transaction(t) = &mut d.content else {
+            return Ok(());
+        };
+        for (key, value) in &self.metadata {
+            d.metadata
+                .insert(key.clone(), MetadataValue::String(value.clone()));
+        }
+        if let Some(payee) = &self.payee {
+            t.payee = Some(payee.clone());
+        }
+        t.tags.extend(self.tags.iter().cloned());
+        let share = self.share.as_ref().map(|s| SplitPosting {
+            account: s.account.clone(),
+            amount: None,
+            percent: Some(s.percent),
+        });
+        let postings: Vec<&SplitPosting> = self.postings.iter().chain(&share).collect();
+        if let Some(salary) = &self.salary {
+            if !postings.is_empty() {
+                anyhow::bail!("the salary template can't be combined with postings or share");
+            }
+            let net = t
+                .postings
+                .first()
+                .and_then(|p| p.amount.clone())
+                .context("transaction has no amount to expand")?;
+            return salary.apply(t, net, self.account.as_deref());
+        }
+        if postings.is_empty() {
+            if let Some(account) = &self.account {
+                t.postings.push(counter_posting(account, None));
+            }
+            return Ok(());
+        }
+
+        let bank_amount = t
+            .postings
+            .first()
+            .and_then(|p| p.amount.clone())
+            .context("transaction has no amount to split")?;
+        let mut remainder = -bank_amount.value;
+        for split in postings {
+            let value = split.value(bank_amount.value)?;
+            remainder -= value;
+            t.postings.push(counter_posting(
+                &split.account,
+                Some(Amount {
+                    value,
+                    currency: bank_amount.currency.clone(),
+                }),
+            ));
+        }
+        match &self.account {
+            Some(account) => t.postings.push(counter_posting(
+                account,
+                Some(Amount {
+                    value: remainder,
+                    currency: bank_amount.currency,
+                }),
+            )),
+            None if !remainder.is_zero() => anyhow::bail!(
+                "split postings do not balance the transaction, the remainder is {} {}",
+                remainder,
+                bank_amount.currency
+            ),
+            None => {}
+        }
+        Ok(())
+    }
+}
+
+/// Patterns matched against the bank transaction. All of the specified patterns have to match.
+#[serde_as]
+#[derive(Deserialize, Debug, Default)]
+pub struct Patterns {
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    narration: Option<Regex>,
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    creditor_name: Option<Regex>,
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    debtor_name: Option<Regex>,
+    /// Matched against the IBAN of the counterparty without spaces, in upper case.
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    creditor_iban: Option<Regex>,
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    debtor_iban: Option<Regex>,
+    /// Matched against both ISO and proprietary bank transaction codes.
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    transaction_code: Option<Regex>,
+}
+
+/// A single user defined rule. All of the specified patterns have to match for the rule to apply.
+#[derive(Deserialize, Debug)]
+pub struct Rule {
+    #[serde(flatten)]
+    patterns: Patterns,
+    #[serde(flatten)]
+    category: Category,
+    /// The rules with a higher priority are tried first (0 by default). The rules with the same
+    /// priority are tried in the order of the files.
+    #[serde(default)]
+    priority: i32,
+    /// Whether the matching stops at this rule (the default). Otherwise, the following matching
+    /// rules are applied too, e.g. a rule only adding tags.
+    #[serde(default = "default_stop")]
+    stop: bool,
+}
+
+fn default_stop() -> bool {
+    true
+}
+
+/// A transaction that is never imported, e.g. an internal transfer the bank reports twice.
+#[derive(Deserialize, Debug)]
+pub struct Ignore {
+    /// Internal (or bank) id of the transaction.
+    id: Option<String>,
+    /// Only ignore the transactions of this account.
+    account: Option<String>,
+    #[serde(flatten)]
+    patterns: Patterns,
+}
+
+fn is_match(re: &Option<Regex>, value: Option<&str>) -> bool {
+    match re {
+        None => true,
+        Some(re) => value.map(|v| re.is_match(v)).unwrap_or(false),
+    }
+}
+
+/// The IBAN of the account, as matched by the patterns.
+fn iban(account: &Option<Box<AccountSchema>>) -> Option<String> {
+    let iban = account.as_ref()?.iban.as_ref()?;
+    Some(iban.replace(' ', "").to_uppercase())
+}
+
+impl Patterns {
+    /// The specified patterns, e.g. `narration /(?i)tesco/`.
+    fn describe(&self) -> String {
+        let patterns = [
+            ("narration", &self.narration),
+            ("creditor_name", &self.creditor_name),
+            ("debtor_name", &self.debtor_name),
+            ("creditor_iban", &self.creditor_iban),
+            ("debtor_iban", &self.debtor_iban),
+            ("transaction_code", &self.transaction_code),
+        ];
+        patterns
+            .into_iter()
+            .filter_map(|(field, re)| Some(format!("{} /{}/", field, re.as_ref()?)))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    fn is_empty(&self) -> bool {
+        self.narration.is_none()
+            && self.creditor_name.is_none()
+            && self.debtor_name.is_none()
+            && self.creditor_iban.is_none()
+            && self.debtor_iban.is_none()
+            && self.transaction_code.is_none()
+    }
+
+    fn matches(&self, t: &TransactionSchema) -> bool {
+        is_match(&self.narration, crate::narration(t).as_deref())
+            && is_match(&self.creditor_name, t.creditor_name.as_deref())
+            && is_match(&self.debtor_name, t.debtor_name.as_deref())
+            && is_match(&self.creditor_iban, iban(&t.creditor_account).as_deref())
+            && is_match(&self.debtor_iban, iban(&t.debtor_account).as_deref())
+            && (is_match(&self.transaction_code, t.bank_transaction_code.as_deref())
+                || is_match(
+                    &self.transaction_code,
+                    t.proprietary_bank_transaction_code.as_deref(),
+                ))
+    }
+}
+
+impl Ignore {
+    fn matches(&self, t: &TransactionSchema, account: &Account) -> bool {
+        let id_matches = match &self.id {
+            None => true,
+            Some(id) => {
+                t.internal_transaction_id.as_ref() == Some(id)
+                    || t.transaction_id.as_ref() == Some(id)
+            }
+        };
+        id_matches
+            && self.account.as_ref().is_none_or(|a| *a == account.0)
+            && self.patterns.matches(t)
+    }
+}
+
+/// Where the category of a transaction came from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Source {
+    Rule,
+    AtmWithdrawal,
+    MerchantCategoryCode,
+    BankTransactionCode,
+}
+
+pub struct Categorization<'a> {
+    /// The category of the matching rules combined, or the one of the code mappings.
+    pub category: Cow<'a, Category>,
+    pub source: Source,
+    /// Indexes of the explicit rules that matched, in the order they were applied.
+    pub rules: Vec<usize>,
+}
+
+impl Categorization<'_> {
+    /// Whether the transaction was matched by an explicit rule that assigns the counter account.
+    pub fn is_complete(&self) -> bool {
+        matches!(self.source, Source::Rule | Source::AtmWithdrawal)
+            && self.category.has_counter_account()
+    }
+}
+
+/// Merchant category code of ATMs ("Automated Cash Disbursements").
+const ATM_MERCHANT_CATEGORY_CODE: &str = "6011";
+
+fn is_atm_withdrawal(t: &TransactionSchema) -> bool {
+    // ISO 20022 sub family of cash withdrawals, e.g. PMNT-CCRD-CWDL.
+    if let Some(code) = &t.bank_transaction_code {
+        if code.ends_with("-CWDL") {
+            return true;
+        }
+    }
+    if t.merchant_category_code.as_deref() == Some(ATM_MERCHANT_CATEGORY_CODE) {
+        return true;
+    }
+    if let Some(code) = &t.proprietary_bank_transaction_code {
+        let code = code.to_uppercase();
+        if code == "ATM" || code.contains("CASH_WITHDRAWAL") || code.contains("CASH WITHDRAWAL") {
+            return true;
+        }
+    }
+    false
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct Rules {
+    /// Other rules files (relative to this one), whose entries follow the ones of this file.
+    #[serde(default)]
+    include: Vec<PathBuf>,
+    /// Whether the code mappings shipped with the importer are used.
+    #[serde(default)]
+    include_defaults: bool,
+    /// The loaded rules files.
+    #[serde(skip)]
+    files: Vec<PathBuf>,
+    #[serde(default)]
+    rules: Vec<Rule>,
+    #[serde(default)]
+    ignore: Vec<Ignore>,
+    /// Category of ATM withdrawals, which are transfers to the cash account rather than spending.
+    /// Without it (or with `null`), they are not detected.
+    #[serde(default, with = "::serde_with::rust::double_option")]
+    atm_withdrawals: Option<Option<Category>>,
+    #[serde(default)]
+    merchant_category_codes: HashMap<String, Category>,
+    #[serde(default)]
+    bank_transaction_codes: HashMap<String, Category>,
+}
+
+/// The rules files being read: the including ones (to detect the cycles) and all the read ones,
+/// by their canonical paths.
+#[derive(Default)]
+struct Includes {
+    stack: Vec<PathBuf>,
+    read: HashSet<PathBuf>,
+}
+
+/// Parses the rules file, followed by the files it includes (relative to it). A file included
+/// from several others is read only once.
+fn read(
+    path: &Path,
+    includes: &mut Includes,
+    files: &mut Vec<(PathBuf, Rules)>,
+) -> anyhow::Result<()> {
+    let canonical = std::fs::canonicalize(path)
+        .with_context(|| format!("failed to read {}", path.display()))?;
+    if includes.stack.contains(&canonical) {
+        anyhow::bail!("{} includes itself", path.display());
+    }
+    if !includes.read.insert(canonical.clone()) {
+        return Ok(());
+    }
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read {}", path.display()))?;
+    let user: Rules = serde_yaml::from_str(&content)
+        .map_err(|e| anyhow::format_err!("failed to parse {}: {}", path.display(), e))?;
+    if user
+        .ignore
+        .iter()
+        .any(|i| i.id.is_none() && i.patterns.is_empty())
+    {
+        anyhow::bail!(
+            "failed to parse {}: ignore entries need an id or a pattern",
+            path.display()
+        );
+    }
+    let included: Vec<PathBuf> = user
+        .include
+        .iter()
+        .map(|i| match path.parent() {
+            Some(dir) if i.is_relative() => dir.join(i),
+            _ => i.clone(),
+        })
+        .collect();
+    files.push((path.to_path_buf(), user));
+    includes.stack.push(canonical);
+    for include in included {
+        read(&include, includes, files)
+            .with_context(|| format!("included from {}", path.display()))?;
+    }
+    includes.stack.pop();
+    Ok(())
+}
+
+impl Rules {
+    /// Loads the rules from the given file (and the files it includes), extended with the default
+    /// code mappings if one of them sets `include_defaults`.
+    pub async fn load(path: Option<&Path>) -> anyhow::Result<Rules> {
+        // The rules files are small, reading them doesn't need to be asynchronous.
+        Rules::load_blocking(path)
+    }
+
+    /// [`Rules::load`] outside of the async code (e.g. the review interface).
+    pub fn load_blocking(path: Option<&Path>) -> anyhow::Result<Rules> {
+        let mut files = vec![];
+        if let Some(path) = path {
+            read(path, &mut Includes::default(), &mut files)?;
+        }
+        let mut rules: Rules = if files.iter().any(|(_, user)| user.include_defaults) {
+            serde_yaml::from_str(DEFAULT_CODES)?
+        } else {
+            Rules::default()
+        };
+        for (path, user) in &mut files {
+            rules.files.push(path.clone());
+            rules.rules.append(&mut user.rules);
+            rules.ignore.append(&mut user.ignore);
+        }
+        // The code mappings of the earlier files take precedence.
+        for (_, user) in files.into_iter().rev() {
+            if user.atm_withdrawals.is_some() {
+                rules.atm_withdrawals = user.atm_withdrawals;
+            }
+            rules
+                .merchant_category_codes
+                .extend(user.merchant_category_codes);
+            rules
+                .bank_transaction_codes
+                .extend(user.bank_transaction_codes);
+        }
+        // Stable, so the rules with the same priority keep their order.
+        rules.rules.sort_by_key(|r| std::cmp::Reverse(r.priority));
+        Ok(rules)
+    }
+
+    /// Disables the ATM withdrawals detection if its account is not in `opened`, as the postings
+    /// to it would make the ledger invalid.
+    pub fn require_opened_atm_account(&mut self, opened: &HashSet<Account>) {
+        if let Some(Some(Category {
+            account: Some(account),
+            ..
+        })) = &self.atm_withdrawals
+        {
+            if !opened.contains(&Account(account.clone())) {
+                warn!(
+                    "the account {} of the ATM withdrawals is not opened, they are not detected",
+                    account
+                );
+                self.atm_withdrawals = None;
+            }
+        }
+    }
+
+    /// The loaded rules files: the given one and the ones it includes.
+    pub fn files(&self) -> &[PathBuf] {
+        &self.files
+    }
+
+    /// Whether the transaction of the account matches one of the ignore entries.
+    pub fn is_ignored(&self, t: &TransactionSchema, account: &Account) -> bool {
+        self.ignore.iter().any(|i| i.matches(t, account))
+    }
+
+    /// The category of the ATM withdrawals detection, the merchant category codes or the bank
+    /// transaction codes (in this order).
+    fn code_category(&self, t: &TransactionSchema) -> Option<(&Category, Source)> {
+        if let Some(Some(category)) = &self.atm_withdrawals {
+            if is_atm_withdrawal(t) {
+                return Some((category, Source::AtmWithdrawal));
+            }
+        }
+        if let Some(category) = t
+            .merchant_category_code
+            .as_ref()
+            .and_then(|mcc| self.merchant_category_codes.get(mcc))
+        {
+            return Some((category, Source::MerchantCategoryCode));
+        }
+        [
+            &t.bank_transaction_code,
+            &t.proprietary_bank_transaction_code,
+        ]
+        .into_iter()
+        .flatten()
+        .find_map(|code| self.bank_transaction_codes.get(code))
+        .map(|category| (category, Source::BankTransactionCode))
+    }
+
+    /// Finds the category for the transaction. The explicit rules are applied by their priority,
+    /// until a matching rule stops the matching, and take precedence over the ATM withdrawals
+    /// detection and the code mappings, which only fill the counter account if no rule did.
+    pub fn categorize(&self, t: &TransactionSchema) -> Option<Categorization<'_>> {
+        let mut matched = vec![];
+        let mut category: Option<Cow<Category>> = None;
+        for (i, rule) in self.rules.iter().enumerate() {
+            if !rule.patterns.matches(t) {
+                continue;
+            }
+            matched.push(i);
+            category = Some(match category {
+                None => Cow::Borrowed(&rule.category),
+                Some(c) => Cow::Owned(c.into_owned().merge(&rule.category)),
+            });
+            if rule.stop {
+                break;
+            }
+        }
+        match category {
+            Some(c) if c.has_counter_account() => Some(Categorization {
+                category: c,
+                source: Source::Rule,
+                rules: matched,
+            }),
+            Some(c) => Some(match self.code_category(t) {
+                Some((code, source)) => Categorization {
+                    category: Cow::Owned(c.into_owned().merge(code)),
+                    source,
+                    rules: matched,
+                },
+                None => Categorization {
+                    category: c,
+                    source: Source::Rule,
+                    rules: matched,
+                },
+            }),
+            None => self
+                .code_category(t)
+                .map(|(category, source)| Categorization {
+                    category: Cow::Borrowed(category),
+                    source,
+                    rules: matched,
+                }),
+        }
+    }
+
+    /// The patterns of the rule (by its index in [`Categorization::rules`]).
+    pub fn describe_rule(&self, i: usize) -> String {
+        self.rules[i].patterns.describe()
+    }
+
+    /// The number of the transactions each rule (and the code mappings) categorized, and the
+    /// counterparties of the uncategorized transactions, the most frequent first.
+    pub fn report(&self, stats: &Stats) -> String {
+        let mut out = String::from("Transactions categorized by the rules:\n");
+        for (i, rule) in self.rules.iter().enumerate() {
+            out += &format!(
+                "{:>6}  {} -> {}\n",
+                stats.rules.get(&i).copied().unwrap_or_default(),
+                self.describe_rule(i),
+                rule.category
+                    .account
+                    .as_deref()
+                    .or(rule.category.salary.as_ref().map(|s| s.income.as_str()))
+                    .unwrap_or("(no account)")
+            );
+        }
+        for (source, name) in [
+            (Source::AtmWithdrawal, "ATM withdrawals"),
+            (Source::MerchantCategoryCode, "merchant category codes"),
+            (Source::BankTransactionCode, "bank transaction codes"),
+        ] {
+            if let Some(count) = stats.sources.get(&source) {
+                out += &format!("{:>6}  {}\n", count, name);
+            }
+        }
+        let mut uncategorized: Vec<(&String, &usize)> = stats.uncategorized.iter().collect();
+        uncategorized.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+        out += &format!(
+            "Uncategorized transactions: {}\n",
+            uncategorized.iter().map(|(_, n)| **n).sum::<usize>()
+        );
+        for (counterparty, count) in uncategorized {
+            out += &format!("{:>6}  {}\n", count, counterparty);
+        }
+        out
+    }
+}
+
+/// Notices the changes of the rules files by their modification time, so the long running commands
+/// (the daemon, the review interface) can reload them.
+pub struct Watcher {
+    /// The rules file and the files it includes, with their modification times.
+    files: Vec<(PathBuf, Option<SystemTime>)>,
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+impl Watcher {
+    /// Watches the files, the first one is the rules file (see [`Rules::files`]).
+    pub fn new(files: &[PathBuf]) -> Watcher {
+        Watcher {
+            files: files.iter().map(|f| (f.clone(), modified(f))).collect(),
+        }
+    }
+
+    /// The rules file.
+    pub fn path(&self) -> &Path {
+        &self.files[0].0
+    }
+
+    /// Whether one of the files changed (or was removed) since the previous call.
+    pub fn changed(&mut self) -> bool {
+        let mut changed = false;
+        for (path, last) in &mut self.files {
+            let modified = modified(path);
+            if modified != *last {
+                *last = modified;
+                changed = true;
+            }
+        }
+        changed
+    }
+}
+
+/// Statistics of the categorization of the imported transactions (`import --rule-stats`).
+#[derive(Default, Debug)]
+pub struct Stats {
+    /// Index of the rule -> the transactions it categorized.
+    rules: HashMap<usize, usize>,
+    /// The transactions categorized by the ATM withdrawals detection and the code mappings.
+    sources: HashMap<Source, usize>,
+    /// Counterparty (or the narration, without one) -> the uncategorized transactions.
+    uncategorized: HashMap<String, usize>,
+}
+
+impl Stats {
+    pub fn record(&mut self, t: &TransactionSchema, categorization: Option<&Categorization>) {
+        match categorization {
+            Some(c) => {
+                for i in &c.rules {
+                    *self.rules.entry(*i).or_default() += 1;
+                }
+                if c.source != Source::Rule {
+                    *self.sources.entry(c.source).or_default() += 1;
+                }
+            }
+            None => {
+                let counterparty = if t.transaction_amount.amount.starts_with('-') {
+                    &t.creditor_name
+                } else {
+                    &t.debtor_name
+                };
+                let name = counterparty
+                    .clone()
+                    .or_else(|| crate::narration(t))
+                    .unwrap_or_default();
+                *self.uncategorized.entry(name).or_default() += 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        mapper::TransactionMapper,
+        test_util::{self, bank},
+    };
+
+    fn transaction(amount: &str, debtor: &str) -> TransactionSchema {
+        test_util::transaction(
+            "t1",
+            "2024-03-05",
+            amount,
+            serde_json::json!({
+                "debtorName": debtor,
+                "remittanceInformationUnstructured": "Payment",
+            }),
+        )
+    }
+
+    fn directive(t: &TransactionSchema) -> Directive<Decimal> {
+        TransactionMapper::default().map(t, &bank()).unwrap()
+    }
+
+    fn category(yaml: &str) -> Category {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    /// The counter postings, with their amounts.
+    fn postings(d: &Directive<Decimal>) -> Vec<(&str, Option<Decimal>)> {
+        let t = d.content.transaction_opt().unwrap();
+        t.postings[1..]
+            .iter()
+            .map(|p| (p.account.0.as_str(), p.amount.as_ref().map(|a| a.value)))
+            .collect()
+    }
+
+    /// The sum of all the postings, zero for a balanced transaction.
+    fn total(d: &Directive<Decimal>) -> Decimal {
+        let t = d.content.transaction_opt().unwrap();
+        t.postings
+            .iter()
+            .map(|p| p.amount.as_ref().unwrap().value)
+            .sum()
+    }
+
+    fn dec(s: &str) -> Decimal {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn split_postings_balance_the_transaction() {
+        let category = category(
+            r#"
+            account: "Expenses:Shopping"
+            postings:
+              - { account: "Expenses:Bank:Fees", amount: 1.50 }
+              - { account: "Expenses:Taxes", percent: 20 }
+            "#,
+        );
+        let mut d = directive(&transaction("-101.50", "Shop"));
+        category.apply(&mut d).unwrap();
+        assert_eq!(
+            postings(&d),
+            vec![
+                ("Expenses:Bank:Fees", Some(dec("1.50"))),
+                ("Expenses:Taxes", Some(dec("20.30"))),
+                ("Expenses:Shopping", Some(dec("79.70"))),
+            ]
+        );
+        assert_eq!(total(&d), Decimal::ZERO);
+
+        // Incoming payments are split with the opposite sign.
+        let mut d = directive(&transaction("50.00", "Shop"));
+        category.apply(&mut d).unwrap();
+        assert_eq!(
+            postings(&d),
+            vec![
+                ("Expenses:Bank:Fees", Some(dec("-1.50"))),
+                ("Expenses:Taxes", Some(dec("-10.00"))),
+                ("Expenses:Shopping", Some(dec("-38.50"))),
+            ]
+        );
+        assert_eq!(total(&d), Decimal::ZERO);
+    }
+
+    #[test]
+    fn split_postings_without_remainder_account_have_to_balance() {
+        let balanced = category(
+            r#"
+            postings:
+              - { account: "Expenses:Rent", percent: 50 }
+              - { account: "Assets:Receivable:Bob", percent: 50 }
+            "#,
+        );
+        let mut d = directive(&transaction("-800.00", "Landlord"));
+        balanced.apply(&mut d).unwrap();
+        assert_eq!(total(&d), Decimal::ZERO);
+
+        let unbalanced = category(
+            r#"
+            postings:
+              - { account: "Expenses:Rent", percent: 50 }
+            "#,
+        );
+        let mut d = directive(&transaction("-800.00", "Landlord"));
+        assert!(unbalanced.apply(&mut d).is_err());
+    }
+
+    #[test]
+    fn balanced_split_is_complete() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("rules.yaml");
+        std::fs::write(
+            &path,
+            r#"
+rules:
+  - debtor_name: "^Landlord"
+    postings:
+      - { account: "Expenses:Rent", percent: 50 }
+      - { account: "Assets:Receivable:Bob", percent: 50 }
+bank_transaction_codes:
+  PMNT-ICDT-STDO: { account: "Expenses:Transfers" }
+"#,
+        )
+        .unwrap();
+        let rules = Rules::load_blocking(Some(&path)).unwrap();
+
+        let mut t = transaction("-800.00", "Landlord");
+        t.bank_transaction_code = Some("PMNT-ICDT-STDO".to_string());
+        let c = rules.categorize(&t).unwrap();
+        assert!(c.is_complete());
+        // The account of the code mapping doesn't get a remainder.
+        assert_eq!(c.category.account, None);
+        let mut d = directive(&t);
+        c.category.apply(&mut d).unwrap();
+        assert_eq!(
+            postings(&d),
+            vec![
+                ("Expenses:Rent", Some(dec("400.00"))),
+                ("Assets:Receivable:Bob", Some(dec("400.00"))),
+            ]
+        );
+        assert_eq!(total(&d), Decimal::ZERO);
+    }
+
+    #[test]
+    fn salary_template_balances_the_credit() {
+        let category = category(
+            r#"
+            salary:
+              income: "Income:Salary"
+              deductions:
+                - { account: "Expenses:Taxes:Income", percent: 20 }
+                - { account: "Expenses:Insurance:Social", percent: 10 }
+            "#,
+        );
+        let mut d = directive(&transaction("2000.00", "ACME Ltd"));
+        category.apply(&mut d).unwrap();
+        assert_eq!(
+            postings(&d),
+            vec![
+                ("Income:Salary", Some(dec("-2857.14"))),
+                ("Expenses:Taxes:Income", Some(dec("571.43"))),
+                ("Expenses:Insurance:Social", Some(dec("285.71"))),
+            ]
+        );
+        assert_eq!(total(&d), Decimal::ZERO);
+    }
+
+    #[test]
+    fn salary_template_posts_the_difference_to_the_fixed_gross() {
+        let category = category(
+            r#"
+            account: "Income:Bonus"
+            salary:
+              income: "Income:Salary"
+              gross: 3000.00
+              deductions:
+                - { account: "Expenses:Taxes:Income", amount: 600.00 }
+            "#,
+        );
+        let mut d = directive(&transaction("2500.00", "ACME Ltd"));
+        category.apply(&mut d).unwrap();
+        assert_eq!(
+            postings(&d),
+            vec![
+                ("Income:Salary", Some(dec("-3000.00"))),
+                ("Income:Bonus", Some(dec("-100.00"))),
+                ("Expenses:Taxes:Income", Some(dec("600.00"))),
+            ]
+        );
+        assert_eq!(total(&d), Decimal::ZERO);
+
+        let mut d = directive(&transaction("-2500.00", "ACME Ltd"));
+        assert!(category.apply(&mut d).is_err());
+    }
+
+    #[test]
+    fn salary_rule_leaves_other_credits_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("rules.yaml");
+        std::fs::write(
+            &path,
+            r#"
+rules:
+  - debtor_name: "^ACME"
+    salary:
+      income: "Income:Salary"
+      deductions:
+        - { account: "Expenses:Taxes:Income", percent: 20 }
+"#,
+        )
+        .unwrap();
+        let rules = Rules::load_blocking(Some(&path)).unwrap();
+
+        let salary = transaction("2000.00", "ACME Ltd");
+        let mut d = directive(&salary);
+        rules
+            .categorize(&salary)
+            .unwrap()
+            .category
+            .apply(&mut d)
+            .unwrap();
+        assert_eq!(postings(&d).len(), 2);
+        assert_eq!(total(&d), Decimal::ZERO);
+
+        // A credit of someone else is not a salary.
+        assert!(rules
+            .categorize(&transaction("2000.00", "Grandma"))
+            .is_none());
+    }
+
+    #[test]
+    fn includes_are_read_once_and_cannot_cycle() {
+        let dir = tempfile::tempdir().unwrap();
+        let write = |name: &str, content: &str| {
+            let path = dir.path().join(name);
+            std::fs::write(&path, content).unwrap();
+            path
+        };
+        let rules = write("rules.yaml", "include: [a.yaml, b.yaml]");
+        write("a.yaml", "include: [common.yaml]");
+        write("b.yaml", "include: [./common.yaml]");
+        write(
+            "common.yaml",
+            "rules: [{ debtor_name: \"^Shop\", account: \"Expenses:Shopping\" }]",
+        );
+        let loaded = Rules::load_blocking(Some(&rules)).unwrap();
+        assert_eq!(loaded.files().len(), 4);
+        assert_eq!(loaded.rules.len(), 1);
+
+        let name = dir.path().file_name().unwrap().to_str().unwrap();
+        write("common.yaml", &format!("include: [../{}/rules.yaml]", name));
+        let err = Rules::load_blocking(Some(&rules)).unwrap_err();
+        assert!(
+            format!("{:#}", err).contains("includes itself"),
+            "{:#}",
+            err
+        );
+    }
+}
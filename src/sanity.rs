@@ -0,0 +1,148 @@
+//! Sanity checks of the imported transactions (`import --sanity-checks`). The suspicious ones get
+//! the `!` flag, with the reasons in the `suspicious` metadata, so they are reviewed instead of
+//! silently merged into the ledger.
+
+use crate::posting_amount;
+use beanru::types::{Account, Currency, Directive, DirectiveContent, MetadataValue};
+use chrono::NaiveDate;
+use rust_decimal::Decimal;
+use std::collections::{HashMap, HashSet};
+use tracing::warn;
+
+pub const SUSPICIOUS_KEY: &str = "suspicious";
+
+/// Flags the new transactions of the account with an amount above `max_amount`, in a currency the
+/// account had no transactions in, or with the same amount as another transaction of the same day.
+pub fn check(
+    existing: &[Directive<Decimal>],
+    new: &mut [Directive<Decimal>],
+    account: &Account,
+    max_amount: Option<Decimal>,
+) {
+    let mut currencies: HashSet<Currency> = HashSet::new();
+    let mut same_day: HashMap<(NaiveDate, Decimal, Currency), usize> = HashMap::new();
+    for d in existing {
+        if let Some(a) = posting_amount(d, account) {
+            currencies.insert(a.currency.clone());
+            *same_day.entry((d.date, a.value, a.currency)).or_default() += 1;
+        }
+    }
+    for d in new.iter() {
+        if let Some(a) = posting_amount(d, account) {
+            *same_day.entry((d.date, a.value, a.currency)).or_default() += 1;
+        }
+    }
+    for d in new.iter_mut() {
+        let Some(a) = posting_amount(d, account) else {
+            continue;
+        };
+        let mut reasons = vec![];
+        if let Some(max) = max_amount {
+            if a.value.abs() > max {
+                reasons.push(format!("amount above {}", max));
+            }
+        }
+        // The first import of the account has nothing to compare with.
+        if !currencies.is_empty() && !currencies.contains(&a.currency) {
+            reasons.push(format!("first {} transaction", a.currency));
+        }
+        let count = same_day[&(d.date, a.value, a.currency.clone())];
+        if count > 1 {
+            reasons.push(format!("{} transactions of {} that day", count, a));
+        }
+        if reasons.is_empty() {
+            continue;
+        }
+        let reasons = reasons.join(", ");
+        warn!(
+            "{}: suspicious transaction on {} ({}): {}",
+            account, d.date, a, reasons
+        );
+        if let DirectiveContent::Transaction(t) = &mut d.content {
+            t.flag = Some('!');
+        }
+        d.metadata
+            .insert(SUSPICIOUS_KEY.into(), MetadataValue::String(reasons));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metadata_str;
+    use crate::test_util::{bank, directive};
+
+    fn payment(id: &str, date: &str, amount: &str, currency: &str) -> Directive<Decimal> {
+        let fields = serde_json::json!({
+            "transactionAmount": { "amount": amount, "currency": currency },
+            "creditorName": "Shop",
+        });
+        directive(id, date, amount, fields)
+    }
+
+    /// The reasons the new transactions are suspicious for (`None` for the fine ones).
+    fn suspicious(
+        existing: &[Directive<Decimal>],
+        mut new: Vec<Directive<Decimal>>,
+        max_amount: Option<Decimal>,
+    ) -> Vec<Option<String>> {
+        check(existing, &mut new, &bank(), max_amount);
+        new.iter()
+            .map(|d| {
+                let flagged = d
+                    .content
+                    .transaction_opt()
+                    .is_some_and(|t| t.flag == Some('!'));
+                let reasons = metadata_str(d, SUSPICIOUS_KEY).map(|r| r.to_string());
+                assert_eq!(flagged, reasons.is_some());
+                reasons
+            })
+            .collect()
+    }
+
+    #[test]
+    fn flags_a_new_currency() {
+        let existing = [payment("1", "2024-03-01", "-10.00", "EUR")];
+        let new = vec![
+            payment("2", "2024-03-02", "-20.00", "EUR"),
+            payment("3", "2024-03-03", "-30.00", "USD"),
+        ];
+        assert_eq!(
+            suspicious(&existing, new, None),
+            [None, Some("first USD transaction".to_string())]
+        );
+        // Nothing to compare with on the first import.
+        let new = vec![payment("3", "2024-03-03", "-30.00", "USD")];
+        assert_eq!(suspicious(&[], new, None), [None]);
+    }
+
+    #[test]
+    fn flags_the_same_amount_on_the_same_day() {
+        let existing = [payment("1", "2024-03-01", "-10.00", "EUR")];
+        let new = vec![
+            payment("2", "2024-03-01", "-10.00", "EUR"),
+            payment("3", "2024-03-01", "-11.00", "EUR"),
+            payment("4", "2024-03-02", "-10.00", "EUR"),
+        ];
+        assert_eq!(
+            suspicious(&existing, new, None),
+            [
+                Some("2 transactions of -10.00 EUR that day".to_string()),
+                None,
+                None
+            ]
+        );
+    }
+
+    #[test]
+    fn flags_amounts_above_the_threshold() {
+        let new = vec![
+            payment("1", "2024-03-01", "-1000.00", "EUR"),
+            payment("2", "2024-03-02", "1000.01", "EUR"),
+        ];
+        assert_eq!(
+            suspicious(&[], new, Some(Decimal::new(1000, 0))),
+            [None, Some("amount above 1000".to_string())]
+        );
+    }
+}
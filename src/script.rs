@@ -0,0 +1,179 @@
+//! User script (in [Rhai](https://rhai.rs)) run on every imported transaction, for the
+//! transformations too complex for the categorization rules. The script gets the transaction as
+//! the `tx` map and can change it, set the counter account or drop it:
+//!
+//! ```rhai
+//! if tx.narration.contains("AMAZON") && tx.amount < -100 {
+//!     tx.counter_account = "Expenses:Electronics";
+//!     tx.tags.push("review");
+//! }
+//! if tx.metadata.to_iban == "GB00XXXX00000000000000" {
+//!     tx.drop = true;
+//! }
+//! ```
+
+use crate::rules::counter_posting;
+use anyhow::Context;
+use beanru::types::{Account, Directive, DirectiveContent, MetadataValue};
+use rhai::{Array, Dynamic, Engine, Map, Scope, AST};
+use rust_decimal::Decimal;
+use std::path::Path;
+
+pub struct Script {
+    engine: Engine,
+    ast: AST,
+}
+
+fn string_or_unit(value: Option<&String>) -> Dynamic {
+    match value {
+        Some(s) => s.clone().into(),
+        None => Dynamic::UNIT,
+    }
+}
+
+/// The string value of the field, or nothing if it is `()`.
+fn opt_string(tx: &Map, key: &str) -> anyhow::Result<Option<String>> {
+    match tx.get(key) {
+        None => Ok(None),
+        Some(v) if v.is_unit() => Ok(None),
+        Some(v) => v
+            .clone()
+            .into_string()
+            .map(Some)
+            .map_err(|t| anyhow::format_err!("tx.{} has to be a string, not {}", key, t)),
+    }
+}
+
+/// The account of the only counter posting without an amount, as added by the rules.
+pub(crate) fn counter_account(d: &Directive<Decimal>) -> Option<String> {
+    match d.content.transaction_opt()?.postings.as_slice() {
+        [_, counter] if counter.amount.is_none() => Some(counter.account.0.clone()),
+        _ => None,
+    }
+}
+
+impl Script {
+    pub fn load(path: &Path) -> anyhow::Result<Script> {
+        let engine = Engine::new();
+        let source = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read the script {}", path.display()))?;
+        let ast = engine
+            .compile(source)
+            .map_err(|e| anyhow::format_err!("{}: {}", path.display(), e))?;
+        Ok(Script { engine, ast })
+    }
+
+    fn to_map(d: &Directive<Decimal>, account: &Account) -> Map {
+        let mut tx = Map::new();
+        tx.insert("date".into(), d.date.to_string().into());
+        tx.insert("account".into(), account.0.clone().into());
+        let counter = counter_account(d);
+        tx.insert("counter_account".into(), string_or_unit(counter.as_ref()));
+        tx.insert("drop".into(), false.into());
+        let mut metadata = Map::new();
+        for (key, value) in &d.metadata {
+            if let MetadataValue::String(s) = value {
+                metadata.insert(key.into(), s.clone().into());
+            }
+        }
+        tx.insert("metadata".into(), metadata.into());
+        let Some(t) = d.content.transaction_opt() else {
+            return tx;
+        };
+        tx.insert("payee".into(), string_or_unit(t.payee.as_ref()));
+        tx.insert("narration".into(), string_or_unit(t.narration.as_ref()));
+        tx.insert(
+            "flag".into(),
+            string_or_unit(t.flag.map(|f| f.to_string()).as_ref()),
+        );
+        let mut tags: Vec<&String> = t.tags.iter().collect();
+        tags.sort();
+        tx.insert(
+            "tags".into(),
+            tags.into_iter()
+                .map(|t| Dynamic::from(t.clone()))
+                .collect::<Array>()
+                .into(),
+        );
+        if let Some(amount) = t
+            .postings
+            .iter()
+            .find(|p| p.account == *account)
+            .and_then(|p| p.amount.as_ref())
+        {
+            tx.insert("amount".into(), Dynamic::from_decimal(amount.value));
+            tx.insert("currency".into(), amount.currency.0.clone().into());
+        }
+        tx
+    }
+
+    fn from_map(tx: &Map, d: &mut Directive<Decimal>) -> anyhow::Result<()> {
+        if let Some(date) = opt_string(tx, "date")? {
+            d.date = chrono::NaiveDate::parse_from_str(&date, "%Y-%m-%d")
+                .with_context(|| format!("invalid tx.date {:?}", date))?;
+        }
+        if let Some(metadata) = tx.get("metadata") {
+            let metadata: Map = metadata
+                .clone()
+                .try_cast()
+                .context("tx.metadata has to be a map")?;
+            d.metadata.retain(|k, v| {
+                !matches!(v, MetadataValue::String(_)) || metadata.contains_key(k.as_str())
+            });
+            for (key, value) in metadata {
+                let value = value.into_string().map_err(|t| {
+                    anyhow::format_err!("tx.metadata.{} has to be a string, not {}", key, t)
+                })?;
+                d.metadata
+                    .insert(key.to_string(), MetadataValue::String(value));
+            }
+        }
+        let counter = counter_account(d);
+        let DirectiveContent::Transaction(t) = &mut d.content else {
+            return Ok(());
+        };
+        t.payee = opt_string(tx, "payee")?;
+        t.narration = opt_string(tx, "narration")?;
+        t.flag = opt_string(tx, "flag")?.and_then(|f| f.chars().next());
+        if let Some(tags) = tx.get("tags") {
+            let tags: Array = tags
+                .clone()
+                .try_cast()
+                .context("tx.tags has to be an array")?;
+            t.tags = tags
+                .into_iter()
+                .map(|tag| {
+                    tag.into_string().map_err(|t| {
+                        anyhow::format_err!("tx.tags has to contain strings, not {}", t)
+                    })
+                })
+                .collect::<anyhow::Result<_>>()?;
+        }
+        let new_counter = opt_string(tx, "counter_account")?;
+        if new_counter != counter {
+            t.postings.truncate(1);
+            if let Some(account) = new_counter {
+                t.postings.push(counter_posting(&account, None));
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs the script on the transaction imported into the account. Returns false if the script
+    /// dropped it.
+    pub fn apply(&self, d: &mut Directive<Decimal>, account: &Account) -> anyhow::Result<bool> {
+        let mut scope = Scope::new();
+        scope.push("tx", Script::to_map(d, account));
+        self.engine
+            .run_ast_with_scope(&mut scope, &self.ast)
+            .map_err(|e| anyhow::format_err!("the script failed: {}", e))?;
+        let tx: Map = scope
+            .get_value("tx")
+            .context("the script has to keep tx a map")?;
+        if tx.get("drop").is_some_and(|v| v.as_bool() == Ok(true)) {
+            return Ok(false);
+        }
+        Script::from_map(&tx, d)?;
+        Ok(true)
+    }
+}
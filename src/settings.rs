@@ -0,0 +1,258 @@
+//! Global settings that don't belong in the ledger, read from
+//! `~/.config/gocardless-importer/config.toml` (or the file given by `--config`). Every setting
+//! can be overridden with a `GOCARDLESS_<NAME>` environment variable, and the command line flags
+//! take precedence over both.
+
+use crate::{format, output::OutputFormat, quirks, ImportOptions};
+use anyhow::Context;
+use serde::Deserialize;
+use serde_with::{serde_as, DisplayFromStr};
+use std::{collections::BTreeMap, path::PathBuf, sync::OnceLock, time::Duration};
+
+#[serde_as]
+#[derive(Deserialize, Default, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Settings {
+    /// Base URL of the GoCardless API.
+    pub api_url: Option<String>,
+    /// Default country of `list-institutions`.
+    pub country: Option<String>,
+    /// Default categorization rules file.
+    pub rules: Option<PathBuf>,
+    /// Default output format of the listing commands.
+    pub output: Option<OutputFormat>,
+    /// Assert unchanged balances again after this many days (see `--balance-interval-days`).
+    pub balance_interval_days: Option<u64>,
+    /// Calls a day the API allows to each account endpoint (4 by default).
+    pub daily_account_calls: Option<u32>,
+    /// Time zone of the dates derived from the date-times of the transactions (by default, the
+    /// one of the country of the institution, or the local one).
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    pub timezone: Option<chrono_tz::Tz>,
+    /// Ledgers imported by `import` without any given.
+    #[serde(default)]
+    pub ledgers: Vec<PathBuf>,
+    #[serde(default)]
+    pub http: Http,
+    /// Institution ID -> quirks of the institution.
+    #[serde(default)]
+    pub quirks: BTreeMap<String, quirks::Profile>,
+    #[serde(default)]
+    pub retry: Retry,
+    #[serde(default)]
+    pub notify: Notify,
+    /// Formatting style of the imported directives.
+    #[serde(default)]
+    pub format: format::Style,
+}
+
+/// Connection settings of the HTTP client (the API and the notification webhook). The proxy from
+/// `HTTPS_PROXY`/`HTTP_PROXY` (and `NO_PROXY`) is used, unless one is configured here.
+#[serde_as]
+#[derive(Deserialize, Default, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Http {
+    /// Proxy of all the requests.
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    pub proxy: Option<reqwest::Url>,
+    /// PEM file with additional trusted CA certificates (e.g. of a corporate proxy).
+    pub ca_bundle: Option<PathBuf>,
+    /// Don't verify the TLS certificates. Dangerous, use only for debugging.
+    #[serde(default)]
+    pub insecure: bool,
+    /// Timeout of a single request (the slow banks can take long to return a large transaction
+    /// history). The requests that timed out are retried.
+    pub timeout_seconds: Option<u64>,
+    /// User-Agent header of the requests.
+    pub user_agent: Option<String>,
+}
+
+/// Timeout of the requests, unless configured otherwise.
+pub const DEFAULT_TIMEOUT_SECONDS: u64 = 120;
+
+impl Http {
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout_seconds.unwrap_or(DEFAULT_TIMEOUT_SECONDS))
+    }
+
+    pub fn user_agent(&self) -> String {
+        self.user_agent
+            .clone()
+            .unwrap_or_else(|| format!("{}/{}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")))
+    }
+}
+
+/// Retries of the API requests that failed because of a network or server error.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Retry {
+    /// Number of attempts, including the first one.
+    pub attempts: u32,
+    /// Delay before the first retry, doubled after every attempt.
+    pub backoff_seconds: u64,
+}
+
+impl Default for Retry {
+    fn default() -> Self {
+        Retry {
+            attempts: 3,
+            backoff_seconds: 2,
+        }
+    }
+}
+
+impl Retry {
+    /// Delay before the attempt (counted from 1).
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        Duration::from_secs(self.backoff_seconds << (attempt - 1).min(16))
+    }
+}
+
+#[serde_as]
+#[derive(Deserialize, Default, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Notify {
+    pub command: Option<String>,
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    pub url: Option<reqwest::Url>,
+}
+
+static SETTINGS: OnceLock<Settings> = OnceLock::new();
+
+/// The default location of the settings file.
+pub fn default_path() -> anyhow::Result<PathBuf> {
+    let config_home = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(std::env::var("HOME")?).join(".config"),
+    };
+    Ok(config_home.join("gocardless-importer").join("config.toml"))
+}
+
+fn env(name: &str) -> Option<String> {
+    std::env::var(format!("GOCARDLESS_{}", name))
+        .ok()
+        .filter(|v| !v.is_empty())
+}
+
+fn parse_env<T: std::str::FromStr>(name: &str) -> anyhow::Result<Option<T>>
+where
+    T::Err: std::fmt::Display,
+{
+    env(name)
+        .map(|v| {
+            v.parse()
+                .map_err(|e| anyhow::format_err!("invalid GOCARDLESS_{}: {}", name, e))
+        })
+        .transpose()
+}
+
+impl Settings {
+    /// Reads the settings file and applies the environment overrides. A missing file is fine,
+    /// unless it was given explicitly.
+    pub fn load(path: Option<PathBuf>) -> anyhow::Result<Settings> {
+        let explicit = path.is_some();
+        let path = match path {
+            Some(path) => path,
+            None => default_path()?,
+        };
+        let mut settings: Settings = match std::fs::read_to_string(&path) {
+            Ok(content) => toml::from_str(&content)
+                .with_context(|| format!("failed to parse {}", path.display()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound && !explicit => Settings::default(),
+            Err(e) => return Err(e).with_context(|| format!("failed to read {}", path.display())),
+        };
+        if let Some(v) = env("API_URL") {
+            settings.api_url = Some(v);
+        }
+        if let Some(v) = env("COUNTRY") {
+            settings.country = Some(v);
+        }
+        if let Some(v) = env("RULES") {
+            settings.rules = Some(v.into());
+        }
+        if let Some(v) = env("OUTPUT") {
+            settings.output = Some(
+                clap::ValueEnum::from_str(&v, true)
+                    .map_err(|e| anyhow::format_err!("invalid GOCARDLESS_OUTPUT: {}", e))?,
+            );
+        }
+        if let Some(v) = parse_env("BALANCE_INTERVAL_DAYS")? {
+            settings.balance_interval_days = Some(v);
+        }
+        if let Some(v) = parse_env("DAILY_ACCOUNT_CALLS")? {
+            settings.daily_account_calls = Some(v);
+        }
+        if let Some(v) = parse_env("TIMEZONE")? {
+            settings.timezone = Some(v);
+        }
+        if let Some(v) = env("LEDGERS") {
+            settings.ledgers = std::env::split_paths(&v).collect();
+        }
+        if let Some(v) = parse_env("HTTP_PROXY")? {
+            settings.http.proxy = Some(v);
+        }
+        if let Some(v) = env("HTTP_CA_BUNDLE") {
+            settings.http.ca_bundle = Some(v.into());
+        }
+        if let Some(v) = parse_env("HTTP_INSECURE")? {
+            settings.http.insecure = v;
+        }
+        if let Some(v) = parse_env("HTTP_TIMEOUT_SECONDS")? {
+            settings.http.timeout_seconds = Some(v);
+        }
+        if let Some(v) = env("HTTP_USER_AGENT") {
+            settings.http.user_agent = Some(v);
+        }
+        if let Some(v) = parse_env("RETRY_ATTEMPTS")? {
+            settings.retry.attempts = v;
+        }
+        if let Some(v) = parse_env("RETRY_BACKOFF_SECONDS")? {
+            settings.retry.backoff_seconds = v;
+        }
+        if let Some(v) = env("NOTIFY_COMMAND") {
+            settings.notify.command = Some(v);
+        }
+        if let Some(v) = parse_env("NOTIFY_URL")? {
+            settings.notify.url = Some(v);
+        }
+        if let Some(v) = parse_env("FORMAT_INDENT")? {
+            settings.format.indent = v;
+        }
+        if let Some(v) = parse_env("FORMAT_AMOUNT_COLUMN")? {
+            settings.format.amount_column = Some(v);
+        }
+        if let Some(v) = env("FORMAT_METADATA_ORDER") {
+            settings.format.metadata_order = v.split(',').map(|k| k.trim().to_string()).collect();
+        }
+        if let Some(v) = parse_env("FORMAT_LINKS_ON_LINE")? {
+            settings.format.links_on_line = v;
+        }
+        Ok(settings)
+    }
+
+    /// Fills the import options that were not given on the command line. The rules are resolved
+    /// by the import, since the ledger can configure them too.
+    pub fn apply(&self, options: &mut ImportOptions) {
+        if options.notify_command.is_none() {
+            options.notify_command = self.notify.command.clone();
+        }
+        if options.notify_url.is_none() {
+            options.notify_url = self.notify.url.clone();
+        }
+        if options.balance_interval_days.is_none() {
+            options.balance_interval_days = self.balance_interval_days;
+        }
+    }
+}
+
+pub fn set(settings: Settings) {
+    SETTINGS.set(settings).expect("settings are already set");
+}
+
+/// The global settings (the defaults, when they were not loaded).
+pub fn get() -> &'static Settings {
+    SETTINGS.get_or_init(Settings::default)
+}
@@ -0,0 +1,137 @@
+//! The `snapshot-balances` command: balance assertions of all the accounts, independent of the
+//! transaction imports (e.g. on the first day of every month, as an audit trail).
+
+use crate::{
+    amount, balance_date, ledger_config, ledger_io, progress, render_directive, source,
+    ImportOptions,
+};
+use anyhow::Context;
+use beanru::types::{Amount, Balance, Currency, Directive, DirectiveContent};
+use std::{collections::HashMap, path::PathBuf};
+use tracing::{info, warn};
+
+/// Fetches the current balance of each configured account and appends a `balance` directive to
+/// the file receiving the directives of the account, unless the ledger already asserts the
+/// balance on that date. Returns the number of added directives.
+pub async fn run(
+    beancount_path: &std::path::Path,
+    options: &ImportOptions,
+) -> anyhow::Result<usize> {
+    let _lock = ledger_io::lock_ledger(beancount_path)?;
+    let (ledger, snapshot) = ledger_io::read_ledger(beancount_path.to_path_buf()).await?;
+    let sources = source::all(options).await?;
+    let today = chrono::Local::now().date_naive();
+
+    let accounts = ledger_config::configured_accounts(&ledger)?;
+    let pb = progress::bar(accounts.len(), "Fetching balances");
+    let mut new_directives = vec![];
+    let mut failed = 0;
+    for account in accounts {
+        pb.set_message(account.account.to_string());
+        pb.inc(1);
+        let Some(importer) = account.metadata_str("importer") else {
+            continue;
+        };
+        let Some(source) = source::find(&sources, importer) else {
+            continue;
+        };
+        if importer == "gocardless" && account.metadata_str("account_id").is_none() {
+            continue;
+        }
+        if let Some(ref filter) = options.filter_account_re {
+            if !filter.is_match(&account.account.0) {
+                continue;
+            }
+        }
+        let res = match source.balances(&account).await {
+            Ok(res) => res,
+            Err(e) => {
+                warn!("{}: failed to fetch the balance: {:#}", account.account, e);
+                failed += 1;
+                continue;
+            }
+        };
+        let Some(b) = res
+            .balances
+            .iter()
+            .flatten()
+            .find(|b| account.imports_currency(&b.balance_amount.currency))
+        else {
+            warn!("{}: the bank reported no balance", account.account);
+            continue;
+        };
+        // Like the import, the pending transactions are not part of the asserted balance.
+        let pending = match source.transactions(&account).await {
+            Ok(res) => res.transactions.pending.unwrap_or_default(),
+            Err(e) => {
+                warn!(
+                    "{}: failed to fetch the pending transactions: {:#}",
+                    account.account, e
+                );
+                failed += 1;
+                continue;
+            }
+        };
+        let mut value = amount::parse(&b.balance_amount.amount, amount::API_SEPARATOR)
+            .with_context(|| format!("balance of {}", account.account))?;
+        for t in pending
+            .iter()
+            .filter(|t| t.transaction_amount.currency == b.balance_amount.currency)
+        {
+            value -= amount::transaction(t)
+                .with_context(|| format!("pending transaction of {}", account.account))?;
+        }
+        let d = Directive {
+            date: balance_date(b.reference_date.as_deref(), None, today)?,
+            content: DirectiveContent::Balance(Balance {
+                account: account.account.clone(),
+                amount: Amount {
+                    value,
+                    currency: Currency(b.balance_amount.currency.clone()),
+                },
+            }),
+            metadata: Default::default(),
+        };
+        let tolerance = account.metadata_decimal("balance_tolerance")?;
+        new_directives.push((ledger_config::target_file(&ledger, &account)?, d, tolerance));
+    }
+    pb.finish_and_clear();
+
+    // The balances are rendered separately and appended to the files, to include the tolerances.
+    let mut batches: HashMap<PathBuf, String> = HashMap::new();
+    let mut added = 0;
+    for (path, d, tolerance) in new_directives {
+        let DirectiveContent::Balance(new) = &d.content else {
+            continue;
+        };
+        let asserted = ledger.files.values().flat_map(|f| &f.directives).any(|e| {
+            e.date == d.date
+                && matches!(&e.content, DirectiveContent::Balance(b)
+                    if b.account == new.account && b.amount.currency == new.amount.currency)
+        });
+        if asserted {
+            info!(
+                "{}: the balance on {} is already asserted",
+                new.account, d.date
+            );
+            continue;
+        }
+        let tolerances = HashMap::from_iter(tolerance.map(|t| (new.account.clone(), t)));
+        *batches.entry(path).or_default() += &format!("\n{}\n", render_directive(&d, &tolerances));
+        added += 1;
+    }
+    if added > 0 {
+        snapshot.check_unmodified().await?;
+        ledger
+            .write(|p, content| {
+                let content = snapshot.restore(&p, content);
+                let batch = batches.get(&p).cloned().unwrap_or_default();
+                async move { Ok(tokio::fs::write(p, content + batch.as_str()).await?) }
+            })
+            .await?;
+    }
+    if failed > 0 {
+        anyhow::bail!("failed to fetch the balance of {} account(s)", failed);
+    }
+    Ok(added)
+}
@@ -0,0 +1,158 @@
+use ::gocardless::models::{AccountBalance, AccountTransactions, TransactionSchema};
+use anyhow::Context;
+use async_trait::async_trait;
+use beanru::types::{Account, MetadataValue};
+use rust_decimal::Decimal;
+use std::{collections::HashMap, path::PathBuf};
+
+pub mod camt;
+pub mod csv;
+pub mod gocardless;
+pub mod mt940;
+pub mod statement;
+
+/// An account of the ledger configured to be imported from one of the sources.
+#[derive(Clone, Debug)]
+pub struct SourceAccount {
+    pub account: Account,
+    /// Metadata of the `open` (or `custom "gocardless" "account"`) directive of the account,
+    /// configuring the source (e.g. `account_id`).
+    pub metadata: HashMap<String, MetadataValue<Decimal>>,
+    /// The ledger file with the configuring directive.
+    pub file: PathBuf,
+}
+
+impl SourceAccount {
+    pub fn metadata_str(&self, key: &str) -> Option<&str> {
+        match self.metadata.get(key) {
+            Some(MetadataValue::String(s)) => Some(s),
+            _ => None,
+        }
+    }
+
+    /// Returns the metadata entry required by the source.
+    pub fn required_metadata(&self, key: &str) -> anyhow::Result<&str> {
+        self.metadata_str(key).ok_or_else(|| {
+            anyhow::format_err!("account {} has no {:?} metadata", self.account, key)
+        })
+    }
+
+    /// The metadata entry with a number (written as a number or a string).
+    pub fn metadata_decimal(&self, key: &str) -> anyhow::Result<Option<Decimal>> {
+        Ok(match self.metadata.get(key) {
+            None => None,
+            Some(MetadataValue::Number(n)) => Some(*n),
+            Some(MetadataValue::String(s)) => {
+                Some(crate::amount::parse(s, crate::amount::API_SEPARATOR)?)
+            }
+            Some(_) => anyhow::bail!("{}: {} has to be a number", self.account, key),
+        })
+    }
+
+    /// The decimal separator of the amounts returned by the bank (the `decimal_separator`
+    /// metadata), `.` as in the API format by default.
+    pub fn decimal_separator(&self) -> anyhow::Result<char> {
+        match self.metadata_str("decimal_separator") {
+            None | Some(".") => Ok('.'),
+            Some(",") => Ok(','),
+            Some(s) => anyhow::bail!(
+                "{}: invalid decimal_separator {:?}, use \".\" or \",\"",
+                self.account,
+                s
+            ),
+        }
+    }
+
+    pub fn exclusions(&self) -> anyhow::Result<Exclusions> {
+        let min_amount = self.metadata_decimal("min_amount")?;
+        let narration = self
+            .metadata_str("exclude_narration")
+            .map(regex::Regex::new)
+            .transpose()
+            .with_context(|| format!("{}: invalid exclude_narration", self.account))?;
+        Ok(Exclusions {
+            min_amount,
+            narration,
+        })
+    }
+
+    /// Whether the transactions in the currency are imported: all of them, unless the account
+    /// lists the currencies in the `currencies` metadata (e.g. `"EUR,USD"`).
+    pub fn imports_currency(&self, currency: &str) -> bool {
+        match self.metadata_str("currencies") {
+            None => true,
+            Some(currencies) => currencies
+                .split(',')
+                .any(|c| c.trim().eq_ignore_ascii_case(currency)),
+        }
+    }
+}
+
+/// Transactions of the account that are never imported, configured with the `min_amount` (e.g.
+/// `0.01`, compared with the absolute amount) and `exclude_narration` (a regular expression)
+/// metadata.
+#[derive(Default, Debug)]
+pub struct Exclusions {
+    min_amount: Option<Decimal>,
+    narration: Option<regex::Regex>,
+}
+
+impl Exclusions {
+    pub fn excludes(&self, t: &TransactionSchema) -> bool {
+        if let Some(min) = self.min_amount {
+            if crate::amount::transaction(t).is_ok_and(|a| a.abs() < min) {
+                return true;
+            }
+        }
+        if let Some(re) = &self.narration {
+            if crate::narration(t).is_some_and(|n| re.is_match(&n)) {
+                return true;
+            }
+        }
+        false
+    }
+}
+
+/// A source of transactions and balances. The transactions are returned in the GoCardless format,
+/// so all the sources share the conversion, deduplication, rules and balance assertions.
+#[async_trait]
+pub trait ImportSource: Send + Sync {
+    /// Name of the source, used in the `importer` metadata of the accounts.
+    fn name(&self) -> &str;
+
+    /// IDs of the accounts available in the source.
+    async fn accounts(&self) -> anyhow::Result<Vec<String>>;
+
+    /// Booked and pending transactions of the account, newest first.
+    async fn transactions(&self, account: &SourceAccount) -> anyhow::Result<AccountTransactions>;
+
+    /// Current balances of the account. The first one is used for the balance assertion.
+    async fn balances(&self, account: &SourceAccount) -> anyhow::Result<AccountBalance>;
+
+    /// The metadata key with the path of the statement files and their extensions, for the
+    /// sources that import files.
+    fn statement_files(&self) -> Option<(&'static str, &'static [&'static str])> {
+        None
+    }
+}
+
+/// All the sources, configured with the options of the import.
+pub async fn all(options: &crate::ImportOptions) -> anyhow::Result<Vec<Box<dyn ImportSource>>> {
+    Ok(vec![
+        Box::new(gocardless::GoCardless::new(
+            options.offline,
+            options.backfill.clone(),
+        )?),
+        Box::new(csv::Csv::load(options.csv_profiles.as_deref()).await?),
+        Box::new(camt::Camt),
+        Box::new(mt940::Mt940),
+    ])
+}
+
+/// Returns the source with the given name.
+pub fn find<'a>(sources: &'a [Box<dyn ImportSource>], name: &str) -> Option<&'a dyn ImportSource> {
+    sources
+        .iter()
+        .find(|s| s.name() == name)
+        .map(|s| s.as_ref())
+}
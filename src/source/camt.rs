@@ -0,0 +1,345 @@
+use super::{
+    statement::{self, ClosingBalance, HashedIds, Statement},
+    ImportSource, SourceAccount,
+};
+use anyhow::Context;
+use async_trait::async_trait;
+use chrono::NaiveDate;
+use gocardless::models::{AccountBalance, AccountSchema, AccountTransactions, TransactionSchema};
+use roxmltree::Node;
+use rust_decimal::Decimal;
+use std::path::Path;
+
+/// Returns the first descendant at the path of element names (ignoring the namespaces).
+fn find<'a, 'input>(node: Node<'a, 'input>, path: &[&str]) -> Option<Node<'a, 'input>> {
+    let Some((name, rest)) = path.split_first() else {
+        return Some(node);
+    };
+    node.children()
+        .filter(|c| c.tag_name().name() == *name)
+        .find_map(|c| find(c, rest))
+}
+
+fn text<'a>(node: Node<'a, '_>, path: &[&str]) -> Option<&'a str> {
+    find(node, path)?
+        .text()
+        .map(|t| t.trim())
+        .filter(|t| !t.is_empty())
+}
+
+fn children<'a, 'input>(
+    node: Node<'a, 'input>,
+    name: &'static str,
+) -> impl Iterator<Item = Node<'a, 'input>> {
+    node.children().filter(move |c| c.tag_name().name() == name)
+}
+
+/// Date of `Dt` or `DtTm` element.
+fn date(node: Option<Node>) -> Option<NaiveDate> {
+    let node = node?;
+    let s = text(node, &["Dt"]).or_else(|| text(node, &["DtTm"]))?;
+    NaiveDate::parse_and_remainder(s, "%Y-%m-%d")
+        .ok()
+        .map(|(d, _)| d)
+}
+
+/// The amount of the `Amt` element, negative with the `DBIT` indicator.
+fn signed(amt: Node, indicator: Option<&str>) -> anyhow::Result<(Decimal, String)> {
+    let mut value: Decimal = amt
+        .text()
+        .unwrap_or_default()
+        .trim()
+        .parse()
+        .context("invalid amount")?;
+    if indicator == Some("DBIT") {
+        value = -value;
+    }
+    let currency = amt.attribute("Ccy").unwrap_or_default().to_string();
+    Ok((value, currency))
+}
+
+/// Signed amount of the element with `Amt` and `CdtDbtInd` children.
+fn amount(node: Node) -> anyhow::Result<(Decimal, String)> {
+    let amt = find(node, &["Amt"]).context("amount is missing")?;
+    signed(amt, text(node, &["CdtDbtInd"]))
+}
+
+/// Signed amount of the transaction details of a batch entry (`Amt` or `AmtDtls/TxAmt/Amt`), with
+/// the indicator of the details or of the entry.
+fn details_amount(details: Node, entry: Node) -> anyhow::Result<Option<(Decimal, String)>> {
+    let Some(amt) = find(details, &["Amt"]).or_else(|| find(details, &["AmtDtls", "TxAmt", "Amt"]))
+    else {
+        return Ok(None);
+    };
+    let indicator = text(details, &["CdtDbtInd"]).or_else(|| text(entry, &["CdtDbtInd"]));
+    Ok(Some(signed(amt, indicator)?))
+}
+
+/// Party of the transaction details; camt.053.001.08 and later wrap it in `Pty`.
+fn party_name(details: Node, party: &str) -> Option<String> {
+    text(details, &["RltdPties", party, "Nm"])
+        .or_else(|| text(details, &["RltdPties", party, "Pty", "Nm"]))
+        .map(|s| s.to_string())
+}
+
+fn iban(details: Node, account: &str) -> Option<AccountSchema> {
+    let iban = text(details, &["RltdPties", account, "Id", "IBAN"])?;
+    Some(AccountSchema {
+        iban: Some(iban.to_string()),
+        ..Default::default()
+    })
+}
+
+/// The transaction of the entry with the transaction details (or of the whole entry without
+/// them). Without an id from the bank, the id is derived from the contents.
+fn transaction(
+    entry: Node,
+    details: Option<Node>,
+    booking_date: NaiveDate,
+    value: Decimal,
+    currency: String,
+    id: Option<String>,
+    ids: &mut HashedIds,
+) -> TransactionSchema {
+    let mut narration: Vec<&str> = vec![];
+    if let Some(d) = details {
+        narration.extend(
+            children(find(d, &["RmtInf"]).unwrap_or(d), "Ustrd")
+                .filter_map(|n| n.text().map(|t| t.trim())),
+        );
+    }
+    if narration.is_empty() {
+        narration.extend(text(entry, &["AddtlNtryInf"]));
+    }
+    let (counterparty, counterparty_account) = match details {
+        Some(d) if value.is_sign_negative() => (party_name(d, "Cdtr"), iban(d, "CdtrAcct")),
+        Some(d) => (party_name(d, "Dbtr"), iban(d, "DbtrAcct")),
+        None => (None, None),
+    };
+    let id = match id {
+        Some(id) => id,
+        None => ids.id(
+            "camt",
+            &[
+                &booking_date.to_string(),
+                &value.to_string(),
+                &currency,
+                &narration.join(" "),
+            ],
+        ),
+    };
+    let mut t = statement::transaction(
+        id,
+        booking_date,
+        value,
+        currency,
+        Some(narration.join(" ")),
+        counterparty,
+    );
+    t.value_date = date(find(entry, &["ValDt"])).map(|d| d.to_string());
+    t.end_to_end_id = details
+        .and_then(|d| text(d, &["Refs", "EndToEndId"]))
+        .filter(|r| *r != "NOTPROVIDED")
+        .map(|r| r.to_string());
+    let domain = find(entry, &["BkTxCd", "Domn"])
+        .or_else(|| details.and_then(|d| find(d, &["BkTxCd", "Domn"])));
+    t.bank_transaction_code = domain.map(|d| {
+        format!(
+            "{}-{}-{}",
+            text(d, &["Cd"]).unwrap_or_default(),
+            text(d, &["Fmly", "Cd"]).unwrap_or_default(),
+            text(d, &["Fmly", "SubFmlyCd"]).unwrap_or_default()
+        )
+    });
+    t.proprietary_bank_transaction_code =
+        text(entry, &["BkTxCd", "Prtry", "Cd"]).map(|c| c.to_string());
+    if value.is_sign_negative() {
+        t.creditor_account = counterparty_account.map(Box::new);
+    } else {
+        t.debtor_account = counterparty_account.map(Box::new);
+    }
+    t
+}
+
+/// Parses an ISO 20022 camt.053 (bank to customer statement) file.
+fn read(path: &Path) -> anyhow::Result<Statement> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read {}", path.display()))?;
+    let doc = roxmltree::Document::parse(&content)
+        .with_context(|| format!("failed to parse {}", path.display()))?;
+    let root = find(doc.root(), &["Document", "BkToCstmrStmt"])
+        .with_context(|| format!("{} is not a camt.053 statement", path.display()))?;
+
+    let mut statement = Statement::default();
+    let mut ids = HashedIds::default();
+    for stmt in children(root, "Stmt") {
+        let mut part = Statement::default();
+        for bal in children(stmt, "Bal") {
+            let code = text(bal, &["Tp", "CdOrPrtry", "Cd"]);
+            if code != Some("CLBD") {
+                continue;
+            }
+            let (amount, currency) = amount(bal)?;
+            // The closing booked balance is at the end of the day.
+            let date = date(find(bal, &["Dt"]))
+                .and_then(|d| d.succ_opt())
+                .context("balance date is missing")?;
+            part.balance = Some(ClosingBalance {
+                date,
+                amount,
+                currency,
+            });
+        }
+        for entry in children(stmt, "Ntry") {
+            let status = text(entry, &["Sts"]).or_else(|| text(entry, &["Sts", "Cd"]));
+            if status.is_some_and(|s| s != "BOOK") {
+                continue;
+            }
+            let (value, currency) = amount(entry)?;
+            let booking_date = date(find(entry, &["BookgDt"]))
+                .or_else(|| date(find(entry, &["ValDt"])))
+                .context("booking date is missing")?;
+            let details: Vec<Node> = children(entry, "NtryDtls")
+                .flat_map(|d| children(d, "TxDtls"))
+                .collect();
+            let entry_id = text(entry, &["AcctSvcrRef"]).or_else(|| text(entry, &["NtryRef"]));
+            // A batch booking has the total in the entry and the transactions in the details.
+            // Unless every one of them has its amount, the entry is imported as one transaction.
+            let mut amounts = vec![];
+            for d in &details {
+                amounts.extend(details_amount(*d, entry)?);
+            }
+            if details.len() > 1 && amounts.len() == details.len() {
+                for (i, (d, (value, currency))) in details.iter().zip(amounts).enumerate() {
+                    let id = text(*d, &["Refs", "AcctSvcrRef"])
+                        .map(|r| r.to_string())
+                        .or_else(|| entry_id.map(|id| format!("{}-{}", id, i + 1)));
+                    part.booked.push(transaction(
+                        entry,
+                        Some(*d),
+                        booking_date,
+                        value,
+                        currency,
+                        id,
+                        &mut ids,
+                    ));
+                }
+            } else {
+                part.booked.push(transaction(
+                    entry,
+                    details.first().copied(),
+                    booking_date,
+                    value,
+                    currency,
+                    entry_id.map(|id| id.to_string()),
+                    &mut ids,
+                ));
+            }
+        }
+        statement.merge(part);
+    }
+    Ok(statement)
+}
+
+/// Metadata key with the path of the statements.
+const PATH_KEY: &str = "camt_path";
+const EXTENSIONS: &[&str] = &["xml"];
+
+/// Accounts imported from camt.053 statements, configured with the `camt_path` metadata (a file
+/// or a directory with `.xml` files, relative to the ledger file).
+pub struct Camt;
+
+#[async_trait]
+impl ImportSource for Camt {
+    fn name(&self) -> &str {
+        "camt"
+    }
+
+    fn statement_files(&self) -> Option<(&'static str, &'static [&'static str])> {
+        Some((PATH_KEY, EXTENSIONS))
+    }
+
+    async fn accounts(&self) -> anyhow::Result<Vec<String>> {
+        Ok(vec![])
+    }
+
+    async fn transactions(&self, account: &SourceAccount) -> anyhow::Result<AccountTransactions> {
+        Ok(statement(account)?.transactions())
+    }
+
+    async fn balances(&self, account: &SourceAccount) -> anyhow::Result<AccountBalance> {
+        Ok(statement(account)?.balances())
+    }
+}
+
+fn statement(account: &SourceAccount) -> anyhow::Result<Statement> {
+    let mut statement = Statement::default();
+    for path in statement::files(account, PATH_KEY, EXTENSIONS)? {
+        statement.merge(read(&path)?);
+    }
+    Ok(statement)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_the_statement() {
+        let statement = read(Path::new("tests/data/statement.xml")).unwrap();
+        // The pending entry is not imported.
+        let [payment, salary] = &statement.booked[..] else {
+            panic!("expected two transactions, got {}", statement.booked.len());
+        };
+
+        assert_eq!(payment.internal_transaction_id.as_deref(), Some("E1"));
+        assert_eq!(payment.booking_date.as_deref(), Some("2023-01-02"));
+        assert_eq!(payment.value_date.as_deref(), Some("2023-01-01"));
+        assert_eq!(payment.transaction_amount.amount, "-12.50");
+        assert_eq!(payment.transaction_amount.currency, "EUR");
+        assert_eq!(payment.creditor_name.as_deref(), Some("Coffee Shop"));
+        assert_eq!(
+            payment.remittance_information_unstructured.as_deref(),
+            Some("Card payment")
+        );
+        assert_eq!(
+            payment.bank_transaction_code.as_deref(),
+            Some("PMNT-CCRD-POSD")
+        );
+        assert_eq!(payment.end_to_end_id, None);
+
+        assert_eq!(salary.booking_date.as_deref(), Some("2023-01-04"));
+        assert_eq!(salary.value_date.as_deref(), Some("2023-01-03"));
+        assert_eq!(salary.transaction_amount.amount, "250.00");
+        assert_eq!(salary.debtor_name.as_deref(), Some("ACME Ltd"));
+        assert_eq!(
+            salary
+                .debtor_account
+                .as_ref()
+                .and_then(|a| a.iban.as_deref()),
+            Some("GB33BUKB20201555555555")
+        );
+        assert_eq!(salary.end_to_end_id.as_deref(), Some("SAL-2023-01"));
+        assert!(salary
+            .internal_transaction_id
+            .as_deref()
+            .is_some_and(|id| id.starts_with("camt-")));
+
+        let balance = statement.balance.unwrap();
+        assert_eq!(balance.date, NaiveDate::from_ymd_opt(2023, 1, 5).unwrap());
+        assert_eq!(balance.amount, "1237.50".parse().unwrap());
+        assert_eq!(balance.currency, "EUR");
+    }
+
+    #[test]
+    fn rejects_an_invalid_amount() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("invalid.xml");
+        let content = std::fs::read_to_string("tests/data/statement.xml")
+            .unwrap()
+            .replace(">12.50<", ">12,50 EUR<");
+        std::fs::write(&path, content).unwrap();
+        let e = read(&path).unwrap_err();
+        assert!(format!("{:#}", e).contains("invalid amount"), "{:#}", e);
+    }
+}
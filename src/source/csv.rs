@@ -0,0 +1,436 @@
+use super::{
+    statement::{self, ClosingBalance, HashedIds, Statement},
+    ImportSource, SourceAccount,
+};
+use crate::amount;
+use anyhow::Context;
+use async_trait::async_trait;
+use chrono::NaiveDate;
+use gocardless::models::{AccountBalance, AccountTransactions};
+use rust_decimal::Decimal;
+use serde::Deserialize;
+use std::{collections::HashMap, path::Path};
+
+/// Layout of the CSV statements of a bank. The columns are referenced by their header.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct CsvProfile {
+    #[serde(default = "default_delimiter")]
+    pub delimiter: char,
+    /// Number of lines before the header.
+    #[serde(default)]
+    pub skip_rows: usize,
+    pub date_column: String,
+    #[serde(default = "default_date_format")]
+    pub date_format: String,
+    /// Column with the signed amount. Alternatively, `debit_column` and `credit_column` can be
+    /// used for banks with separate columns for outgoing and incoming amounts.
+    pub amount_column: Option<String>,
+    pub debit_column: Option<String>,
+    pub credit_column: Option<String>,
+    #[serde(default = "default_decimal_separator")]
+    pub decimal_separator: char,
+    /// Column with the currency, or the currency of all transactions.
+    pub currency_column: Option<String>,
+    pub currency: Option<String>,
+    /// Columns joined into the narration.
+    #[serde(default)]
+    pub narration_columns: Vec<String>,
+    pub counterparty_column: Option<String>,
+    /// Column with a unique id of the transaction. Without it, the id is a hash of the row.
+    pub id_column: Option<String>,
+    /// Column with the balance after the transaction, used for the balance assertion.
+    pub balance_column: Option<String>,
+}
+
+fn default_delimiter() -> char {
+    ','
+}
+
+fn default_date_format() -> String {
+    "%Y-%m-%d".into()
+}
+
+fn default_decimal_separator() -> char {
+    '.'
+}
+
+fn metadata_yaml(value: &beanru::types::MetadataValue<Decimal>) -> serde_yaml::Value {
+    use beanru::types::MetadataValue;
+    match value {
+        // Allows lists in the metadata, e.g. `csv_narration_columns: "[Title, Reference]"`. The
+        // other strings are kept as they are, so a column named e.g. `yes` or `Date: booked` is
+        // not turned into something else.
+        MetadataValue::String(s) if s.trim_start().starts_with('[') => {
+            serde_yaml::from_str(s).unwrap_or_else(|_| serde_yaml::Value::String(s.clone()))
+        }
+        MetadataValue::String(s) => serde_yaml::Value::String(s.clone()),
+        MetadataValue::Number(n) => serde_yaml::from_str(&n.to_string()).unwrap_or_default(),
+        MetadataValue::Bool(b) => serde_yaml::Value::Bool(*b),
+        MetadataValue::Currency(c) => serde_yaml::Value::String(c.0.clone()),
+        MetadataValue::Account(a) => serde_yaml::Value::String(a.0.clone()),
+        MetadataValue::Date(d) => serde_yaml::Value::String(d.to_string()),
+    }
+}
+
+/// Metadata key with the path of the statements.
+const PATH_KEY: &str = "csv_path";
+const EXTENSIONS: &[&str] = &["csv"];
+
+/// Accounts imported from CSV statements downloaded from the bank. The account is configured with
+/// `csv_*` metadata: `csv_path` (a file or a directory with `.csv` files, relative to the ledger
+/// file), `csv_profile` (a profile from the profiles file) and any of the profile fields, e.g.
+/// `csv_date_column`.
+#[derive(Default)]
+pub struct Csv {
+    profiles: HashMap<String, serde_yaml::Mapping>,
+}
+
+impl Csv {
+    /// Loads the profiles (a YAML map of profile names to `CsvProfile`s).
+    pub async fn load(path: Option<&Path>) -> anyhow::Result<Csv> {
+        let Some(path) = path else {
+            return Ok(Csv::default());
+        };
+        let content = tokio::fs::read_to_string(path)
+            .await
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        let profiles = serde_yaml::from_str(&content)
+            .with_context(|| format!("failed to parse {}", path.display()))?;
+        Ok(Csv { profiles })
+    }
+
+    fn profile(&self, account: &SourceAccount) -> anyhow::Result<CsvProfile> {
+        let mut mapping = match account.metadata_str("csv_profile") {
+            Some(name) => self
+                .profiles
+                .get(name)
+                .cloned()
+                .with_context(|| format!("unknown CSV profile {:?}", name))?,
+            None => serde_yaml::Mapping::new(),
+        };
+        for (key, value) in &account.metadata {
+            let Some(field) = key.strip_prefix("csv_") else {
+                continue;
+            };
+            if field == "path" || field == "profile" {
+                continue;
+            }
+            mapping.insert(field.into(), metadata_yaml(value));
+        }
+        serde_yaml::from_value(serde_yaml::Value::Mapping(mapping))
+            .with_context(|| format!("invalid CSV profile of {}", account.account))
+    }
+
+    fn parse_amount(profile: &CsvProfile, s: &str) -> anyhow::Result<Option<Decimal>> {
+        if s.trim().is_empty() {
+            return Ok(None);
+        }
+        amount::parse(s, profile.decimal_separator).map(Some)
+    }
+
+    fn read(profile: &CsvProfile, path: &Path) -> anyhow::Result<Statement> {
+        if !profile.delimiter.is_ascii() {
+            anyhow::bail!(
+                "the CSV delimiter {:?} is not an ASCII character",
+                profile.delimiter
+            );
+        }
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        let content: String = content
+            .lines()
+            .skip(profile.skip_rows)
+            .collect::<Vec<_>>()
+            .join("\n");
+        let mut reader = ::csv::ReaderBuilder::new()
+            .delimiter(profile.delimiter as u8)
+            .flexible(true)
+            .from_reader(content.as_bytes());
+        let headers = reader.headers()?.clone();
+        let column = |name: &str| -> anyhow::Result<usize> {
+            headers
+                .iter()
+                .position(|h| h.trim() == name)
+                .with_context(|| format!("no column {:?} in {}", name, path.display()))
+        };
+        let date_column = column(&profile.date_column)?;
+        let amount_column = profile.amount_column.as_deref().map(column).transpose()?;
+        let debit_column = profile.debit_column.as_deref().map(column).transpose()?;
+        let credit_column = profile.credit_column.as_deref().map(column).transpose()?;
+        if amount_column.is_none() && debit_column.is_none() && credit_column.is_none() {
+            anyhow::bail!("the CSV profile has no amount column");
+        }
+        let currency_column = profile.currency_column.as_deref().map(column).transpose()?;
+        let narration_columns = profile
+            .narration_columns
+            .iter()
+            .map(|c| column(c))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let counterparty_column = profile
+            .counterparty_column
+            .as_deref()
+            .map(column)
+            .transpose()?;
+        let id_column = profile.id_column.as_deref().map(column).transpose()?;
+        let balance_column = profile.balance_column.as_deref().map(column).transpose()?;
+
+        let mut rows = vec![];
+        let mut ids = HashedIds::default();
+        for (i, record) in reader.records().enumerate() {
+            let record = record?;
+            let get = |c: usize| record.get(c).unwrap_or_default().trim();
+            let context = || format!("{}: row {}", path.display(), i + 1);
+            if record.iter().all(|f| f.trim().is_empty()) {
+                continue;
+            }
+            let date = NaiveDate::parse_from_str(get(date_column), &profile.date_format)
+                .with_context(|| format!("{}: invalid date {:?}", context(), get(date_column)))?;
+            let mut amount = Decimal::ZERO;
+            if let Some(c) = amount_column {
+                amount += Self::parse_amount(profile, get(c))
+                    .with_context(context)?
+                    .unwrap_or_default();
+            }
+            if let Some(c) = debit_column {
+                amount -= Self::parse_amount(profile, get(c))
+                    .with_context(context)?
+                    .unwrap_or_default()
+                    .abs();
+            }
+            if let Some(c) = credit_column {
+                amount += Self::parse_amount(profile, get(c))
+                    .with_context(context)?
+                    .unwrap_or_default()
+                    .abs();
+            }
+            let currency = match (currency_column, &profile.currency) {
+                (Some(c), _) => get(c).to_string(),
+                (None, Some(currency)) => currency.clone(),
+                (None, None) => anyhow::bail!("the CSV profile has no currency"),
+            };
+            let narration: Vec<&str> = narration_columns
+                .iter()
+                .map(|c| get(*c))
+                .filter(|s| !s.is_empty())
+                .collect();
+            let id = match id_column.map(get).filter(|s| !s.is_empty()) {
+                Some(id) => id.to_string(),
+                None => {
+                    let fields: Vec<&str> = record.iter().map(|f| f.trim()).collect();
+                    ids.id("csv", &fields)
+                }
+            };
+            let t = statement::transaction(
+                id,
+                date,
+                amount,
+                currency,
+                Some(narration.join(" ")),
+                counterparty_column.map(|c| get(c).to_string()),
+            );
+            let balance = balance_column
+                .map(|c| Self::parse_amount(profile, get(c)))
+                .transpose()
+                .with_context(context)?
+                .flatten();
+            rows.push((date, t, balance));
+        }
+        // Statements are ordered by date, but in either direction.
+        if rows.first().map(|r| r.0) > rows.last().map(|r| r.0) {
+            rows.reverse();
+        }
+        let balance = rows.iter().rev().find_map(|(date, t, balance)| {
+            Some(ClosingBalance {
+                date: date.succ_opt()?,
+                amount: (*balance)?,
+                currency: t.transaction_amount.currency.clone(),
+            })
+        });
+        Ok(Statement {
+            booked: rows.into_iter().map(|(_, t, _)| t).collect(),
+            balance,
+        })
+    }
+
+    /// All the statements of the account.
+    fn statement(&self, account: &SourceAccount) -> anyhow::Result<Statement> {
+        let profile = self.profile(account)?;
+        let mut statement = Statement::default();
+        for path in statement::files(account, PATH_KEY, EXTENSIONS)? {
+            statement.merge(Self::read(&profile, &path)?);
+        }
+        Ok(statement)
+    }
+}
+
+#[async_trait]
+impl ImportSource for Csv {
+    fn name(&self) -> &str {
+        "csv"
+    }
+
+    fn statement_files(&self) -> Option<(&'static str, &'static [&'static str])> {
+        Some((PATH_KEY, EXTENSIONS))
+    }
+
+    async fn accounts(&self) -> anyhow::Result<Vec<String>> {
+        Ok(vec![])
+    }
+
+    async fn transactions(&self, account: &SourceAccount) -> anyhow::Result<AccountTransactions> {
+        Ok(self.statement(account)?.transactions())
+    }
+
+    async fn balances(&self, account: &SourceAccount) -> anyhow::Result<AccountBalance> {
+        Ok(self.statement(account)?.balances())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn profile(yaml: &str) -> CsvProfile {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    fn read(profile: &CsvProfile, content: &str) -> anyhow::Result<Statement> {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("statement.csv");
+        std::fs::write(&path, content).unwrap();
+        Csv::read(profile, &path)
+    }
+
+    #[test]
+    fn maps_the_columns() {
+        let profile = profile(
+            r#"
+            delimiter: ";"
+            skip_rows: 2
+            date_column: "Datum"
+            date_format: "%d.%m.%Y"
+            debit_column: "Soll"
+            credit_column: "Haben"
+            decimal_separator: ","
+            currency: "EUR"
+            narration_columns: ["Buchungstext", "Verwendungszweck"]
+            counterparty_column: "Name"
+            id_column: "Referenz"
+            balance_column: "Saldo"
+            "#,
+        );
+        // Newest first, with the thousands separated by dots.
+        let statement = read(
+            &profile,
+            "Kontoauszug\n\
+             DE89370400440532013000\n\
+             Datum;Name;Buchungstext;Verwendungszweck;Soll;Haben;Saldo;Referenz\n\
+             04.01.2023;ACME Ltd;Gutschrift;Gehalt Januar;;1.250,00;2.237,50;R2\n\
+             02.01.2023;Coffee Shop;Kartenzahlung;;12,50;;987,50;R1\n",
+        )
+        .unwrap();
+        let [payment, salary] = &statement.booked[..] else {
+            panic!("expected two transactions, got {}", statement.booked.len());
+        };
+        assert_eq!(payment.internal_transaction_id.as_deref(), Some("R1"));
+        assert_eq!(payment.booking_date.as_deref(), Some("2023-01-02"));
+        assert_eq!(payment.transaction_amount.amount, "-12.50");
+        assert_eq!(payment.transaction_amount.currency, "EUR");
+        assert_eq!(payment.creditor_name.as_deref(), Some("Coffee Shop"));
+        assert_eq!(
+            payment.remittance_information_unstructured.as_deref(),
+            Some("Kartenzahlung")
+        );
+        assert_eq!(salary.transaction_amount.amount, "1250.00");
+        assert_eq!(salary.debtor_name.as_deref(), Some("ACME Ltd"));
+        assert_eq!(
+            salary.remittance_information_unstructured.as_deref(),
+            Some("Gutschrift Gehalt Januar")
+        );
+
+        let balance = statement.balance.unwrap();
+        assert_eq!(balance.date, NaiveDate::from_ymd_opt(2023, 1, 5).unwrap());
+        assert_eq!(balance.amount, "2237.50".parse().unwrap());
+    }
+
+    #[test]
+    fn signed_amounts() {
+        let profile = profile(
+            r#"
+            date_column: "Date"
+            amount_column: "Amount"
+            currency_column: "Currency"
+            "#,
+        );
+        let statement = read(
+            &profile,
+            "Date,Amount,Currency\n\
+             2023-01-02,\"-1,234.56\",USD\n\
+             2023-01-02,-1234.56,USD\n\
+             ,,\n\
+             2023-01-03,+20,USD\n",
+        )
+        .unwrap();
+        let amounts: Vec<&str> = statement
+            .booked
+            .iter()
+            .map(|t| t.transaction_amount.amount.as_str())
+            .collect();
+        assert_eq!(amounts, vec!["-1234.56", "-1234.56", "20"]);
+        // Identical rows without an id column get different ids.
+        assert_ne!(
+            statement.booked[0].internal_transaction_id,
+            statement.booked[1].internal_transaction_id
+        );
+        assert_eq!(statement.booked[0].transaction_amount.currency, "USD");
+        assert!(statement.balance.is_none());
+    }
+
+    #[test]
+    fn rejects_bad_rows() {
+        let profile = profile(
+            r#"
+            date_column: "Date"
+            amount_column: "Amount"
+            currency: "EUR"
+            "#,
+        );
+        let e = read(&profile, "Date,Amount\n2023-01-02,1.00\n02/01/2023,2.00\n").unwrap_err();
+        assert!(
+            format!("{:#}", e).contains("row 2: invalid date"),
+            "{:#}",
+            e
+        );
+
+        let e = read(&profile, "Date,Amount\n2023-01-02,one\n").unwrap_err();
+        assert!(format!("{:#}", e).contains("invalid amount"), "{:#}", e);
+
+        let e = read(&profile, "Day,Amount\n2023-01-02,1.00\n").unwrap_err();
+        assert!(format!("{:#}", e).contains("no column \"Date\""), "{:#}", e);
+
+        let profile = CsvProfile {
+            delimiter: '§',
+            ..profile
+        };
+        let e = read(&profile, "Date§Amount\n2023-01-02§1.00\n").unwrap_err();
+        assert!(
+            format!("{:#}", e).contains("not an ASCII character"),
+            "{:#}",
+            e
+        );
+    }
+
+    #[test]
+    fn metadata_strings_are_lists_only_in_brackets() {
+        use beanru::types::MetadataValue;
+        let yaml = |s: &str| metadata_yaml(&MetadataValue::String(s.to_string()));
+        assert_eq!(
+            yaml("[Title, Reference]"),
+            serde_yaml::from_str::<serde_yaml::Value>("[Title, Reference]").unwrap()
+        );
+        for s in ["yes", "Date: booked", "null", "2024"] {
+            assert_eq!(yaml(s), serde_yaml::Value::String(s.to_string()));
+        }
+    }
+}
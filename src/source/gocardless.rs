@@ -0,0 +1,304 @@
+use super::{ImportSource, SourceAccount};
+use crate::{
+    amount, config_with_token, quirks, quota, recording, requisitions, settings, store::Store,
+    timezone,
+};
+use anyhow::Context;
+use async_trait::async_trait;
+use beanru::types::Account;
+use gocardless::{
+    apis::configuration::Configuration,
+    models::{AccountBalance, AccountTransactions},
+};
+use std::sync::Mutex;
+use tokio::sync::OnceCell;
+use tracing::{debug, info, warn};
+
+/// Accounts connected through GoCardless requisitions. The fetched transactions and balances are
+/// saved in the local store, and the transactions are returned from the store, so they include
+/// the ones the bank no longer returns.
+pub struct GoCardless {
+    config: OnceCell<Configuration>,
+    store: Mutex<Store>,
+    offline: bool,
+    backfill: Option<Account>,
+}
+
+impl GoCardless {
+    /// In the offline mode, only the transactions and balances in the store are returned. The
+    /// transactions of the `backfill` account are requested for the longest period the bank
+    /// allows.
+    pub fn new(offline: bool, backfill: Option<Account>) -> anyhow::Result<GoCardless> {
+        // The replayed responses should not end up in the real store.
+        let store = if recording::is_replay() {
+            Store::open_in_memory()?
+        } else {
+            Store::open_default()?
+        };
+        Ok(GoCardless {
+            config: OnceCell::new(),
+            store: Mutex::new(store),
+            offline,
+            backfill,
+        })
+    }
+
+    /// The API configuration, with the access token obtained on the first use.
+    async fn config(&self) -> anyhow::Result<&Configuration> {
+        self.config
+            .get_or_try_init(|| async {
+                if recording::is_replay() {
+                    Ok(Configuration::default())
+                } else {
+                    config_with_token().await
+                }
+            })
+            .await
+    }
+
+    /// The institution of the account, from the `institution_id` metadata, the cached
+    /// requisitions or the API, in this order.
+    async fn institution(
+        &self,
+        account: &SourceAccount,
+        account_id: &str,
+    ) -> anyhow::Result<Option<String>> {
+        if let Some(id) = account.metadata_str("institution_id") {
+            return Ok(Some(id.to_string()));
+        }
+        if self.offline {
+            return cached_institution(account_id);
+        }
+        retrieve_institution(self.config().await?, account_id).await
+    }
+
+    /// The quirks configured for the institution of the account.
+    async fn quirks(
+        &self,
+        account: &SourceAccount,
+        account_id: &str,
+    ) -> anyhow::Result<Option<&'static quirks::Profile>> {
+        let quirks = &settings::get().quirks;
+        if quirks.is_empty() {
+            return Ok(None);
+        }
+        let institution = self.institution(account, account_id).await?;
+        Ok(institution.and_then(|i| quirks.get(&i)))
+    }
+}
+
+#[async_trait]
+impl ImportSource for GoCardless {
+    fn name(&self) -> &str {
+        "gocardless"
+    }
+
+    async fn accounts(&self) -> anyhow::Result<Vec<String>> {
+        let config = self.config().await?;
+        let res = crate::timed("retrieve_all_requisitions", || {
+            gocardless::apis::requisitions_api::retrieve_all_requisitions(config, None, None)
+        })
+        .await?;
+        Ok(res
+            .results
+            .unwrap_or_default()
+            .into_iter()
+            .flat_map(|r| r.accounts.unwrap_or_default())
+            .map(|id| id.to_string())
+            .collect())
+    }
+
+    async fn transactions(&self, account: &SourceAccount) -> anyhow::Result<AccountTransactions> {
+        let account_id = account.required_metadata("account_id")?;
+        // The stored transactions booked before the fetched ones are not imported again, so the
+        // ones deleted from the ledger don't come back. Offline, all of them are.
+        let mut from = None;
+        if !self.offline {
+            let config = self.config().await?;
+            if let Some(iban) = account.metadata_str("iban") {
+                verify_iban(config, account, account_id, iban).await?;
+            }
+            let date_from = if self.backfill.as_ref() == Some(&account.account) {
+                Some(backfill_from(config, account_id).await?)
+            } else {
+                None
+            };
+            let query: Vec<_> = date_from.into_iter().map(|d| ("date_from", d)).collect();
+            let res = retrieve_transactions(config, account_id, &query).await?;
+            let new = self
+                .store
+                .lock()
+                .unwrap()
+                .save_transactions(account_id, &res)?;
+            debug!(account = %account.account, new, "saved transactions in the store");
+            from = Some(
+                res.transactions
+                    .booked
+                    .iter()
+                    .filter_map(|t| t.booking_date.as_deref()?.parse().ok())
+                    .min()
+                    .unwrap_or_else(|| chrono::Local::now().date_naive()),
+            );
+        }
+        let mut res = self.store.lock().unwrap().transactions(account_id, from)?;
+        amount::normalize_transactions(&mut res.transactions, account.decimal_separator()?)
+            .with_context(|| format!("transactions of {}", account.account))?;
+        if let Some(profile) = self.quirks(account, account_id).await? {
+            profile.apply(&mut res.transactions).with_context(|| {
+                format!(
+                    "failed to normalize the transactions of {}",
+                    account.account
+                )
+            })?;
+        }
+        timezone::fill_missing_dates(&mut res.transactions, Some(account), || {
+            self.institution(account, account_id)
+        })
+        .await?;
+        Ok(res)
+    }
+
+    async fn balances(&self, account: &SourceAccount) -> anyhow::Result<AccountBalance> {
+        let account_id = account.required_metadata("account_id")?;
+        let mut res = if self.offline {
+            self.store
+                .lock()
+                .unwrap()
+                .balances(account_id)?
+                .unwrap_or_default()
+        } else {
+            let config = self.config().await?;
+            let res = retrieve_balances(config, account_id).await?;
+            self.store.lock().unwrap().save_balances(account_id, &res)?;
+            res
+        };
+        amount::normalize_balances(&mut res, account.decimal_separator()?)
+            .with_context(|| format!("balances of {}", account.account))?;
+        Ok(res)
+    }
+}
+
+/// Retrieves the transactions of the account, counting the call in the daily quota.
+pub async fn retrieve_transactions(
+    config: &Configuration,
+    account_id: &str,
+    query: &[(&str, String)],
+) -> anyhow::Result<AccountTransactions> {
+    quota::counted(account_id, quota::TRANSACTIONS, |headers| async move {
+        recording::call("retrieve_account_transactions", account_id, || {
+            quota::get(config, account_id, quota::TRANSACTIONS, query, &headers)
+        })
+        .await
+    })
+    .await
+}
+
+/// Retrieves the balances of the account, counting the call in the daily quota.
+pub async fn retrieve_balances(
+    config: &Configuration,
+    account_id: &str,
+) -> anyhow::Result<AccountBalance> {
+    quota::counted(account_id, quota::BALANCES, |headers| async move {
+        recording::call("retrieve_account_balances", account_id, || {
+            quota::get(config, account_id, quota::BALANCES, &[], &headers)
+        })
+        .await
+    })
+    .await
+}
+
+/// The institution of the account in the cached requisitions.
+pub fn cached_institution(account_id: &str) -> anyhow::Result<Option<String>> {
+    Ok(requisitions::Cache::load()?
+        .institution(account_id)
+        .map(|i| i.to_string()))
+}
+
+/// The institution of the account, from the cached requisitions or the API.
+pub async fn retrieve_institution(
+    config: &Configuration,
+    account_id: &str,
+) -> anyhow::Result<Option<String>> {
+    if let Some(id) = cached_institution(account_id)? {
+        return Ok(Some(id));
+    }
+    Ok(
+        recording::call("retrieve_account_metadata", account_id, || {
+            gocardless::apis::accounts_api::retrieve_account_metadata(config, account_id)
+        })
+        .await?
+        .institution_id,
+    )
+}
+
+/// The first day of the longest transaction history the institution of the account provides.
+async fn backfill_from(config: &Configuration, account_id: &str) -> anyhow::Result<String> {
+    let metadata = recording::call("retrieve_account_metadata", account_id, || {
+        gocardless::apis::accounts_api::retrieve_account_metadata(config, account_id)
+    })
+    .await?;
+    let institution_id = metadata
+        .institution_id
+        .with_context(|| format!("the institution of account {} is unknown", account_id))?;
+    let institution = recording::call("retrieve_institution", &institution_id, || {
+        gocardless::apis::institutions_api::retrieve_institution(config, &institution_id)
+    })
+    .await?;
+    let days: u64 = institution
+        .transaction_total_days
+        .as_deref()
+        .and_then(|d| d.parse().ok())
+        .unwrap_or(90);
+    info!(
+        institution = institution.name,
+        days, "backfilling the transactions"
+    );
+    let from = chrono::Local::now().date_naive() - chrono::Days::new(days);
+    Ok(from.format("%Y-%m-%d").to_string())
+}
+
+/// The IBAN without the spaces, in upper case.
+fn normalize_iban(iban: &str) -> String {
+    iban.chars()
+        .filter(|c| !c.is_whitespace())
+        .collect::<String>()
+        .to_uppercase()
+}
+
+/// Checks that the `iban` metadata of the account matches the IBAN of the connected account, to
+/// catch an `account_id` of a different account. A mismatch fails the import of the account, or
+/// is only reported with `iban_check: "warn"`. The IBAN is read from the cached requisitions, and
+/// requested only for the accounts that are not cached.
+async fn verify_iban(
+    config: &Configuration,
+    account: &SourceAccount,
+    account_id: &str,
+    expected: &str,
+) -> anyhow::Result<()> {
+    let actual = match requisitions::Cache::load()?.account(account_id) {
+        Some(cached) => cached.iban.clone(),
+        None => {
+            recording::call("retrieve_account_metadata", account_id, || {
+                gocardless::apis::accounts_api::retrieve_account_metadata(config, account_id)
+            })
+            .await?
+            .iban
+        }
+    };
+    let Some(actual) = actual else {
+        debug!(account = %account.account, "the bank did not report the IBAN");
+        return Ok(());
+    };
+    if normalize_iban(&actual) == normalize_iban(expected) {
+        return Ok(());
+    }
+    let message = format!(
+        "the IBAN of {} is {}, but account {} has IBAN {}",
+        account.account, expected, account_id, actual
+    );
+    if account.metadata_str("iban_check") == Some("warn") {
+        warn!("{}", message);
+        return Ok(());
+    }
+    anyhow::bail!("{} (check the account_id)", message)
+}
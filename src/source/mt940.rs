@@ -0,0 +1,287 @@
+use super::{
+    statement::{self, ClosingBalance, HashedIds, Statement},
+    ImportSource, SourceAccount,
+};
+use anyhow::Context;
+use async_trait::async_trait;
+use chrono::{Datelike, NaiveDate};
+use gocardless::models::{AccountBalance, AccountTransactions};
+use regex::Regex;
+use rust_decimal::Decimal;
+use std::path::Path;
+
+/// Splits the statement into (tag, content) fields, e.g. ("61", "2301020102D12,50NTRF...").
+fn fields(content: &str) -> Vec<(String, String)> {
+    let tag_re = Regex::new(r"^:(\d{2}[A-Z]?):(.*)$").unwrap();
+    let mut fields: Vec<(String, String)> = vec![];
+    for line in content.lines() {
+        let line = line.trim_end_matches('\r');
+        if let Some(c) = tag_re.captures(line) {
+            fields.push((c[1].to_string(), c[2].to_string()));
+        } else if line.starts_with('-') || line.starts_with('{') {
+            // End of the message, or a SWIFT header block.
+            continue;
+        } else if let Some((_, content)) = fields.last_mut() {
+            content.push('\n');
+            content.push_str(line);
+        }
+    }
+    fields
+}
+
+fn amount(s: &str) -> anyhow::Result<Decimal> {
+    s.replace(',', ".")
+        .parse()
+        .with_context(|| format!("invalid amount {:?}", s))
+}
+
+fn date(s: &str) -> anyhow::Result<NaiveDate> {
+    NaiveDate::parse_from_str(s, "%y%m%d").with_context(|| format!("invalid date {:?}", s))
+}
+
+/// Balance field (`:60F:`, `:62F:`, ...), e.g. `C230131EUR987,50`.
+fn parse_balance(content: &str) -> anyhow::Result<(NaiveDate, Decimal, String)> {
+    let re = Regex::new(r"^([CD])(\d{6})([A-Z]{3})([\d,]+)").unwrap();
+    let c = re
+        .captures(content.trim())
+        .with_context(|| format!("invalid balance {:?}", content))?;
+    let mut value = amount(&c[4])?;
+    if &c[1] == "D" {
+        value = -value;
+    }
+    Ok((date(&c[2])?, value, c[3].to_string()))
+}
+
+/// Information to the account owner (`:86:`): (narration, counterparty). The structured format
+/// used by German banks (`?20`-`?29` remittance information, `?32`-`?33` name) is recognized,
+/// otherwise the whole field is the narration.
+fn information(content: &str) -> (String, Option<String>) {
+    let content = content.replace('\n', "");
+    if content.len() < 4 || content.as_bytes()[3] != b'?' {
+        return (content.trim().to_string(), None);
+    }
+    let mut narration = String::new();
+    let mut name = String::new();
+    for sub in content[4..].split('?') {
+        if sub.len() < 2 || !sub.is_char_boundary(2) {
+            continue;
+        }
+        let (code, value) = sub.split_at(2);
+        match code {
+            "20" | "21" | "22" | "23" | "24" | "25" | "26" | "27" | "28" | "29" | "60" | "61"
+            | "62" | "63" => narration += value,
+            "32" | "33" => name += value,
+            _ => {}
+        }
+    }
+    let name = Some(name.trim().to_string()).filter(|n| !n.is_empty());
+    (narration.trim().to_string(), name)
+}
+
+/// Parses a SWIFT MT940 statement file (which can contain multiple statements).
+fn read(path: &Path) -> anyhow::Result<Statement> {
+    let content =
+        std::fs::read(path).with_context(|| format!("failed to read {}", path.display()))?;
+    // MT940 files are often in Latin-1 rather than UTF-8.
+    let content = match String::from_utf8(content) {
+        Ok(s) => s,
+        Err(e) => e.into_bytes().iter().map(|b| *b as char).collect(),
+    };
+    // Value date, entry date (MMDD), debit/credit mark, funds code, amount, transaction type,
+    // reference of the account owner, reference of the bank.
+    let line_re = Regex::new(
+        r"^(\d{6})(\d{4})?(R?[CD])([A-Z])?([\d,]+)([NSF][A-Z0-9]{3})([^/\n]*)(?://([^\n]*))?",
+    )
+    .unwrap();
+
+    let mut booked = vec![];
+    let mut balance: Option<ClosingBalance> = None;
+    let mut ids = HashedIds::default();
+    let mut currency = String::new();
+    let mut last: Option<usize> = None;
+    for (tag, content) in fields(&content) {
+        let context = || format!("{}: :{}:{}", path.display(), tag, content);
+        match tag.as_str() {
+            "60F" | "60M" => currency = parse_balance(&content).with_context(context)?.2,
+            "62F" | "62M" => {
+                let (date, amount, currency) = parse_balance(&content).with_context(context)?;
+                // The closing balance is at the end of the day.
+                let date = date.succ_opt().context("invalid date")?;
+                if balance.as_ref().is_none_or(|b| b.date <= date) {
+                    balance = Some(ClosingBalance {
+                        date,
+                        amount,
+                        currency,
+                    });
+                }
+            }
+            "61" => {
+                let c = line_re
+                    .captures(&content)
+                    .with_context(|| format!("{}: invalid statement line", context()))?;
+                let value_date = date(&c[1]).with_context(context)?;
+                // The entry date has no year, it's close to the value date.
+                let booking_date = match c.get(2) {
+                    Some(md) => {
+                        let md = md.as_str();
+                        let mut d = date(&format!("{:02}{}", value_date.year() % 100, md))
+                            .with_context(context)?;
+                        if (d - value_date).num_days() > 180 {
+                            d = d.with_year(d.year() - 1).unwrap_or(d);
+                        } else if (value_date - d).num_days() > 180 {
+                            d = d.with_year(d.year() + 1).unwrap_or(d);
+                        }
+                        d
+                    }
+                    None => value_date,
+                };
+                let mut value = amount(&c[5]).with_context(context)?;
+                // D (debit) and RC (reversal of credit) decrease the balance.
+                if &c[3] == "D" || &c[3] == "RC" {
+                    value = -value;
+                }
+                let bank_reference = c.get(8).map(|r| r.as_str().trim().to_string());
+                let owner_reference = c[7].trim().to_string();
+                let id = match &bank_reference {
+                    Some(r) if !r.is_empty() && r != "NONREF" => r.clone(),
+                    _ => ids.id(
+                        "mt940",
+                        &[
+                            &booking_date.to_string(),
+                            &value.to_string(),
+                            &owner_reference,
+                            content.lines().nth(1).unwrap_or_default(),
+                        ],
+                    ),
+                };
+                let mut t =
+                    statement::transaction(id, booking_date, value, currency.clone(), None, None);
+                t.value_date = Some(value_date.to_string());
+                t.proprietary_bank_transaction_code = Some(c[6].to_string());
+                if !owner_reference.is_empty() && owner_reference != "NONREF" {
+                    t.end_to_end_id = Some(owner_reference);
+                }
+                booked.push(t);
+                last = Some(booked.len() - 1);
+            }
+            "86" => {
+                let Some(t) = last.take().map(|i| &mut booked[i]) else {
+                    continue;
+                };
+                let (narration, counterparty) = information(&content);
+                if !narration.is_empty() {
+                    t.remittance_information_unstructured = Some(narration);
+                }
+                if t.transaction_amount.amount.starts_with('-') {
+                    t.creditor_name = counterparty;
+                } else {
+                    t.debtor_name = counterparty;
+                }
+            }
+            _ => {}
+        }
+    }
+    let mut statement = Statement::default();
+    statement.merge(Statement { booked, balance });
+    Ok(statement)
+}
+
+/// Metadata key with the path of the statements.
+const PATH_KEY: &str = "mt940_path";
+const EXTENSIONS: &[&str] = &["sta", "mt940", "txt"];
+
+/// Accounts imported from MT940 statements, configured with the `mt940_path` metadata (a file or
+/// a directory with `.sta`, `.mt940` or `.txt` files, relative to the ledger file).
+pub struct Mt940;
+
+#[async_trait]
+impl ImportSource for Mt940 {
+    fn name(&self) -> &str {
+        "mt940"
+    }
+
+    fn statement_files(&self) -> Option<(&'static str, &'static [&'static str])> {
+        Some((PATH_KEY, EXTENSIONS))
+    }
+
+    async fn accounts(&self) -> anyhow::Result<Vec<String>> {
+        Ok(vec![])
+    }
+
+    async fn transactions(&self, account: &SourceAccount) -> anyhow::Result<AccountTransactions> {
+        Ok(statement(account)?.transactions())
+    }
+
+    async fn balances(&self, account: &SourceAccount) -> anyhow::Result<AccountBalance> {
+        Ok(statement(account)?.balances())
+    }
+}
+
+fn statement(account: &SourceAccount) -> anyhow::Result<Statement> {
+    let mut statement = Statement::default();
+    for path in statement::files(account, PATH_KEY, EXTENSIONS)? {
+        statement.merge(read(&path)?);
+    }
+    Ok(statement)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_the_statement() {
+        let statement = read(Path::new("tests/data/statement.sta")).unwrap();
+        let [payment, salary] = &statement.booked[..] else {
+            panic!("expected two transactions, got {}", statement.booked.len());
+        };
+
+        assert_eq!(payment.internal_transaction_id.as_deref(), Some("B1"));
+        assert_eq!(payment.booking_date.as_deref(), Some("2023-01-02"));
+        assert_eq!(payment.transaction_amount.amount, "-12.50");
+        assert_eq!(payment.transaction_amount.currency, "EUR");
+        assert_eq!(payment.creditor_name.as_deref(), Some("Coffee Shop"));
+        assert_eq!(
+            payment.remittance_information_unstructured.as_deref(),
+            Some("Card payment")
+        );
+        assert_eq!(payment.end_to_end_id, None);
+
+        // The entry date differs from the value date.
+        assert_eq!(salary.booking_date.as_deref(), Some("2023-01-04"));
+        assert_eq!(salary.value_date.as_deref(), Some("2023-01-03"));
+        assert_eq!(salary.transaction_amount.amount, "250.00");
+        assert_eq!(salary.debtor_name, None);
+        assert_eq!(
+            salary.remittance_information_unstructured.as_deref(),
+            Some("Salary January")
+        );
+        assert_eq!(salary.end_to_end_id.as_deref(), Some("SAL-2023-01"));
+        assert!(salary
+            .internal_transaction_id
+            .as_deref()
+            .is_some_and(|id| id.starts_with("mt940-")));
+
+        let balance = statement.balance.unwrap();
+        assert_eq!(balance.date, NaiveDate::from_ymd_opt(2023, 1, 5).unwrap());
+        assert_eq!(balance.amount, "1237.50".parse().unwrap());
+        assert_eq!(balance.currency, "EUR");
+    }
+
+    #[test]
+    fn rejects_an_invalid_statement_line() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("invalid.sta");
+        std::fs::write(
+            &path,
+            ":20:STARTUMS\n:60F:C230101EUR1000,00\n:61:23010X0102D12,50NTRFNONREF\n-\n",
+        )
+        .unwrap();
+        let e = read(&path).unwrap_err();
+        assert!(
+            format!("{:#}", e).contains("invalid statement line"),
+            "{:#}",
+            e
+        );
+    }
+}
@@ -0,0 +1,154 @@
+//! Shared parts of the sources reading statement files downloaded from the bank.
+
+use super::SourceAccount;
+use chrono::NaiveDate;
+use gocardless::models::{
+    AccountBalance, AccountTransactions, BalanceAmountSchema, BalanceSchema, BankTransaction,
+    TransactionAmountSchema, TransactionSchema,
+};
+use rust_decimal::Decimal;
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+/// Balance at the end of the statement.
+#[derive(Clone, Debug)]
+pub struct ClosingBalance {
+    /// The first day the balance holds on (the day after the last transaction).
+    pub date: NaiveDate,
+    pub amount: Decimal,
+    pub currency: String,
+}
+
+/// Transactions and the closing balance read from the statement files.
+#[derive(Default, Debug)]
+pub struct Statement {
+    /// Booked transactions, oldest first.
+    pub booked: Vec<TransactionSchema>,
+    pub balance: Option<ClosingBalance>,
+}
+
+impl Statement {
+    /// Adds the transactions of another statement (e.g. of the next month), keeping the most
+    /// recent closing balance.
+    pub fn merge(&mut self, other: Statement) {
+        self.booked.extend(other.booked);
+        self.booked
+            .sort_by(|a, b| a.booking_date.cmp(&b.booking_date));
+        if let Some(b) = other.balance {
+            if self.balance.as_ref().is_none_or(|a| a.date <= b.date) {
+                self.balance = Some(b);
+            }
+        }
+    }
+
+    /// The transactions in the order returned by the API (newest first).
+    pub fn transactions(&self) -> AccountTransactions {
+        let mut booked = self.booked.clone();
+        booked.reverse();
+        AccountTransactions {
+            transactions: Box::new(BankTransaction {
+                booked,
+                pending: None,
+            }),
+        }
+    }
+
+    pub fn balances(&self) -> AccountBalance {
+        let Some(b) = &self.balance else {
+            return AccountBalance::default();
+        };
+        AccountBalance {
+            balances: Some(vec![BalanceSchema {
+                balance_amount: Box::new(BalanceAmountSchema {
+                    amount: b.amount.to_string(),
+                    currency: b.currency.clone(),
+                }),
+                balance_type: "closingBooked".into(),
+                reference_date: Some(b.date.to_string()),
+                ..Default::default()
+            }]),
+        }
+    }
+}
+
+/// A booked transaction with the common fields of the statements. The counterparty is the
+/// creditor of payments and the debtor of incoming transfers.
+pub fn transaction(
+    id: String,
+    date: NaiveDate,
+    amount: Decimal,
+    currency: String,
+    narration: Option<String>,
+    counterparty: Option<String>,
+) -> TransactionSchema {
+    let mut t = TransactionSchema {
+        internal_transaction_id: Some(id),
+        booking_date: Some(date.to_string()),
+        transaction_amount: Box::new(TransactionAmountSchema {
+            amount: amount.to_string(),
+            currency,
+        }),
+        remittance_information_unstructured: narration.filter(|n| !n.is_empty()),
+        ..Default::default()
+    };
+    let counterparty = counterparty.filter(|c| !c.is_empty());
+    if amount.is_sign_negative() {
+        t.creditor_name = counterparty;
+    } else {
+        t.debtor_name = counterparty;
+    }
+    t
+}
+
+/// Ids of the transactions the statement has no id for: a stable (FNV-1a) hash of their fields.
+/// Identical transactions (e.g. two coffees on the same day) get different ids.
+#[derive(Default)]
+pub struct HashedIds {
+    occurrences: HashMap<u64, usize>,
+}
+
+impl HashedIds {
+    pub fn id(&mut self, prefix: &str, fields: &[&str]) -> String {
+        let mut hash: u64 = 0xcbf29ce484222325;
+        for field in fields {
+            for b in field.bytes().chain([0]) {
+                hash ^= b as u64;
+                hash = hash.wrapping_mul(0x100000001b3);
+            }
+        }
+        let n = self.occurrences.entry(hash).or_default();
+        *n += 1;
+        format!("{}-{:016x}-{}", prefix, hash, n)
+    }
+}
+
+/// The statement files of the account, from the `<key>` metadata: a file or a directory with
+/// files with one of the extensions, relative to the ledger file.
+pub fn files(
+    account: &SourceAccount,
+    key: &str,
+    extensions: &[&str],
+) -> anyhow::Result<Vec<PathBuf>> {
+    let path = Path::new(account.required_metadata(key)?);
+    let path = match account.file.parent() {
+        Some(dir) if path.is_relative() => dir.join(path),
+        _ => path.to_path_buf(),
+    };
+    if !path.is_dir() {
+        return Ok(vec![path]);
+    }
+    let mut files = vec![];
+    for entry in std::fs::read_dir(&path)? {
+        let p = entry?.path();
+        let matches = p
+            .extension()
+            .is_some_and(|e| extensions.iter().any(|ext| e.eq_ignore_ascii_case(ext)));
+        if matches {
+            files.push(p);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
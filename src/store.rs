@@ -0,0 +1,385 @@
+use chrono::NaiveDate;
+use gocardless::models::{AccountBalance, AccountTransactions, BankTransaction, TransactionSchema};
+use rusqlite::{params, Connection, OptionalExtension};
+use rust_decimal::Decimal;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Local SQLite database with every transaction and balance fetched from GoCardless, so the
+/// history is kept beyond the period the bank gives access to.
+pub struct Store {
+    conn: Connection,
+}
+
+/// Filters of the stored transactions; all the given ones have to match.
+#[derive(clap::Args, Debug, Default)]
+pub struct Query {
+    /// The ID of the account.
+    #[arg(long)]
+    pub account_id: Option<String>,
+    /// Transactions booked on or after the date.
+    #[arg(long)]
+    pub from: Option<NaiveDate>,
+    /// Transactions booked on or before the date.
+    #[arg(long)]
+    pub to: Option<NaiveDate>,
+    /// Text contained in any of the fields (e.g. narration or counterparty), ignoring case.
+    #[arg(long)]
+    pub text: Option<String>,
+    /// Minimum amount (negative for payments).
+    #[arg(long, allow_hyphen_values = true)]
+    pub min_amount: Option<Decimal>,
+    /// Maximum amount (negative for payments).
+    #[arg(long, allow_hyphen_values = true)]
+    pub max_amount: Option<Decimal>,
+}
+
+/// A booked transaction from the store.
+#[derive(Serialize)]
+pub struct StoredTransaction {
+    pub account_id: String,
+    pub first_seen: String,
+    pub transaction: TransactionSchema,
+}
+
+/// What is stored for an account.
+#[derive(Serialize, Debug)]
+pub struct AccountStats {
+    pub account_id: String,
+    pub transactions: usize,
+    /// Booking date of the oldest and the newest transaction.
+    pub first_booking_date: Option<String>,
+    pub last_booking_date: Option<String>,
+    pub balances: usize,
+    /// When the transactions or balances of the account were last fetched.
+    pub last_fetched: Option<String>,
+}
+
+/// Numbers of the removed entries.
+#[derive(Serialize, Debug, Default)]
+pub struct Cleared {
+    pub transactions: usize,
+    pub pending_transactions: usize,
+    pub balances: usize,
+}
+
+/// Key of the transaction in the store: the internal transaction id, or the bank's transaction
+/// id, or (when the bank provides neither) the date, amount and description. Identical
+/// transactions without ids (e.g. two coffees on the same day) are told apart by their occurrence
+/// in the response, counted in `occurrences`.
+fn key(t: &TransactionSchema, occurrences: &mut HashMap<String, usize>) -> String {
+    if let Some(id) = t
+        .internal_transaction_id
+        .as_ref()
+        .or(t.transaction_id.as_ref())
+    {
+        return id.clone();
+    }
+    let key = format!(
+        "{}|{}|{}|{}",
+        t.booking_date.as_deref().unwrap_or_default(),
+        t.transaction_amount.amount,
+        t.transaction_amount.currency,
+        crate::narration(t).unwrap_or_default()
+    );
+    let n = occurrences.entry(key.clone()).or_default();
+    *n += 1;
+    // The first occurrence keeps the key without a counter, as stored before.
+    match *n {
+        1 => key,
+        n => format!("{}|{}", key, n),
+    }
+}
+
+impl Store {
+    /// Opens the store in the config directory (`~/.gocardless/store.sqlite`).
+    pub fn open_default() -> anyhow::Result<Store> {
+        let dir = crate::base_config_dir()?;
+        std::fs::create_dir_all(&dir)?;
+        Store::open(&dir.join("store.sqlite"))
+    }
+
+    pub fn open(path: &Path) -> anyhow::Result<Store> {
+        Store::init(Connection::open(path)?)
+    }
+
+    /// Opens a temporary store, dropped at the end of the import.
+    pub fn open_in_memory() -> anyhow::Result<Store> {
+        Store::init(Connection::open_in_memory()?)
+    }
+
+    fn init(conn: Connection) -> anyhow::Result<Store> {
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS transactions (
+                account_id TEXT NOT NULL,
+                id TEXT NOT NULL,
+                booking_date TEXT,
+                data TEXT NOT NULL,
+                first_seen TEXT NOT NULL,
+                last_seen TEXT NOT NULL,
+                PRIMARY KEY (account_id, id)
+            );
+            CREATE TABLE IF NOT EXISTS pending_transactions (
+                account_id TEXT NOT NULL,
+                data TEXT NOT NULL,
+                fetched_at TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS balances (
+                account_id TEXT NOT NULL,
+                data TEXT NOT NULL,
+                fetched_at TEXT NOT NULL
+            );",
+        )?;
+        Ok(Store { conn })
+    }
+
+    /// Saves the fetched transactions of the account. Booked transactions are added (or updated,
+    /// if the bank changed them), pending transactions replace the previously fetched ones.
+    /// Returns the number of booked transactions that were not in the store before.
+    pub fn save_transactions(
+        &mut self,
+        account_id: &str,
+        res: &AccountTransactions,
+    ) -> anyhow::Result<usize> {
+        let now = chrono::Utc::now().to_rfc3339();
+        let tx = self.conn.transaction()?;
+        let mut new = 0;
+        let mut occurrences = HashMap::new();
+        for t in &res.transactions.booked {
+            let key = key(t, &mut occurrences);
+            let inserted = tx.execute(
+                "INSERT INTO transactions (account_id, id, booking_date, data, first_seen, last_seen)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?5)
+                 ON CONFLICT (account_id, id) DO NOTHING",
+                params![
+                    account_id,
+                    key,
+                    t.booking_date,
+                    serde_json::to_string(t)?,
+                    now
+                ],
+            )?;
+            if inserted == 0 {
+                tx.execute(
+                    "UPDATE transactions SET data = ?3, booking_date = ?4, last_seen = ?5
+                     WHERE account_id = ?1 AND id = ?2",
+                    params![
+                        account_id,
+                        key,
+                        serde_json::to_string(t)?,
+                        t.booking_date,
+                        now
+                    ],
+                )?;
+            }
+            new += inserted;
+        }
+        tx.execute(
+            "DELETE FROM pending_transactions WHERE account_id = ?1",
+            params![account_id],
+        )?;
+        for t in res.transactions.pending.iter().flatten() {
+            tx.execute(
+                "INSERT INTO pending_transactions (account_id, data, fetched_at) VALUES (?1, ?2, ?3)",
+                params![account_id, serde_json::to_string(t)?, now],
+            )?;
+        }
+        tx.commit()?;
+        Ok(new)
+    }
+
+    /// Returns the stored transactions of the account booked on or after the date (all of them
+    /// without it), newest first (like the API).
+    pub fn transactions(
+        &self,
+        account_id: &str,
+        from: Option<NaiveDate>,
+    ) -> anyhow::Result<AccountTransactions> {
+        let mut booked = vec![];
+        let mut stmt = self.conn.prepare(
+            "SELECT data FROM transactions
+             WHERE account_id = ?1 AND (?2 IS NULL OR booking_date IS NULL OR booking_date >= ?2)
+             ORDER BY booking_date DESC, rowid ASC",
+        )?;
+        let from = from.map(|d| d.to_string());
+        for data in stmt.query_map(params![account_id, from], |row| row.get::<_, String>(0))? {
+            booked.push(serde_json::from_str(&data?)?);
+        }
+        let mut pending = vec![];
+        let mut stmt = self.conn.prepare(
+            "SELECT data FROM pending_transactions WHERE account_id = ?1 ORDER BY rowid ASC",
+        )?;
+        for data in stmt.query_map(params![account_id], |row| row.get::<_, String>(0))? {
+            pending.push(serde_json::from_str(&data?)?);
+        }
+        Ok(AccountTransactions {
+            transactions: Box::new(BankTransaction {
+                booked,
+                pending: Some(pending),
+            }),
+        })
+    }
+
+    /// Returns the stored booked transactions matching the query, oldest first.
+    pub fn query(&self, query: &Query) -> anyhow::Result<Vec<StoredTransaction>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT account_id, first_seen, data FROM transactions
+             WHERE (?1 IS NULL OR account_id = ?1)
+               AND (?2 IS NULL OR booking_date >= ?2)
+               AND (?3 IS NULL OR booking_date <= ?3)
+               AND (?4 IS NULL OR data LIKE '%' || ?4 || '%')
+             ORDER BY booking_date ASC, rowid DESC",
+        )?;
+        let rows = stmt.query_map(
+            params![
+                query.account_id,
+                query.from.map(|d| d.to_string()),
+                query.to.map(|d| d.to_string()),
+                query.text
+            ],
+            |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                ))
+            },
+        )?;
+        let mut res = vec![];
+        for row in rows {
+            let (account_id, first_seen, data) = row?;
+            let transaction: TransactionSchema = serde_json::from_str(&data)?;
+            let amount = crate::amount::transaction(&transaction)?;
+            if query.min_amount.is_some_and(|min| amount < min)
+                || query.max_amount.is_some_and(|max| amount > max)
+            {
+                continue;
+            }
+            res.push(StoredTransaction {
+                account_id,
+                first_seen,
+                transaction,
+            });
+        }
+        Ok(res)
+    }
+
+    /// Saves the fetched balances of the account, keeping the previously fetched ones.
+    pub fn save_balances(&mut self, account_id: &str, res: &AccountBalance) -> anyhow::Result<()> {
+        self.conn.execute(
+            "INSERT INTO balances (account_id, data, fetched_at) VALUES (?1, ?2, ?3)",
+            params![
+                account_id,
+                serde_json::to_string(res)?,
+                chrono::Utc::now().to_rfc3339()
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Removes the stored data of the account (or of all the accounts) last fetched before the
+    /// given time (or at any time).
+    pub fn clear(
+        &mut self,
+        account_id: Option<&str>,
+        fetched_before: Option<chrono::DateTime<chrono::Utc>>,
+    ) -> anyhow::Result<Cleared> {
+        let before = fetched_before.map(|t| t.to_rfc3339());
+        let tx = self.conn.transaction()?;
+        let delete = |table: &str, time: &str| {
+            tx.execute(
+                &format!(
+                    "DELETE FROM {} WHERE (?1 IS NULL OR account_id = ?1)
+                     AND (?2 IS NULL OR {} < ?2)",
+                    table, time
+                ),
+                params![account_id, before],
+            )
+        };
+        let cleared = Cleared {
+            transactions: delete("transactions", "last_seen")?,
+            pending_transactions: delete("pending_transactions", "fetched_at")?,
+            balances: delete("balances", "fetched_at")?,
+        };
+        tx.commit()?;
+        // Returns the freed space to the file system.
+        self.conn.execute_batch("VACUUM")?;
+        Ok(cleared)
+    }
+
+    /// Statistics of the stored data, per account.
+    pub fn stats(&self) -> anyhow::Result<Vec<AccountStats>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT account_id, SUM(transactions), MIN(first_booking), MAX(last_booking),
+                    SUM(balances), MAX(fetched)
+             FROM (
+                SELECT account_id, COUNT(*) AS transactions, MIN(booking_date) AS first_booking,
+                       MAX(booking_date) AS last_booking, 0 AS balances, MAX(last_seen) AS fetched
+                FROM transactions GROUP BY account_id
+                UNION ALL
+                SELECT account_id, 0, NULL, NULL, COUNT(*), MAX(fetched_at)
+                FROM balances GROUP BY account_id
+             )
+             GROUP BY account_id ORDER BY account_id",
+        )?;
+        let rows = stmt.query_map([], |row| {
+            Ok(AccountStats {
+                account_id: row.get(0)?,
+                transactions: row.get(1)?,
+                first_booking_date: row.get(2)?,
+                last_booking_date: row.get(3)?,
+                balances: row.get(4)?,
+                last_fetched: row.get(5)?,
+            })
+        })?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    /// Returns the most recently fetched balances of the account.
+    pub fn balances(&self, account_id: &str) -> anyhow::Result<Option<AccountBalance>> {
+        let data: Option<String> = self
+            .conn
+            .query_row(
+                "SELECT data FROM balances WHERE account_id = ?1 ORDER BY rowid DESC LIMIT 1",
+                params![account_id],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(data.map(|d| serde_json::from_str(&d)).transpose()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn coffee() -> TransactionSchema {
+        serde_json::from_value(serde_json::json!({
+            "bookingDate": "2024-03-01",
+            "transactionAmount": { "amount": "-3.50", "currency": "EUR" },
+            "remittanceInformationUnstructured": "Coffee",
+        }))
+        .unwrap()
+    }
+
+    fn response(booked: Vec<TransactionSchema>) -> AccountTransactions {
+        AccountTransactions {
+            transactions: Box::new(BankTransaction {
+                booked,
+                pending: None,
+            }),
+        }
+    }
+
+    #[test]
+    fn keeps_identical_transactions_without_ids() {
+        let mut store = Store::open_in_memory().unwrap();
+        let res = response(vec![coffee(), coffee()]);
+        assert_eq!(store.save_transactions("acc", &res).unwrap(), 2);
+        // Fetching the same transactions again doesn't add them twice.
+        assert_eq!(store.save_transactions("acc", &res).unwrap(), 0);
+        let stored = store.transactions("acc", None).unwrap();
+        assert_eq!(stored.transactions.booked.len(), 2);
+    }
+}
@@ -0,0 +1,44 @@
+//! Fixtures shared by the unit tests.
+
+use crate::mapper::TransactionMapper;
+use beanru::types::{Account, Directive};
+use gocardless::models::TransactionSchema;
+use rust_decimal::Decimal;
+
+/// The account the fixtures are imported into.
+pub(crate) fn bank() -> Account {
+    Account("Assets:Bank".to_string())
+}
+
+/// A booked transaction with the id, date and amount (in EUR). The other `fields` of the API
+/// format (e.g. `{"creditorName": "Shop"}`) are added to it, or replace the default ones.
+pub(crate) fn transaction(
+    id: &str,
+    date: &str,
+    amount: &str,
+    fields: serde_json::Value,
+) -> TransactionSchema {
+    let mut t = serde_json::json!({
+        "internalTransactionId": id,
+        "bookingDate": date,
+        "transactionAmount": { "amount": amount, "currency": "EUR" },
+    });
+    if let serde_json::Value::Object(fields) = fields {
+        for (key, value) in fields {
+            t[key] = value;
+        }
+    }
+    serde_json::from_value(t).unwrap()
+}
+
+/// The [`transaction`] imported into the [`bank`] account by the default mapper.
+pub(crate) fn directive(
+    id: &str,
+    date: &str,
+    amount: &str,
+    fields: serde_json::Value,
+) -> Directive<Decimal> {
+    TransactionMapper::default()
+        .map(&transaction(id, date, amount, fields), &bank())
+        .unwrap()
+}
@@ -0,0 +1,226 @@
+//! Dates of the transactions derived from the booking (or value) date-times, when the bank doesn't
+//! report the dates. The date-times are converted to the time zone of the account, so the
+//! late-evening transactions end up on the same day as in the statement of the bank. The time zone
+//! is the `timezone` metadata of the account, the `timezone` setting, the time zone of the country
+//! of the institution or the local time zone, in this order. All the commands converting the
+//! transactions fill the dates with [`fill_missing_dates`], so they agree on them.
+
+use crate::{settings, source::SourceAccount};
+use chrono::{DateTime, NaiveDate};
+use chrono_tz::Tz;
+use gocardless::models::{BankTransaction, TransactionSchema};
+use std::future::Future;
+
+/// Time zones of the countries the institutions are in.
+const COUNTRY_ZONES: &[(&str, &str)] = &[
+    ("AT", "Europe/Vienna"),
+    ("BE", "Europe/Brussels"),
+    ("BG", "Europe/Sofia"),
+    ("CY", "Asia/Nicosia"),
+    ("CZ", "Europe/Prague"),
+    ("DE", "Europe/Berlin"),
+    ("DK", "Europe/Copenhagen"),
+    ("EE", "Europe/Tallinn"),
+    ("ES", "Europe/Madrid"),
+    ("FI", "Europe/Helsinki"),
+    ("FR", "Europe/Paris"),
+    ("GB", "Europe/London"),
+    ("GR", "Europe/Athens"),
+    ("HR", "Europe/Zagreb"),
+    ("HU", "Europe/Budapest"),
+    ("IE", "Europe/Dublin"),
+    ("IS", "Atlantic/Reykjavik"),
+    ("IT", "Europe/Rome"),
+    ("LI", "Europe/Vaduz"),
+    ("LT", "Europe/Vilnius"),
+    ("LU", "Europe/Luxembourg"),
+    ("LV", "Europe/Riga"),
+    ("MT", "Europe/Malta"),
+    ("NL", "Europe/Amsterdam"),
+    ("NO", "Europe/Oslo"),
+    ("PL", "Europe/Warsaw"),
+    ("PT", "Europe/Lisbon"),
+    ("RO", "Europe/Bucharest"),
+    ("SE", "Europe/Stockholm"),
+    ("SI", "Europe/Ljubljana"),
+    ("SK", "Europe/Bratislava"),
+];
+
+/// The time zone of the country of the institution, from the country code in its BIC (e.g.
+/// `GB` of `REVOLUT_REVOGB21`).
+pub fn of_institution(institution_id: &str) -> Option<Tz> {
+    let bic = institution_id.rsplit('_').next()?;
+    let country = bic.get(4..6)?;
+    let (_, zone) = COUNTRY_ZONES.iter().find(|(c, _)| *c == country)?;
+    zone.parse().ok()
+}
+
+/// The date of the date-time in the time zone (the local one, without it). Date-times without an
+/// offset are left to the conversion, which takes their date as it is.
+fn local_date(date_time: &str, tz: Option<Tz>) -> Option<NaiveDate> {
+    let dt = DateTime::parse_from_rfc3339(date_time).ok()?;
+    Some(match tz {
+        Some(tz) => dt.with_timezone(&tz).date_naive(),
+        None => dt.with_timezone(&chrono::Local).date_naive(),
+    })
+}
+
+fn missing(t: &TransactionSchema) -> bool {
+    (t.booking_date.is_none() && t.booking_date_time.is_some())
+        || (t.value_date.is_none() && t.value_date_time.is_some())
+}
+
+/// Whether some of the transactions have a date-time without the date.
+pub fn missing_dates(transactions: &BankTransaction) -> bool {
+    transactions
+        .booked
+        .iter()
+        .chain(transactions.pending.iter().flatten())
+        .any(missing)
+}
+
+fn fill(t: &mut TransactionSchema, tz: Option<Tz>) {
+    if t.booking_date.is_none() {
+        t.booking_date = t
+            .booking_date_time
+            .as_deref()
+            .and_then(|dt| local_date(dt, tz))
+            .map(|d| d.to_string());
+    }
+    if t.value_date.is_none() {
+        t.value_date = t
+            .value_date_time
+            .as_deref()
+            .and_then(|dt| local_date(dt, tz))
+            .map(|d| d.to_string());
+    }
+}
+
+/// The time zone of the account (if configured in the ledger): the `timezone` metadata, the
+/// `timezone` setting or the time zone of the institution, looked up only when needed. Nothing for
+/// the local time zone.
+pub async fn of_account<Fut>(
+    account: Option<&SourceAccount>,
+    institution: impl FnOnce() -> Fut,
+) -> anyhow::Result<Option<Tz>>
+where
+    Fut: Future<Output = anyhow::Result<Option<String>>>,
+{
+    if let Some(account) = account {
+        if let Some(tz) = account.metadata_str("timezone") {
+            let tz = tz.parse().map_err(|e| {
+                anyhow::format_err!("{}: invalid timezone {:?}: {}", account.account, tz, e)
+            })?;
+            return Ok(Some(tz));
+        }
+    }
+    if let Some(tz) = settings::get().timezone {
+        return Ok(Some(tz));
+    }
+    Ok(institution().await?.as_deref().and_then(of_institution))
+}
+
+/// Fills the missing dates of the transactions from their date-times, in the time zone of the
+/// account (see [`of_account`]).
+pub async fn fill_missing_dates<Fut>(
+    transactions: &mut BankTransaction,
+    account: Option<&SourceAccount>,
+    institution: impl FnOnce() -> Fut,
+) -> anyhow::Result<()>
+where
+    Fut: Future<Output = anyhow::Result<Option<String>>>,
+{
+    if missing_dates(transactions) {
+        let tz = of_account(account, institution).await?;
+        fill_dates(transactions, tz);
+    }
+    Ok(())
+}
+
+/// Fills the missing booking and value dates of the booked and pending transactions from their
+/// date-times, converted to the time zone.
+pub fn fill_dates(transactions: &mut BankTransaction, tz: Option<Tz>) {
+    for t in &mut transactions.booked {
+        fill(t, tz);
+    }
+    for t in transactions.pending.iter_mut().flatten() {
+        fill(t, tz);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util;
+
+    fn zone(name: &str) -> Tz {
+        name.parse().unwrap()
+    }
+
+    fn transactions(
+        booked: Vec<TransactionSchema>,
+        pending: Vec<TransactionSchema>,
+    ) -> BankTransaction {
+        BankTransaction {
+            booked,
+            pending: Some(pending),
+        }
+    }
+
+    /// A transaction with only the date-times, no dates.
+    fn transaction(booking_date_time: &str) -> TransactionSchema {
+        test_util::transaction(
+            "t1",
+            "2024-01-01",
+            "-12.00",
+            serde_json::json!({
+                "bookingDate": null,
+                "bookingDateTime": booking_date_time,
+                "valueDateTime": booking_date_time,
+            }),
+        )
+    }
+
+    #[test]
+    fn zone_of_the_country_of_the_institution() {
+        assert_eq!(
+            of_institution("REVOLUT_REVOGB21"),
+            Some(zone("Europe/London"))
+        );
+        assert_eq!(of_institution("N26_NTSBDEB1"), Some(zone("Europe/Berlin")));
+        assert_eq!(of_institution("CHASE_CHASUS33"), None);
+        assert_eq!(of_institution("SANDBOX"), None);
+    }
+
+    #[test]
+    fn fills_the_dates_in_the_time_zone() {
+        let mut t = transactions(
+            vec![transaction("2024-01-15T23:30:00Z")],
+            vec![transaction("2024-01-16T10:00:00Z")],
+        );
+        assert!(missing_dates(&t));
+        fill_dates(&mut t, Some(zone("Europe/Berlin")));
+        assert!(!missing_dates(&t));
+        // Already the next day in Berlin.
+        assert_eq!(t.booked[0].booking_date.as_deref(), Some("2024-01-16"));
+        assert_eq!(t.booked[0].value_date.as_deref(), Some("2024-01-16"));
+        let pending = &t.pending.as_ref().unwrap()[0];
+        assert_eq!(pending.booking_date.as_deref(), Some("2024-01-16"));
+
+        let mut t = transactions(vec![transaction("2024-01-15T23:30:00Z")], vec![]);
+        fill_dates(&mut t, Some(zone("Europe/London")));
+        assert_eq!(t.booked[0].booking_date.as_deref(), Some("2024-01-15"));
+    }
+
+    #[test]
+    fn keeps_the_dates_of_the_bank() {
+        let mut booked = transaction("2024-01-15T23:30:00Z");
+        booked.booking_date = Some("2024-01-15".to_string());
+        let mut t = transactions(vec![booked], vec![]);
+        // The value date is still missing.
+        assert!(missing_dates(&t));
+        fill_dates(&mut t, Some(zone("Europe/Berlin")));
+        assert_eq!(t.booked[0].booking_date.as_deref(), Some("2024-01-15"));
+        assert_eq!(t.booked[0].value_date.as_deref(), Some("2024-01-16"));
+    }
+}
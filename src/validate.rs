@@ -0,0 +1,132 @@
+use crate::ledger_io::Snapshot;
+use beanru::types::{Directive, Ledger};
+use rust_decimal::Decimal;
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+/// A new directive that would make the ledger invalid.
+pub struct Problem {
+    pub path: PathBuf,
+    /// The offending directive, unless the whole file can't be read.
+    pub directive: Option<Directive<Decimal>>,
+    pub message: String,
+}
+
+/// Renders the ledger as it would be written and reads it back with beanru, which validates it.
+/// Returns the files beanru rejects and the new directives (the ones after `original_len` in each
+/// file) it doesn't read back as they were imported.
+pub async fn check_new_directives(
+    ledger: &Ledger<Decimal>,
+    main_path: &Path,
+    original_len: &HashMap<PathBuf, usize>,
+) -> anyhow::Result<Vec<Problem>> {
+    let rendered = Arc::new(Mutex::new(HashMap::new()));
+    ledger
+        .write(|p, content| {
+            rendered.lock().unwrap().insert(p, content);
+            async { Ok(()) }
+        })
+        .await?;
+    let rendered = std::mem::take(&mut *rendered.lock().unwrap());
+    let read =
+        Ledger::<Decimal>::read(main_path.to_path_buf(), |p| {
+            let content = rendered.get(&p).cloned();
+            async move {
+                content.ok_or_else(|| anyhow::format_err!("{} is not in the ledger", p.display()))
+            }
+        })
+        .await;
+    let read = match read {
+        Ok(read) => read,
+        Err(e) => {
+            return Ok(vec![Problem {
+                path: main_path.to_path_buf(),
+                directive: None,
+                message: format!("{:#}", e),
+            }])
+        }
+    };
+
+    let mut problems = vec![];
+    for (path, file) in &ledger.files {
+        let start = original_len.get(path).copied().unwrap_or_default();
+        let read_back = read.files.get(path).map(|f| &f.directives[..]);
+        for (i, d) in file.directives.iter().enumerate().skip(start) {
+            if read_back.and_then(|r| r.get(i)) != Some(d) {
+                problems.push(Problem {
+                    path: path.clone(),
+                    directive: Some(d.clone()),
+                    message: "the directive doesn't read back as it was imported".into(),
+                });
+            }
+        }
+    }
+    Ok(problems)
+}
+
+/// Runs `bean-check` and returns the number of reported errors, together with the output.
+async fn run_bean_check(path: &Path) -> anyhow::Result<(usize, String)> {
+    let output = tokio::process::Command::new("bean-check")
+        .arg(path)
+        .output()
+        .await
+        .map_err(|e| anyhow::format_err!("failed to run bean-check: {}", e))?;
+    let mut out = String::from_utf8_lossy(&output.stdout).to_string();
+    out += &String::from_utf8_lossy(&output.stderr);
+    let error_re = regex::Regex::new(r"(?m)^\S.*:\d+:\s").unwrap();
+    Ok((error_re.find_iter(&out).count(), out))
+}
+
+fn common_root(paths: &[&PathBuf]) -> PathBuf {
+    let mut root = paths
+        .first()
+        .and_then(|p| p.parent())
+        .map(|p| p.to_path_buf())
+        .unwrap_or_default();
+    for p in paths {
+        while !p.starts_with(&root) {
+            if !root.pop() {
+                break;
+            }
+        }
+    }
+    root
+}
+
+/// Writes the ledger to a temporary directory and runs `bean-check` on it. Returns the output of
+/// `bean-check` when the ledger has more errors than the ledger on disk.
+pub async fn bean_check(
+    ledger: &Ledger<Decimal>,
+    snapshot: &Snapshot,
+    main_path: &Path,
+) -> anyhow::Result<Option<String>> {
+    let paths: Vec<&PathBuf> = ledger.files.keys().collect();
+    let root = common_root(&paths);
+    let dir = tempfile::tempdir()?;
+    let tmp = dir.path().to_path_buf();
+    let mirror = |p: &Path| tmp.join(p.strip_prefix(&root).unwrap_or(p));
+
+    ledger
+        .write(|p, content| {
+            let content = snapshot.restore(&p, content);
+            let p = mirror(&p);
+            async move {
+                if let Some(parent) = p.parent() {
+                    tokio::fs::create_dir_all(parent).await?;
+                }
+                Ok(tokio::fs::write(p, content).await?)
+            }
+        })
+        .await?;
+
+    let (before, _) = run_bean_check(main_path).await?;
+    let (after, output) = run_bean_check(&mirror(main_path)).await?;
+    if after > before {
+        Ok(Some(output))
+    } else {
+        Ok(None)
+    }
+}
@@ -0,0 +1,208 @@
+//! The `verify-config` command: checks only the importer configuration of the ledger, without
+//! contacting the API, and reports the problems with their file and line.
+
+use crate::{ledger_config, ledger_io, source::SourceAccount};
+use console::style;
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+/// Metadata keys of the configured accounts read by the importer.
+const KNOWN_KEYS: &[&str] = &[
+    "importer",
+    "account_id",
+    "institution_id",
+    "timezone",
+    "decimal_separator",
+    "iban",
+    "iban_check",
+    "owner_name",
+    "product",
+    "target_file",
+    "currencies",
+    "min_amount",
+    "exclude_narration",
+    "card_pattern",
+    "balance_tolerance",
+    "suspicious_amount",
+    "round_ups",
+    "round_up_pattern",
+    "csv_path",
+    "csv_profile",
+    "camt_path",
+    "mt940_path",
+    "statements_dir",
+];
+
+/// The format of the GoCardless account ids (UUIDs).
+const ACCOUNT_ID_PATTERN: &str =
+    r"^[0-9a-fA-F]{8}-[0-9a-fA-F]{4}-[0-9a-fA-F]{4}-[0-9a-fA-F]{4}-[0-9a-fA-F]{12}$";
+
+struct Problem {
+    file: PathBuf,
+    line: Option<usize>,
+    message: String,
+    /// Warnings are reported, but don't fail the command.
+    warning: bool,
+}
+
+/// Finds the lines of the configuration in the ledger files, which the parsed directives don't
+/// keep.
+#[derive(Default)]
+struct Lines {
+    files: HashMap<PathBuf, Vec<String>>,
+}
+
+impl Lines {
+    fn file(&mut self, path: &Path) -> &[String] {
+        self.files.entry(path.to_path_buf()).or_insert_with(|| {
+            std::fs::read_to_string(path)
+                .unwrap_or_default()
+                .lines()
+                .map(|l| l.to_string())
+                .collect()
+        })
+    }
+
+    /// Index of the `open` or `custom "gocardless" "account"` directive of the account.
+    fn directive(&mut self, a: &SourceAccount) -> Option<usize> {
+        self.file(&a.file).iter().position(|l| {
+            let mut words = l.split_whitespace();
+            let (Some(_date), Some(kind)) = (words.next(), words.next()) else {
+                return false;
+            };
+            match kind {
+                "open" => words.next() == Some(&a.account.0),
+                "custom" => l.contains("\"account\"") && l.contains(&a.account.0),
+                _ => false,
+            }
+        })
+    }
+
+    /// Line (1-based) of the metadata key of the account, or of its directive.
+    fn line(&mut self, a: &SourceAccount, key: Option<&str>) -> Option<usize> {
+        let start = self.directive(a)?;
+        let Some(key) = key else {
+            return Some(start + 1);
+        };
+        let prefix = format!("{}:", key);
+        let found = self.file(&a.file)[start + 1..]
+            .iter()
+            .take_while(|l| l.starts_with(char::is_whitespace) && !l.trim().is_empty())
+            .position(|l| l.trim_start().starts_with(&prefix));
+        Some(found.map_or(start, |i| start + 1 + i) + 1)
+    }
+}
+
+pub async fn run(beancount_path: &Path) -> anyhow::Result<()> {
+    let (ledger, _) = ledger_io::read_ledger(beancount_path.to_path_buf()).await?;
+    let accounts = ledger_config::configured_accounts(&ledger)?;
+    let account_id_re = regex::Regex::new(ACCOUNT_ID_PATTERN).unwrap();
+    let mut lines = Lines::default();
+    let mut problems = vec![];
+    let mut problem = |a: &SourceAccount, key: Option<&str>, message: String, warning: bool| {
+        problems.push(Problem {
+            file: a.file.clone(),
+            line: lines.line(a, key),
+            message: format!("{}: {}", a.account, message),
+            warning,
+        })
+    };
+
+    let mut by_account_id: HashMap<&str, Vec<&SourceAccount>> = HashMap::new();
+    for a in &accounts {
+        let mut keys: Vec<&String> = a.metadata.keys().collect();
+        keys.sort();
+        for key in keys {
+            if !KNOWN_KEYS.contains(&key.as_str()) {
+                problem(
+                    a,
+                    Some(key),
+                    format!("unknown metadata {:?}", key),
+                    // The `open` directives may have metadata of other tools.
+                    !ledger_config::is_custom_account(&ledger, a),
+                );
+            }
+        }
+        let importer = a.metadata_str("importer").unwrap_or_default();
+        match (importer, a.metadata_str("account_id")) {
+            ("gocardless", None) => {
+                problem(a, None, "no account_id".to_string(), false);
+            }
+            (_, Some(id)) if !account_id_re.is_match(id) => {
+                problem(
+                    a,
+                    Some("account_id"),
+                    format!("malformed account_id {:?}, expected a UUID", id),
+                    false,
+                );
+            }
+            (_, Some(id)) => by_account_id.entry(id).or_default().push(a),
+            _ => {}
+        }
+        if let Some(target) = a.metadata_str("target_file") {
+            let path = ledger_config::resolve(&a.file, target);
+            if !path.exists() {
+                problem(
+                    a,
+                    Some("target_file"),
+                    format!("the target file {} doesn't exist", path.display()),
+                    false,
+                );
+            } else if let Err(e) = ledger_config::target_file(&ledger, a) {
+                problem(a, Some("target_file"), format!("{:#}", e), false);
+            }
+        }
+    }
+    // The same account can be imported into several ledger accounts by currency.
+    let mut duplicates: Vec<(&str, Vec<&SourceAccount>)> = by_account_id
+        .into_iter()
+        .filter(|(_, accounts)| {
+            accounts.len() > 1
+                && accounts
+                    .iter()
+                    .any(|a| a.metadata_str("currencies").is_none())
+        })
+        .collect();
+    duplicates.sort_by_key(|(id, _)| *id);
+    for (id, accounts) in duplicates {
+        let names: Vec<&str> = accounts.iter().map(|a| a.account.0.as_str()).collect();
+        for a in accounts {
+            problem(
+                a,
+                Some("account_id"),
+                format!(
+                    "account_id {} is configured for several accounts ({}) without `currencies`",
+                    id,
+                    names.join(", ")
+                ),
+                false,
+            );
+        }
+    }
+
+    problems.sort_by(|a, b| (&a.file, a.line).cmp(&(&b.file, b.line)));
+    let mut errors = 0;
+    for p in &problems {
+        let location = match p.line {
+            Some(line) => format!("{}:{}", p.file.display(), line),
+            None => p.file.display().to_string(),
+        };
+        let kind = if p.warning {
+            style("warning").yellow()
+        } else {
+            errors += 1;
+            style("error").red()
+        };
+        println!("{}: {}: {}", location, kind, p.message);
+    }
+    if errors > 0 {
+        anyhow::bail!("found {} problem(s) in the importer configuration", errors);
+    }
+    println!(
+        "The importer configuration of {} account(s) is valid",
+        accounts.len()
+    );
+    Ok(())
+}
@@ -0,0 +1,52 @@
+//! Golden-file tests of the rendered beancount entries. Each directory in `tests/golden` holds the
+//! recorded response of the transactions request (`transactions.json`), optionally the
+//! categorization rules (`rules.yaml`), and the expected output of `extract` (`expected.beancount`).
+//!
+//! After an intended change of the output, run the tests with `UPDATE_GOLDEN=1` to rewrite the
+//! expected files, and review their diff.
+
+use std::{path::Path, process::Command};
+
+fn extract(case: &Path, home: &Path) -> String {
+    let mut cmd = Command::new(env!("CARGO_BIN_EXE_beancount-gocardless-importer"));
+    // The settings of the user would change the output.
+    cmd.env_clear().env("HOME", home).args([
+        "extract".as_ref(),
+        case.join("transactions.json").as_os_str(),
+        "--account".as_ref(),
+        "Assets:Bank".as_ref(),
+    ]);
+    let rules = case.join("rules.yaml");
+    if rules.exists() {
+        cmd.arg("--rules").arg(rules);
+    }
+    let output = cmd.output().unwrap();
+    assert!(
+        output.status.success(),
+        "extract of {} failed:\n{}",
+        case.display(),
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn golden() {
+    let home = tempfile::tempdir().unwrap();
+    let mut cases: Vec<_> = std::fs::read_dir("tests/golden")
+        .unwrap()
+        .map(|e| e.unwrap().path())
+        .collect();
+    cases.sort();
+    assert!(!cases.is_empty());
+    for case in cases {
+        let actual = extract(&case, home.path());
+        let expected_path = case.join("expected.beancount");
+        if std::env::var_os("UPDATE_GOLDEN").is_some() {
+            std::fs::write(&expected_path, &actual).unwrap();
+            continue;
+        }
+        let expected = std::fs::read_to_string(&expected_path).unwrap();
+        assert_eq!(actual, expected, "{}", case.display());
+    }
+}